@@ -1,6 +1,9 @@
 use anyhow::*;
 use glob::glob;
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 use std::{
     fs::{read_to_string, write, File},
     path::Path,
@@ -114,14 +117,44 @@ fn render_filename(path: &Path) -> String {
         )
 }
 
+/// Reads a setting from an environment variable, using the default if the variable is not set.
+/// The build script is rerun whenever the variable changes.
+fn env_or<T>(name: &str, default: T) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    println!("cargo:rerun-if-env-changed={}", name);
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|error| anyhow!("could not parse {}={:?}: {}", name, value, error)),
+        Err(std::env::VarError::NotPresent) => Ok(default),
+        Err(error) => bail!("could not read {}: {}", name, error),
+    }
+}
+
 fn pack_textures() -> Result<()> {
+    // The packing can be tuned with environment variables, so that projects with more or larger images
+    // don't need to edit this script.
     let config = TexturePackerConfig {
-        max_width: 512,
-        max_height: 512,
-        allow_rotation: false,
-        border_padding: 2,
+        max_width: env_or("QS_ATLAS_MAX_WIDTH", 512)?,
+        max_height: env_or("QS_ATLAS_MAX_HEIGHT", 512)?,
+        allow_rotation: env_or("QS_ATLAS_ALLOW_ROTATION", false)?,
+        border_padding: env_or("QS_ATLAS_BORDER_PADDING", 2)?,
         ..Default::default()
     };
+    println!(
+        "packing UI textures into an atlas of at most {}x{} pixels, with {} pixels of padding{}",
+        config.max_width,
+        config.max_height,
+        config.border_padding,
+        if config.allow_rotation {
+            " and rotation allowed"
+        } else {
+            ""
+        }
+    );
 
     let mut packer = TexturePacker::new_skyline(config);
 
@@ -130,7 +163,16 @@ fn pack_textures() -> Result<()> {
         let texture = ImageImporter::import_from_file(&path).unwrap();
         let canonical_path = path.canonicalize()?;
         let name = canonical_path.strip_prefix(Path::new("./assets_raw/ui/").canonicalize()?)?;
-        packer.pack_own(render_filename(name), texture).unwrap();
+        let name = render_filename(name);
+        if packer.pack_own(name.clone(), texture).is_err() {
+            bail!(
+                "{} does not fit in the {}x{} UI texture atlas; \
+                 set QS_ATLAS_MAX_WIDTH and QS_ATLAS_MAX_HEIGHT to allow a larger atlas",
+                name,
+                config.max_width,
+                config.max_height
+            );
+        }
     }
 
     // Print the information
@@ -178,6 +220,66 @@ fn pack_textures() -> Result<()> {
     let atlas_file = File::create("./assets/ui/atlas.json").unwrap();
     serde_json::to_writer(&atlas_file, &atlas)?;
 
+    write_frame_names(atlas.frames.keys())?;
+
+    Ok(())
+}
+
+/// Converts a frame name such as `button_hovered.png` into a constant name such as `BUTTON_HOVERED`.
+fn frame_identifier(name: &str) -> String {
+    let stem = Path::new(name).with_extension("");
+    let mut identifier: String = render_filename(&stem)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !identifier.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        identifier.insert(0, '_');
+    }
+    identifier
+}
+
+/// Writes a Rust source file containing a constant for each frame name, so that the client can refer to frames
+/// without typos. The file is included by the client's `assets::atlas` module.
+///
+/// If several frames have the same identifier (for example `a-b.png` and `a_b.png`), none of them gets a constant,
+/// and a comment in the generated file lists them.
+fn write_frame_names<'a>(names: impl Iterator<Item = &'a String>) -> Result<()> {
+    let mut identifiers = BTreeMap::<String, Vec<&String>>::new();
+    for name in names {
+        identifiers
+            .entry(frame_identifier(name))
+            .or_default()
+            .push(name);
+    }
+
+    let mut source = String::new();
+    for (identifier, mut names) in identifiers {
+        names.sort();
+        if let [name] = names[..] {
+            source += &format!(
+                "/// The frame packed from `assets_raw/ui/{}`.\npub const {}: &str = {:?};\n",
+                name, identifier, name
+            );
+        } else {
+            println!(
+                "cargo:warning=UI atlas frames {:?} would all be called {}, so none of them has a constant",
+                names, identifier
+            );
+            source += &format!(
+                "// {} is not defined, because the frames {:?} would all have that name.\n",
+                identifier, names
+            );
+        }
+    }
+
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR")?);
+    write(out_dir.join("atlas_frames.rs"), source)?;
     Ok(())
 }
 
@@ -7,6 +7,17 @@ use std::sync::Arc;
 use tokio::io::AsyncReadExt;
 use wgpu::{Device, Queue};
 
+#[cfg(feature = "svg")]
+mod svg;
+#[cfg(feature = "svg")]
+pub use svg::*;
+
+/// The names of the frames in the UI texture atlas, generated by the build script from the images in `assets_raw/ui`.
+/// Using these instead of string literals means that a frame that doesn't exist is a compile error.
+pub mod atlas {
+    include!(concat!(env!("OUT_DIR"), "/atlas_frames.rs"));
+}
+
 /// Loads textures from a file.
 pub struct TextureAssetLoader {
     device: Arc<Device>,
@@ -19,27 +30,75 @@ impl TextureAssetLoader {
     }
 }
 
+/// Opens the file at the given path and passes it to `read` on a thread where blocking is allowed.
+/// This lets synchronous decoders, like those in the `image` crate, read the file a piece at a time,
+/// instead of the whole file being read into memory before it is decoded.
+async fn read_blocking<T: Send + 'static>(
+    path: &AssetPath,
+    read: impl FnOnce(std::io::BufReader<std::fs::File>) -> Result<T, LoadError> + Send + 'static,
+) -> Result<T, LoadError> {
+    let path = path.clone();
+    tokio::task::spawn_blocking(move || match path.resolve().and_then(std::fs::File::open) {
+        Ok(file) => read(std::io::BufReader::new(file)),
+        Err(_) => Err(LoadError::FileNotFound),
+    })
+    .await
+    .expect("asset reading task panicked")
+}
+
+/// Returns the image format that the file at the given path should be in, judging by its extension.
+fn image_format(path: &AssetPath) -> Option<image::ImageFormat> {
+    image::ImageFormat::from_path(path.to_path()).ok()
+}
+
+/// Decodes the image at the given path. If a format is given, the file must be in that format;
+/// otherwise, the format is guessed from the file's contents.
+async fn decode_image(
+    path: &AssetPath,
+    format: Option<image::ImageFormat>,
+) -> Result<image::DynamicImage, LoadError> {
+    let name = format!("{:?}", path);
+    read_blocking(path, move |reader| {
+        let mut reader = image::io::Reader::new(reader);
+        match format {
+            Some(format) => reader.set_format(format),
+            None => {
+                reader = reader
+                    .with_guessed_format()
+                    .map_err(|_| LoadError::FileNotReadable)?
+            }
+        }
+        match reader.decode() {
+            Ok(image) => Ok(image),
+            Err(image::ImageError::IoError(_)) => Err(LoadError::FileNotReadable),
+            Err(error) => {
+                tracing::warn!("could not decode {} as {:?}: {}", name, format, error);
+                Err(LoadError::InvalidData)
+            }
+        }
+    })
+    .await
+}
+
 #[async_trait::async_trait]
 impl Loader<AssetPath, Texture> for TextureAssetLoader {
     async fn load(&self, key: AssetPath) -> Result<Texture, LoadError> {
-        match key.read_file().await {
-            Ok(mut reader) => {
-                let mut result = Vec::new();
-                match reader.read_to_end(&mut result).await {
-                    Ok(_) => {
-                        match Texture::from_bytes(&self.device, &self.queue, &result, "texture") {
-                            Ok(texture) => Ok(texture),
-                            Err(_) => Err(LoadError::InvalidData),
-                        }
-                    }
-                    Err(_) => Err(LoadError::FileNotReadable),
-                }
-            }
-            Err(_) => Err(LoadError::FileNotFound),
-        }
+        let image = decode_image(&key, image_format(&key)).await?;
+        Texture::from_image(&self.device, &self.queue, &image, Some("texture"))
+            .map_err(|_| LoadError::InvalidData)
+    }
+
+    fn approx_bytes(&self, texture: &Texture) -> usize {
+        texture_bytes(texture)
     }
 }
 
+/// Estimates the memory used by a texture's pixels, which are stored as four bytes each.
+fn texture_bytes(texture: &Texture) -> usize {
+    let (width, height) = texture.dimensions;
+    width as usize * height as usize * 4
+}
+
 /// Loads textures and texture atlas data from two files.
 pub struct PartitionedTextureAssetLoader {
     device: Arc<Device>,
@@ -68,42 +127,64 @@ impl Loader<PartitionedTextureAtlasPaths, PartitionedTexture> for PartitionedTex
         let PartitionedTextureAtlasPaths { texture, atlas } = key;
 
         // Read the texture information, then the texture atlas.
-        let base_texture = match texture.read_file().await {
-            Ok(mut reader) => {
-                let mut result = Vec::new();
-                match reader.read_to_end(&mut result).await {
-                    Ok(_) => {
-                        match Texture::from_bytes(&self.device, &self.queue, &result, "texture") {
-                            Ok(texture) => Ok(texture),
-                            Err(_) => Err(LoadError::InvalidData),
-                        }
-                    }
-                    Err(_) => Err(LoadError::FileNotReadable),
-                }
-            }
-            Err(_) => Err(LoadError::FileNotFound),
-        }?;
+        let image = decode_image(&texture, image_format(&texture)).await?;
+        let base_texture = Texture::from_image(&self.device, &self.queue, &image, Some("texture"))
+            .map_err(|_| LoadError::InvalidData)?;
+        drop(image);
 
-        // Read the texture atlas and deserialise it from JSON.
-        let atlas = match atlas.read_file().await {
-            Ok(mut reader) => {
-                let mut result = Vec::new();
-                match reader.read_to_end(&mut result).await {
-                    Ok(_) => match serde_json::from_slice(&result) {
-                        Ok(atlas) => Ok(atlas),
-                        Err(_) => Err(LoadError::InvalidData),
-                    },
-                    Err(_) => Err(LoadError::FileNotReadable),
+        // Deserialise the texture atlas from JSON as it is read.
+        let atlas = read_blocking(&atlas, |reader| {
+            serde_json::from_reader(reader).map_err(|error| {
+                if error.is_io() {
+                    LoadError::FileNotReadable
+                } else {
+                    LoadError::InvalidData
                 }
-            }
-            Err(_) => Err(LoadError::FileNotFound),
-        }?;
+            })
+        })
+        .await?;
 
         Ok(PartitionedTexture {
             base_texture,
             atlas,
         })
     }
+
+    fn approx_bytes(&self, texture: &PartitionedTexture) -> usize {
+        texture_bytes(&texture.base_texture)
+    }
+}
+
+/// Identifies a single font face in a file. Most font files contain one face, but a TrueType collection (`.ttc`)
+/// bundles several, which are numbered from zero.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct FontKey {
+    pub path: AssetPath,
+    pub face_index: u32,
+}
+
+impl FontKey {
+    /// Refers to the face at the given index in a font collection.
+    pub fn face(path: AssetPath, face_index: u32) -> Self {
+        Self { path, face_index }
+    }
+}
+
+/// Refers to the first (usually the only) face in the file.
+impl From<AssetPath> for FontKey {
+    fn from(path: AssetPath) -> Self {
+        Self::face(path, 0)
+    }
+}
+
+/// Returns the number of faces in a font file, which is more than one only for TrueType collections.
+fn face_count(data: &[u8]) -> u32 {
+    match data {
+        [b't', b't', b'c', b'f', _, _, _, _, a, b, c, d, ..] => {
+            u32::from_be_bytes([*a, *b, *c, *d])
+        }
+        _ => 1,
+    }
 }
 
 /// Loads fonts from a file.
@@ -116,18 +197,28 @@ impl Default for FontAssetLoader {
 }
 
 #[async_trait::async_trait]
-impl Loader<AssetPath, Font<'static>> for FontAssetLoader {
-    /// The asset should be a `.ttf` file, not an `.otf` file. This increases
-    /// compatibility with the `rusttype` libary that we use to load fonts.
-    async fn load(&self, key: AssetPath) -> Result<Font<'static>, LoadError> {
-        match key.read_file().await {
+impl Loader<FontKey, Font<'static>> for FontAssetLoader {
+    /// The asset may be a TrueType (`.ttf`) or OpenType (`.otf`) font. OpenType fonts with CFF or CFF2 outlines are supported,
+    /// and variable fonts are loaded at their default instance.
+    /// TrueType collections (`.ttc`) are detected from their contents, and the key's face index chooses which face to load.
+    async fn load(&self, key: FontKey) -> Result<Font<'static>, LoadError> {
+        match key.path.read_file().await {
             Ok(mut reader) => {
                 let mut result = Vec::new();
                 match reader.read_to_end(&mut result).await {
-                    Ok(_) => match Font::try_from_vec(result) {
-                        Some(font) => Ok(font),
-                        None => Err(LoadError::InvalidData),
-                    },
+                    Ok(_) => {
+                        let count = face_count(&result);
+                        if key.face_index >= count {
+                            return Err(LoadError::FaceIndexOutOfRange {
+                                index: key.face_index,
+                                count,
+                            });
+                        }
+                        match Font::try_from_vec_and_index(result, key.face_index) {
+                            Some(font) => Ok(font),
+                            None => Err(LoadError::InvalidData),
+                        }
+                    }
                     Err(_) => Err(LoadError::FileNotReadable),
                 }
             }
@@ -135,3 +226,79 @@ impl Loader<AssetPath, Font<'static>> for FontAssetLoader {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atlas_frames_have_constants() {
+        assert_eq!(atlas::BUTTON, "button.png");
+        assert_eq!(atlas::BUTTON_HOVERED, "button_hovered.png");
+    }
+
+    #[tokio::test]
+    async fn images_are_decoded_from_the_file() {
+        let image = decode_image(&AssetPath::new(vec!["test.png".to_string()]), None)
+            .await
+            .unwrap();
+        assert_eq!(image::GenericImageView::dimensions(&image), (128, 128));
+
+        let missing = decode_image(&AssetPath::new(vec!["missing.png".to_string()]), None).await;
+        assert!(matches!(missing, Err(LoadError::FileNotFound)));
+    }
+
+    #[tokio::test]
+    async fn images_must_be_in_the_format_their_extension_names() {
+        let png = AssetPath::new(vec!["test.png".to_string()]);
+        assert_eq!(image_format(&png), Some(image::ImageFormat::Png));
+        assert_eq!(
+            image_format(&AssetPath::new(vec!["noextension".to_string()])),
+            None
+        );
+
+        let as_jpeg = decode_image(&png, Some(image::ImageFormat::Jpeg)).await;
+        assert!(matches!(as_jpeg, Err(LoadError::InvalidData)));
+    }
+
+    #[tokio::test]
+    async fn otf_fonts_load_and_rasterise() {
+        let font = FontAssetLoader::default()
+            .load(AssetPath::new(vec!["Cantarell-VF.otf".to_string()]).into())
+            .await
+            .expect("could not load .otf font");
+
+        let glyph = font
+            .glyph('a')
+            .scaled(rusttype::Scale::uniform(32.0))
+            .positioned(rusttype::point(0.0, 0.0));
+        let mut covered = 0;
+        glyph.draw(|_, _, coverage| {
+            if coverage > 0.5 {
+                covered += 1;
+            }
+        });
+        assert!(covered > 0, "the glyph has no outline");
+    }
+
+    #[tokio::test]
+    async fn each_face_of_a_collection_loads() {
+        let loader = FontAssetLoader::default();
+        let path = AssetPath::new(vec!["test.ttc".to_string()]);
+        for index in 0..2 {
+            let font = loader
+                .load(FontKey::face(path.clone(), index))
+                .await
+                .unwrap_or_else(|error| panic!("face {} did not load: {:?}", index, error));
+            assert!(font.glyph_count() > 0);
+        }
+
+        match loader.load(FontKey::face(path, 2)).await {
+            Err(LoadError::FaceIndexOutOfRange { index: 2, count: 2 }) => {}
+            other => panic!(
+                "expected an out of range error, got {:?}",
+                other.map(|_| ())
+            ),
+        }
+    }
+}
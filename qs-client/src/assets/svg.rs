@@ -0,0 +1,104 @@
+use super::*;
+
+/// An SVG image, together with the size in pixels to rasterise it at.
+/// The same image may be loaded at several sizes, and each size is a separate asset.
+#[derive(Debug, Eq, PartialEq, Clone, Hash)]
+pub struct SvgTextureKey {
+    pub path: AssetPath,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Loads textures by rasterising `.svg` files.
+pub struct SvgTextureAssetLoader {
+    device: Arc<Device>,
+    queue: Arc<Queue>,
+}
+
+impl SvgTextureAssetLoader {
+    pub fn new(device: Arc<Device>, queue: Arc<Queue>) -> Self {
+        Self { device, queue }
+    }
+}
+
+#[async_trait::async_trait]
+impl Loader<SvgTextureKey, Texture> for SvgTextureAssetLoader {
+    async fn load(&self, key: SvgTextureKey) -> Result<Texture, LoadError> {
+        let SvgTextureKey {
+            path,
+            width,
+            height,
+        } = key;
+        let image = read_blocking(&path, move |mut reader| {
+            let mut data = Vec::new();
+            std::io::Read::read_to_end(&mut reader, &mut data)
+                .map_err(|_| LoadError::FileNotReadable)?;
+            rasterise_svg(&data, width, height)
+        })
+        .await?;
+        Texture::from_image(
+            &self.device,
+            &self.queue,
+            &image::DynamicImage::ImageRgba8(image),
+            Some("svg texture"),
+        )
+        .map_err(|_| LoadError::InvalidData)
+    }
+
+    fn approx_bytes(&self, texture: &Texture) -> usize {
+        texture_bytes(texture)
+    }
+}
+
+/// Parses an SVG document and draws it in the top left corner of an image of the given size,
+/// scaled up or down to be as large as possible without changing its aspect ratio.
+fn rasterise_svg(data: &[u8], width: u32, height: u32) -> Result<image::RgbaImage, LoadError> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default()).map_err(|error| {
+        tracing::warn!("could not parse svg: {}", error);
+        LoadError::InvalidData
+    })?;
+    let size = tree.svg_node().size;
+    let zoom = (width as f64 / size.width()).min(height as f64 / size.height());
+    let mut pixmap = tiny_skia::Pixmap::new(width, height).ok_or(LoadError::InvalidData)?;
+    resvg::render(&tree, usvg::FitTo::Zoom(zoom as f32), pixmap.as_mut())
+        .ok_or(LoadError::InvalidData)?;
+
+    // Pixmaps store premultiplied colours, but textures are uploaded with straight alpha.
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let colour = pixel.demultiply();
+            vec![colour.red(), colour.green(), colour.blue(), colour.alpha()]
+        })
+        .collect();
+    Ok(image::RgbaImage::from_raw(width, height, pixels).expect("pixmap has the wrong size"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rasterising_a_rectangle() {
+        // The rectangle covers the left half of the image.
+        let svg =
+            br#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10" viewBox="0 0 10 10">
+            <rect x="0" y="0" width="5" height="10" fill="red"/>
+        </svg>"#;
+        let image = rasterise_svg(svg, 20, 20).unwrap();
+        let opaque = image.pixels().filter(|pixel| pixel[3] > 0).count();
+        assert_eq!(opaque, 20 * 10);
+        assert_eq!(image.get_pixel(2, 2), &image::Rgba([255, 0, 0, 255]));
+
+        // A wider image leaves the image's aspect ratio alone, so the rectangle is no wider.
+        let image = rasterise_svg(svg, 40, 20).unwrap();
+        let opaque = image.pixels().filter(|pixel| pixel[3] > 0).count();
+        assert_eq!(opaque, 20 * 10);
+
+        assert!(matches!(
+            rasterise_svg(b"not an svg", 20, 20),
+            Err(LoadError::InvalidData)
+        ));
+    }
+}
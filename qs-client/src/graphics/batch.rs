@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use crate::graphics::Texture;
+use crate::graphics::{Texture, Viewport, WorldRect};
 use wgpu::*;
 
 /// The maximum anout of vertices that may be drawn in a single batched draw call.
@@ -9,6 +9,11 @@ use wgpu::*;
 const MAX_VERTEX_COUNT: usize = 40960;
 /// The maximum anout of indices that may be drawn in a single batched draw call.
 const MAX_INDEX_COUNT: usize = 81920;
+/// How many sets of uniforms the uniform buffer can hold. Each call to `render` writes its uniforms into the next slot,
+/// so that a render pass that has been submitted but not yet executed never sees its uniforms overwritten.
+const UNIFORM_SLOT_COUNT: BufferAddress = 16;
+/// The distance between each slot in the uniform buffer. Dynamic offsets must be a multiple of 256 bytes.
+const UNIFORM_SLOT_STRIDE: BufferAddress = 256;
 
 /// This is the internal representation of every vertex that is to be drawn. Per-vertex
 /// colouring is supported, so that (for example) gradients can be easily implemented.
@@ -21,6 +26,7 @@ const MAX_INDEX_COUNT: usize = 81920;
 #[derive(Copy, Clone, Debug)]
 pub struct Vertex {
     pub position: [f32; 3],
+    /// The colour in linear space, which is multiplied with the texture's colour. See `Colour`.
     pub color: [f32; 4],
     pub tex_coords: [f32; 2],
 }
@@ -57,7 +63,7 @@ impl Vertex {
 }
 
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 struct Uniforms {
     combined: cgmath::Matrix4<f32>,
 }
@@ -68,7 +74,7 @@ unsafe impl bytemuck::Zeroable for Uniforms {}
 impl Uniforms {
     pub fn new(camera: &crate::graphics::Camera) -> Self {
         Self {
-            combined: camera.get_projection_matrix() * camera.get_view_matrix(),
+            combined: camera.get_combined_matrix(),
         }
     }
 }
@@ -82,6 +88,114 @@ pub enum Renderable {
     Quadrilateral(Vertex, Vertex, Vertex, Vertex),
 }
 
+impl Renderable {
+    /// The smallest rectangle containing every vertex of this item, ignoring Z. Empty items have no bounds.
+    pub fn bounds(&self) -> Option<WorldRect> {
+        let vertices: &[Vertex] = match self {
+            Renderable::Empty => return None,
+            Renderable::Triangle(v0, v1, v2) => &[*v0, *v1, *v2],
+            Renderable::Quadrilateral(v0, v1, v2, v3) => &[*v0, *v1, *v2, *v3],
+        };
+        let mut bounds = WorldRect {
+            min: cgmath::Point2::new(f32::INFINITY, f32::INFINITY),
+            max: cgmath::Point2::new(f32::NEG_INFINITY, f32::NEG_INFINITY),
+        };
+        for vertex in vertices {
+            bounds.min.x = bounds.min.x.min(vertex.position[0]);
+            bounds.min.y = bounds.min.y.min(vertex.position[1]);
+            bounds.max.x = bounds.max.x.max(vertex.position[0]);
+            bounds.max.y = bounds.max.y.max(vertex.position[1]);
+        }
+        Some(bounds)
+    }
+
+    /// Multiplies the alpha of every vertex of this item.
+    pub fn with_alpha(self, alpha: f32) -> Self {
+        let fade = |mut vertex: Vertex| {
+            vertex.color[3] *= alpha;
+            vertex
+        };
+        match self {
+            Renderable::Empty => Renderable::Empty,
+            Renderable::Triangle(v0, v1, v2) => Renderable::Triangle(fade(v0), fade(v1), fade(v2)),
+            Renderable::Quadrilateral(v0, v1, v2, v3) => {
+                Renderable::Quadrilateral(fade(v0), fade(v1), fade(v2), fade(v3))
+            }
+        }
+    }
+}
+
+/// Counts how much work a batch sent to the graphics card. This is useful for checking how well rendering is batched.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BatchStats {
+    pub draw_calls: u32,
+    pub vertices: u32,
+    pub indices: u32,
+    /// How many times a `MultiBatch` had to render the data it had gathered so far, for example because of a new layer.
+    /// Individual batches do not count flushes.
+    pub flushes: u32,
+    /// How many times the camera uniforms were uploaded. Batches sharing a `UniformBuffer` only upload the camera
+    /// when it differs from the one the previous batch used.
+    pub uniform_writes: u32,
+}
+
+impl std::ops::Add for BatchStats {
+    type Output = BatchStats;
+
+    fn add(self, other: BatchStats) -> BatchStats {
+        BatchStats {
+            draw_calls: self.draw_calls + other.draw_calls,
+            vertices: self.vertices + other.vertices,
+            indices: self.indices + other.indices,
+            flushes: self.flushes + other.flushes,
+            uniform_writes: self.uniform_writes + other.uniform_writes,
+        }
+    }
+}
+
+/// Holds `UNIFORM_SLOT_COUNT` sets of camera uniforms, which are bound using a dynamic offset.
+/// Several batches may share a uniform buffer, so that when they draw with the same camera one after another,
+/// the camera's matrix is only uploaded once.
+pub struct UniformBuffer {
+    buffer: Buffer,
+    /// The slot that was most recently written to, and what was written there.
+    slot: BufferAddress,
+    contents: Option<Uniforms>,
+}
+
+impl UniformBuffer {
+    pub fn new(device: &Device) -> Self {
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("batch_ubo"),
+            size: UNIFORM_SLOT_COUNT * UNIFORM_SLOT_STRIDE,
+            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            buffer,
+            slot: 0,
+            contents: None,
+        }
+    }
+
+    /// Makes sure that the current slot contains the uniforms for this camera, moving on to the next slot
+    /// if it doesn't. Returns the slot, and whether it had to be written.
+    fn write(&mut self, queue: &Queue, camera: &crate::graphics::Camera) -> (BufferAddress, bool) {
+        let uniforms = Uniforms::new(camera);
+        if self.contents == Some(uniforms) {
+            return (self.slot, false);
+        }
+        self.slot = (self.slot + 1) % UNIFORM_SLOT_COUNT;
+        queue.write_buffer(
+            &self.buffer,
+            self.slot * UNIFORM_SLOT_STRIDE,
+            bytemuck::cast_slice(&[uniforms]),
+        );
+        self.contents = Some(uniforms);
+        (self.slot, true)
+    }
+}
+
 /// The `Batch` combines multiple render calls with the same uniform parameters (textures, camera matrix, etc.)
 /// into a single render pass.
 pub struct Batch {
@@ -92,15 +206,30 @@ pub struct Batch {
 
     vertex_buffer: Buffer,
     index_buffer: Buffer,
-    uniform_buffer: Buffer,
+    uniform_buffer: Arc<Mutex<UniformBuffer>>,
+    uniform_bind_group_layout: BindGroupLayout,
+    uniform_bind_group: BindGroup,
+    /// The slot in the uniform buffer that the current call to `render` is using.
+    uniform_slot: BufferAddress,
 
     texture_bind_group_layout: BindGroupLayout,
-    uniform_bind_group_layout: BindGroupLayout,
+
+    /// Everything this batch has drawn since the last call to `reset_stats`.
+    stats: BatchStats,
+
+    /// If true, items which are entirely outside the camera's view are not drawn.
+    cull_offscreen: bool,
+
+    /// The format of the textures that this batch can render to.
+    format: TextureFormat,
 }
 
 impl Batch {
     /// Creates a new batch. Note that allocating enough room on the graphics card to store a batch is a relatively
     /// expensive operation - don't create a batch every frame or just for one object, for example.
+    ///
+    /// The shaders may be included at compile time with `wgpu::include_spirv!`, or loaded at runtime
+    /// with `wgpu::util::make_spirv`.
     pub fn new(
         device: Arc<Device>,
         queue: Arc<Queue>,
@@ -180,12 +309,9 @@ impl Batch {
             mapped_at_creation: false,
         });
 
-        let uniform_buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("batch_ubo"),
-            size: std::mem::size_of::<Uniforms>() as BufferAddress,
-            usage: BufferUsage::UNIFORM | BufferUsage::COPY_DST,
-            mapped_at_creation: false,
-        });
+        let uniform_buffer = UniformBuffer::new(&device);
+        let uniform_bind_group =
+            create_uniform_bind_group(&device, &uniform_bind_group_layout, &uniform_buffer);
 
         Batch {
             device,
@@ -195,18 +321,69 @@ impl Batch {
 
             vertex_buffer,
             index_buffer,
-            uniform_buffer,
+            uniform_buffer: Arc::new(Mutex::new(uniform_buffer)),
+            uniform_bind_group_layout,
+            uniform_bind_group,
+            uniform_slot: 0,
 
             texture_bind_group_layout,
-            uniform_bind_group_layout,
+
+            stats: BatchStats::default(),
+            cull_offscreen: false,
+            format: swap_chain_format,
         }
     }
 
+    /// Returns everything this batch has drawn since the last call to `reset_stats`.
+    pub fn stats(&self) -> BatchStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = BatchStats::default();
+    }
+
+    /// If this is true, `render` skips items that are entirely outside the camera's view, so that they aren't uploaded
+    /// to the graphics card. This is off by default, because working out each item's bounds costs CPU time,
+    /// which is wasted when most things are on screen.
+    pub fn set_cull_offscreen(&mut self, cull_offscreen: bool) {
+        self.cull_offscreen = cull_offscreen;
+    }
+
+    /// The format of the textures that this batch can render to.
+    pub fn format(&self) -> TextureFormat {
+        self.format
+    }
+
+    pub fn device(&self) -> &Arc<Device> {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &Arc<Queue> {
+        &self.queue
+    }
+
+    /// The uniform buffer that this batch writes its camera uniforms to.
+    pub fn uniform_buffer(&self) -> Arc<Mutex<UniformBuffer>> {
+        Arc::clone(&self.uniform_buffer)
+    }
+
+    /// Makes this batch write its camera uniforms to the given buffer, which may be shared with other batches.
+    pub fn set_uniform_buffer(&mut self, uniform_buffer: Arc<Mutex<UniformBuffer>>) {
+        self.uniform_bind_group = create_uniform_bind_group(
+            &self.device,
+            &self.uniform_bind_group_layout,
+            &uniform_buffer.lock().unwrap(),
+        );
+        self.uniform_buffer = uniform_buffer;
+    }
+
     /// Renders the contents of the `verts` and `inds` buffers to the screen.
     #[inline(always)]
     fn flush(
         &mut self,
-        frame: &SwapChainTexture,
+        frame: &TextureView,
+        viewport: Option<Viewport>,
 
         texture: &Texture,
 
@@ -214,6 +391,10 @@ impl Batch {
         inds: &mut Vec<u16>,
     ) {
         if !inds.is_empty() {
+            self.stats.draw_calls += 1;
+            self.stats.vertices += verts.len() as u32;
+            self.stats.indices += inds.len() as u32;
+
             if inds.len() % 2 == 1 {
                 inds.push(0); // dummy value to align the slice to a size that is a multiple of 4 bytes
             }
@@ -243,22 +424,11 @@ impl Batch {
                         label: Some("texture_bind_group"),
                     });
 
-                // Describe how we want to send the uniforms to the GPU.
-                let uniform_bind_group =
-                    self.device.create_bind_group(&wgpu::BindGroupDescriptor {
-                        layout: &self.uniform_bind_group_layout,
-                        entries: &[wgpu::BindGroupEntry {
-                            binding: 0,
-                            resource: wgpu::BindingResource::Buffer(self.uniform_buffer.slice(..)),
-                        }],
-                        label: Some("uniform_bind_group"),
-                    });
-
                 // Begin recording a render pass. When we drop this struct, `wgpu` will finish recording.
                 // This allows us to send this recorded list of commands to the GPU.
                 let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                     color_attachments: &[wgpu::RenderPassColorAttachmentDescriptor {
-                        attachment: &frame.view,
+                        attachment: frame,
                         resolve_target: None,
                         ops: wgpu::Operations {
                             load: wgpu::LoadOp::Load,
@@ -268,9 +438,23 @@ impl Batch {
                     depth_stencil_attachment: None,
                 });
                 render_pass.set_pipeline(&self.render_pipeline);
+                if let Some(viewport) = viewport {
+                    render_pass.set_viewport(
+                        viewport.x,
+                        viewport.y,
+                        viewport.width,
+                        viewport.height,
+                        0.0,
+                        1.0,
+                    );
+                }
 
                 render_pass.set_bind_group(0, &texture_bind_group, &[]);
-                render_pass.set_bind_group(1, &uniform_bind_group, &[]);
+                render_pass.set_bind_group(
+                    1,
+                    &self.uniform_bind_group,
+                    &[(self.uniform_slot * UNIFORM_SLOT_STRIDE) as DynamicOffset],
+                );
 
                 render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
                 render_pass.set_index_buffer(self.index_buffer.slice(..));
@@ -300,7 +484,8 @@ impl Batch {
     #[allow(clippy::too_many_arguments)] // We're going to ignore clippy's suggestion here because the function is internal and inline always.
     fn ensure_capacity(
         &mut self,
-        frame: &SwapChainTexture,
+        frame: &TextureView,
+        viewport: Option<Viewport>,
 
         texture: &Texture,
 
@@ -311,13 +496,16 @@ impl Batch {
         new_inds: usize,
     ) {
         if verts.len() + new_verts > MAX_VERTEX_COUNT || inds.len() + new_inds > MAX_INDEX_COUNT {
-            self.flush(frame, texture, verts, inds);
+            self.flush(frame, viewport, texture, verts, inds);
         }
     }
 
+    /// Renders the given items to the frame. If a viewport is given, rendering is confined to that part of the frame.
+    /// The viewport must lie inside the frame; see `Viewport::clamp_to`.
     pub fn render(
         &mut self,
-        frame: &SwapChainTexture,
+        frame: &TextureView,
+        viewport: Option<Viewport>,
 
         texture: &Texture,
         camera: &crate::graphics::Camera,
@@ -327,15 +515,32 @@ impl Batch {
         let mut verts = Vec::<Vertex>::new();
         let mut inds = Vec::<u16>::new();
 
-        let uniforms = Uniforms::new(camera);
-        self.queue
-            .write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniforms]));
+        let (uniform_slot, written) = self
+            .uniform_buffer
+            .lock()
+            .unwrap()
+            .write(&self.queue, camera);
+        self.uniform_slot = uniform_slot;
+        if written {
+            self.stats.uniform_writes += 1;
+        }
 
+        let view = if self.cull_offscreen {
+            Some(camera.visible_rect())
+        } else {
+            None
+        };
         for renderable in items {
+            if let Some(view) = &view {
+                match renderable.bounds() {
+                    Some(bounds) if bounds.overlaps(view) => {}
+                    _ => continue,
+                }
+            }
             match renderable {
                 Renderable::Empty => {}
                 Renderable::Triangle(v0, v1, v2) => {
-                    self.ensure_capacity(frame, texture, &mut verts, &mut inds, 3, 3);
+                    self.ensure_capacity(frame, viewport, texture, &mut verts, &mut inds, 3, 3);
                     let i0 = verts.len() as u16;
                     verts.push(v0);
                     verts.push(v1);
@@ -345,7 +550,7 @@ impl Batch {
                     inds.push(i0 + 2);
                 }
                 Renderable::Quadrilateral(v0, v1, v2, v3) => {
-                    self.ensure_capacity(frame, texture, &mut verts, &mut inds, 4, 6);
+                    self.ensure_capacity(frame, viewport, texture, &mut verts, &mut inds, 4, 6);
                     let i0 = verts.len() as u16;
                     verts.push(v0);
                     verts.push(v1);
@@ -361,6 +566,106 @@ impl Batch {
             }
         }
 
-        self.flush(frame, texture, &mut verts, &mut inds);
+        self.flush(frame, viewport, texture, &mut verts, &mut inds);
+    }
+}
+
+/// Describes how we want to send the uniforms to the GPU.
+/// The bind group only covers a single slot; the slot is chosen using a dynamic offset.
+fn create_uniform_bind_group(
+    device: &Device,
+    layout: &BindGroupLayout,
+    uniform_buffer: &UniformBuffer,
+) -> BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: wgpu::BindingResource::Buffer(
+                uniform_buffer
+                    .buffer
+                    .slice(0..std::mem::size_of::<Uniforms>() as BufferAddress),
+            ),
+        }],
+        label: Some("uniform_bind_group"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::multi_batch::{
+        texture_bind_group_layout_descriptor, uniform_bind_group_layout_descriptor,
+    };
+    use crate::graphics::{Camera, CameraData, GraphicsContext, RenderTarget};
+    use cgmath::Point2;
+
+    fn tile(x: f32, y: f32) -> Renderable {
+        let vertex = |x, y| Vertex {
+            position: [x, y, 0.0],
+            color: [1.0; 4],
+            tex_coords: [0.0, 0.0],
+        };
+        Renderable::Quadrilateral(
+            vertex(x, y),
+            vertex(x + 1.0, y),
+            vertex(x + 1.0, y + 1.0),
+            vertex(x, y + 1.0),
+        )
+    }
+
+    /// A camera looking at the ten by ten units nearest the origin of a hundred by hundred grid of tiles.
+    fn grid_camera() -> Camera {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(-5.0, -5.0),
+            view_height: 10.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        camera.update_window_size(100, 100);
+        camera
+    }
+
+    fn grid() -> Vec<Renderable> {
+        (0..100)
+            .flat_map(|x| (0..100).map(move |y| tile(x as f32, y as f32)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn offscreen_tiles_are_culled() {
+        let context = match GraphicsContext::headless().await {
+            Some(context) => context,
+            None => return,
+        };
+        let (device, queue) = (&context.device, &context.queue);
+        let mut batch = Batch::new(
+            Arc::clone(device),
+            Arc::clone(queue),
+            wgpu::include_spirv!("shader.vert.spv"),
+            wgpu::include_spirv!("shader.frag.spv"),
+            device.create_bind_group_layout(&texture_bind_group_layout_descriptor()),
+            device.create_bind_group_layout(&uniform_bind_group_layout_descriptor()),
+            RenderTarget::FORMAT,
+        );
+        let texture =
+            Texture::from_image(device, queue, &image::DynamicImage::new_rgba8(1, 1), None)
+                .unwrap();
+        let target = RenderTarget::new(device, 8, 8);
+        let camera = grid_camera();
+
+        let mut vertices_drawn = |cull_offscreen| {
+            batch.set_cull_offscreen(cull_offscreen);
+            batch.reset_stats();
+            batch.render(target.view(), None, &texture, &camera, grid().into_iter());
+            batch.stats().vertices
+        };
+        assert_eq!(vertices_drawn(false), 4 * 100 * 100);
+        // Tiles that only touch the edge of the view are still drawn.
+        assert_eq!(vertices_drawn(true), 4 * 11 * 11);
+        assert!(Renderable::Empty.bounds().is_none());
     }
 }
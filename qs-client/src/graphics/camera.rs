@@ -1,6 +1,6 @@
 use std::sync::RwLock;
 
-use cgmath::{ortho, prelude::*, Matrix4, Point2};
+use cgmath::{ortho, prelude::*, Matrix4, Point2, Vector2, Vector4};
 
 /// The Z axis is expected to be in range 0.0 to 1.0, not -1.0 to 1.0.
 /// Multiplying on the left by this matrix converts OpenGL style matrices into `wgpu` style matrices.
@@ -12,6 +12,7 @@ pub const OPENGL_TO_WGPU_MATRIX: cgmath::Matrix4<f32> = cgmath::Matrix4::new(
     0.0, 0.0, 0.5, 1.0,
 );
 
+#[derive(Debug, Clone)]
 pub enum CameraData {
     Orthographic {
         /// Where is the eye in 2D space?
@@ -20,30 +21,45 @@ pub enum CameraData {
         view_height: f32,
         /// What is the width/height of the render area?
         aspect_ratio: f32,
+        /// How many device pixels high is the render area? This is set by `update_window_size`.
+        window_height: u32,
+        /// If true, the eye is rounded to the nearest device pixel when generating the view matrix,
+        /// so that pixel art is rendered crisply even when the eye is at a fractional position.
+        pixel_snap: bool,
+        /// The distances to the near and far clipping planes. The camera looks towards negative Z,
+        /// so a vertex is visible if its Z coordinate is between `-far` and `-near`.
+        /// Usually these are `DEFAULT_NEAR` and `DEFAULT_FAR`.
+        near: f32,
+        far: f32,
     },
 }
 
 impl CameraData {
+    /// The near clipping plane used by default, which lets through vertices with Z coordinates up to 1000.
+    pub const DEFAULT_NEAR: f32 = -1000.0;
+    /// The far clipping plane used by default, which lets through vertices with Z coordinates down to -1000.
+    pub const DEFAULT_FAR: f32 = 1000.0;
+
     pub fn generate_projection_matrix(&self) -> Matrix4<f32> {
         match self {
             CameraData::Orthographic {
                 view_height,
                 aspect_ratio,
+                near,
+                far,
                 ..
             } => {
                 let width = aspect_ratio * view_height;
                 let half_width = 0.5 * width;
                 let half_height = 0.5 * view_height;
-                let near = -1000.0;
-                let far = 1000.0;
                 OPENGL_TO_WGPU_MATRIX
                     * ortho(
                         -half_width,
                         half_width,
                         -half_height,
                         half_height,
-                        near,
-                        far,
+                        *near,
+                        *far,
                     )
             }
         }
@@ -51,40 +67,110 @@ impl CameraData {
 
     pub fn generate_view_matrix(&self) -> Matrix4<f32> {
         match self {
-            CameraData::Orthographic { eye, .. } => {
-                Matrix4::from_translation(eye.to_vec().extend(0.0))
+            CameraData::Orthographic {
+                eye,
+                view_height,
+                window_height,
+                pixel_snap,
+                ..
+            } => {
+                let mut translation = eye.to_vec();
+                if *pixel_snap && *window_height > 0 {
+                    let pixels_per_unit = *window_height as f32 / view_height;
+                    translation =
+                        translation.map(|x| (x * pixels_per_unit).round() / pixels_per_unit);
+                }
+                Matrix4::from_translation(translation.extend(0.0))
             }
         }
     }
 
     pub fn update_window_size(&mut self, width: u32, height: u32) {
         match self {
-            CameraData::Orthographic { aspect_ratio, .. } => {
+            CameraData::Orthographic {
+                aspect_ratio,
+                window_height,
+                ..
+            } => {
                 *aspect_ratio = width as f32 / height as f32;
+                *window_height = height;
             }
         }
     }
 }
 
+/// A rectangle in world coordinates.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct WorldRect {
+    pub min: Point2<f32>,
+    pub max: Point2<f32>,
+}
+
+impl WorldRect {
+    /// Do these rectangles share any points? Rectangles that only touch along an edge count as overlapping.
+    pub fn overlaps(&self, other: &WorldRect) -> bool {
+        self.min.x <= other.max.x
+            && other.min.x <= self.max.x
+            && self.min.y <= other.max.y
+            && other.min.y <= self.max.y
+    }
+}
+
 pub struct Camera {
     data: CameraData,
+    /// If set, the eye is moved so that the camera never shows anything outside this rectangle.
+    bounds: Option<WorldRect>,
 
     /// Caches the value of the camera's projection matrix.
     projection_matrix: RwLock<Option<Matrix4<f32>>>,
     /// Caches the value of the camera's view matrix.
     view_matrix: RwLock<Option<Matrix4<f32>>>,
+
+    /// Applied to every vertex before the view matrix. See `transformed`.
+    transform: Matrix4<f32>,
+
+    /// Where the area this camera renders to starts, in device pixels from the top left corner of the window.
+    /// This is only non-zero if the view is letterboxed. See `set_viewport_origin`.
+    viewport_origin: Point2<f32>,
 }
 
 impl Camera {
     pub fn new(data: CameraData) -> Camera {
         Camera {
             data,
+            bounds: None,
 
             projection_matrix: RwLock::new(None),
             view_matrix: RwLock::new(None),
+            transform: Matrix4::identity(),
+            viewport_origin: Point2::new(0.0, 0.0),
+        }
+    }
+
+    /// Returns a copy of this camera that draws everything transformed by `matrix` first, for example to slide or scale
+    /// a UI panel without laying it out again. Transforming a transformed camera applies the new matrix before the old one,
+    /// so that nested transforms compose like nested coordinate systems.
+    pub fn transformed(&self, matrix: Matrix4<f32>) -> Camera {
+        Camera {
+            data: self.data.clone(),
+            bounds: self.bounds,
+            projection_matrix: RwLock::new(*self.projection_matrix.read().unwrap()),
+            view_matrix: RwLock::new(*self.view_matrix.read().unwrap()),
+            transform: self.transform * matrix,
+            viewport_origin: self.viewport_origin,
         }
     }
 
+    /// The matrix applied to every vertex before the view matrix. This is the identity unless the camera was `transformed`.
+    pub fn get_transform(&self) -> Matrix4<f32> {
+        self.transform
+    }
+
+    /// Converts vertex positions into clip space. This is the projection, view and transform matrices multiplied together.
+    pub fn get_combined_matrix(&self) -> Matrix4<f32> {
+        self.get_projection_matrix() * self.get_view_matrix() * self.transform
+    }
+
     pub fn get_projection_matrix(&self) -> Matrix4<f32> {
         let mut proj = self.projection_matrix.write().unwrap();
         match *proj {
@@ -114,6 +200,7 @@ impl Camera {
     }
 
     /// Deletes all the caches for known matrices.
+    /// Changes made through this reference are not clamped to the camera's bounds.
     pub fn get_data_mut(&mut self) -> &mut CameraData {
         *self.projection_matrix.write().unwrap() = None;
         *self.view_matrix.write().unwrap() = None;
@@ -122,5 +209,347 @@ impl Camera {
 
     pub fn update_window_size(&mut self, width: u32, height: u32) {
         self.get_data_mut().update_window_size(width, height);
+        self.clamp_to_bounds();
+    }
+
+    /// Tells the camera where the area it renders to starts in the window, in device pixels from the top left corner,
+    /// so that `screen_to_world` can convert positions inside a letterboxed view.
+    pub fn set_viewport_origin(&mut self, origin: Point2<f32>) {
+        self.viewport_origin = origin;
+    }
+
+    /// Moves the eye, keeping the view inside the camera's bounds.
+    pub fn set_eye(&mut self, new_eye: Point2<f32>) {
+        let CameraData::Orthographic { eye, .. } = self.get_data_mut();
+        *eye = new_eye;
+        self.clamp_to_bounds();
+    }
+
+    /// Stops the camera from showing anything outside the given rectangle, or lets it move freely if this is `None`.
+    /// If the view is larger than the bounds, it is centred on them.
+    pub fn set_bounds(&mut self, bounds: Option<WorldRect>) {
+        self.bounds = bounds;
+        self.clamp_to_bounds();
+    }
+
+    /// Moves the eye so that the view is inside the bounds.
+    fn clamp_to_bounds(&mut self) {
+        let bounds = match self.bounds {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let CameraData::Orthographic {
+            eye,
+            view_height,
+            aspect_ratio,
+            ..
+        } = self.get_data_mut();
+        let half_size = Vector2::new(0.5 * *aspect_ratio * *view_height, 0.5 * *view_height);
+        let clamp_axis = |centre: f32, half_size: f32, min: f32, max: f32| {
+            if max - min < 2.0 * half_size {
+                0.5 * (min + max)
+            } else {
+                centre.max(min + half_size).min(max - half_size)
+            }
+        };
+        // The view matrix translates the world by the eye, so the view is centred on `-eye`.
+        *eye = Point2::new(
+            -clamp_axis(-eye.x, half_size.x, bounds.min.x, bounds.max.x),
+            -clamp_axis(-eye.y, half_size.y, bounds.min.y, bounds.max.y),
+        );
+    }
+
+    /// Converts a position in the window, in device pixels from the top left corner, into world coordinates.
+    /// This uses the window size last given to `update_window_size` and the origin given to `set_viewport_origin`.
+    /// Returns `None` if the window has no area, or if the camera's transform squashes everything flat.
+    pub fn screen_to_world(&self, screen: Point2<f32>) -> Option<Point2<f32>> {
+        let CameraData::Orthographic {
+            aspect_ratio,
+            window_height,
+            ..
+        } = self.data;
+        if window_height == 0 {
+            return None;
+        }
+        let window_height = window_height as f32;
+        let window_width = aspect_ratio * window_height;
+        let screen = screen - self.viewport_origin.to_vec();
+        let ndc = Vector4::new(
+            2.0 * screen.x / window_width - 1.0,
+            1.0 - 2.0 * screen.y / window_height,
+            0.0,
+            1.0,
+        );
+        let world = self.get_combined_matrix().invert()? * ndc;
+        Some(Point2::new(world.x, world.y))
+    }
+
+    /// The rectangle of the world that this camera can see. If the camera is transformed, this is the smallest
+    /// rectangle containing everything visible before the transform is applied, and if the transform squashes
+    /// everything flat, nothing is visible.
+    pub fn visible_rect(&self) -> WorldRect {
+        let mut rect = WorldRect {
+            min: Point2::new(f32::INFINITY, f32::INFINITY),
+            max: Point2::new(f32::NEG_INFINITY, f32::NEG_INFINITY),
+        };
+        let inverse = match self.get_combined_matrix().invert() {
+            Some(inverse) => inverse,
+            None => return rect,
+        };
+        for &(x, y) in &[(-1.0, -1.0), (1.0, -1.0), (-1.0, 1.0), (1.0, 1.0)] {
+            let corner = inverse * Vector4::new(x, y, 0.0, 1.0);
+            rect.min = Point2::new(rect.min.x.min(corner.x), rect.min.y.min(corner.y));
+            rect.max = Point2::new(rect.max.x.max(corner.x), rect.max.y.max(corner.y));
+        }
+        rect
+    }
+
+    /// Zooms in by the given factor, so that everything looks `factor` times larger. Factors less than one zoom out.
+    /// The eye stays where it is, so the centre of the view doesn't move.
+    ///
+    /// Panics if `factor` is not positive.
+    pub fn zoom(&mut self, factor: f32) {
+        assert!(
+            factor > 0.0,
+            "zoom factors must be positive, not {}",
+            factor
+        );
+        let CameraData::Orthographic { view_height, .. } = self.get_data_mut();
+        *view_height /= factor;
+        self.clamp_to_bounds();
+    }
+
+    /// Sets how many device pixels high each unit of the world appears, based on the current window size.
+    ///
+    /// Panics if `pixels_per_unit` is not positive.
+    pub fn set_zoom(&mut self, pixels_per_unit: f32) {
+        assert!(
+            pixels_per_unit > 0.0,
+            "zoom factors must be positive, not {}",
+            pixels_per_unit
+        );
+        let CameraData::Orthographic {
+            view_height,
+            window_height,
+            ..
+        } = self.get_data_mut();
+        *view_height = *window_height as f32 / pixels_per_unit;
+        self.clamp_to_bounds();
+    }
+
+    /// Zooms in by the given factor, like `zoom`, but keeps the world position under the given point in the window
+    /// where it is on screen. This is how maps and editors usually zoom towards the mouse.
+    /// If the point can't be converted into world coordinates, this zooms towards the centre of the view instead.
+    pub fn zoom_to_point(&mut self, screen: Point2<f32>, factor: f32) {
+        let before = self.screen_to_world(screen);
+        self.zoom(factor);
+        let after = self.screen_to_world(screen);
+        if let (Some(before), Some(after)) = (before, after) {
+            // The view matrix translates the world by the eye, so moving the eye moves the world under the point the other way.
+            let CameraData::Orthographic { eye, .. } = self.get_data_mut();
+            *eye += after - before;
+            self.clamp_to_bounds();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_snap_rounds_eye_to_whole_pixels() {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(0.33, -0.27),
+            view_height: 2.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: true,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        // Each unit of the view is five pixels high.
+        camera.update_window_size(10, 10);
+
+        let translation = camera.get_view_matrix().w;
+        assert!((translation.x - 0.4).abs() < 1e-6);
+        assert!((translation.y + 0.2).abs() < 1e-6);
+
+        // The logical position of the camera is unchanged.
+        let CameraData::Orthographic { eye, .. } = camera.get_data();
+        assert_eq!(*eye, Point2::new(0.33, -0.27));
+    }
+
+    #[test]
+    fn nested_transforms_compose() {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(0.0, 0.0),
+            view_height: 2.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        camera.update_window_size(10, 10);
+
+        // Scaling by two, then moving right by a quarter of the view.
+        let transformed = camera
+            .transformed(Matrix4::from_translation(cgmath::Vector3::new(
+                0.5, 0.0, 0.0,
+            )))
+            .transformed(Matrix4::from_scale(2.0));
+        let clip = transformed.get_combined_matrix() * Vector4::new(0.25, 0.25, 0.0, 1.0);
+        assert!((clip.x - 1.0).abs() < 1e-6 && (clip.y - 0.5).abs() < 1e-6);
+
+        // Culling happens before the transform, so the visible area shrinks and moves the other way.
+        let rect = transformed.visible_rect();
+        assert!((rect.min.x + 0.75).abs() < 1e-6 && (rect.max.x - 0.25).abs() < 1e-6);
+        assert!((rect.min.y + 0.5).abs() < 1e-6 && (rect.max.y - 0.5).abs() < 1e-6);
+
+        // Nothing is visible through a transform that squashes everything into a point.
+        let squashed = camera.transformed(Matrix4::from_scale(0.0));
+        assert!(!squashed.visible_rect().overlaps(&camera.visible_rect()));
+    }
+
+    #[test]
+    fn far_plane_changes_projected_depth() {
+        let camera = |far| {
+            Camera::new(CameraData::Orthographic {
+                eye: Point2::new(0.0, 0.0),
+                view_height: 2.0,
+                aspect_ratio: 1.0,
+                window_height: 0,
+                pixel_snap: false,
+                near: CameraData::DEFAULT_NEAR,
+                far,
+            })
+        };
+        let depth = |camera: Camera| {
+            let projected =
+                camera.get_projection_matrix() * cgmath::Vector4::new(0.0, 0.0, -500.0, 1.0);
+            projected.z / projected.w
+        };
+
+        // The vertex is three quarters of the way from the near plane to the far plane.
+        assert!((depth(camera(CameraData::DEFAULT_FAR)) - 0.75).abs() < 1e-6);
+        // Moving the far plane further away brings it closer to the near plane in depth.
+        assert!((depth(camera(3000.0)) - 0.375).abs() < 1e-6);
+    }
+
+    #[test]
+    fn zooming_to_a_point_keeps_it_still() {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(3.0, -1.0),
+            view_height: 10.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        camera.update_window_size(200, 100);
+
+        let cursor = Point2::new(150.0, 20.0);
+        let world = camera.screen_to_world(cursor).unwrap();
+        camera.zoom_to_point(cursor, 2.0);
+        let CameraData::Orthographic { view_height, .. } = camera.get_data();
+        assert_eq!(*view_height, 5.0);
+        let moved = camera.screen_to_world(cursor).unwrap();
+        assert!((moved.x - world.x).abs() < 1e-4 && (moved.y - world.y).abs() < 1e-4);
+
+        // Ten pixels per unit in a window a hundred pixels high shows ten units.
+        camera.set_zoom(10.0);
+        let CameraData::Orthographic { view_height, .. } = camera.get_data();
+        assert_eq!(*view_height, 10.0);
+    }
+
+    #[test]
+    fn screen_positions_are_relative_to_the_viewport() {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(0.0, 0.0),
+            view_height: 10.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        // Until the camera knows the window size, no position in it can be converted.
+        assert_eq!(camera.screen_to_world(Point2::new(0.0, 0.0)), None);
+
+        // A square view letterboxed in a window 200 pixels wide and 100 pixels high starts 50 pixels from the left.
+        camera.update_window_size(100, 100);
+        camera.set_viewport_origin(Point2::new(50.0, 0.0));
+        let centre = camera.screen_to_world(Point2::new(100.0, 50.0)).unwrap();
+        assert!(centre.x.abs() < 1e-4 && centre.y.abs() < 1e-4);
+        let top_left = camera.screen_to_world(Point2::new(50.0, 0.0)).unwrap();
+        assert!((top_left.x + 5.0).abs() < 1e-4 && (top_left.y - 5.0).abs() < 1e-4);
+    }
+
+    #[test]
+    #[should_panic(expected = "zoom factors must be positive")]
+    fn zooming_by_zero_panics() {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(0.0, 0.0),
+            view_height: 10.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        camera.zoom(0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "zoom factors must be positive")]
+    fn negative_pixels_per_unit_panic() {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(0.0, 0.0),
+            view_height: 10.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        camera.update_window_size(100, 100);
+        camera.set_zoom(-1.0);
+    }
+
+    #[test]
+    fn bounds_keep_the_view_inside() {
+        let mut camera = Camera::new(CameraData::Orthographic {
+            eye: Point2::new(0.0, 0.0),
+            view_height: 10.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        camera.update_window_size(100, 100);
+        camera.set_bounds(Some(WorldRect {
+            min: Point2::new(0.0, 0.0),
+            max: Point2::new(40.0, 20.0),
+        }));
+        let corners = |camera: &Camera| {
+            (
+                camera.screen_to_world(Point2::new(0.0, 100.0)).unwrap(),
+                camera.screen_to_world(Point2::new(100.0, 0.0)).unwrap(),
+            )
+        };
+
+        camera.set_eye(Point2::new(100.0, 100.0));
+        let (bottom_left, top_right) = corners(&camera);
+        assert!((bottom_left.x - 0.0).abs() < 1e-4 && (bottom_left.y - 0.0).abs() < 1e-4);
+        assert!((top_right.x - 10.0).abs() < 1e-4 && (top_right.y - 10.0).abs() < 1e-4);
+
+        // Zooming out until the view is taller than the bounds centres it vertically.
+        camera.zoom(0.25);
+        let (bottom_left, top_right) = corners(&camera);
+        assert!((bottom_left.y + 10.0).abs() < 1e-4 && (top_right.y - 30.0).abs() < 1e-4);
+        assert!((bottom_left.x - 0.0).abs() < 1e-4 && (top_right.x - 40.0).abs() < 1e-4);
     }
 }
@@ -0,0 +1,554 @@
+use std::sync::Arc;
+use wgpu::*;
+
+use crate::ui::Colour;
+
+/// Chooses which graphics card the application renders with, and which graphics APIs it may use to do so.
+#[derive(Debug, Copy, Clone)]
+pub struct ApplicationConfig {
+    /// Whether to prefer a discrete graphics card (`HighPerformance`) or an integrated one (`LowPower`).
+    pub power_preference: PowerPreference,
+    /// The graphics APIs to look for graphics cards with. Setting this to a single backend, such as `BackendBit::VULKAN`,
+    /// can help with debugging.
+    pub backends: BackendBit,
+    /// How glyphs are filtered when text is drawn. `Nearest` keeps small text crisp, while `Linear` looks smoother
+    /// when text is scaled or drawn at fractional positions. See `TextRenderer::set_glyph_filter`.
+    pub glyph_filter: FilterMode,
+}
+
+impl Default for ApplicationConfig {
+    fn default() -> Self {
+        Self {
+            power_preference: PowerPreference::Default,
+            // Vulkan, Metal and DX12.
+            backends: BackendBit::PRIMARY,
+            glyph_filter: FilterMode::Nearest,
+        }
+    }
+}
+
+/// Why we couldn't connect to a graphics card.
+#[derive(Debug)]
+pub enum ContextError {
+    /// No graphics card supports the requested backends (and can present to the surface, if one was given).
+    NoSuitableAdapter,
+    /// A graphics card was found, but refused to give us a device.
+    RequestDevice(RequestDeviceError),
+}
+
+impl std::fmt::Display for ContextError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContextError::NoSuitableAdapter => {
+                write!(f, "no graphics card supports the requested backends")
+            }
+            ContextError::RequestDevice(_) => {
+                write!(f, "the graphics card refused to give us a device")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ContextError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ContextError::NoSuitableAdapter => None,
+            ContextError::RequestDevice(error) => Some(error),
+        }
+    }
+}
+
+/// Finds the graphics card that `GraphicsContext::new` connects to. This is a wgpu `Instance`,
+/// except in tests, which can check which graphics card is asked for without needing one.
+pub trait AdapterSource {
+    fn request_adapter(
+        &self,
+        options: &RequestAdapterOptions<'_>,
+    ) -> futures::future::BoxFuture<'static, Option<Adapter>>;
+}
+
+impl AdapterSource for Instance {
+    fn request_adapter(
+        &self,
+        options: &RequestAdapterOptions<'_>,
+    ) -> futures::future::BoxFuture<'static, Option<Adapter>> {
+        Box::pin(Instance::request_adapter(self, options))
+    }
+}
+
+/// Describes the graphics card that `GraphicsContext::new` asks for.
+fn adapter_options(
+    power_preference: PowerPreference,
+    compatible_surface: Option<&Surface>,
+) -> RequestAdapterOptions<'_> {
+    RequestAdapterOptions {
+        power_preference,
+        compatible_surface,
+    }
+}
+
+/// Describes the graphics card that a `GraphicsContext` is connected to, which is useful to know in bug reports.
+#[derive(Debug, Clone)]
+pub struct AdapterDetails {
+    /// The graphics card's name and type, and the backend used to talk to it.
+    pub info: AdapterInfo,
+    /// The limits and features of the device we were given, not everything that the graphics card supports.
+    pub limits: Limits,
+    pub features: Features,
+}
+
+impl AdapterDetails {
+    /// Is this a software renderer, such as llvmpipe, which runs on the CPU? These usually work, but are very slow.
+    pub fn is_software(&self) -> bool {
+        let name = self.info.name.to_lowercase();
+        self.info.device_type == DeviceType::Cpu
+            || ["llvmpipe", "softpipe", "swiftshader", "basic render driver"]
+                .iter()
+                .any(|software| name.contains(software))
+    }
+
+    /// Writes the details to the log, warning if this is a software renderer.
+    pub fn log(&self) {
+        tracing::info!(
+            "rendering with {} ({:?}, {:?} backend), limits {:?}, features {:?}",
+            self.info.name,
+            self.info.device_type,
+            self.info.backend,
+            self.limits,
+            self.features
+        );
+        if self.is_software() {
+            tracing::warn!(
+                "{} is a software renderer, so rendering will be slow; check that your graphics drivers are installed",
+                self.info.name
+            );
+        }
+    }
+}
+
+/// Encapsulates the handles to the graphics card that are needed to render anything.
+pub struct GraphicsContext {
+    /// Device is a connection to the graphics card.
+    pub device: Arc<Device>,
+    /// The queue allows us to send commands to the device, which are executed asynchronously.
+    pub queue: Arc<Queue>,
+    pub adapter: AdapterDetails,
+}
+
+impl GraphicsContext {
+    /// Connects to a graphics card. If `compatible_surface` is given, the graphics card must be able to present to it.
+    pub async fn new(
+        instance: &impl AdapterSource,
+        compatible_surface: Option<&Surface>,
+        power_preference: PowerPreference,
+    ) -> Result<Self, ContextError> {
+        let adapter = instance
+            .request_adapter(&adapter_options(power_preference, compatible_surface))
+            .await
+            .ok_or(ContextError::NoSuitableAdapter)?;
+
+        let (device, queue) = adapter
+            .request_device(
+                &DeviceDescriptor {
+                    features: Features::empty(),
+                    limits: Limits::default(),
+                    shader_validation: true,
+                },
+                None,
+            )
+            .await
+            .map_err(ContextError::RequestDevice)?;
+
+        let adapter = AdapterDetails {
+            info: adapter.get_info(),
+            limits: device.limits(),
+            features: device.features(),
+        };
+        Ok(Self {
+            device: Arc::new(device),
+            queue: Arc::new(queue),
+            adapter,
+        })
+    }
+
+    /// Connects to a graphics card without creating a window, so that we can render into a `RenderTarget`.
+    /// This is useful for testing rendering code.
+    /// Returns `None` if no suitable graphics card could be found.
+    pub async fn headless() -> Option<Self> {
+        // Using BackendBit::PRIMARY we request the Vulkan + Metal + DX12 backends.
+        let instance = Instance::new(BackendBit::PRIMARY);
+        Self::new(&instance, None, PowerPreference::Default)
+            .await
+            .ok()
+    }
+}
+
+/// A texture that can be rendered into instead of a swap chain. Its contents can then be read back to the CPU.
+pub struct RenderTarget {
+    texture: wgpu::Texture,
+    view: TextureView,
+    width: u32,
+    height: u32,
+}
+
+impl RenderTarget {
+    /// The format of every render target. This has four bytes per pixel, in RGBA order.
+    pub const FORMAT: TextureFormat = TextureFormat::Rgba8UnormSrgb;
+
+    pub fn new(device: &Device, width: u32, height: u32) -> Self {
+        Self::with_format(device, width, height, Self::FORMAT)
+    }
+
+    /// Creates a render target with a different format, for example to match the swap chain's format
+    /// so that it can be drawn to by the same batches. Pixels can only be read back from targets
+    /// created with the default `FORMAT`.
+    pub fn with_format(device: &Device, width: u32, height: u32, format: TextureFormat) -> Self {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("render_target"),
+            size: Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format,
+            usage: TextureUsage::OUTPUT_ATTACHMENT | TextureUsage::COPY_SRC | TextureUsage::SAMPLED,
+        });
+        let view = texture.create_view(&TextureViewDescriptor::default());
+        Self {
+            texture,
+            view,
+            width,
+            height,
+        }
+    }
+
+    /// The view that should be passed to rendering functions in place of the swap chain's current frame.
+    pub fn view(&self) -> &TextureView {
+        &self.view
+    }
+
+    /// Turns this render target into a texture, so that what was rendered to it can be drawn by a batch.
+    pub fn into_texture(self, device: &Device) -> crate::graphics::Texture {
+        crate::graphics::Texture::from_wgpu(device, self.texture, (self.width, self.height))
+    }
+
+    /// Copies the contents of this render target to the CPU.
+    /// Returns the pixels as tightly packed rows of RGBA bytes, starting from the top left.
+    pub async fn read_pixels(&self, device: &Device, queue: &Queue) -> Vec<u8> {
+        // Each row copied into a buffer must be padded to a multiple of `COPY_BYTES_PER_ROW_ALIGNMENT`.
+        let bytes_per_row = 4 * self.width;
+        let padding = (COPY_BYTES_PER_ROW_ALIGNMENT - bytes_per_row % COPY_BYTES_PER_ROW_ALIGNMENT)
+            % COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = bytes_per_row + padding;
+
+        let buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("render_target_readback"),
+            size: (padded_bytes_per_row * self.height) as BufferAddress,
+            usage: BufferUsage::MAP_READ | BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+            label: Some("Render Target Readback Encoder"),
+        });
+        encoder.copy_texture_to_buffer(
+            TextureCopyView {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: Origin3d::ZERO,
+            },
+            BufferCopyView {
+                buffer: &buffer,
+                layout: TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: padded_bytes_per_row,
+                    rows_per_image: self.height,
+                },
+            },
+            Extent3d {
+                width: self.width,
+                height: self.height,
+                depth: 1,
+            },
+        );
+        queue.submit(std::iter::once(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapped = slice.map_async(MapMode::Read);
+        device.poll(Maintain::Wait);
+        mapped.await.expect("could not read render target");
+
+        let data = slice.get_mapped_range();
+        let mut pixels = Vec::with_capacity((bytes_per_row * self.height) as usize);
+        for row in data.chunks(padded_bytes_per_row as usize) {
+            pixels.extend_from_slice(&row[..bytes_per_row as usize]);
+        }
+        pixels
+    }
+}
+
+/// Everything a custom renderer needs to draw into a window's frame. See `Application::set_on_render`.
+///
+/// Each frame is drawn with several command buffers, so custom rendering should record its own command encoder
+/// and submit it to the queue. Render pipelines must target `SWAP_CHAIN_FORMAT`.
+pub struct FrameContext<'a> {
+    pub device: &'a Device,
+    pub queue: &'a Queue,
+    /// The texture that the frame is being drawn to. The world has already been drawn, but the UI has not.
+    pub frame: &'a TextureView,
+    /// The physical size of the frame, in pixels.
+    pub frame_size: (u32, u32),
+    /// The part of the frame the world is drawn in, if the window is letterboxed.
+    pub viewport: Option<Viewport>,
+    /// The camera the world is drawn with.
+    pub camera: &'a super::Camera,
+}
+
+/// A rectangular part of a frame that rendering can be confined to, for example to render split screen or a minimap.
+/// This is measured in physical pixels, with the origin in the top left of the frame.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Viewport {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl Viewport {
+    /// Restricts this viewport to the part of it that lies inside a frame of the given size.
+    /// Returns `None` if no part of the viewport lies inside the frame.
+    pub fn clamp_to(self, frame_width: u32, frame_height: u32) -> Option<Viewport> {
+        let x = self.x.max(0.0);
+        let y = self.y.max(0.0);
+        let width = (self.x + self.width).min(frame_width as f32) - x;
+        let height = (self.y + self.height).min(frame_height as f32) - y;
+        if width > 0.0 && height > 0.0 {
+            Some(Viewport {
+                x,
+                y,
+                width,
+                height,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Returns the largest viewport with the given width/height aspect ratio that fits in a frame of the given size,
+    /// centred in the frame. The rest of the frame is left as bars along two of its edges, like a letterboxed film.
+    pub fn letterboxed(frame_width: u32, frame_height: u32, aspect_ratio: f32) -> Viewport {
+        let (frame_width, frame_height) = (frame_width as f32, frame_height as f32);
+        let (width, height) = if frame_width > frame_height * aspect_ratio {
+            // The frame is too wide, so there are bars on the left and right.
+            (frame_height * aspect_ratio, frame_height)
+        } else {
+            (frame_width, frame_width / aspect_ratio)
+        };
+        Viewport {
+            x: 0.5 * (frame_width - width),
+            y: 0.5 * (frame_height - height),
+            width,
+            height,
+        }
+    }
+}
+
+/// Clears the given texture (for example, the swap chain's current frame or a `RenderTarget`) to a single colour.
+/// Like every `Colour`, the clear colour is in linear space; it is converted to sRGB if the target is an sRGB texture.
+pub fn clear(device: &Device, queue: &Queue, target: &TextureView, colour: Colour) {
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
+        label: Some("Clear Colour Encoder"),
+    });
+    let render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
+        color_attachments: &[RenderPassColorAttachmentDescriptor {
+            attachment: target,
+            resolve_target: None,
+            ops: Operations {
+                load: LoadOp::Clear(Color {
+                    r: colour.r as f64,
+                    g: colour.g as f64,
+                    b: colour.b as f64,
+                    a: colour.a as f64,
+                }),
+                store: true,
+            },
+        }],
+        depth_stencil_attachment: None,
+    });
+    // Drop the render pass to tell `wgpu` to stop recording commands for this render pass.
+    drop(render_pass);
+    // Send the render pass into the queue to be actually rendered.
+    queue.submit(std::iter::once(encoder.finish()));
+}
+
+/// How windows are cleared at the start of each frame.
+#[derive(Debug, Copy, Clone)]
+pub struct ClearSettings {
+    /// The colour that the frame is cleared to.
+    pub colour: Colour,
+    /// If this is false, the frame is not cleared, so each frame is drawn on top of the previous frame.
+    pub each_frame: bool,
+}
+
+impl Default for ClearSettings {
+    fn default() -> Self {
+        Self {
+            colour: Colour::rgb(0.1, 0.1, 0.1),
+            each_frame: true,
+        }
+    }
+}
+
+impl ClearSettings {
+    /// Prepares a frame to be drawn on, by clearing it if these settings ask for it.
+    pub fn begin_frame(&self, device: &Device, queue: &Queue, target: &TextureView) {
+        if self.each_frame {
+            clear(device, queue, target, self.colour);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letterboxing_a_4_3_window_to_16_9() {
+        assert_eq!(
+            Viewport::letterboxed(800, 600, 16.0 / 9.0),
+            Viewport {
+                x: 0.0,
+                y: 75.0,
+                width: 800.0,
+                height: 450.0,
+            }
+        );
+        // A window that is too wide gets bars on the left and right instead.
+        assert_eq!(
+            Viewport::letterboxed(1000, 450, 16.0 / 9.0),
+            Viewport {
+                x: 100.0,
+                y: 0.0,
+                width: 800.0,
+                height: 450.0,
+            }
+        );
+    }
+
+    /// Records which graphics card was asked for, but never finds one.
+    #[derive(Default)]
+    struct NoAdapters {
+        requested: std::sync::Mutex<Vec<(PowerPreference, bool)>>,
+    }
+
+    impl AdapterSource for NoAdapters {
+        fn request_adapter(
+            &self,
+            options: &RequestAdapterOptions<'_>,
+        ) -> futures::future::BoxFuture<'static, Option<Adapter>> {
+            self.requested.lock().unwrap().push((
+                options.power_preference,
+                options.compatible_surface.is_some(),
+            ));
+            Box::pin(async { None })
+        }
+    }
+
+    #[tokio::test]
+    async fn adapter_is_requested_with_the_configured_power_preference() {
+        let config = ApplicationConfig {
+            power_preference: PowerPreference::HighPerformance,
+            ..Default::default()
+        };
+        let source = NoAdapters::default();
+        let error = GraphicsContext::new(&source, None, config.power_preference)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            *source.requested.lock().unwrap(),
+            vec![(PowerPreference::HighPerformance, false)]
+        );
+        assert!(matches!(error, ContextError::NoSuitableAdapter));
+        assert_eq!(
+            error.to_string(),
+            "no graphics card supports the requested backends"
+        );
+    }
+
+    #[test]
+    fn llvmpipe_is_a_software_renderer() {
+        let details = |name: &str, device_type| AdapterDetails {
+            info: AdapterInfo {
+                name: name.to_string(),
+                vendor: 0,
+                device: 0,
+                device_type,
+                backend: Backend::Vulkan,
+            },
+            limits: Limits::default(),
+            features: Features::empty(),
+        };
+        assert!(details("llvmpipe (LLVM 11.0.0, 256 bits)", DeviceType::Other).is_software());
+        assert!(details("Some GPU", DeviceType::Cpu).is_software());
+        assert!(!details("AMD Radeon RX 580", DeviceType::DiscreteGpu).is_software());
+    }
+
+    #[tokio::test]
+    async fn headless_context_describes_its_adapter() {
+        let context = match GraphicsContext::headless().await {
+            Some(context) => context,
+            None => return,
+        };
+        assert!(!context.adapter.info.name.is_empty());
+        assert!(context.adapter.limits.max_bind_groups > 0);
+    }
+
+    #[tokio::test]
+    async fn clear_and_read_back() {
+        let context = match GraphicsContext::headless().await {
+            Some(context) => context,
+            None => return,
+        };
+
+        let target = RenderTarget::new(&context.device, 4, 4);
+        clear(
+            &context.device,
+            &context.queue,
+            target.view(),
+            Colour::rgb(1.0, 0.0, 0.0),
+        );
+
+        let pixels = target.read_pixels(&context.device, &context.queue).await;
+        assert_eq!(pixels.len(), 4 * 4 * 4);
+        for pixel in pixels.chunks(4) {
+            assert_eq!(pixel, &[255, 0, 0, 255]);
+        }
+    }
+
+    #[tokio::test]
+    async fn empty_frames_are_cleared_to_the_configured_colour() {
+        let context = match GraphicsContext::headless().await {
+            Some(context) => context,
+            None => return,
+        };
+        let target = RenderTarget::new(&context.device, 4, 4);
+        let mut settings = ClearSettings {
+            colour: Colour::rgb(0.0, 0.0, 1.0),
+            each_frame: true,
+        };
+        settings.begin_frame(&context.device, &context.queue, target.view());
+        let pixels = target.read_pixels(&context.device, &context.queue).await;
+        assert!(pixels.chunks(4).all(|pixel| pixel == [0, 0, 255, 255]));
+
+        // If frames aren't cleared, the previous frame is left as it was.
+        settings.colour = Colour::rgb(1.0, 0.0, 0.0);
+        settings.each_frame = false;
+        settings.begin_frame(&context.device, &context.queue, target.view());
+        let pixels = target.read_pixels(&context.device, &context.queue).await;
+        assert!(pixels.chunks(4).all(|pixel| pixel == [0, 0, 255, 255]));
+    }
+}
@@ -0,0 +1,258 @@
+use std::time::{Duration, Instant};
+
+use qs_common::profile::InterpolatedStopwatch;
+use winit::event_loop::ControlFlow;
+
+/// Keeps track of how much time passes between frames.
+pub struct FrameClock {
+    last_frame_time: Instant,
+    delta_time: Duration,
+    fps_counter: InterpolatedStopwatch,
+}
+
+impl FrameClock {
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now())
+    }
+
+    /// Creates a clock whose first frame is measured from the given time.
+    pub fn starting_at(start: Instant) -> Self {
+        Self {
+            last_frame_time: start,
+            delta_time: Duration::from_secs(0),
+            fps_counter: InterpolatedStopwatch::starting_at(100, start),
+        }
+    }
+
+    /// Call this at the start of every frame. Returns the time since the previous frame.
+    pub fn tick(&mut self) -> Duration {
+        self.tick_at(Instant::now())
+    }
+
+    /// Like `tick`, but the frame is recorded as starting at the given time instead of now.
+    pub fn tick_at(&mut self, now: Instant) -> Duration {
+        self.delta_time = now.duration_since(self.last_frame_time);
+        self.last_frame_time = now;
+        self.fps_counter.tick_at(now);
+        self.delta_time
+    }
+
+    /// The time between the start of the previous frame and the start of the current frame.
+    pub fn delta_time(&self) -> Duration {
+        self.delta_time
+    }
+
+    /// The average number of frames per second over the last hundred frames, or zero before the first frame.
+    pub fn fps(&self) -> f64 {
+        let average = self.fps_counter.average_time().as_secs_f64();
+        if average > 0.0 {
+            1.0 / average
+        } else {
+            0.0
+        }
+    }
+
+    /// How many frames have been ticked so far.
+    pub fn frames(&self) -> u64 {
+        self.fps_counter.ticks
+    }
+}
+
+impl Default for FrameClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Converts the variable time between frames into a whole number of fixed-length updates,
+/// so that simulations (such as physics) run deterministically regardless of the frame rate.
+pub struct FixedTimestep {
+    fixed_delta: Duration,
+    /// The most time that a single frame may add to the accumulator. If frames take longer than this
+    /// (for example, when the window is being dragged), the simulation slows down instead of running
+    /// ever more fixed updates to catch up.
+    max_frame_time: Duration,
+    /// Time that has passed but has not yet been simulated by a fixed update.
+    accumulator: Duration,
+}
+
+impl FixedTimestep {
+    /// Panics if `fixed_delta` is zero, since no amount of time could ever be simulated.
+    pub fn new(fixed_delta: Duration) -> Self {
+        assert_nonzero_fixed_delta(fixed_delta);
+        Self {
+            fixed_delta,
+            max_frame_time: Duration::from_millis(250),
+            accumulator: Duration::from_secs(0),
+        }
+    }
+
+    pub fn fixed_delta(&self) -> Duration {
+        self.fixed_delta
+    }
+
+    /// Panics if `fixed_delta` is zero, like `new`.
+    pub fn set_fixed_delta(&mut self, fixed_delta: Duration) {
+        assert_nonzero_fixed_delta(fixed_delta);
+        self.fixed_delta = fixed_delta;
+    }
+
+    /// Adds the time taken by the last frame, returning how many fixed updates should be run this frame.
+    pub fn advance(&mut self, delta_time: Duration) -> u32 {
+        self.accumulator += delta_time.min(self.max_frame_time);
+        let mut updates = 0;
+        while self.accumulator >= self.fixed_delta {
+            self.accumulator -= self.fixed_delta;
+            updates += 1;
+        }
+        updates
+    }
+
+    /// How far the current frame is between the last fixed update and the next one, from 0 to 1.
+    /// This can be used to interpolate between the previous and current simulation states when rendering.
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.fixed_delta.as_secs_f32()
+    }
+}
+
+/// A zero fixed delta would make `advance` loop forever.
+fn assert_nonzero_fixed_delta(fixed_delta: Duration) {
+    assert!(
+        !fixed_delta.is_zero(),
+        "the fixed delta must be longer than zero"
+    );
+}
+
+impl Default for FixedTimestep {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1) / 60)
+    }
+}
+
+/// Decides whether another frame needs to be drawn, so that the application can sleep while nothing on screen is changing.
+///
+/// Anything that changes what is on screen, such as input or a UI that needs laying out again, should call `mark_dirty`.
+/// While `continuous` is set, for example because something is being animated, every frame is drawn regardless.
+pub struct RedrawScheduler {
+    dirty: bool,
+    continuous: bool,
+}
+
+impl RedrawScheduler {
+    /// How long to sleep for when nothing needs to be drawn, before checking again for changes made by background tasks,
+    /// such as text that has finished typesetting.
+    pub const IDLE_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// The first frame always needs to be drawn.
+    pub fn new() -> Self {
+        Self {
+            dirty: true,
+            continuous: false,
+        }
+    }
+
+    pub fn mark_dirty(&mut self) {
+        self.dirty = true;
+    }
+
+    pub fn set_continuous(&mut self, continuous: bool) {
+        self.continuous = continuous;
+    }
+
+    /// Call this once all events have been handled. Returns true if a frame should be drawn,
+    /// and considers the frame clean again until something else marks it dirty.
+    pub fn take_redraw(&mut self) -> bool {
+        let redraw = self.dirty || self.continuous;
+        self.dirty = false;
+        redraw
+    }
+
+    /// If we're drawing a frame, we should poll for further events immediately; otherwise we can wait for a while.
+    pub fn control_flow(&self, redraw: bool, now: Instant) -> ControlFlow {
+        if redraw {
+            ControlFlow::Poll
+        } else {
+            ControlFlow::WaitUntil(now + Self::IDLE_INTERVAL)
+        }
+    }
+}
+
+impl Default for RedrawScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_time_is_time_between_ticks() {
+        let start = Instant::now();
+        let mut clock = FrameClock::starting_at(start);
+
+        let mut elapsed = Duration::from_secs(0);
+        for &millis in &[16, 17, 33, 8] {
+            let previous = elapsed;
+            elapsed += Duration::from_millis(millis);
+            assert_eq!(clock.tick_at(start + elapsed), elapsed - previous);
+            assert_eq!(clock.delta_time(), Duration::from_millis(millis));
+        }
+        assert_eq!(clock.frames(), 4);
+    }
+
+    #[test]
+    fn fixed_updates_follow_accumulated_time() {
+        let mut timestep = FixedTimestep::new(Duration::from_millis(10));
+        let updates: Vec<u32> = [25, 4, 1, 9, 30]
+            .iter()
+            .map(|&millis| timestep.advance(Duration::from_millis(millis)))
+            .collect();
+        assert_eq!(updates, vec![2, 0, 1, 0, 3]);
+        assert!((timestep.alpha() - 0.9).abs() < 1e-6);
+    }
+
+    #[test]
+    fn long_frames_are_clamped() {
+        let mut timestep = FixedTimestep::new(Duration::from_millis(10));
+        assert_eq!(timestep.advance(Duration::from_secs(10)), 25);
+        assert_eq!(timestep.alpha(), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "the fixed delta must be longer than zero")]
+    fn zero_fixed_delta_is_rejected() {
+        FixedTimestep::default().set_fixed_delta(Duration::from_secs(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "the fixed delta must be longer than zero")]
+    fn zero_fixed_delta_is_rejected_on_creation() {
+        FixedTimestep::new(Duration::from_secs(0));
+    }
+
+    #[test]
+    fn redraws_stop_when_nothing_changes() {
+        let mut scheduler = RedrawScheduler::new();
+        assert!(scheduler.take_redraw());
+        // With no input and no animations, no more frames are drawn and the event loop sleeps.
+        let now = Instant::now();
+        for _ in 0..3 {
+            let redraw = scheduler.take_redraw();
+            assert!(!redraw);
+            assert_eq!(
+                scheduler.control_flow(redraw, now),
+                ControlFlow::WaitUntil(now + RedrawScheduler::IDLE_INTERVAL)
+            );
+        }
+
+        scheduler.mark_dirty();
+        assert!(scheduler.take_redraw());
+        assert!(!scheduler.take_redraw());
+
+        scheduler.set_continuous(true);
+        assert!(scheduler.take_redraw() && scheduler.take_redraw());
+        assert_eq!(scheduler.control_flow(true, now), ControlFlow::Poll);
+    }
+}
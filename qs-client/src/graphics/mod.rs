@@ -1,5 +1,6 @@
+use std::collections::HashMap;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::Duration;
 use stretch::{
     geometry::{Point, Size},
     number::Number,
@@ -7,20 +8,19 @@ use stretch::{
 };
 use wgpu::*;
 use winit::{
-    dpi::PhysicalPosition,
     event::*,
-    event_loop::{ControlFlow, EventLoop},
-    window::{Window, WindowBuilder},
+    event_loop::{ControlFlow, EventLoop, EventLoopWindowTarget},
+    window::{WindowBuilder, WindowId},
 };
 
 use crate::{
     assets::{
-        FontAssetLoader, PartitionedTextureAssetLoader, PartitionedTextureAtlasPaths,
-        TextureAssetLoader,
+        atlas, FontAssetLoader, FontKey, PartitionedTextureAssetLoader,
+        PartitionedTextureAtlasPaths, TextureAssetLoader,
     },
+    input::InputMap,
     ui::*,
 };
-use qs_common::profile::InterpolatedStopwatch;
 use qs_common::{
     assets::{AssetManager, AssetPath},
     profile::ProfileSegmentGuard,
@@ -38,25 +38,52 @@ mod text;
 pub use text::*;
 mod multi_batch;
 pub use multi_batch::*;
+mod context;
+pub use context::*;
+mod window;
+pub use window::*;
+mod frame_clock;
+pub use frame_clock::*;
+mod window_geometry;
+pub use window_geometry::*;
+#[cfg(feature = "shaderc")]
+mod shader;
+#[cfg(feature = "shaderc")]
+pub use shader::*;
+
+/// Draws custom content into the frame of the window with the given ID.
+type RenderCallback = Box<dyn FnMut(WindowId, FrameContext<'_>)>;
 
 /// This struct represents the state of the whole application and contains all of the `winit`
 /// and `wgpu` data for rendering things to the screen.
 pub struct Application {
-    window: Window,
-
-    surface: Surface,
+    /// Kept so that we can create surfaces for windows opened at runtime.
+    instance: Instance,
     device: Arc<Device>,
     queue: Arc<Queue>,
-
-    /// The dimensions of the window's area we can render to.
-    size: winit::dpi::PhysicalSize<u32>,
-
-    /// Provides a way for us to recreate the swap chain when we (for example) resize the window.
-    swap_chain_descriptor: SwapChainDescriptor,
-    swap_chain: SwapChain,
-
-    last_frame_time: Instant,
-    fps_counter: InterpolatedStopwatch,
+    adapter: AdapterDetails,
+
+    /// Every open window, which all share the same device and queue.
+    windows: HashMap<WindowId, WindowState>,
+    /// The window that was opened when the application started. Closing it exits the application.
+    main_window: WindowId,
+
+    /// How every window is cleared at the start of each frame.
+    clear: ClearSettings,
+
+    frame_clock: FrameClock,
+    /// Frames are only drawn when something has changed, or while there are callbacks that expect to run every frame.
+    redraw: RedrawScheduler,
+    /// Called once per frame, before the frame is rendered, with the time since the previous frame.
+    on_update: Option<Box<dyn FnMut(Duration)>>,
+    fixed_timestep: FixedTimestep,
+    /// Called a whole number of times per frame, depending on how much time has passed, with the fixed timestep.
+    fixed_update: Option<Box<dyn FnMut(Duration)>>,
+    /// Called while rendering each window, after the world is drawn but before the UI.
+    on_render: Option<RenderCallback>,
+
+    /// Maps keys and mouse buttons to actions. This receives any input that the UI did not use.
+    input: InputMap,
 
     texture_am: AssetManager<AssetPath, Texture, TextureAssetLoader>,
     _partitioned_texture_am: AssetManager<
@@ -64,17 +91,15 @@ pub struct Application {
         PartitionedTexture,
         PartitionedTextureAssetLoader,
     >,
-    _font_am: AssetManager<AssetPath, rusttype::Font<'static>, FontAssetLoader>,
-    camera: Camera,
-    ui_camera: Camera,
+    _font_am: AssetManager<FontKey, rusttype::Font<'static>, FontAssetLoader>,
     multi_batch: MultiBatch,
 
-    mouse_position: PhysicalPosition<f64>,
+    /// Shows the frame rate and profiler output over the main window's UI. Toggled by the `toggle_debug_overlay` action.
+    debug_overlay: DebugOverlay,
 
     test_font_family: Arc<FontFamily>,
     /// A test widget.
     test_text: RichText,
-    ui: UI,
 }
 
 impl Application {
@@ -82,118 +107,50 @@ impl Application {
     /// In order to keep the event loop (which is global to all windows) from polluting the
     /// lifetime of the application, we return them separately.
     ///
+    /// The config chooses which graphics card is used. If no graphics card matching it is available, an error is returned.
+    ///
     /// # Panics
     /// Some `wgpu` types are created asynchronously, so this function is asynchronous.
     /// However, it must be called on the main thread to ensure that `winit` is happy with cross platform support.
-    pub async fn new() -> (Application, EventLoop<()>) {
+    pub async fn new(
+        config: ApplicationConfig,
+    ) -> Result<(Application, EventLoop<()>), ContextError> {
         let event_loop = EventLoop::new();
-        let window = WindowBuilder::new()
-            .with_title("Quest Sage")
-            .build(&event_loop)
-            .unwrap();
+        // Reopen the main window where it was when the application was last closed,
+        // unless the monitor it was on has since been disconnected.
+        let geometry = WindowGeometry::load()
+            .filter(|geometry| geometry.is_visible_on_monitors(event_loop.available_monitors()));
+        let mut builder = WindowBuilder::new().with_title("Quest Sage");
+        if let Some(geometry) = geometry {
+            builder = geometry.apply_to_builder(builder);
+        }
+        let window = builder.build(&event_loop).unwrap();
+        if let Some(geometry) = geometry {
+            geometry.move_window(&window);
+        }
 
-        // The amount of pixels we have to work with in our window.
-        let size = window.inner_size();
         let scale_factor = window.scale_factor();
 
         // These three variables essentially encapsulate various handles to the graphics card
         // and specifically the window we're working with.
-        // Using BackendBit::PRIMARY we request the Vulkan + Metal + DX12 backends.
-        let instance = Instance::new(BackendBit::PRIMARY);
+        let instance = Instance::new(config.backends);
         let surface = unsafe { instance.create_surface(&window) };
-        let adapter = instance
-            .request_adapter(&RequestAdapterOptions {
-                power_preference: PowerPreference::Default,
-                compatible_surface: Some(&surface),
-            })
-            .await
-            .unwrap();
-
-        // Device is a connection to the graphics card. The queue allows us to
-        // send commands to the device, which are executed asynchronously.
-        let (device, queue) = adapter
-            .request_device(
-                &DeviceDescriptor {
-                    features: Features::empty(),
-                    limits: Limits::default(),
-                    shader_validation: true,
-                },
-                None,
-            )
-            .await
-            .unwrap();
-        let device = Arc::new(device);
-        let queue = Arc::new(queue);
-
-        // The swap chain represents the images that will be presented to the `surface` above.
-        // When we resize the window, we need to recreate the swap chain because the images
-        // to be presented are now a different size.
-        let swap_chain_descriptor = SwapChainDescriptor {
-            usage: TextureUsage::OUTPUT_ATTACHMENT,
-            format: TextureFormat::Bgra8UnormSrgb,
-            width: size.width,
-            height: size.height,
-            present_mode: PresentMode::Immediate,
-        };
-        let swap_chain = device.create_swap_chain(&surface, &swap_chain_descriptor);
-
-        // Define how we want to bind textures in our render pipeline.
-        let texture_bind_group_layout_desc = &BindGroupLayoutDescriptor {
-            entries: &[
-                BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStage::FRAGMENT,
-                    ty: BindingType::SampledTexture {
-                        multisampled: false,
-                        dimension: TextureViewDimension::D2,
-                        component_type: TextureComponentType::Uint,
-                    },
-                    count: None,
-                },
-                BindGroupLayoutEntry {
-                    binding: 1,
-                    visibility: ShaderStage::FRAGMENT,
-                    ty: BindingType::Sampler { comparison: false },
-                    count: None,
-                },
-            ],
-            label: Some("texture_bind_group_layout"),
-        };
-        // Define how we want to bind uniforms.
-        let uniform_bind_group_layout_desc = wgpu::BindGroupLayoutDescriptor {
-            entries: &[wgpu::BindGroupLayoutEntry {
-                binding: 0,
-                visibility: wgpu::ShaderStage::VERTEX,
-                ty: wgpu::BindingType::UniformBuffer {
-                    dynamic: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            }],
-            label: Some("uniform_bind_group_layout"),
+        let GraphicsContext {
+            device,
+            queue,
+            adapter,
+        } = match GraphicsContext::new(&instance, Some(&surface), config.power_preference).await {
+            Ok(context) => context,
+            Err(error) => {
+                tracing::error!(
+                    "could not find a suitable graphics card with {:?}: {:?}",
+                    config,
+                    error
+                );
+                return Err(error);
+            }
         };
-
-        let camera = Camera::new(CameraData::Orthographic {
-            eye: cgmath::Point2::new(0.0, 0.0),
-            view_height: 2.0,
-            aspect_ratio: 1.0,
-        });
-        let ui_camera = Camera::new(CameraData::Orthographic {
-            eye: cgmath::Point2::new(0.0, 0.0),
-            view_height: 800.0,
-            aspect_ratio: 1.0,
-        });
-
-        // Let's create a batch to render many shapes in a single render pass.
-        let batch = Batch::new(
-            Arc::clone(&device),
-            Arc::clone(&queue),
-            include_spirv!("shader.vert.spv"),
-            include_spirv!("shader.frag.spv"),
-            device.create_bind_group_layout(&texture_bind_group_layout_desc),
-            device.create_bind_group_layout(&uniform_bind_group_layout_desc),
-            swap_chain_descriptor.format,
-        );
+        adapter.log();
 
         let mut texture_am = AssetManager::new(TextureAssetLoader::new(
             Arc::clone(&device),
@@ -207,24 +164,21 @@ impl Application {
 
         let mut font_am = AssetManager::new(FontAssetLoader::default());
 
-        let text_renderer = TextRenderer::new(
+        let multi_batch = MultiBatch::with_default_shaders(
             Arc::clone(&device),
             Arc::clone(&queue),
-            device.create_bind_group_layout(&texture_bind_group_layout_desc),
-            device.create_bind_group_layout(&uniform_bind_group_layout_desc),
-            swap_chain_descriptor.format,
+            SWAP_CHAIN_FORMAT,
             scale_factor as f32,
+            config.glyph_filter,
         );
 
-        let multi_batch = MultiBatch::new(batch, text_renderer);
-
         let mut test_text = RichText::new(Default::default());
         let test_font_family = Arc::new(FontFamily::new(vec![FontFace::new(
             "Noto Sans".to_string(),
-            font_am.get(AssetPath::new(vec!["NotoSans-Regular.ttf".to_string()])),
-            Some(font_am.get(AssetPath::new(vec!["NotoSans-Bold.ttf".to_string()]))),
-            Some(font_am.get(AssetPath::new(vec!["NotoSans-Italic.ttf".to_string()]))),
-            Some(font_am.get(AssetPath::new(vec!["NotoSans-BoldItalic.ttf".to_string()]))),
+            font_am.get(AssetPath::new(vec!["NotoSans-Regular.ttf".to_string()]).into()),
+            Some(font_am.get(AssetPath::new(vec!["NotoSans-Bold.ttf".to_string()]).into())),
+            Some(font_am.get(AssetPath::new(vec!["NotoSans-Italic.ttf".to_string()]).into())),
+            Some(font_am.get(AssetPath::new(vec!["NotoSans-BoldItalic.ttf".to_string()]).into())),
         )]));
         let _ = test_text.set_text(Arc::clone(&test_font_family))
         .h1(|b| b
@@ -268,14 +222,14 @@ impl Application {
             atlas: AssetPath::new(vec!["ui".to_string(), "atlas.json".to_string()]),
         });
 
-        let tr_button = TextureRegion::new(ui_atlas.clone(), "button.png".to_string()).await;
+        let tr_button = TextureRegion::new(ui_atlas.clone(), atlas::BUTTON.to_string()).await;
         let tr_button_hovered =
-            TextureRegion::new(ui_atlas.clone(), "button_hovered.png".to_string()).await;
+            TextureRegion::new(ui_atlas.clone(), atlas::BUTTON_HOVERED.to_string()).await;
         let tr_button_pressed =
-            TextureRegion::new(ui_atlas.clone(), "button_pressed.png".to_string()).await;
+            TextureRegion::new(ui_atlas.clone(), atlas::BUTTON_PRESSED.to_string()).await;
         let tr_button_disabled =
-            TextureRegion::new(ui_atlas.clone(), "button_disabled.png".to_string()).await;
-        let tr_caret = TextureRegion::new(ui_atlas.clone(), "caret.png".to_string()).await;
+            TextureRegion::new(ui_atlas.clone(), atlas::BUTTON_DISABLED.to_string()).await;
+        let tr_caret = TextureRegion::new(ui_atlas.clone(), atlas::CARET.to_string()).await;
 
         let button_style = ButtonStyle {
             released_texture: NinePatch {
@@ -284,6 +238,8 @@ impl Application {
                 right_margin: 2,
                 top_margin: 2,
                 bottom_margin: 2,
+                horizontal_fill: NinePatchFill::Stretch,
+                vertical_fill: NinePatchFill::Stretch,
             },
             hovered_texture: NinePatch {
                 texture_region: tr_button_hovered,
@@ -291,6 +247,8 @@ impl Application {
                 right_margin: 2,
                 top_margin: 2,
                 bottom_margin: 2,
+                horizontal_fill: NinePatchFill::Stretch,
+                vertical_fill: NinePatchFill::Stretch,
             },
             pressed_texture: NinePatch {
                 texture_region: tr_button_pressed,
@@ -298,6 +256,8 @@ impl Application {
                 right_margin: 2,
                 top_margin: 2,
                 bottom_margin: 2,
+                horizontal_fill: NinePatchFill::Stretch,
+                vertical_fill: NinePatchFill::Stretch,
             },
             disabled_texture: NinePatch {
                 texture_region: tr_button_disabled,
@@ -305,6 +265,8 @@ impl Application {
                 right_margin: 2,
                 top_margin: 2,
                 bottom_margin: 2,
+                horizontal_fill: NinePatchFill::Stretch,
+                vertical_fill: NinePatchFill::Stretch,
             },
         };
 
@@ -323,22 +285,33 @@ impl Application {
             },
         );
 
-        let test_field = Field::new(
+        let mut test_field = Field::new(
             NinePatch {
                 texture_region: tr_caret,
                 left_margin: 1,
                 right_margin: 1,
                 top_margin: 2,
                 bottom_margin: 2,
+                horizontal_fill: NinePatchFill::Stretch,
+                vertical_fill: NinePatchFill::Stretch,
             },
             Arc::clone(&test_font_family),
             Default::default(),
             Default::default(),
         );
+        test_field.set_text("Hello, world! This is a field.");
+
+        let debug_overlay = DebugOverlay::new(Arc::clone(&test_font_family));
 
         let root = Widget::new(
             (),
-            vec![test_text.get_widget(), test_button, test_field.get_widget()],
+            vec![
+                test_text.get_widget(),
+                test_button,
+                test_field.get_widget(),
+                // The overlay is the last child, so that it is drawn over everything else.
+                debug_overlay.get_widget(),
+            ],
             vec![Box::new(ImageElement {
                 size: Size {
                     width: Dimension::Points(100.0),
@@ -351,6 +324,8 @@ impl Application {
                     a: 0.7,
                 },
                 texture: texture_am.get(AssetPath::new(vec!["white.png".to_string()])),
+                scale_mode: ScaleMode::Stretch,
+                mask: ImageMask::Rectangle,
             })],
             Style {
                 //align_self: stretch::style::AlignSelf::Stretch,
@@ -361,147 +336,138 @@ impl Application {
             },
         );
 
-        let ui = UI::new(
+        let mut ui = UI::new(
             root,
             Size {
                 width: Number::Defined(100.0),
                 height: Number::Defined(100.0),
             },
         );
+        ui.set_modal_backdrop(
+            texture_am.get(AssetPath::new(vec!["white.png".to_string()])),
+            Colour::rgba(0.0, 0.0, 0.0, 0.5),
+        );
 
-        let mut app = Application {
-            window,
+        let main_window = window.id();
+        let mut windows = HashMap::new();
+        windows.insert(main_window, WindowState::new(window, surface, &device, ui));
 
-            surface,
+        let app = Application {
+            instance,
             device,
             queue,
+            adapter,
+
+            windows,
+            main_window,
 
-            size,
+            clear: ClearSettings::default(),
 
-            swap_chain_descriptor,
-            swap_chain,
+            frame_clock: FrameClock::new(),
+            redraw: RedrawScheduler::new(),
+            on_update: None,
+            on_render: None,
+            fixed_timestep: FixedTimestep::default(),
+            fixed_update: None,
 
-            last_frame_time: Instant::now(),
-            fps_counter: InterpolatedStopwatch::new(100),
+            input: InputMap::default_bindings(),
+
+            debug_overlay,
 
             texture_am,
             _partitioned_texture_am: partitioned_texture_am,
             _font_am: font_am,
-            camera,
-            ui_camera,
             multi_batch,
 
-            mouse_position: PhysicalPosition { x: 0.0, y: 0.0 },
-
             test_font_family,
             test_text,
-            ui,
         };
 
-        // Call resize at the start so that we initialise cameras etc with the correct aspect ratio.
-        app.resize(size, Some(scale_factor));
+        Ok((app, event_loop))
+    }
 
-        (app, event_loop)
+    /// Opens a new window that displays the given UI. This may be called while the event loop is running.
+    pub fn open_window(
+        &mut self,
+        window_target: &EventLoopWindowTarget<()>,
+        builder: WindowBuilder,
+        ui: UI,
+    ) -> WindowId {
+        let window = builder.build(window_target).unwrap();
+        let surface = unsafe { self.instance.create_surface(&window) };
+        let id = window.id();
+        self.windows
+            .insert(id, WindowState::new(window, surface, &self.device, ui));
+        id
     }
 
-    /// # Arguments
-    /// If `scale_factor` is `None`, then the scale factor did not change.
-    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>, scale_factor: Option<f64>) {
-        tracing::info!("Got new size: {:?} with scale {:?}", new_size, scale_factor);
-        self.size = new_size;
-        self.swap_chain_descriptor.width = new_size.width;
-        self.swap_chain_descriptor.height = new_size.height;
-        self.swap_chain = self
-            .device
-            .create_swap_chain(&self.surface, &self.swap_chain_descriptor);
-
-        self.camera
-            .update_window_size(new_size.width, new_size.height);
-        let CameraData::Orthographic {
-            ref mut view_height,
-            ..
-        } = self.ui_camera.get_data_mut();
-        *view_height = new_size.height as f32;
-        self.ui_camera
-            .update_window_size(new_size.width, new_size.height);
-
-        self.ui.update_size(Size {
-            width: Number::Defined(new_size.width as f32),
-            height: Number::Defined(new_size.height as f32),
-        })
+    /// Saves the size and position of the main window, so that it can be restored next time the application starts.
+    fn save_main_window_geometry(&self) {
+        if let Some(geometry) = self
+            .windows
+            .get(&self.main_window)
+            .and_then(|window| WindowGeometry::of(&window.window))
+        {
+            geometry.save();
+        }
     }
 
-    pub fn update_cursor(&mut self, pos: PhysicalPosition<f64>) {
-        self.mouse_position = pos;
-        self.ui.mouse_move(Point {
-            x: pos.x as f32,
-            y: pos.y as f32,
-        });
+    /// Sets the colour that windows are cleared to at the start of each frame.
+    pub fn set_clear_colour(&mut self, colour: Colour) {
+        self.clear.colour = colour;
     }
 
-    pub fn mouse_input(&mut self, button: MouseButton, state: ElementState) {
-        self.ui.mouse_input(button, state);
+    /// If `clear_each_frame` is false, windows will not be cleared at the start of each frame,
+    /// so that each frame is drawn over the previous one.
+    pub fn set_clear_each_frame(&mut self, clear_each_frame: bool) {
+        self.clear.each_frame = clear_each_frame;
     }
 
-    /// Renders a single frame, submitting it to the swap chain.
-    pub async fn render(&mut self, mut profiler: ProfileSegmentGuard<'_>) {
-        let this_frame_time = Instant::now();
-        let delta_duration = this_frame_time - self.last_frame_time;
-        self.last_frame_time = this_frame_time;
-        let _delta_seconds = delta_duration.as_secs_f32();
-        self.fps_counter.tick();
+    /// Describes the graphics card the application is rendering with.
+    pub fn adapter(&self) -> &AdapterDetails {
+        &self.adapter
+    }
 
-        if self.fps_counter.ticks % 100 == 0 {
-            self.test_text
-                .set_text(Arc::clone(&self.test_font_family))
-                .write(&format!("{} frames", self.fps_counter.ticks))
-                .finish();
-            /*tracing::trace!(
-                "{:.2} FPS",
-                1.0 / self.fps_counter.average_time().as_secs_f64()
-            );*/
+    /// Letterboxes the main window's world to the given width/height aspect ratio, such as `16.0 / 9.0`,
+    /// or lets it fill the window if `aspect_ratio` is `None`. See `WindowState::set_design_aspect_ratio`.
+    pub fn set_design_aspect_ratio(&mut self, aspect_ratio: Option<f32>) {
+        if let Some(window) = self.windows.get_mut(&self.main_window) {
+            window.set_design_aspect_ratio(aspect_ratio);
+        }
+    }
+
+    /// Renders a single frame to the given window, submitting it to the window's swap chain.
+    /// This never waits for assets: anything drawn with a texture that hasn't loaded yet is skipped this frame.
+    pub fn render(&mut self, window_id: WindowId, mut profiler: ProfileSegmentGuard<'_>) {
+        let is_main_window = window_id == self.main_window;
+        if is_main_window {
+            self.update();
+        }
+
+        let window = match self.windows.get_mut(&window_id) {
+            Some(window) => window,
+            None => return,
+        };
+        if !window.can_render() {
+            return;
         }
 
         {
-            //let CameraData::Orthographic { ref mut eye, .. } = self.camera.get_data_mut();
+            //let CameraData::Orthographic { ref mut eye, .. } = window.camera.get_data_mut();
             //eye.x += 0.5 * delta_seconds;
         }
 
         // Get a handle to a texture that we can render the next frame to.
-        let frame = self
-            .swap_chain
-            .get_current_frame()
-            .expect("Timeout getting texture")
-            .output;
-
-        // Clear the screen with a default colour.
-        let mut encoder = self
-            .device
-            .create_command_encoder(&CommandEncoderDescriptor {
-                label: Some("Clear Colour Encoder"),
-            });
-        let render_pass = encoder.begin_render_pass(&RenderPassDescriptor {
-            color_attachments: &[RenderPassColorAttachmentDescriptor {
-                attachment: &frame.view,
-                resolve_target: None,
-                ops: Operations {
-                    load: LoadOp::Clear(Color {
-                        r: 0.1,
-                        g: 0.1,
-                        b: 0.1,
-                        a: 1.0,
-                    }),
-                    store: true,
-                },
-            }],
-            depth_stencil_attachment: None,
-        });
-        // Drop the render pass to tell `wgpu` to stop recording commands for this render pass.
-        drop(render_pass);
-        // Send the render pass into the queue to be actually rendered.
-        self.queue.submit(std::iter::once(encoder.finish()));
+        let frame = match window.get_current_frame(&self.device) {
+            Some(frame) => frame.output,
+            None => return,
+        };
 
-        {
+        self.clear
+            .begin_frame(&self.device, &self.queue, &frame.view);
+
+        // Only the main window shows the test background.
+        if is_main_window {
             let _guard = profiler.task("background").time();
             // Actually render stuff here.
             use itertools::iproduct;
@@ -535,96 +501,285 @@ impl Application {
                     )
                 });
 
+            let batch = &mut self.multi_batch.batch;
             self.texture_am
                 .get(AssetPath::new(vec!["test.png".to_string()]))
-                .if_loaded(|tex| {
-                    self.multi_batch
-                        .batch
-                        .render(&frame, tex, &self.camera, renderables);
-                })
-                .await;
+                .try_if_loaded(|tex| {
+                    batch.render(
+                        &frame.view,
+                        window.world_viewport(),
+                        tex,
+                        &window.camera,
+                        renderables,
+                    );
+                });
+        }
+
+        if let Some(on_render) = &mut self.on_render {
+            let _guard = profiler.task("custom").time();
+            on_render(
+                window_id,
+                FrameContext {
+                    device: &self.device,
+                    queue: &self.queue,
+                    frame: &frame.view,
+                    frame_size: (window.size.width, window.size.height),
+                    viewport: window.world_viewport(),
+                    camera: &window.camera,
+                },
+            );
         }
 
         {
             let guard = profiler.task("ui").time();
-            self.multi_batch
-                .render(
-                    self.ui.generate_render_info(
-                        Point {
-                            x: self.size.width as f32 * -0.5,
-                            y: self.size.height as f32 * -0.5,
-                        },
-                        /*Some(
-                            self.texture_am
-                                .get(AssetPath::new(vec!["white.png".to_string()])),
-                        ),*/
-                        None,
-                    ),
-                    &frame,
-                    &self.ui_camera,
-                    guard,
-                )
-                .await;
+            self.multi_batch.render(
+                window.ui.generate_render_info(
+                    Point {
+                        x: window.size.width as f32 * -0.5,
+                        y: window.size.height as f32 * -0.5,
+                    },
+                    /*Some(&DebugDrawConfig::new(
+                        self.texture_am
+                            .get(AssetPath::new(vec!["white.png".to_string()])),
+                    )),*/
+                    None,
+                ),
+                &frame.view,
+                (window.size.width, window.size.height),
+                None,
+                &window.ui_camera,
+                guard,
+            );
         }
     }
 
+    /// Ticks the frame clock and runs the update callbacks. This is called once per frame of the main window.
+    fn update(&mut self) {
+        let delta_time = self.frame_clock.tick();
+
+        let fixed_updates = self.fixed_timestep.advance(delta_time);
+        if let Some(fixed_update) = &mut self.fixed_update {
+            for _ in 0..fixed_updates {
+                fixed_update(self.fixed_timestep.fixed_delta());
+            }
+        }
+
+        if let Some(on_update) = &mut self.on_update {
+            on_update(delta_time);
+        }
+
+        if self.frame_clock.frames() % 100 == 0 {
+            self.test_text
+                .set_text(Arc::clone(&self.test_font_family))
+                .write(&format!("{} frames", self.frame_clock.frames()))
+                .finish();
+            /*tracing::trace!(
+                "{:.2} FPS",
+                self.frame_clock.fps()
+            );*/
+        }
+    }
+
+    /// The time between the start of the previous frame and the start of the current frame.
+    pub fn delta_time(&self) -> Duration {
+        self.frame_clock.delta_time()
+    }
+
+    /// The average number of frames per second over recent frames.
+    pub fn fps(&self) -> f64 {
+        self.frame_clock.fps()
+    }
+
+    /// Sets a function to be called once per frame, before the frame is rendered.
+    /// It is given the time since the previous frame.
+    pub fn set_on_update(&mut self, on_update: impl FnMut(Duration) + 'static) {
+        self.on_update = Some(Box::new(on_update));
+    }
+
+    /// Sets a function to be called with a fixed timestep, for deterministic simulation such as physics.
+    /// It is called as many times per frame as is needed to keep up with real time, before `on_update`.
+    pub fn set_fixed_update(&mut self, fixed_update: impl FnMut(Duration) + 'static) {
+        self.fixed_update = Some(Box::new(fixed_update));
+    }
+
+    /// Sets a function to draw custom content, such as geometry with its own shaders, into every window's frame.
+    /// It is called with the window being rendered, after the world is drawn and before the UI is drawn over it.
+    pub fn set_on_render(&mut self, on_render: impl FnMut(WindowId, FrameContext<'_>) + 'static) {
+        self.on_render = Some(Box::new(on_render));
+    }
+
+    /// The graphics card that every window is rendered with. Custom renderers can use this to create
+    /// their own buffers, textures and pipelines.
+    pub fn device(&self) -> Arc<Device> {
+        Arc::clone(&self.device)
+    }
+
+    /// The queue that every window's commands are submitted to.
+    pub fn queue(&self) -> Arc<Queue> {
+        Arc::clone(&self.queue)
+    }
+
+    /// Sets the time simulated by each call to the fixed update function. By default, this is a sixtieth of a second.
+    /// Panics if `fixed_delta` is zero.
+    pub fn set_fixed_delta(&mut self, fixed_delta: Duration) {
+        self.fixed_timestep.set_fixed_delta(fixed_delta);
+    }
+
+    /// How far the current frame is between the last fixed update and the next one, from 0 to 1.
+    /// Rendering code can use this to interpolate between the previous and current simulation states.
+    pub fn interpolation_alpha(&self) -> f32 {
+        self.fixed_timestep.alpha()
+    }
+
+    /// The input map, which can be queried to find out which actions were triggered this frame.
+    pub fn input(&self) -> &InputMap {
+        &self.input
+    }
+
+    /// The input map, which can be used to change which keys are bound to which actions.
+    pub fn input_mut(&mut self) -> &mut InputMap {
+        &mut self.input
+    }
+
+    /// Draws another frame of every window, even if nothing seems to have changed.
+    /// Frames are drawn automatically after input, and whenever a UI needs to be laid out again.
+    pub fn request_redraw(&mut self) {
+        self.redraw.mark_dirty();
+    }
+
     /// Executes the application.
     pub fn run(mut self, event_loop: EventLoop<()>) {
         let mut profiler = qs_common::profile::CycleProfiler::new(25);
 
         event_loop.run(move |event, _, control_flow| {
             match event {
-                Event::WindowEvent { event, window_id } if window_id == self.window.id() => {
+                Event::WindowEvent { event, window_id } => {
+                    let device = &self.device;
+                    let window = match self.windows.get_mut(&window_id) {
+                        Some(window) => window,
+                        None => return,
+                    };
+                    self.redraw.mark_dirty();
                     match event {
-                        WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                        WindowEvent::CloseRequested => {
+                            // Closing the main window exits the application, closing any other window just closes it.
+                            if window_id == self.main_window {
+                                self.save_main_window_geometry();
+                            }
+                            self.windows.remove(&window_id);
+                            if window_id == self.main_window || self.windows.is_empty() {
+                                *control_flow = ControlFlow::Exit;
+                            }
+                        }
 
                         WindowEvent::KeyboardInput { input, .. } => {
-                            if let KeyboardInput {
-                                state: ElementState::Pressed,
-                                virtual_keycode: Some(VirtualKeyCode::Escape),
-                                ..
-                            } = input
-                            {
-                                *control_flow = ControlFlow::Exit;
+                            // Keys the UI used (for example, escape closing a modal) do not trigger actions.
+                            // Releases are always passed on so that keys are not stuck down.
+                            let processed = window.keyboard_input(input);
+                            if !processed || input.state == ElementState::Released {
+                                self.input.keyboard_input(input);
                             }
                         }
 
+                        WindowEvent::ModifiersChanged(modifiers) => {
+                            window.ui.modifiers_changed(modifiers);
+                        }
+
                         WindowEvent::CursorMoved { position, .. } => {
-                            self.update_cursor(position);
+                            window.update_cursor(position);
                         }
 
                         WindowEvent::MouseInput { button, state, .. } => {
-                            self.mouse_input(button, state);
+                            // Like keys, clicks on the UI do not trigger actions, but releases are always passed on.
+                            let processed = window.mouse_input(button, state);
+                            if !processed || state == ElementState::Released {
+                                self.input.mouse_input(button, state);
+                            }
                         }
 
-                        WindowEvent::Resized(new_size) => self.resize(new_size, None),
+                        // Frames drawn while the main window is in the background don't represent normal play.
+                        WindowEvent::Focused(focused) if window_id == self.main_window => {
+                            if focused {
+                                profiler.resume();
+                            } else {
+                                profiler.pause();
+                            }
+                        }
+
+                        WindowEvent::Resized(new_size) => window.resize(device, new_size, None),
                         WindowEvent::ScaleFactorChanged {
                             new_inner_size,
                             scale_factor,
-                        } => self.resize(*new_inner_size, Some(scale_factor)),
+                        } => window.resize(device, *new_inner_size, Some(scale_factor)),
 
                         _ => {}
                     }
                 }
 
-                Event::RedrawRequested(window_id) if window_id == self.window.id() => {
+                Event::RedrawRequested(window_id) => {
                     profiler.stopwatch.tick();
                     {
                         let mut main_segment = profiler.main_segment.time();
                         {
                             let render = main_segment.task("render").time();
-                            futures::executor::block_on(self.render(render));
+                            self.render(window_id, render);
                         }
                     }
+                    // Only refresh the overlay every few frames, so that typesetting it doesn't distort the measurements.
+                    if self.debug_overlay.is_visible()
+                        && !profiler.is_paused()
+                        && profiler.main_segment.ticks % 30 == 0
+                    {
+                        self.debug_overlay.update(
+                            self.frame_clock.fps(),
+                            &profiler.snapshot(),
+                            &qs_common::profile::background_profiler().snapshot(),
+                        );
+                    }
                     if profiler.main_segment.ticks % 100 == 0 {
                         //tracing::trace!("{}", profiler);
                     }
                 }
 
                 Event::MainEventsCleared => {
-                    // RedrawRequested will only trigger once, unless we manually
-                    // request it.
-                    self.window.request_redraw();
+                    // Actions are checked once per frame, after all of the frame's input events have been handled.
+                    if self.input.just_pressed("quit") {
+                        *control_flow = ControlFlow::Exit;
+                    }
+                    if self.input.just_pressed("toggle_debug_overlay") {
+                        self.debug_overlay.toggle();
+                    }
+
+                    // Windows are only redrawn if something changed since the last frame. Otherwise, we sleep until
+                    // there is more input, waking up now and then to check whether a background task changed a UI.
+                    if self.windows.values().any(|window| window.ui.needs_layout()) {
+                        self.redraw.mark_dirty();
+                    }
+                    self.redraw.set_continuous(
+                        self.on_update.is_some()
+                            || self.fixed_update.is_some()
+                            || self.on_render.is_some()
+                            || self.debug_overlay.is_visible(),
+                    );
+                    let redraw = self.redraw.take_redraw();
+                    if redraw {
+                        for window in self.windows.values() {
+                            window.window.request_redraw();
+                        }
+                    }
+                    if *control_flow != ControlFlow::Exit {
+                        *control_flow = self.redraw.control_flow(redraw, std::time::Instant::now());
+                    }
+                }
+
+                Event::RedrawEventsCleared => {
+                    // Every window has been updated and rendered, so this frame's input has been handled.
+                    self.input.end_frame();
+                }
+
+                Event::LoopDestroyed => {
+                    // The main window is still open if we exited for some other reason, such as pressing escape.
+                    self.save_main_window_geometry();
                 }
 
                 _ => {}
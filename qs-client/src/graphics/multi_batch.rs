@@ -1,18 +1,43 @@
 use std::mem::take;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use crate::graphics::*;
-use futures::future::{BoxFuture, FutureExt};
 use qs_common::assets::Asset;
 use stretch::geometry::Point;
+use wgpu::{
+    BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Device, FilterMode, Queue,
+    ShaderStage, TextureComponentType, TextureFormat, TextureViewDimension,
+};
 
 /// A `MultiBatch` renders layers of content by sending data to multiple batches
 /// to be rendered with the fewest possible draw calls (within reasonable computational complexity).
 ///
 /// To render something using a multibatch, it must be split into several layers, where the elements of
 /// each layer are ideally rendered concurrently where possible.
+///
+/// Images in a layer are painted in the order they appear in the tree. By default, all of the text in a layer is
+/// drawn before all of its images, so text never appears above an image in the same layer even if it comes later
+/// in the tree. Use separate layers, or `set_preserve_adjacent_order`, if this matters.
 pub struct MultiBatch {
     pub batch: Batch,
     pub text_renderer: TextRenderer,
+
+    /// If true, we flush each time we switch between drawing text and drawing images.
+    preserve_adjacent_order: bool,
+    /// If true, opacity groups are rendered to a texture which is then faded, instead of fading each vertex.
+    offscreen_opacity: bool,
+    /// Frame-sized textures that opacity groups are rendered to, kept between frames so that they aren't allocated
+    /// every frame. A group takes one from the end while it is being rendered, so nested groups each get their own.
+    offscreen_targets: Vec<Texture>,
+    /// Identifies this multibatch, so that materials added to other multibatches can be told apart from its own.
+    id: u64,
+    /// The materials that have been added with `add_material`, indexed by their `MaterialId`s.
+    materials: Vec<Material>,
+
+    /// How many times the current call to `render` has rendered the data it had gathered.
+    flushes: u32,
+    last_frame_stats: BatchStats,
 }
 
 /// What texture do we need to use to render the `batch_render_data`?
@@ -35,115 +60,545 @@ impl BatchRenderTexture {
     }
 }
 
+/// Images from an unordered renderable that share a texture and material, so they can be drawn together.
+type ImageGroup = (BatchRenderTexture, Option<MaterialId>, Vec<Renderable>);
+
 struct MultiBatchRenderState<'a> {
     text_render_data: &'a mut Vec<(Point<f32>, RenderableWord)>,
     batch_render_data: &'a mut Vec<Renderable>,
     batch_render_texture: &'a mut BatchRenderTexture,
-    frame: &'a wgpu::SwapChainTexture,
+    /// The material to draw the `batch_render_data` with, or `None` to use the default shaders.
+    batch_render_material: &'a mut Option<MaterialId>,
+    frame: &'a wgpu::TextureView,
+    /// The size of the frame in physical pixels.
+    frame_size: (u32, u32),
+    viewport: Option<Viewport>,
     camera: &'a Camera,
 }
 
 impl MultiBatch {
-    pub fn new(batch: Batch, text_renderer: TextRenderer) -> Self {
+    /// The text renderer is made to share the batch's uniform buffer, as is every material added later,
+    /// so that the camera is only uploaded again when it changes.
+    pub fn new(batch: Batch, mut text_renderer: TextRenderer) -> Self {
+        text_renderer.set_uniform_buffer(batch.uniform_buffer());
         Self {
             batch,
             text_renderer,
+            preserve_adjacent_order: false,
+            offscreen_opacity: false,
+            offscreen_targets: Vec::new(),
+            id: MULTI_BATCH_COUNTER.fetch_add(1, Ordering::Relaxed),
+            materials: Vec::new(),
+            flushes: 0,
+            last_frame_stats: BatchStats::default(),
         }
     }
 
+    /// Creates a multibatch that uses the default shaders, rendering to textures with the given format.
+    /// Text is drawn with glyphs sampled using the given filter.
+    pub fn with_default_shaders(
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        format: TextureFormat,
+        scale_factor: f32,
+        glyph_filter: FilterMode,
+    ) -> Self {
+        let texture_bind_group_layout_desc = texture_bind_group_layout_descriptor();
+        let uniform_bind_group_layout_desc = uniform_bind_group_layout_descriptor();
+
+        // Let's create a batch to render many shapes in a single render pass.
+        let batch = Batch::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            wgpu::include_spirv!("shader.vert.spv"),
+            wgpu::include_spirv!("shader.frag.spv"),
+            device.create_bind_group_layout(&texture_bind_group_layout_desc),
+            device.create_bind_group_layout(&uniform_bind_group_layout_desc),
+            format,
+        );
+
+        let text_renderer = TextRenderer::new(
+            device,
+            queue,
+            &texture_bind_group_layout_desc,
+            &uniform_bind_group_layout_desc,
+            format,
+            scale_factor,
+            glyph_filter,
+        );
+
+        Self::new(batch, text_renderer)
+    }
+
     /// The rendering algorithm essentially is that we should keep adding data to a list of
     /// text/batch items to render until we hit a new layer, after which we should render the intermediate
     /// lists to the batches.
-    pub async fn render(
+    ///
+    /// If a viewport is given, the renderable is confined to that part of the frame.
+    /// It is clamped to the frame, whose size must be given in physical pixels.
+    pub fn render(
         &mut self,
         renderable: MultiRenderable,
-        frame: &wgpu::SwapChainTexture,
+        frame: &wgpu::TextureView,
+        frame_size: (u32, u32),
+        viewport: Option<Viewport>,
         camera: &Camera,
         _profiler: qs_common::profile::ProfileSegmentGuard<'_>,
     ) {
+        let viewport = match viewport {
+            Some(viewport) => match viewport.clamp_to(frame_size.0, frame_size.1) {
+                Some(viewport) => Some(viewport),
+                // The viewport is entirely outside the frame, so nothing would be visible.
+                None => return,
+            },
+            None => None,
+        };
+
+        self.batch.reset_stats();
+        self.text_renderer.reset_stats();
+        for material in &mut self.materials {
+            material.batch.reset_stats();
+        }
+        self.flushes = 0;
+
         let mut text_render_data: Vec<(Point<f32>, RenderableWord)> = Vec::new();
         let mut batch_render_data: Vec<Renderable> = Vec::new();
         let mut batch_render_texture = BatchRenderTexture::Nothing;
+        let mut batch_render_material = None;
         let mut state = MultiBatchRenderState {
             text_render_data: &mut text_render_data,
             batch_render_data: &mut batch_render_data,
             batch_render_texture: &mut batch_render_texture,
+            batch_render_material: &mut batch_render_material,
             frame,
+            frame_size,
+            viewport,
             camera,
         };
 
-        state.incremental_render(renderable, self).await;
-        state.perform_render(self).await;
+        state.incremental_render(renderable, self);
+        state.perform_render(self);
+
+        let material_stats = self
+            .materials
+            .iter()
+            .fold(BatchStats::default(), |stats, material| {
+                stats + material.batch.stats()
+            });
+        self.last_frame_stats = BatchStats {
+            flushes: self.flushes,
+            ..self.batch.stats() + self.text_renderer.stats() + material_stats
+        };
+    }
+
+    /// If `preserve_adjacent_order` is true, text and images in the same layer are stacked in the order they appear,
+    /// at the cost of a draw call each time the renderable switches between text and images.
+    /// If false (the default), all the text in a layer is drawn underneath all of its images.
+    pub fn set_preserve_adjacent_order(&mut self, preserve_adjacent_order: bool) {
+        self.preserve_adjacent_order = preserve_adjacent_order;
+    }
+
+    /// If `offscreen_opacity` is true, each `Opacity` group is rendered to a texture the size of the frame, which is then
+    /// drawn over the frame at the group's alpha. This costs a clear and two draw calls per group, and the multibatch keeps
+    /// a texture for each level of nested groups, but items that overlap
+    /// inside the group are blended with each other before they are faded, so they don't show through each other.
+    /// If false (the default), the alpha of every vertex in the group is multiplied instead.
+    pub fn set_offscreen_opacity(&mut self, offscreen_opacity: bool) {
+        self.offscreen_opacity = offscreen_opacity;
+    }
+
+    /// Adds a material that images can be drawn with, returning the ID that `MultiRenderable::Custom` refers to it by.
+    pub fn add_material(&mut self, mut material: Material) -> MaterialId {
+        material
+            .batch
+            .set_uniform_buffer(self.batch.uniform_buffer());
+        self.materials.push(material);
+        MaterialId {
+            multi_batch: self.id,
+            index: self.materials.len() - 1,
+        }
+    }
+
+    /// Returns `None` if the material was added to a different multibatch.
+    pub fn material(&self, id: MaterialId) -> Option<&Material> {
+        if id.multi_batch == self.id {
+            self.materials.get(id.index)
+        } else {
+            None
+        }
+    }
+
+    /// Materials from other multibatches can't be drawn with, so renderables using them are logged and skipped.
+    fn owns_material(&self, id: MaterialId) -> bool {
+        let owned = id.multi_batch == self.id;
+        if !owned {
+            tracing::error!(
+                "cannot draw with {:?}, which was added to another multibatch",
+                id
+            );
+        }
+        owned
+    }
+
+    /// Returns everything that was drawn by the most recent call to `render`.
+    pub fn last_frame_stats(&self) -> BatchStats {
+        self.last_frame_stats
+    }
+}
+
+/// Describes how the default shaders, and every material, bind the texture they draw with.
+pub(super) fn texture_bind_group_layout_descriptor() -> BindGroupLayoutDescriptor<'static> {
+    BindGroupLayoutDescriptor {
+        entries: &[
+            BindGroupLayoutEntry {
+                binding: 0,
+                visibility: ShaderStage::FRAGMENT,
+                ty: BindingType::SampledTexture {
+                    multisampled: false,
+                    dimension: TextureViewDimension::D2,
+                    component_type: TextureComponentType::Uint,
+                },
+                count: None,
+            },
+            BindGroupLayoutEntry {
+                binding: 1,
+                visibility: ShaderStage::FRAGMENT,
+                ty: BindingType::Sampler { comparison: false },
+                count: None,
+            },
+        ],
+        label: Some("texture_bind_group_layout"),
+    }
+}
+
+/// Describes how the default shaders, and every material, bind the camera uniforms.
+pub(super) fn uniform_bind_group_layout_descriptor() -> BindGroupLayoutDescriptor<'static> {
+    BindGroupLayoutDescriptor {
+        entries: &[BindGroupLayoutEntry {
+            binding: 0,
+            visibility: ShaderStage::VERTEX,
+            ty: BindingType::UniformBuffer {
+                dynamic: true,
+                min_binding_size: None,
+            },
+            count: None,
+        }],
+        label: Some("uniform_bind_group_layout"),
+    }
+}
+
+/// A pair of shaders that images can be drawn with instead of the default shaders, for effects such as
+/// a greyscale or a wave distortion. Add it to a `MultiBatch` with `add_material`, then draw with it using
+/// `MultiRenderable::Custom`.
+///
+/// The shaders are given the same vertices, texture and camera uniforms as the default shaders `shader.vert` and `shader.frag`.
+pub struct Material {
+    pub name: String,
+    batch: Batch,
+}
+
+impl Material {
+    /// Creates a material from compiled SPIR-V shaders, which may have been loaded or compiled at runtime.
+    pub fn from_spirv(
+        name: impl Into<String>,
+        device: Arc<Device>,
+        queue: Arc<Queue>,
+        vertex_spirv: &[u8],
+        fragment_spirv: &[u8],
+        format: TextureFormat,
+    ) -> Self {
+        let texture_bind_group_layout =
+            device.create_bind_group_layout(&texture_bind_group_layout_descriptor());
+        let uniform_bind_group_layout =
+            device.create_bind_group_layout(&uniform_bind_group_layout_descriptor());
+        let batch = Batch::new(
+            device,
+            queue,
+            wgpu::util::make_spirv(vertex_spirv),
+            wgpu::util::make_spirv(fragment_spirv),
+            texture_bind_group_layout,
+            uniform_bind_group_layout,
+            format,
+        );
+        Self {
+            name: name.into(),
+            batch,
+        }
     }
 }
 
+/// Identifies a material that has been added to a `MultiBatch`. It can only be drawn with by that multibatch.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct MaterialId {
+    multi_batch: u64,
+    index: usize,
+}
+
+static MULTI_BATCH_COUNTER: AtomicU64 = AtomicU64::new(0);
+
 impl<'a> MultiBatchRenderState<'a> {
     /// Appends render information to the given data, calling `perform_render` if we need to.
-    fn incremental_render<'b>(
-        &'b mut self,
-        renderable: MultiRenderable,
-        batch: &'b mut MultiBatch,
-    ) -> BoxFuture<()> {
-        async move {
-            match renderable {
-                MultiRenderable::Nothing => {}
-                MultiRenderable::Layered(layers) => {
-                    for (layer, index) in layers.into_iter().zip(0i32..) {
-                        if index != 0 {
-                            self.perform_render(batch).await;
-                        }
-                        self.incremental_render(layer, batch).await;
+    fn incremental_render(&mut self, renderable: MultiRenderable, batch: &mut MultiBatch) {
+        match renderable {
+            MultiRenderable::Nothing => {}
+            MultiRenderable::Layered(layers) => {
+                for (layer, index) in layers.into_iter().zip(0i32..) {
+                    if index != 0 {
+                        self.perform_render(batch);
                     }
+                    self.incremental_render(layer, batch);
                 }
-                MultiRenderable::Adjacent(items) => {
-                    for item in items {
-                        self.incremental_render(item, batch).await;
-                    }
+            }
+            MultiRenderable::Adjacent(items) => {
+                for item in items {
+                    self.incremental_render(item, batch);
                 }
-                MultiRenderable::Text { word, offset } => {
-                    self.text_render_data.push((offset, word));
+            }
+            MultiRenderable::Unordered(items) => {
+                let mut groups = Vec::new();
+                for item in items {
+                    self.group_images(item, &mut groups, batch);
                 }
-                MultiRenderable::Image {
-                    texture,
-                    mut renderables,
-                } => {
-                    let new_render_texture = BatchRenderTexture::Texture(texture);
-                    if !self
-                        .batch_render_texture
-                        .compatible_with(new_render_texture.clone())
-                    {
-                        self.perform_render(batch).await;
-                    }
-                    *self.batch_render_texture = new_render_texture;
-
-                    self.batch_render_data.append(&mut renderables);
+                for (texture, material, renderables) in groups {
+                    self.add_images(texture, material, renderables, batch);
                 }
-                MultiRenderable::ImageRegion {
-                    texture,
-                    mut renderables,
-                } => {
-                    let new_render_texture =
-                        BatchRenderTexture::PartitionedTexture(texture.partitioned_texture.clone());
-                    if !self
-                        .batch_render_texture
-                        .compatible_with(new_render_texture.clone())
-                    {
-                        self.perform_render(batch).await;
-                    }
-                    *self.batch_render_texture = new_render_texture;
+            }
+            MultiRenderable::WithCamera { camera, inner } => {
+                // The camera's matrix is stored in a uniform buffer, so anything already queued
+                // must be rendered with the previous camera before we switch.
+                self.perform_render(batch);
+                let mut state = MultiBatchRenderState {
+                    text_render_data: &mut *self.text_render_data,
+                    batch_render_data: &mut *self.batch_render_data,
+                    batch_render_texture: &mut *self.batch_render_texture,
+                    batch_render_material: &mut *self.batch_render_material,
+                    frame: self.frame,
+                    frame_size: self.frame_size,
+                    viewport: self.viewport,
+                    camera: &camera,
+                };
+                state.incremental_render(*inner, batch);
+                state.perform_render(batch);
+            }
+            MultiRenderable::Transformed { matrix, inner } => {
+                self.incremental_render(
+                    MultiRenderable::WithCamera {
+                        camera: Arc::new(self.camera.transformed(matrix)),
+                        inner,
+                    },
+                    batch,
+                );
+            }
+            MultiRenderable::Opacity { alpha, inner } => {
+                if batch.offscreen_opacity {
+                    self.render_offscreen(alpha, *inner, batch);
+                } else {
+                    self.incremental_render(inner.with_alpha(alpha), batch);
+                }
+            }
+            MultiRenderable::Text { word, offset } => {
+                if batch.preserve_adjacent_order && !self.batch_render_data.is_empty() {
+                    self.perform_render(batch);
+                }
+                self.text_render_data.push((offset, word));
+            }
+            MultiRenderable::Image {
+                texture,
+                renderables,
+            } => {
+                self.add_images(
+                    BatchRenderTexture::Texture(texture),
+                    None,
+                    renderables,
+                    batch,
+                );
+            }
+            MultiRenderable::ImageRegion {
+                texture,
+                renderables,
+            } => {
+                self.add_images(
+                    BatchRenderTexture::PartitionedTexture(texture.partitioned_texture.clone()),
+                    None,
+                    renderables,
+                    batch,
+                );
+            }
+            MultiRenderable::Custom {
+                material,
+                texture,
+                renderables,
+            } => {
+                if !batch.owns_material(material) {
+                    return;
+                }
+                self.add_images(
+                    BatchRenderTexture::Texture(texture),
+                    Some(material),
+                    renderables,
+                    batch,
+                );
+            }
+        }
+    }
 
-                    self.batch_render_data.append(&mut renderables);
+    /// Sorts the images in an unordered renderable into groups that can each be drawn in one draw call.
+    /// Everything that isn't an image is rendered straight away. In particular, a nested `Adjacent` or `Layered`
+    /// is rendered as a single unit, so the images inside it keep their order.
+    fn group_images(
+        &mut self,
+        renderable: MultiRenderable,
+        groups: &mut Vec<ImageGroup>,
+        batch: &mut MultiBatch,
+    ) {
+        let (texture, material, mut renderables) = match renderable {
+            MultiRenderable::Unordered(items) => {
+                for item in items {
+                    self.group_images(item, groups, batch);
+                }
+                return;
+            }
+            MultiRenderable::Image {
+                texture,
+                renderables,
+            } => (BatchRenderTexture::Texture(texture), None, renderables),
+            MultiRenderable::ImageRegion {
+                texture,
+                renderables,
+            } => (
+                BatchRenderTexture::PartitionedTexture(texture.partitioned_texture.clone()),
+                None,
+                renderables,
+            ),
+            MultiRenderable::Custom {
+                material,
+                texture,
+                renderables,
+            } => {
+                if !batch.owns_material(material) {
+                    return;
                 }
+                (
+                    BatchRenderTexture::Texture(texture),
+                    Some(material),
+                    renderables,
+                )
+            }
+            other => {
+                self.incremental_render(other, batch);
+                return;
             }
+        };
+        match groups
+            .iter_mut()
+            .find(|(group_texture, group_material, _)| {
+                *group_texture == texture && *group_material == material
+            }) {
+            Some((_, _, group)) => group.append(&mut renderables),
+            None => groups.push((texture, material, renderables)),
+        }
+    }
+
+    /// Queues images to be drawn with the given texture and material, first rendering anything already queued
+    /// that cannot be drawn in the same draw call.
+    fn add_images(
+        &mut self,
+        texture: BatchRenderTexture,
+        material: Option<MaterialId>,
+        mut renderables: Vec<Renderable>,
+        batch: &mut MultiBatch,
+    ) {
+        if !self.batch_render_texture.compatible_with(texture.clone())
+            || *self.batch_render_material != material && !self.batch_render_data.is_empty()
+            || batch.preserve_adjacent_order && !self.text_render_data.is_empty()
+        {
+            self.perform_render(batch);
+        }
+        *self.batch_render_texture = texture;
+        *self.batch_render_material = material;
+
+        self.batch_render_data.append(&mut renderables);
+    }
+
+    /// Renders the inner renderable to a transparent texture the size of the frame, then draws the texture over the frame
+    /// with the given alpha.
+    ///
+    /// Translucent items that are drawn onto the transparent texture are faded twice, so they come out a little
+    /// fainter than they would if they were drawn straight onto the frame. Opaque items are unaffected.
+    fn render_offscreen(&mut self, alpha: f32, inner: MultiRenderable, batch: &mut MultiBatch) {
+        self.perform_render(batch);
+        let (width, height) = self.frame_size;
+        if width == 0 || height == 0 {
+            return;
+        }
+
+        let device = Arc::clone(batch.batch.device());
+        let queue = Arc::clone(batch.batch.queue());
+        // Targets are only allocated again when the frame changes size.
+        let texture = match batch.offscreen_targets.pop() {
+            Some(texture) if texture.dimensions == (width, height) => texture,
+            _ => RenderTarget::with_format(&device, width, height, batch.batch.format())
+                .into_texture(&device),
+        };
+        clear(
+            &device,
+            &queue,
+            &texture.view,
+            crate::ui::Colour::rgba(0.0, 0.0, 0.0, 0.0),
+        );
+        {
+            let mut state = MultiBatchRenderState {
+                text_render_data: &mut *self.text_render_data,
+                batch_render_data: &mut *self.batch_render_data,
+                batch_render_texture: &mut *self.batch_render_texture,
+                batch_render_material: &mut *self.batch_render_material,
+                frame: &texture.view,
+                frame_size: self.frame_size,
+                viewport: self.viewport,
+                camera: self.camera,
+            };
+            state.incremental_render(inner, batch);
+            state.perform_render(batch);
         }
-        .boxed()
+
+        // The inner renderable was already confined to the viewport, so the texture is drawn over the whole frame,
+        // using a camera that shows the area from -1 to 1 on both axes.
+        let camera = Camera::new(CameraData::Orthographic {
+            eye: cgmath::Point2::new(0.0, 0.0),
+            view_height: 2.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        let vertex = |x, y, u, v| Vertex {
+            position: [x, y, 0.0],
+            color: [1.0, 1.0, 1.0, alpha],
+            tex_coords: [u, v],
+        };
+        let quad = Renderable::Quadrilateral(
+            vertex(-1.0, 1.0, 0.0, 0.0),
+            vertex(1.0, 1.0, 1.0, 0.0),
+            vertex(1.0, -1.0, 1.0, 1.0),
+            vertex(-1.0, -1.0, 0.0, 1.0),
+        );
+        batch.flushes += 1;
+        batch
+            .batch
+            .render(self.frame, None, &texture, &camera, std::iter::once(quad));
+        batch.offscreen_targets.push(texture);
     }
 
-    async fn perform_render<'b>(&'b mut self, batch: &'b mut MultiBatch) {
+    fn perform_render(&mut self, batch: &mut MultiBatch) {
+        if !self.text_render_data.is_empty() || !self.batch_render_data.is_empty() {
+            batch.flushes += 1;
+        }
         if !self.text_render_data.is_empty() {
             batch.text_renderer.draw_text(
                 take(self.text_render_data),
                 self.frame,
+                self.viewport,
                 self.camera,
                 //profiler.task("text").time(),
             );
@@ -151,29 +606,33 @@ impl<'a> MultiBatchRenderState<'a> {
         if !self.batch_render_data.is_empty() {
             let render_texture =
                 std::mem::replace(self.batch_render_texture, BatchRenderTexture::Nothing);
+            let image_batch = match self.batch_render_material.take() {
+                Some(material) => &mut batch.materials[material.index].batch,
+                None => &mut batch.batch,
+            };
             match render_texture {
                 BatchRenderTexture::Nothing => {}
                 BatchRenderTexture::Texture(tex) => {
-                    tex.if_loaded(|tex| {
-                        batch.batch.render(
+                    tex.try_if_loaded(|tex| {
+                        image_batch.render(
                             self.frame,
+                            self.viewport,
                             &tex,
                             self.camera,
                             take(self.batch_render_data).into_iter(),
                         );
-                    })
-                    .await;
+                    });
                 }
                 BatchRenderTexture::PartitionedTexture(tex) => {
-                    tex.if_loaded(|tex| {
-                        batch.batch.render(
+                    tex.try_if_loaded(|tex| {
+                        image_batch.render(
                             self.frame,
+                            self.viewport,
                             &tex.base_texture,
                             self.camera,
                             take(self.batch_render_data).into_iter(),
                         );
-                    })
-                    .await;
+                    });
                 }
             }
         }
@@ -188,15 +647,54 @@ pub enum MultiRenderable {
     /// This is a list of layers of renderables to render.
     /// - If this is empty, nothing will be rendered. No draw calls will be used.
     /// - If this has one element, that element will be rendered alongside sibling
-    /// elements. The order of rendering is not guaranteed.
+    /// elements, in the same way as an element of `Adjacent`.
     /// - If this has more than one element, then previous layers are rendered before later layers.
-    /// The whole element is rendered alongside sibling elements; the order of rendering between
-    /// siblings is not guaranteed.
+    /// The whole element is rendered alongside sibling elements, in the same way as an element of `Adjacent`.
     Layered(Vec<MultiRenderable>),
 
-    /// The list of items are rendered alongside each other with no regard for ordering.
+    /// The list of items are rendered alongside each other, in as few draw calls as possible.
+    ///
+    /// Images are painted in list order, so a later image is always drawn over an earlier one that it overlaps.
+    /// Images with the same texture share a draw call, which draws them in order, and a new draw call is started
+    /// whenever the texture changes. There is no depth buffer to reorder them.
+    ///
+    /// Text is batched separately, so by default all of the text is drawn underneath all of the images.
+    /// If the multibatch is set to preserve adjacent order, text is painted in list order too.
     Adjacent(Vec<MultiRenderable>),
 
+    /// Like `Adjacent`, but the items may be drawn in any order. Images are grouped by texture before they are drawn,
+    /// so that interleaved images from a few textures only need one draw call per texture.
+    ///
+    /// There is no depth buffer, so only use this for content whose order doesn't matter, such as opaque images that
+    /// don't overlap. Overlapping images, especially translucent ones, should be `Adjacent` so they stack correctly.
+    /// A nested `Adjacent` or `Layered` is still drawn in its own order, as one unit.
+    Unordered(Vec<MultiRenderable>),
+
+    /// Renders the inner renderable using a different camera, for example to draw a screen-space HUD
+    /// over a world that is rendered with a moving camera.
+    /// Anything rendered before this element is rendered first, and the inner element is rendered
+    /// before anything after this element.
+    WithCamera {
+        camera: Arc<Camera>,
+        inner: Box<MultiRenderable>,
+    },
+
+    /// Renders the inner renderable with its vertices transformed by the given matrix, for example to slide a whole
+    /// UI panel in without laying it out again. Like `WithCamera`, this is drawn separately from its siblings.
+    /// Nested transforms are combined, so an inner transform is applied within its parent's coordinate system.
+    Transformed {
+        matrix: cgmath::Matrix4<f32>,
+        inner: Box<MultiRenderable>,
+    },
+
+    /// Renders the inner renderable faded by the given alpha, for example to fade a whole UI panel in or out.
+    /// How the group is faded depends on the multibatch's `set_offscreen_opacity` setting.
+    /// Nested groups are combined, so a group at alpha 0.5 inside another at 0.5 is drawn at 0.25.
+    Opacity {
+        alpha: f32,
+        inner: Box<MultiRenderable>,
+    },
+
     /// Render some text using the text render batch.
     Text {
         word: RenderableWord,
@@ -215,4 +713,744 @@ pub enum MultiRenderable {
         texture: TextureRegion,
         renderables: Vec<Renderable>,
     },
+
+    /// Render a region (or multiple regions) of a texture using a material that was added to the multibatch,
+    /// instead of the default shaders.
+    Custom {
+        material: MaterialId,
+        texture: Asset<Texture>,
+        renderables: Vec<Renderable>,
+    },
+}
+
+impl MultiRenderable {
+    /// Multiplies the alpha of everything inside this renderable, as an `Opacity` group does when it isn't rendered
+    /// offscreen. Each item is faded separately, so items that overlap show through each other.
+    pub fn with_alpha(self, alpha: f32) -> Self {
+        let fade_all = |items: Vec<MultiRenderable>| {
+            items
+                .into_iter()
+                .map(|item| item.with_alpha(alpha))
+                .collect()
+        };
+        let fade_renderables = |renderables: Vec<Renderable>| {
+            renderables
+                .into_iter()
+                .map(|renderable| renderable.with_alpha(alpha))
+                .collect()
+        };
+        match self {
+            MultiRenderable::Nothing => MultiRenderable::Nothing,
+            MultiRenderable::Layered(items) => MultiRenderable::Layered(fade_all(items)),
+            MultiRenderable::Adjacent(items) => MultiRenderable::Adjacent(fade_all(items)),
+            MultiRenderable::Unordered(items) => MultiRenderable::Unordered(fade_all(items)),
+            MultiRenderable::WithCamera { camera, inner } => MultiRenderable::WithCamera {
+                camera,
+                inner: Box::new(inner.with_alpha(alpha)),
+            },
+            MultiRenderable::Transformed { matrix, inner } => MultiRenderable::Transformed {
+                matrix,
+                inner: Box::new(inner.with_alpha(alpha)),
+            },
+            MultiRenderable::Opacity {
+                alpha: inner_alpha,
+                inner,
+            } => MultiRenderable::Opacity {
+                alpha: inner_alpha * alpha,
+                inner,
+            },
+            MultiRenderable::Text { word, offset } => MultiRenderable::Text {
+                word: word.with_alpha(alpha),
+                offset,
+            },
+            MultiRenderable::Image {
+                texture,
+                renderables,
+            } => MultiRenderable::Image {
+                texture,
+                renderables: fade_renderables(renderables),
+            },
+            MultiRenderable::ImageRegion {
+                texture,
+                renderables,
+            } => MultiRenderable::ImageRegion {
+                texture,
+                renderables: fade_renderables(renderables),
+            },
+            MultiRenderable::Custom {
+                material,
+                texture,
+                renderables,
+            } => MultiRenderable::Custom {
+                material,
+                texture,
+                renderables: fade_renderables(renderables),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{FontAssetLoader, TextureAssetLoader};
+    use crate::test_fixtures::{noto_sans_regular, regular_only_family, FontAssetManager};
+    use crate::ui::{Colour, RichText};
+    use qs_common::assets::{AssetManager, AssetPath};
+
+    /// A quad with the given colour covering the horizontal range `x0..x1`, and the vertical range `-1..1`.
+    fn quad(x0: f32, x1: f32, colour: [f32; 4]) -> Renderable {
+        rect(x0, -1.0, x1, 1.0, colour)
+    }
+
+    /// A quad with the given colour covering the horizontal range `x0..x1`, and the vertical range `y0..y1`.
+    fn rect(x0: f32, y0: f32, x1: f32, y1: f32, colour: [f32; 4]) -> Renderable {
+        let vertex = |x, y, u, v| Vertex {
+            position: [x, y, 0.0],
+            color: colour,
+            tex_coords: [u, v],
+        };
+        Renderable::Quadrilateral(
+            vertex(x0, y1, 0.0, 0.0),
+            vertex(x1, y1, 1.0, 0.0),
+            vertex(x1, y0, 1.0, 1.0),
+            vertex(x0, y0, 0.0, 1.0),
+        )
+    }
+
+    fn camera_at(x: f32) -> Camera {
+        Camera::new(CameraData::Orthographic {
+            eye: cgmath::Point2::new(x, 0.0),
+            view_height: 2.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        })
+    }
+
+    /// Typesets some blue text in Noto Sans, returning its first word.
+    /// The font asset manager must be kept alive while the word is drawn.
+    async fn blue_word(text: &str, font_am: &mut FontAssetManager) -> RenderableWord {
+        let font = noto_sans_regular(font_am).await;
+        let font_family = regular_only_family(font);
+        let blue = Colour {
+            r: 0.0,
+            g: 0.0,
+            b: 1.0,
+            a: 1.0,
+        };
+
+        let mut rich_text = RichText::new(Default::default());
+        rich_text
+            .set_text(font_family)
+            .coloured(blue, |builder| builder.write(text))
+            .finish()
+            .await
+            .unwrap()
+            .unwrap();
+        let paragraph = rich_text.get_widget().0.read().unwrap().get_children()[0].clone();
+        let word = paragraph.0.read().unwrap().get_children()[0].clone();
+        let word = word.0.read().unwrap();
+        // Layouts can't be constructed directly, so take the layout of an empty node at the origin.
+        let mut stretch = stretch::Stretch::new();
+        let node = stretch.new_node(Default::default(), Vec::new()).unwrap();
+        stretch
+            .compute_layout(node, stretch::geometry::Size::undefined())
+            .unwrap();
+        let layout = *stretch.layout(node).unwrap();
+        match word.get_element().generate_render_info(&layout) {
+            MultiRenderable::Text { word, .. } => word,
+            _ => panic!("a word should render as text"),
+        }
+    }
+
+    type TextureAssetManager = AssetManager<AssetPath, Texture, TextureAssetLoader>;
+
+    /// Renders to an 8x8 render target, using a white texture for every image.
+    struct TestRenderer {
+        context: GraphicsContext,
+        multi_batch: MultiBatch,
+        white: Asset<Texture>,
+        _texture_am: TextureAssetManager,
+    }
+
+    impl TestRenderer {
+        /// Returns `None` if there is no graphics card to render with.
+        async fn new() -> Option<Self> {
+            let context = GraphicsContext::headless().await?;
+
+            let mut texture_am = AssetManager::new(TextureAssetLoader::new(
+                Arc::clone(&context.device),
+                Arc::clone(&context.queue),
+            ));
+            let white = texture_am.get(AssetPath::new(vec!["white.png".to_string()]));
+            white.wait_until_loaded().await;
+
+            let multi_batch = MultiBatch::with_default_shaders(
+                Arc::clone(&context.device),
+                Arc::clone(&context.queue),
+                RenderTarget::FORMAT,
+                1.0,
+                FilterMode::Nearest,
+            );
+            Some(Self {
+                context,
+                multi_batch,
+                white,
+                _texture_am: texture_am,
+            })
+        }
+
+        /// An image with the given colour covering the horizontal range `x0..x1` of the view.
+        fn image(&self, x0: f32, x1: f32, colour: [f32; 4]) -> MultiRenderable {
+            MultiRenderable::Image {
+                texture: self.white.clone(),
+                renderables: vec![quad(x0, x1, colour)],
+            }
+        }
+
+        /// Renders onto a black background with a camera that shows the area from -1 to 1 on both axes.
+        /// Returns the rendered pixels.
+        async fn render(
+            &mut self,
+            renderable: MultiRenderable,
+            viewport: Option<Viewport>,
+        ) -> Vec<u8> {
+            self.render_with_camera(renderable, viewport, &camera_at(0.0))
+                .await
+        }
+
+        async fn render_with_camera(
+            &mut self,
+            renderable: MultiRenderable,
+            viewport: Option<Viewport>,
+            camera: &Camera,
+        ) -> Vec<u8> {
+            let device = &self.context.device;
+            let queue = &self.context.queue;
+            let target = RenderTarget::new(device, 8, 8);
+            clear(device, queue, target.view(), crate::ui::Colour::BLACK);
+
+            let mut profiler = qs_common::profile::CycleProfiler::new(1);
+            self.multi_batch.render(
+                renderable,
+                target.view(),
+                (8, 8),
+                viewport,
+                camera,
+                profiler.main_segment.time(),
+            );
+            target.read_pixels(device, queue).await
+        }
+    }
+
+    fn pixel(pixels: &[u8], x: usize, y: usize) -> &[u8] {
+        &pixels[4 * (8 * y + x)..4 * (8 * y + x) + 4]
+    }
+
+    #[tokio::test]
+    async fn each_camera_applies_to_its_own_subtree() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        // The same quad is drawn on the left half of the screen, then moved to the right half by the second camera.
+        let renderable = MultiRenderable::Adjacent(vec![
+            renderer.image(-1.0, 0.0, [1.0, 0.0, 0.0, 1.0]),
+            MultiRenderable::WithCamera {
+                camera: Arc::new(camera_at(1.0)),
+                inner: Box::new(renderer.image(-1.0, 0.0, [0.0, 1.0, 0.0, 1.0])),
+            },
+        ]);
+        let pixels = renderer.render(renderable, None).await;
+
+        assert_eq!(pixel(&pixels, 1, 4), &[255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 6, 4), &[0, 255, 0, 255]);
+    }
+
+    #[tokio::test]
+    async fn transforms_move_their_subtree() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        // Both quads start on the left half of the screen. The transformed quad is moved a quarter of the view right
+        // by each of two nested translations, so it covers the right half.
+        let translation = cgmath::Matrix4::from_translation(cgmath::Vector3::new(0.5, 0.0, 0.0));
+        let renderable = MultiRenderable::Adjacent(vec![
+            renderer.image(-1.0, -0.5, [1.0, 0.0, 0.0, 1.0]),
+            MultiRenderable::Transformed {
+                matrix: translation,
+                inner: Box::new(MultiRenderable::Transformed {
+                    matrix: translation,
+                    inner: Box::new(renderer.image(-1.0, 0.0, [0.0, 1.0, 0.0, 1.0])),
+                }),
+            },
+        ]);
+        let pixels = renderer.render(renderable, None).await;
+
+        assert_eq!(pixel(&pixels, 1, 4), &[255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 3, 4), &[0, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 6, 4), &[0, 255, 0, 255]);
+    }
+
+    #[tokio::test]
+    async fn every_camera_in_a_frame_keeps_its_own_matrix() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        // Each column of pixels is drawn with its own camera, so the uniform buffer is written many times
+        // before the frame is finished.
+        let columns = (0..8)
+            .map(|column| {
+                let colour = if column % 2 == 0 {
+                    [1.0, 0.0, 0.0, 1.0]
+                } else {
+                    [0.0, 1.0, 0.0, 1.0]
+                };
+                MultiRenderable::WithCamera {
+                    camera: Arc::new(camera_at(column as f32 * 0.25)),
+                    inner: Box::new(renderer.image(-1.0, -0.75, colour)),
+                }
+            })
+            .collect();
+        let pixels = renderer
+            .render(MultiRenderable::Layered(columns), None)
+            .await;
+
+        for x in 0..8 {
+            let expected: &[u8] = if x % 2 == 0 {
+                &[255, 0, 0, 255]
+            } else {
+                &[0, 255, 0, 255]
+            };
+            assert_eq!(pixel(&pixels, x, 4), expected, "column {}", x);
+        }
+    }
+
+    #[tokio::test]
+    async fn viewport_confines_rendering() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        // This quad covers the whole view, which is squashed into the top left quadrant of the frame.
+        let renderable = renderer.image(-1.0, 1.0, [1.0, 1.0, 1.0, 1.0]);
+        let viewport = Viewport {
+            x: 0.0,
+            y: 0.0,
+            width: 4.0,
+            height: 4.0,
+        };
+        let pixels = renderer.render(renderable, Some(viewport)).await;
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let expected: &[u8] = if x < 4 && y < 4 {
+                    &[255, 255, 255, 255]
+                } else {
+                    &[0, 0, 0, 255]
+                };
+                assert_eq!(pixel(&pixels, x, y), expected, "pixel ({}, {})", x, y);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn each_layer_costs_one_draw_call() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        // The two adjacent images share a texture, so they are drawn together.
+        let renderable = MultiRenderable::Layered(vec![
+            MultiRenderable::Adjacent(vec![
+                renderer.image(-1.0, 0.0, [1.0, 0.0, 0.0, 1.0]),
+                renderer.image(0.0, 1.0, [0.0, 1.0, 0.0, 1.0]),
+            ]),
+            renderer.image(-0.5, 0.5, [0.0, 0.0, 1.0, 1.0]),
+            MultiRenderable::Nothing,
+        ]);
+        renderer.render(renderable, None).await;
+
+        assert_eq!(
+            renderer.multi_batch.last_frame_stats(),
+            BatchStats {
+                draw_calls: 2,
+                vertices: 12,
+                indices: 18,
+                flushes: 2,
+                uniform_writes: 1,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rendering_does_not_wait_for_busy_textures() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+
+        // Holding the texture's lock makes it look like it is still being loaded. Rendering skips it instead of waiting.
+        let data = renderer.white.data.upgrade().unwrap();
+        let _guard = data.write().await;
+        let renderable = renderer.image(-1.0, 1.0, [1.0, 1.0, 1.0, 1.0]);
+        let pixels = renderer.render(renderable, None).await;
+        assert_eq!(renderer.multi_batch.last_frame_stats().draw_calls, 0);
+        assert_eq!(pixel(&pixels, 4, 4), &[0, 0, 0, 255]);
+    }
+
+    #[tokio::test]
+    async fn the_camera_is_uploaded_once_for_many_layers() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let word = blue_word("H", &mut font_am).await;
+
+        // Text and images alternate, so the text renderer and the batch take turns to draw.
+        let layers = (0..4)
+            .map(|_| {
+                MultiRenderable::Adjacent(vec![
+                    MultiRenderable::Text {
+                        word: word.clone(),
+                        offset: Point { x: 0.0, y: 0.0 },
+                    },
+                    renderer.image(-1.0, 1.0, [1.0, 1.0, 1.0, 1.0]),
+                ])
+            })
+            .collect();
+        renderer
+            .render(MultiRenderable::Layered(layers), None)
+            .await;
+
+        let stats = renderer.multi_batch.last_frame_stats();
+        assert_eq!(stats.draw_calls, 8);
+        assert_eq!(stats.uniform_writes, 1);
+    }
+
+    #[tokio::test]
+    async fn unordered_images_are_grouped_by_texture() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let other = renderer
+            ._texture_am
+            .get(AssetPath::new(vec!["test.png".to_string()]));
+        other.wait_until_loaded().await;
+        let other_image = |x0, x1| MultiRenderable::Image {
+            texture: other.clone(),
+            renderables: vec![quad(x0, x1, [1.0, 1.0, 1.0, 1.0])],
+        };
+        let images = |renderer: &TestRenderer| {
+            vec![
+                renderer.image(-1.0, -0.5, [1.0, 0.0, 0.0, 1.0]),
+                other_image(-0.5, 0.0),
+                renderer.image(0.0, 0.5, [1.0, 0.0, 0.0, 1.0]),
+                other_image(0.5, 1.0),
+            ]
+        };
+
+        // In list order, every image switches texture.
+        let adjacent = MultiRenderable::Adjacent(images(&renderer));
+        renderer.render(adjacent, None).await;
+        assert_eq!(renderer.multi_batch.last_frame_stats().draw_calls, 4);
+
+        let unordered = MultiRenderable::Unordered(images(&renderer));
+        let pixels = renderer.render(unordered, None).await;
+        assert_eq!(renderer.multi_batch.last_frame_stats().draw_calls, 2);
+        assert_eq!(pixel(&pixels, 0, 4), &[255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 4, 4), &[255, 0, 0, 255]);
+
+        // Images inside a nested adjacent or layered renderable still stack in order.
+        let blue = [0.0, 0.0, 1.0, 1.0];
+        let green = [0.0, 1.0, 0.0, 1.0];
+        let unordered = MultiRenderable::Unordered(vec![
+            MultiRenderable::Adjacent(vec![
+                renderer.image(-1.0, 0.0, [1.0, 0.0, 0.0, 1.0]),
+                other_image(-1.0, 0.0),
+                renderer.image(-1.0, -0.5, blue),
+            ]),
+            MultiRenderable::Layered(vec![other_image(0.0, 1.0), renderer.image(0.5, 1.0, green)]),
+        ]);
+        let pixels = renderer.render(unordered, None).await;
+        assert_eq!(pixel(&pixels, 0, 4), &[0, 0, 255, 255]);
+        assert_eq!(pixel(&pixels, 7, 4), &[0, 255, 0, 255]);
+    }
+
+    #[tokio::test]
+    async fn preserving_adjacent_order_stacks_text_between_images() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let word = blue_word("HHHH", &mut font_am).await;
+
+        // Look at the middle of the word. Text is drawn with y increasing upwards from `-height`.
+        let (width, height) = (word.size.0 as f32, word.size.1 as f32);
+        let camera = Camera::new(CameraData::Orthographic {
+            eye: cgmath::Point2::new(width / 2.0, -height / 2.0),
+            view_height: height,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        let (left, right) = (width / 2.0 - height / 2.0, width / 2.0 + height / 2.0);
+        let white = renderer.white.clone();
+        let layer = || {
+            MultiRenderable::Adjacent(vec![
+                MultiRenderable::Image {
+                    texture: white.clone(),
+                    renderables: vec![rect(left, -height, right, 0.0, [1.0, 0.0, 0.0, 1.0])],
+                },
+                MultiRenderable::Text {
+                    word: word.clone(),
+                    offset: Point { x: 0.0, y: 0.0 },
+                },
+                // This image covers the left half of the view.
+                MultiRenderable::Image {
+                    texture: white.clone(),
+                    renderables: vec![rect(left, -height, width / 2.0, 0.0, [0.0, 1.0, 0.0, 1.0])],
+                },
+            ])
+        };
+        let blue_in_right_half =
+            |pixels: &[u8]| (0..8).any(|y| (4..8).any(|x| pixel(pixels, x, y)[2] > 0));
+
+        // By default, the text is drawn first, so the red image hides it.
+        let pixels = renderer.render_with_camera(layer(), None, &camera).await;
+        assert!(!blue_in_right_half(&pixels));
+
+        renderer.multi_batch.set_preserve_adjacent_order(true);
+        let pixels = renderer.render_with_camera(layer(), None, &camera).await;
+        assert!(blue_in_right_half(&pixels));
+        for y in 0..8 {
+            for x in 0..4 {
+                assert_eq!(
+                    pixel(&pixels, x, y),
+                    &[0, 255, 0, 255],
+                    "pixel ({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+        assert_eq!(renderer.multi_batch.last_frame_stats().flushes, 3);
+    }
+
+    #[tokio::test]
+    async fn materials_do_not_leak_into_each_other() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        // The text shader takes its alpha from the texture's red channel, ignoring the vertex colour's alpha,
+        // so it draws a transparent quad opaquely where the default shader would not.
+        let material = renderer.multi_batch.add_material(Material::from_spirv(
+            "text",
+            Arc::clone(&renderer.context.device),
+            Arc::clone(&renderer.context.queue),
+            include_bytes!("shader.vert.spv"),
+            include_bytes!("text.frag.spv"),
+            RenderTarget::FORMAT,
+        ));
+
+        let renderable = MultiRenderable::Adjacent(vec![
+            MultiRenderable::Custom {
+                material,
+                texture: renderer.white.clone(),
+                renderables: vec![quad(-1.0, 0.0, [1.0, 0.0, 0.0, 0.0])],
+            },
+            renderer.image(0.0, 1.0, [0.0, 1.0, 0.0, 0.0]),
+        ]);
+        let pixels = renderer.render(renderable, None).await;
+
+        assert_eq!(pixel(&pixels, 1, 4), &[255, 0, 0, 255]);
+        assert_eq!(pixel(&pixels, 6, 4), &[0, 0, 0, 255]);
+        assert_eq!(renderer.multi_batch.last_frame_stats().draw_calls, 2);
+    }
+
+    #[tokio::test]
+    async fn materials_from_other_multibatches_are_skipped() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let mut other = MultiBatch::with_default_shaders(
+            Arc::clone(&renderer.context.device),
+            Arc::clone(&renderer.context.queue),
+            RenderTarget::FORMAT,
+            1.0,
+            FilterMode::Nearest,
+        );
+        let material = |renderer: &TestRenderer| {
+            Material::from_spirv(
+                "text",
+                Arc::clone(&renderer.context.device),
+                Arc::clone(&renderer.context.queue),
+                include_bytes!("shader.vert.spv"),
+                include_bytes!("text.frag.spv"),
+                RenderTarget::FORMAT,
+            )
+        };
+        // Both multibatches have a material with the same index, but only the other one can draw with this ID.
+        renderer.multi_batch.add_material(material(&renderer));
+        let foreign = other.add_material(material(&renderer));
+        assert!(renderer.multi_batch.material(foreign).is_none());
+
+        let white = renderer.white.clone();
+        let custom = || MultiRenderable::Custom {
+            material: foreign,
+            texture: white.clone(),
+            renderables: vec![quad(-1.0, 1.0, [1.0, 0.0, 0.0, 1.0])],
+        };
+        // Unordered renderables are grouped by material before they are drawn, so check that path too.
+        for &unordered in &[false, true] {
+            let renderable = if unordered {
+                MultiRenderable::Unordered(vec![custom()])
+            } else {
+                custom()
+            };
+            let pixels = renderer.render(renderable, None).await;
+            assert_eq!(pixel(&pixels, 4, 4), &[0, 0, 0, 255]);
+            assert_eq!(renderer.multi_batch.last_frame_stats().draw_calls, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn opacity_groups_halve_the_alpha_of_their_contents() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let white = [1.0, 1.0, 1.0, 1.0];
+        // Two opaque white quads overlap in the middle of the view.
+        let group = |renderer: &TestRenderer| MultiRenderable::Opacity {
+            alpha: 0.5,
+            inner: Box::new(MultiRenderable::Adjacent(vec![
+                renderer.image(-1.0, 0.5, white),
+                renderer.image(-0.5, 1.0, white),
+            ])),
+        };
+        // Half of linear white over black, converted to sRGB.
+        let half = 188;
+
+        let pixels = renderer.render(group(&renderer), None).await;
+        assert!((pixel(&pixels, 0, 4)[0] as i32 - half).abs() <= 1);
+        // Each quad is faded separately, so the overlap is brighter.
+        assert!(pixel(&pixels, 4, 4)[0] as i32 > half + 1);
+
+        renderer.multi_batch.set_offscreen_opacity(true);
+        for _ in 0..2 {
+            let pixels = renderer.render(group(&renderer), None).await;
+            for x in 0..8 {
+                let red = pixel(&pixels, x, 4)[0] as i32;
+                assert!((red - half).abs() <= 1, "column {} is {}", x, red);
+            }
+        }
+        // The target is kept between frames instead of being allocated again.
+        assert_eq!(renderer.multi_batch.offscreen_targets.len(), 1);
+
+        // Sibling groups share a target, and a nested group needs one more.
+        let nested = MultiRenderable::Adjacent(vec![
+            group(&renderer),
+            MultiRenderable::Opacity {
+                alpha: 1.0,
+                inner: Box::new(group(&renderer)),
+            },
+        ]);
+        renderer.render(nested, None).await;
+        assert_eq!(renderer.multi_batch.offscreen_targets.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn fading_a_word_fades_every_glyph() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let word = blue_word("Hi", &mut font_am).await;
+        let faded = MultiRenderable::Opacity {
+            alpha: 0.5,
+            inner: Box::new(MultiRenderable::Text {
+                word: word.clone(),
+                offset: Point { x: 0.0, y: 0.0 },
+            }),
+        }
+        .with_alpha(0.5);
+
+        // The inner group's alpha is combined with the outer alpha, and is only applied when the group is rendered.
+        let (alpha, inner) = match faded {
+            MultiRenderable::Opacity { alpha, inner } => (alpha, inner),
+            _ => panic!("fading a group should keep the group"),
+        };
+        assert_eq!(alpha, 0.25);
+        match inner.with_alpha(alpha) {
+            MultiRenderable::Text { word: faded, .. } => {
+                assert_eq!(faded.alpha(), 0.25);
+                // The text renderer fades the quads it cached for the opaque word, rather than building new ones.
+                assert_eq!(faded.id(), word.id());
+            }
+            _ => panic!("fading text should keep the text"),
+        }
+    }
+
+    #[test]
+    fn viewport_is_clamped_to_frame() {
+        let viewport = Viewport {
+            x: -2.0,
+            y: 6.0,
+            width: 4.0,
+            height: 4.0,
+        };
+        assert_eq!(
+            viewport.clamp_to(8, 8),
+            Some(Viewport {
+                x: 0.0,
+                y: 6.0,
+                width: 2.0,
+                height: 2.0,
+            })
+        );
+        let outside = Viewport { x: 8.0, ..viewport };
+        assert_eq!(outside.clamp_to(8, 8), None);
+    }
+
+    #[tokio::test]
+    async fn overlapping_images_are_painted_in_list_order() {
+        let mut renderer = match TestRenderer::new().await {
+            Some(renderer) => renderer,
+            None => return,
+        };
+        let (red, green) = ([1.0, 0.0, 0.0, 1.0], [0.0, 1.0, 0.0, 1.0]);
+
+        // The green image only covers the middle of the red one, so the middle shows whichever was drawn last.
+        for _ in 0..3 {
+            let green_on_top = MultiRenderable::Adjacent(vec![
+                renderer.image(-1.0, 1.0, red),
+                renderer.image(-0.5, 0.5, green),
+            ]);
+            let pixels = renderer.render(green_on_top, None).await;
+            assert_eq!(pixel(&pixels, 4, 4), &[0, 255, 0, 255]);
+            assert_eq!(pixel(&pixels, 0, 4), &[255, 0, 0, 255]);
+
+            let red_on_top = MultiRenderable::Adjacent(vec![
+                renderer.image(-0.5, 0.5, green),
+                renderer.image(-1.0, 1.0, red),
+            ]);
+            let pixels = renderer.render(red_on_top, None).await;
+            assert_eq!(pixel(&pixels, 4, 4), &[255, 0, 0, 255]);
+        }
+    }
 }
@@ -0,0 +1,169 @@
+/// The stage of the render pipeline that a shader runs in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ShaderKind {
+    Vertex,
+    Fragment,
+    Compute,
+}
+
+impl From<ShaderKind> for shaderc::ShaderKind {
+    fn from(kind: ShaderKind) -> Self {
+        match kind {
+            ShaderKind::Vertex => shaderc::ShaderKind::Vertex,
+            ShaderKind::Fragment => shaderc::ShaderKind::Fragment,
+            ShaderKind::Compute => shaderc::ShaderKind::Compute,
+        }
+    }
+}
+
+/// A single problem that the shader compiler found in a shader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShaderDiagnostic {
+    /// The line of the shader source that the problem is on, counting from 1, if the compiler said which line it was.
+    pub line: Option<u32>,
+    pub message: String,
+}
+
+/// The reasons why a shader could not be compiled.
+#[derive(Debug)]
+pub enum ShaderError {
+    /// The shader compiler could not be started.
+    CompilerUnavailable,
+    /// The shader source has errors.
+    Compilation(Vec<ShaderDiagnostic>),
+    /// The compiler failed for some other reason, such as running out of resources.
+    Internal(String),
+    /// The source looks like WGSL, which can't be compiled yet. Only GLSL is supported.
+    WgslUnsupported,
+}
+
+/// The name that the shader is given in the compiler's messages.
+const SOURCE_NAME: &str = "shader";
+
+/// Compiles a GLSL shader into SPIR-V while the game is running, for example to create a `Material` from a shader
+/// that is being edited. The result can be given to `Material::from_spirv` or `wgpu::util::make_spirv`.
+///
+/// Only GLSL is accepted. Source that looks like WGSL is rejected with `ShaderError::WgslUnsupported`, rather than
+/// being given to the GLSL compiler and producing confusing syntax errors.
+///
+/// Each call starts a new compiler, which is relatively expensive, so don't compile shaders every frame.
+/// The shaders that ship with the game are compiled by the build script instead.
+pub fn compile_shader(source: &str, kind: ShaderKind) -> Result<Vec<u8>, ShaderError> {
+    if looks_like_wgsl(source) {
+        return Err(ShaderError::WgslUnsupported);
+    }
+    let mut compiler = shaderc::Compiler::new().ok_or(ShaderError::CompilerUnavailable)?;
+    match compiler.compile_into_spirv(source, kind.into(), SOURCE_NAME, "main", None) {
+        Ok(artifact) => Ok(artifact.as_binary_u8().to_vec()),
+        Err(shaderc::Error::CompilationError(_, messages)) => Err(ShaderError::Compilation(
+            messages
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(parse_diagnostic)
+                .collect(),
+        )),
+        Err(error) => Err(ShaderError::Internal(error.to_string())),
+    }
+}
+
+/// Does this source contain a line that can only start a WGSL declaration, such as a function or an attribute?
+/// Lines in GLSL never start like this.
+fn looks_like_wgsl(source: &str) -> bool {
+    source.lines().map(str::trim_start).any(|line| {
+        line.starts_with("fn ")
+            || line.starts_with('@')
+            || line.starts_with("[[")
+            || line.starts_with("var<")
+    })
+}
+
+/// Parses a line of compiler output such as `shader:3: error: 'x' : undeclared identifier`.
+fn parse_diagnostic(line: &str) -> ShaderDiagnostic {
+    let location = line
+        .strip_prefix(SOURCE_NAME)
+        .and_then(|rest| rest.strip_prefix(':'))
+        .and_then(|rest| {
+            let (line_number, message) = rest.split_at(rest.find(':')?);
+            Some((line_number.parse().ok()?, message[1..].trim()))
+        });
+    match location {
+        Some((line, message)) => ShaderDiagnostic {
+            line: Some(line),
+            message: message.to_string(),
+        },
+        None => ShaderDiagnostic {
+            line: None,
+            message: line.to_string(),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::*;
+    use std::sync::Arc;
+
+    const VERTEX_SHADER: &str = "#version 450
+layout(location=0) in vec3 a_position;
+layout(location=1) in vec4 a_color;
+layout(location=2) in vec2 a_tex_coords;
+
+layout(location=0) out vec4 v_color;
+layout(location=1) out vec2 v_tex_coords;
+
+layout(set=1, binding=0) uniform Uniforms {
+    mat4 u_combined;
+};
+
+void main() {
+    v_color = a_color;
+    v_tex_coords = a_tex_coords;
+    gl_Position = u_combined * vec4(a_position, 1.0);
+}
+";
+
+    #[tokio::test]
+    async fn compiled_shader_makes_a_material() {
+        let spirv = compile_shader(VERTEX_SHADER, ShaderKind::Vertex).unwrap();
+
+        let context = match GraphicsContext::headless().await {
+            Some(context) => context,
+            None => return,
+        };
+        Material::from_spirv(
+            "compiled",
+            Arc::clone(&context.device),
+            Arc::clone(&context.queue),
+            &spirv,
+            include_bytes!("shader.frag.spv"),
+            RenderTarget::FORMAT,
+        );
+    }
+
+    #[test]
+    fn wgsl_is_rejected_before_compiling() {
+        let source = "@fragment
+fn main() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0, 0.0, 0.0, 1.0);
+}
+";
+        assert!(matches!(
+            compile_shader(source, ShaderKind::Fragment),
+            Err(ShaderError::WgslUnsupported)
+        ));
+        assert!(!looks_like_wgsl(VERTEX_SHADER));
+    }
+
+    #[test]
+    fn errors_say_which_line_they_are_on() {
+        let source = VERTEX_SHADER.replace("v_color = a_color;", "v_color = a_colour;");
+        match compile_shader(&source, ShaderKind::Vertex) {
+            Err(ShaderError::Compilation(diagnostics)) => {
+                assert_eq!(diagnostics[0].line, Some(14));
+                assert!(diagnostics[0].message.contains("a_colour"));
+            }
+            result => panic!("expected a compilation error, got {:?}", result),
+        }
+    }
+}
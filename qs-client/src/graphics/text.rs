@@ -1,17 +1,64 @@
-use crate::graphics::Batch;
+use crate::graphics::{Batch, BatchStats, UniformBuffer};
 use crate::ui::*;
-use rusttype::gpu_cache::Cache;
-use std::sync::Arc;
+use owned_ttf_parser::{AsFaceRef, RasterGlyphImage};
+use rusttype::gpu_cache::{Cache, CacheBuilder, CacheWriteErr, CachedBy};
+use rusttype::{Font, GlyphId, PositionedGlyph, Scale};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use stretch::geometry::Point;
 use wgpu::*;
 
-use super::{Renderable, Vertex};
+use super::{Renderable, Vertex, Viewport};
+
+/// The largest width or height that the glyph cache is allowed to grow to.
+/// `wgpu` does not yet expose texture size limits, so this is the minimum size that all WebGPU implementations must support.
+const MAX_CACHE_SIZE: u32 = 8192;
+
+/// How many words' glyph quads the text renderer keeps once a draw call has finished with them.
+/// Past this, only the words used by the most recent draw call are kept.
+const MAX_CACHED_WORDS: usize = 4096;
+
+/// How the glyph cache decides where and how often to rasterise glyphs.
+/// The defaults are the same as `rusttype`'s.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GlyphCacheSettings {
+    /// If true, a transparent pixel is left around every glyph, so that neighbouring glyphs don't bleed in
+    /// when the cache is sampled with linear filtering.
+    pub pad_glyphs: bool,
+    /// Glyphs whose scales differ by at most this many pixels share a rasterisation.
+    /// Higher tolerances make the cache smaller, but text may be drawn at a slightly wrong size.
+    pub scale_tolerance: f32,
+    /// Glyphs whose subpixel positions differ by at most this many pixels share a rasterisation.
+    pub position_tolerance: f32,
+}
+
+impl Default for GlyphCacheSettings {
+    fn default() -> Self {
+        Self {
+            pad_glyphs: true,
+            scale_tolerance: 0.1,
+            position_tolerance: 0.1,
+        }
+    }
+}
+
+impl GlyphCacheSettings {
+    fn cache_builder(self, width: u32, height: u32) -> CacheBuilder {
+        Cache::builder()
+            .dimensions(width, height)
+            .pad_glyphs(self.pad_glyphs)
+            .scale_tolerance(self.scale_tolerance)
+            .position_tolerance(self.position_tolerance)
+            .multithread(true)
+    }
+}
 
 /// Caches rendered glyphs to speed up the rendering process of text.
 /// Contains a font used to render this text.
 /// Contains its own batch configured for the text rendering workflow.
 pub struct TextRenderer {
-    /// `wgpu` handles so that we can dynamically update the texture.
+    /// `wgpu` handles so that we can dynamically update (and recreate) the texture.
+    device: Arc<Device>,
     queue: Arc<Queue>,
     batch: Batch,
 
@@ -23,32 +70,56 @@ pub struct TextRenderer {
     cache: Cache<'static>,
     /// The texture containing pre-rendered GPU-side font glyphs.
     font_texture: crate::graphics::Texture,
+    /// How the font texture is sampled. `Nearest` keeps small text crisp, `Linear` is smoother for large text.
+    glyph_filter: FilterMode,
+    /// If false, glyphs are rounded to whole pixels before they are cached. This means that repeated characters
+    /// can share a single cache entry, at the cost of slightly uneven spacing.
+    subpixel_positioning: bool,
 
     /// Sometimes when we add new elements to the cache, we need to reorder or delete previous elements.
     /// Whenever this happens, we increment the 'generation' of the cache. Whenever the generation of the
     /// cache does not match with cached texture coordinates in `TypesetText`, we will need to recalculate them.
     cache_generation: u64,
+
+    /// Glyphs drawn from colour bitmaps (e.g. emoji) can't be stored in the single-channel glyph cache,
+    /// so they are packed into their own RGBA texture and drawn with the standard shader instead.
+    colour_batch: Batch,
+    colour_cache: ColourGlyphCache,
+
+    /// The glyph quads of recently drawn words, so that static text doesn't need to be rebuilt every frame.
+    word_quads: WordQuadCache,
 }
 
 impl TextRenderer {
     /// # Arguments
     /// - `font_size`: The size of the font, in points.
     /// - `scale_factor`: The UI scale factor.
+    /// - `glyph_filter`: How glyphs are filtered when they are sampled from the glyph cache.
     pub fn new(
         device: Arc<Device>,
         queue: Arc<Queue>,
-        texture_bind_group_layout: BindGroupLayout,
-        uniform_bind_group_layout: BindGroupLayout,
+        texture_bind_group_layout_desc: &BindGroupLayoutDescriptor,
+        uniform_bind_group_layout_desc: &BindGroupLayoutDescriptor,
         swap_chain_format: TextureFormat,
         scale_factor: f32,
+        glyph_filter: FilterMode,
     ) -> Self {
         let batch = Batch::new(
             Arc::clone(&device),
             Arc::clone(&queue),
             include_spirv!("text.vert.spv"),
             include_spirv!("text.frag.spv"),
-            texture_bind_group_layout,
-            uniform_bind_group_layout,
+            device.create_bind_group_layout(texture_bind_group_layout_desc),
+            device.create_bind_group_layout(uniform_bind_group_layout_desc),
+            swap_chain_format,
+        );
+        let colour_batch = Batch::new(
+            Arc::clone(&device),
+            Arc::clone(&queue),
+            include_spirv!("shader.vert.spv"),
+            include_spirv!("shader.frag.spv"),
+            device.create_bind_group_layout(texture_bind_group_layout_desc),
+            device.create_bind_group_layout(uniform_bind_group_layout_desc),
             swap_chain_format,
         );
 
@@ -56,172 +127,1171 @@ impl TextRenderer {
         let (cache_width, cache_height) =
             ((SIZE * scale_factor) as u32, (SIZE * scale_factor) as u32);
 
-        let cache = Cache::builder()
-            .dimensions(cache_width, cache_height)
-            .multithread(true)
+        let cache = GlyphCacheSettings::default()
+            .cache_builder(cache_width, cache_height)
             .build();
 
-        let font_texture = device.create_texture(&TextureDescriptor {
-            label: Some("font_cache"),
-            size: wgpu::Extent3d {
-                width: cache_width,
-                height: cache_height,
-                depth: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::R8Unorm,
-            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
-        });
-        let font_texture = crate::graphics::Texture::from_wgpu_with_sampler(
-            &*device,
-            font_texture,
-            &wgpu::SamplerDescriptor {
-                address_mode_u: wgpu::AddressMode::ClampToEdge,
-                address_mode_v: wgpu::AddressMode::ClampToEdge,
-                address_mode_w: wgpu::AddressMode::ClampToEdge,
-                mag_filter: wgpu::FilterMode::Nearest,
-                min_filter: wgpu::FilterMode::Nearest,
-                mipmap_filter: wgpu::FilterMode::Nearest,
-                ..Default::default()
-            },
-            (cache_width, cache_height),
-        );
+        let font_texture = create_font_texture(&device, cache_width, cache_height, glyph_filter);
+        let colour_cache = ColourGlyphCache::new(&device, cache_width, cache_height);
 
         Self {
+            device,
             queue,
             batch,
 
             //scale_factor,
             cache,
             font_texture,
+            glyph_filter,
+            subpixel_positioning: true,
 
             cache_generation: 0,
+
+            colour_batch,
+            colour_cache,
+
+            word_quads: WordQuadCache::default(),
         }
     }
 
+    /// Returns everything both of the text renderer's batches have drawn since the last call to `reset_stats`.
+    pub fn stats(&self) -> BatchStats {
+        self.batch.stats() + self.colour_batch.stats()
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.batch.reset_stats();
+        self.colour_batch.reset_stats();
+    }
+
+    /// Makes both of the text renderer's batches write their camera uniforms to the given buffer.
+    /// See `Batch::set_uniform_buffer`.
+    pub fn set_uniform_buffer(&mut self, uniform_buffer: Arc<Mutex<UniformBuffer>>) {
+        self.batch.set_uniform_buffer(Arc::clone(&uniform_buffer));
+        self.colour_batch.set_uniform_buffer(uniform_buffer);
+    }
+
+    /// How glyphs are filtered when they are sampled from the glyph cache.
+    pub fn glyph_filter(&self) -> FilterMode {
+        self.glyph_filter
+    }
+
+    /// Changes how glyphs are filtered when they are sampled from the glyph cache, for example from a graphics quality
+    /// setting. The glyph cache is kept, since only the sampler changes.
+    pub fn set_glyph_filter(&mut self, glyph_filter: FilterMode) {
+        self.glyph_filter = glyph_filter;
+        self.font_texture.sampler = self
+            .device
+            .create_sampler(&font_sampler_descriptor(glyph_filter));
+    }
+
+    /// If `subpixel_positioning` is true (the default), glyphs are rasterised at their exact positions for smoother text.
+    /// Otherwise, glyphs are rounded to whole pixels, so that fewer distinct glyphs need to be rasterised.
+    pub fn set_subpixel_positioning(&mut self, subpixel_positioning: bool) {
+        if self.subpixel_positioning != subpixel_positioning {
+            self.subpixel_positioning = subpixel_positioning;
+            // Cached word quads point at glyphs that were positioned the old way.
+            self.cache_generation += 1;
+        }
+    }
+
+    /// Changes how the glyph cache packs glyphs and decides when glyphs can share a rasterisation.
+    /// This clears the glyph cache, so every glyph is rasterised again the next time it is drawn.
+    pub fn set_glyph_cache_settings(&mut self, settings: GlyphCacheSettings) {
+        let (width, height) = self.cache.dimensions();
+        settings
+            .cache_builder(width, height)
+            .rebuild(&mut self.cache);
+        self.cache_generation += 1;
+    }
+
     /// Text is a list of words together with an offset at which to draw them.
+    /// If a viewport is given, the text is confined to that part of the frame.
     pub fn draw_text(
         &mut self,
         text: Vec<(Point<f32>, RenderableWord)>,
-        frame: &wgpu::SwapChainTexture,
+        frame: &wgpu::TextureView,
+        viewport: Option<Viewport>,
         camera: &crate::graphics::Camera,
         //mut profiler: qs_common::profile::ProfileSegmentGuard<'_>,
     ) {
         {
             //let _guard = profiler.task("queuing glyphs").time();
             for (_, word) in &text {
-                for RenderableGlyph { font, glyph, .. } in &word.glyphs {
-                    self.cache.queue_glyph(*font, glyph.clone());
+                for RenderableGlyph {
+                    font,
+                    glyph,
+                    colour_glyph,
+                    ..
+                } in &word.glyphs
+                {
+                    // Colour glyphs have no outline to rasterise, so they are cached separately when they are drawn.
+                    if !colour_glyph {
+                        self.cache
+                            .queue_glyph(*font, quantise_glyph(glyph, self.subpixel_positioning));
+                    }
                 }
             }
         }
 
         {
             //let _guard = profiler.task("caching glyphs").time();
-            let cache = &mut self.cache;
-            let queue = &self.queue;
-            let font_texture = &self.font_texture;
-            let cache_method = cache
-                .cache_queued(|rect, data| {
-                    queue.write_texture(
-                        wgpu::TextureCopyView {
-                            texture: &font_texture.texture,
-                            mip_level: 0,
-                            origin: wgpu::Origin3d {
-                                x: rect.min.x,
-                                y: rect.min.y,
-                                z: 0,
-                            },
-                        },
-                        data,
-                        wgpu::TextureDataLayout {
-                            offset: 0,
-                            bytes_per_row: rect.width(),
-                            rows_per_image: 0,
-                        },
-                        wgpu::Extent3d {
-                            width: rect.width(),
-                            height: rect.height(),
-                            depth: 1,
-                        },
-                    );
-                })
-                .unwrap();
-            if let rusttype::gpu_cache::CachedBy::Reordering = cache_method {
-                self.cache_generation += 1;
+            if !self.cache_queued_or_grow() {
+                return;
             }
         }
 
+        // Shadows and outlines are drawn before all of the glyphs, so that they never cover a neighbouring glyph.
+        let mut underlay_items = Vec::new();
         let mut items = Vec::new();
+        let mut colour_items = Vec::new();
         {
             //let _guard = profiler.task("creating texture coordinates").time();
-            /*if self.cache_generation == cache_generation && self.cached_renderables.is_some() {
-                items = self.cached_renderables.as_ref().unwrap().clone();
-            } else */
-            {
-                for (offset, word) in text {
-                    for RenderableGlyph {
-                        font,
-                        colour,
-                        glyph,
-                        ..
-                    } in &word.glyphs
-                    {
-                        if let Some((uv_rect, pixel_rect)) = self
-                            .cache
-                            .rect_for(*font, glyph)
-                            .expect("Could not load cache entry for glyph")
-                        {
-                            // TODO this includes the height of descenders of glyphs, which is not intended!
-                            // This displays text slightly too low!
-                            let line_height = word.size.1 as f32;
-                            let (x1, y1) = (
-                                pixel_rect.min.x as f32 + offset.x,
-                                -pixel_rect.min.y as f32 - line_height - offset.y,
+            self.word_quads.begin_draw(self.cache_generation);
+            for (offset, word) in text {
+                let line_height = word.size.1 as f32;
+                for RenderableGlyph {
+                    font,
+                    colour,
+                    glyph,
+                    ..
+                } in word.glyphs.iter().filter(|glyph| glyph.colour_glyph)
+                {
+                    let entry = match self.colour_cache.get_or_insert(&self.queue, *font, glyph) {
+                        Ok(entry) => entry,
+                        Err(ColourGlyphCacheFull) => {
+                            // Draw the colour glyphs queued so far before their bitmaps are overwritten.
+                            self.colour_batch.render(
+                                frame,
+                                viewport,
+                                &self.colour_cache.texture,
+                                camera,
+                                std::mem::take(&mut colour_items).into_iter(),
                             );
-                            let (x2, y2) = (
-                                pixel_rect.max.x as f32 + offset.x,
-                                -pixel_rect.max.y as f32 - line_height - offset.y,
-                            );
-                            let (u1, v1) = (uv_rect.min.x, uv_rect.min.y);
-                            let (u2, v2) = (uv_rect.max.x, uv_rect.max.y);
-                            let color = (*colour).into();
-                            items.push(Renderable::Quadrilateral(
-                                Vertex {
-                                    position: [x1, y1, 0.0],
-                                    color,
-                                    tex_coords: [u1, v1],
-                                },
-                                Vertex {
-                                    position: [x2, y1, 0.0],
-                                    color,
-                                    tex_coords: [u2, v1],
-                                },
-                                Vertex {
-                                    position: [x2, y2, 0.0],
-                                    color,
-                                    tex_coords: [u2, v2],
-                                },
-                                Vertex {
-                                    position: [x1, y2, 0.0],
-                                    color,
-                                    tex_coords: [u1, v2],
-                                },
-                            ));
+                            self.colour_cache.clear();
+                            self.colour_cache
+                                .get_or_insert(&self.queue, *font, glyph)
+                                .unwrap_or(None)
                         }
+                    };
+                    if let Some(entry) = entry {
+                        colour_items.push(entry.renderable(
+                            glyph,
+                            offset,
+                            line_height,
+                            colour.a * word.alpha(),
+                        ));
                     }
                 }
 
-                //word.cached_renderables = Some(items.clone());
+                let (cache, subpixel_positioning) = (&self.cache, self.subpixel_positioning);
+                self.word_quads.extend(
+                    word.id(),
+                    offset,
+                    word.alpha(),
+                    &mut underlay_items,
+                    &mut items,
+                    |underlay_items, items| {
+                        push_word_quads(
+                            cache,
+                            subpixel_positioning,
+                            &word,
+                            offset,
+                            underlay_items,
+                            items,
+                        )
+                    },
+                );
             }
+            self.word_quads.evict_stale();
         }
 
         {
             //let _guard = profiler.task("rendering text").time();
-            self.batch
-                .render(frame, &self.font_texture, camera, items.into_iter());
+            self.batch.render(
+                frame,
+                viewport,
+                &self.font_texture,
+                camera,
+                underlay_items.into_iter().chain(items),
+            );
+            if !colour_items.is_empty() {
+                self.colour_batch.render(
+                    frame,
+                    viewport,
+                    &self.colour_cache.texture,
+                    camera,
+                    colour_items.into_iter(),
+                );
+            }
+        }
+    }
+
+    /// Uploads all queued glyphs to the font texture.
+    fn cache_queued(&mut self) -> Result<CachedBy, CacheWriteErr> {
+        let queue = &self.queue;
+        let font_texture = &self.font_texture;
+        self.cache.cache_queued(|rect, data| {
+            queue.write_texture(
+                wgpu::TextureCopyView {
+                    texture: &font_texture.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d {
+                        x: rect.min.x,
+                        y: rect.min.y,
+                        z: 0,
+                    },
+                },
+                data,
+                wgpu::TextureDataLayout {
+                    offset: 0,
+                    bytes_per_row: rect.width(),
+                    rows_per_image: 0,
+                },
+                wgpu::Extent3d {
+                    width: rect.width(),
+                    height: rect.height(),
+                    depth: 1,
+                },
+            );
+        })
+    }
+
+    /// Caches the queued glyphs, growing the cache if they don't fit. Returns false if they couldn't be cached,
+    /// in which case the queue is cleared.
+    fn cache_queued_or_grow(&mut self) -> bool {
+        let cache_method = match self.cache_queued() {
+            Ok(cache_method) => cache_method,
+            Err(error) => {
+                // The queued glyphs didn't fit in the cache, so make the cache larger and try again.
+                if !self.grow_cache() {
+                    tracing::error!("could not cache glyphs: {}", error);
+                    self.cache.clear_queue();
+                    return false;
+                }
+                match self.cache_queued() {
+                    Ok(cache_method) => cache_method,
+                    Err(error) => {
+                        tracing::error!("could not cache glyphs after growing cache: {}", error);
+                        self.cache.clear_queue();
+                        return false;
+                    }
+                }
+            }
+        };
+        if let CachedBy::Reordering = cache_method {
+            self.cache_generation += 1;
+        }
+        true
+    }
+
+    /// Doubles the size of the glyph cache (up to `MAX_CACHE_SIZE`), recreating the font texture to match.
+    /// All previously cached glyphs are discarded, but queued glyphs are kept.
+    /// Returns false if the cache was already as large as it is allowed to be.
+    fn grow_cache(&mut self) -> bool {
+        let (width, height) = self.cache.dimensions();
+        if width >= MAX_CACHE_SIZE && height >= MAX_CACHE_SIZE {
+            return false;
+        }
+        let (width, height) = (
+            (width * 2).min(MAX_CACHE_SIZE),
+            (height * 2).min(MAX_CACHE_SIZE),
+        );
+        tracing::info!("growing glyph cache to {}x{}", width, height);
+
+        self.cache
+            .to_builder()
+            .dimensions(width, height)
+            .rebuild(&mut self.cache);
+        self.font_texture = create_font_texture(&self.device, width, height, self.glyph_filter);
+        // Every glyph will be moved to a new position in the cache.
+        self.cache_generation += 1;
+        true
+    }
+}
+
+/// Creates a texture on the graphics card that can be used to store the contents of the glyph cache.
+fn create_font_texture(
+    device: &Device,
+    width: u32,
+    height: u32,
+    glyph_filter: FilterMode,
+) -> crate::graphics::Texture {
+    let font_texture = device.create_texture(&TextureDescriptor {
+        label: Some("font_cache"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::R8Unorm,
+        usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+    });
+    crate::graphics::Texture::from_wgpu_with_sampler(
+        device,
+        font_texture,
+        &font_sampler_descriptor(glyph_filter),
+        (width, height),
+    )
+}
+
+/// If `subpixel_positioning` is false, rounds the glyph's position to the nearest whole pixel.
+fn quantise_glyph(
+    glyph: &PositionedGlyph<'static>,
+    subpixel_positioning: bool,
+) -> PositionedGlyph<'static> {
+    let mut glyph = glyph.clone();
+    if !subpixel_positioning {
+        let position = glyph.position();
+        glyph.set_position(rusttype::point(position.x.round(), position.y.round()));
+    }
+    glyph
+}
+
+/// Creates the quads for every glyph in a word drawn at the given offset, except for colour glyphs.
+/// Every glyph must already be in the glyph cache.
+fn push_word_quads(
+    cache: &Cache<'static>,
+    subpixel_positioning: bool,
+    word: &RenderableWord,
+    offset: Point<f32>,
+    underlay_items: &mut Vec<Renderable>,
+    items: &mut Vec<Renderable>,
+) {
+    let line_height = word.size.1 as f32;
+    for glyph_info in word.glyphs.iter().filter(|glyph| !glyph.colour_glyph) {
+        if let Some((uv_rect, pixel_rect)) = cache
+            .rect_for(
+                glyph_info.font,
+                &quantise_glyph(&glyph_info.glyph, subpixel_positioning),
+            )
+            .expect("Could not load cache entry for glyph")
+        {
+            push_glyph_quads(
+                glyph_info,
+                offset,
+                line_height,
+                uv_rect,
+                pixel_rect,
+                underlay_items,
+                items,
+            );
+        }
+    }
+}
+
+/// The quads built for a single word at a single position.
+struct CachedWord {
+    /// The value of `WordQuadCache::draws` when this entry was last used.
+    last_used: u64,
+    underlay_items: Vec<Renderable>,
+    items: Vec<Renderable>,
+}
+
+/// Remembers the glyph quads of words that were drawn recently, keyed by the word's ID and where it was drawn.
+/// The quads are cached at the word's full opacity, so fading a word in or out doesn't build them again.
+/// The glyphs must still be queued in the glyph cache every frame, since the cache only keeps glyphs that were queued,
+/// but their texture coordinates only need to be looked up again when the cache is reordered.
+#[derive(Default)]
+struct WordQuadCache {
+    entries: HashMap<(u64, u32, u32), CachedWord>,
+    /// The glyph cache generation that every entry was built for.
+    generation: u64,
+    /// How many draw calls have used this cache.
+    draws: u64,
+}
+
+impl WordQuadCache {
+    /// Starts a new draw call. If the glyph cache has been reordered since the last one, every entry is forgotten.
+    fn begin_draw(&mut self, generation: u64) {
+        if generation != self.generation {
+            self.entries.clear();
+            self.generation = generation;
+        }
+        self.draws += 1;
+    }
+
+    /// Adds the quads for a word to the given lists, calling `build` to create them if they weren't cached.
+    /// The added quads' alpha is multiplied by `alpha`.
+    fn extend(
+        &mut self,
+        word_id: u64,
+        offset: Point<f32>,
+        alpha: f32,
+        underlay_items: &mut Vec<Renderable>,
+        items: &mut Vec<Renderable>,
+        build: impl FnOnce(&mut Vec<Renderable>, &mut Vec<Renderable>),
+    ) {
+        let draws = self.draws;
+        let entry = self
+            .entries
+            .entry((word_id, offset.x.to_bits(), offset.y.to_bits()))
+            .or_insert_with(|| {
+                let mut entry = CachedWord {
+                    last_used: draws,
+                    underlay_items: Vec::new(),
+                    items: Vec::new(),
+                };
+                build(&mut entry.underlay_items, &mut entry.items);
+                entry
+            });
+        entry.last_used = draws;
+        if alpha == 1.0 {
+            underlay_items.extend_from_slice(&entry.underlay_items);
+            items.extend_from_slice(&entry.items);
+        } else {
+            let fade = |item: &Renderable| item.with_alpha(alpha);
+            underlay_items.extend(entry.underlay_items.iter().map(fade));
+            items.extend(entry.items.iter().map(fade));
+        }
+    }
+
+    /// If the cache has grown too large, forgets every word that wasn't used by the current draw call.
+    fn evict_stale(&mut self) {
+        if self.entries.len() > MAX_CACHED_WORDS {
+            let draws = self.draws;
+            self.entries.retain(|_, entry| entry.last_used == draws);
+        }
+    }
+}
+
+/// Creates the quad for a glyph whose cache entry has the given texture coordinates and pixel bounds, adding it to `items`.
+/// The glyph's shadow and outline, if it has them, are added to `underlay_items`.
+fn push_glyph_quads(
+    glyph: &RenderableGlyph,
+    offset: Point<f32>,
+    line_height: f32,
+    uv_rect: rusttype::Rect<f32>,
+    pixel_rect: rusttype::Rect<i32>,
+    underlay_items: &mut Vec<Renderable>,
+    items: &mut Vec<Renderable>,
+) {
+    let quad = |dx: f32, dy: f32, colour: Colour| {
+        // TODO this includes the height of descenders of glyphs, which is not intended!
+        // This displays text slightly too low!
+        let (x1, y1) = (
+            pixel_rect.min.x as f32 + offset.x + dx,
+            -pixel_rect.min.y as f32 - line_height - offset.y - dy,
+        );
+        let (x2, y2) = (
+            pixel_rect.max.x as f32 + offset.x + dx,
+            -pixel_rect.max.y as f32 - line_height - offset.y - dy,
+        );
+        let (u1, v1) = (uv_rect.min.x, uv_rect.min.y);
+        let (u2, v2) = (uv_rect.max.x, uv_rect.max.y);
+        let color = colour.into();
+        Renderable::Quadrilateral(
+            Vertex {
+                position: [x1, y1, 0.0],
+                color,
+                tex_coords: [u1, v1],
+            },
+            Vertex {
+                position: [x2, y1, 0.0],
+                color,
+                tex_coords: [u2, v1],
+            },
+            Vertex {
+                position: [x2, y2, 0.0],
+                color,
+                tex_coords: [u2, v2],
+            },
+            Vertex {
+                position: [x1, y2, 0.0],
+                color,
+                tex_coords: [u1, v2],
+            },
+        )
+    };
+
+    if let Some(shadow) = glyph.shadow {
+        underlay_items.push(quad(shadow.offset.0, shadow.offset.1, shadow.colour));
+    }
+    if let Some(outline) = glyph.outline {
+        // Approximate the outline by drawing the glyph shifted in each of eight directions.
+        for i in 0..8 {
+            let angle = i as f32 * std::f32::consts::FRAC_PI_4;
+            underlay_items.push(quad(
+                outline.width * angle.cos(),
+                outline.width * angle.sin(),
+                outline.colour,
+            ));
+        }
+    }
+    items.push(quad(0.0, 0.0, glyph.colour));
+}
+
+/// Describes how the glyph cache texture is sampled.
+fn font_sampler_descriptor(glyph_filter: FilterMode) -> SamplerDescriptor<'static> {
+    wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: glyph_filter,
+        min_filter: glyph_filter,
+        mipmap_filter: wgpu::FilterMode::Nearest,
+        ..Default::default()
+    }
+}
+
+/// Returns the colour bitmap that `font` provides for the given glyph, if it has one.
+/// Bitmaps are stored at a handful of fixed sizes, so the strike chosen may not exactly match `scale`.
+pub fn raster_glyph_image<'a>(
+    font: &'a Font<'static>,
+    id: GlyphId,
+    scale: Scale,
+) -> Option<RasterGlyphImage<'a>> {
+    let face = match font {
+        Font::Ref(face) => face.as_face_ref(),
+        Font::Owned(face) => face.as_face_ref(),
+    };
+    let pixels_per_em = scale.y.round().max(1.0).min(u16::MAX as f32) as u16;
+    face.glyph_raster_image(owned_ttf_parser::GlyphId(id.0), pixels_per_em)
+}
+
+/// Where a colour glyph is stored in the colour glyph texture.
+#[derive(Debug, Clone, Copy)]
+struct ColourGlyphEntry {
+    /// The texture coordinates of the top left and bottom right of the bitmap.
+    uv_min: (f32, f32),
+    uv_max: (f32, f32),
+    /// The offset of the bottom left of the bitmap from the glyph's origin, in pixels, where positive Y is up.
+    /// This is measured at the size of the strike that the bitmap came from.
+    offset: (f32, f32),
+    /// The dimensions of the bitmap.
+    size: (f32, f32),
+    /// The size of the strike the bitmap came from. This differs from the size of the glyph, so the bitmap needs to be scaled.
+    pixels_per_em: f32,
+}
+
+impl ColourGlyphEntry {
+    /// Creates a quad that draws this bitmap in place of the given glyph.
+    /// Colour glyphs are not tinted; only the alpha value of the text colour is used.
+    fn renderable(
+        &self,
+        glyph: &PositionedGlyph<'static>,
+        offset: Point<f32>,
+        line_height: f32,
+        alpha: f32,
+    ) -> Renderable {
+        let factor = glyph.scale().y / self.pixels_per_em;
+        let position = glyph.position();
+        // Convert from glyph space (positive Y is down) to screen space in the same way as ordinary glyphs.
+        let x1 = position.x + self.offset.0 * factor + offset.x;
+        let x2 = x1 + self.size.0 * factor;
+        let y2 = -(position.y - self.offset.1 * factor) - line_height - offset.y;
+        let y1 = y2 + self.size.1 * factor;
+        let (u1, v1) = self.uv_min;
+        let (u2, v2) = self.uv_max;
+        let color = [1.0, 1.0, 1.0, alpha];
+        Renderable::Quadrilateral(
+            Vertex {
+                position: [x1, y1, 0.0],
+                color,
+                tex_coords: [u1, v1],
+            },
+            Vertex {
+                position: [x2, y1, 0.0],
+                color,
+                tex_coords: [u2, v1],
+            },
+            Vertex {
+                position: [x2, y2, 0.0],
+                color,
+                tex_coords: [u2, v2],
+            },
+            Vertex {
+                position: [x1, y2, 0.0],
+                color,
+                tex_coords: [u1, v2],
+            },
+        )
+    }
+}
+
+/// Stores decoded colour glyph bitmaps in an RGBA texture.
+/// When the texture fills up, the glyphs queued so far must be drawn before it is cleared, since their bitmaps are
+/// about to be overwritten. The glyphs that are still in use are then uploaded again as they are drawn.
+struct ColourGlyphCache {
+    texture: crate::graphics::Texture,
+    /// Maps font IDs, glyph IDs and font sizes to the location of their bitmaps.
+    /// Glyphs whose bitmaps could not be decoded are stored as `None` so that we don't keep retrying them.
+    entries: HashMap<(usize, u16, u32), Option<ColourGlyphEntry>>,
+    packer: RowPacker,
+}
+
+/// The colour glyph cache has no room for another bitmap until it is cleared.
+#[derive(Debug)]
+struct ColourGlyphCacheFull;
+
+/// Packs bitmaps into rows from the top of a texture downwards.
+#[derive(Debug)]
+struct RowPacker {
+    dimensions: (u32, u32),
+    /// The position at which the next bitmap will be placed.
+    cursor: (u32, u32),
+    /// The height of the tallest bitmap in the current row.
+    row_height: u32,
+}
+
+impl RowPacker {
+    fn new(dimensions: (u32, u32)) -> Self {
+        Self {
+            dimensions,
+            cursor: (0, 0),
+            row_height: 0,
+        }
+    }
+
+    /// Finds room for a bitmap of the given size, returning where its top left corner should go,
+    /// or `None` if there are no rows left.
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        // Move to a new row if this bitmap doesn't fit on the current one.
+        if self.cursor.0 + width > self.dimensions.0 {
+            self.cursor = (0, self.cursor.1 + self.row_height);
+            self.row_height = 0;
+        }
+        if self.cursor.1 + height > self.dimensions.1 {
+            return None;
+        }
+        let position = self.cursor;
+        self.cursor.0 += width;
+        self.row_height = self.row_height.max(height);
+        Some(position)
+    }
+
+    /// Forgets every bitmap, so that the next one is placed at the top of the texture.
+    fn clear(&mut self) {
+        self.cursor = (0, 0);
+        self.row_height = 0;
+    }
+}
+
+impl ColourGlyphCache {
+    fn new(device: &Device, width: u32, height: u32) -> Self {
+        let texture = device.create_texture(&TextureDescriptor {
+            label: Some("colour_glyph_cache"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+        Self {
+            texture: crate::graphics::Texture::from_wgpu(device, texture, (width, height)),
+            entries: HashMap::new(),
+            packer: RowPacker::new((width, height)),
+        }
+    }
+
+    /// Forgets every bitmap. Anything drawn with the old bitmaps must already have been rendered.
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.packer.clear();
+    }
+
+    /// Finds where the bitmap for this glyph is stored, decoding and uploading it to the texture if it is not already there.
+    /// Returns `Ok(None)` if the glyph has no usable bitmap. Bitmaps are never overwritten by this function;
+    /// if there is no room for a new one, nothing is uploaded and the cache must be cleared first.
+    fn get_or_insert(
+        &mut self,
+        queue: &Queue,
+        font: usize,
+        glyph: &PositionedGlyph<'static>,
+    ) -> Result<Option<ColourGlyphEntry>, ColourGlyphCacheFull> {
+        let key = (font, glyph.id().0, glyph.scale().y.to_bits());
+        if let Some(entry) = self.entries.get(&key) {
+            return Ok(*entry);
+        }
+
+        let entry = self.insert(queue, glyph)?;
+        self.entries.insert(key, entry);
+        Ok(entry)
+    }
+
+    fn insert(
+        &mut self,
+        queue: &Queue,
+        glyph: &PositionedGlyph<'static>,
+    ) -> Result<Option<ColourGlyphEntry>, ColourGlyphCacheFull> {
+        let image = match raster_glyph_image(glyph.font(), glyph.id(), glyph.scale()) {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+        let bitmap = match image::load_from_memory_with_format(image.data, image::ImageFormat::Png)
+        {
+            Ok(bitmap) => bitmap.to_rgba(),
+            Err(error) => {
+                tracing::error!("could not decode colour glyph: {}", error);
+                return Ok(None);
+            }
+        };
+        let (width, height) = bitmap.dimensions();
+        let (texture_width, texture_height) = self.texture.dimensions;
+        if width > texture_width || height > texture_height {
+            tracing::error!(
+                "colour glyph of size {}x{} does not fit in the colour glyph cache",
+                width,
+                height
+            );
+            return Ok(None);
+        }
+
+        let (x, y) = self
+            .packer
+            .allocate(width, height)
+            .ok_or(ColourGlyphCacheFull)?;
+        queue.write_texture(
+            wgpu::TextureCopyView {
+                texture: &self.texture.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+            },
+            &bitmap,
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: 4 * width,
+                rows_per_image: 0,
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth: 1,
+            },
+        );
+
+        Ok(Some(ColourGlyphEntry {
+            uv_min: (
+                x as f32 / texture_width as f32,
+                y as f32 / texture_height as f32,
+            ),
+            uv_max: (
+                (x + width) as f32 / texture_width as f32,
+                (y + height) as f32 / texture_height as f32,
+            ),
+            offset: (image.x as f32, image.y as f32),
+            size: (width as f32, height as f32),
+            pixels_per_em: image.pixels_per_em as f32,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::FontAssetLoader;
+    use crate::graphics::MultiRenderable;
+    use crate::test_fixtures::{noto_sans_regular, regular_only_family};
+    use qs_common::assets::AssetManager;
+
+    /// Caches the letter 'a' at several fractional positions, returning how many times a glyph was rasterised.
+    async fn rasterised_glyph_count(subpixel_positioning: bool) -> usize {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font = noto_sans_regular(&mut font_am).await;
+
+        let mut count = 0;
+        font.if_loaded(|font| {
+            let mut cache = Cache::builder().dimensions(256, 256).build();
+            for &x in &[0.0, 10.3, 20.6, 30.9] {
+                let glyph = font
+                    .glyph('a')
+                    .scaled(Scale::uniform(24.0))
+                    .positioned(rusttype::point(x, 0.0));
+                cache.queue_glyph(0, quantise_glyph(&glyph, subpixel_positioning));
+            }
+            cache
+                .cache_queued(|_, _| count += 1)
+                .expect("could not cache glyphs");
+        })
+        .await;
+        count
+    }
+
+    #[tokio::test]
+    async fn scale_tolerance_shares_cache_entries() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font = noto_sans_regular(&mut font_am).await;
+
+        let mut counts = Vec::new();
+        for &scale_tolerance in &[0.1, 1.0] {
+            let settings = GlyphCacheSettings {
+                scale_tolerance,
+                ..Default::default()
+            };
+            let mut count = 0;
+            font.if_loaded(|font| {
+                let mut cache = settings.cache_builder(256, 256).build();
+                for &scale in &[24.0, 24.3, 24.6, 24.9] {
+                    let glyph = font
+                        .glyph('a')
+                        .scaled(Scale::uniform(scale))
+                        .positioned(rusttype::point(0.0, 0.0));
+                    cache.queue_glyph(0, glyph);
+                }
+                cache
+                    .cache_queued(|_, _| count += 1)
+                    .expect("could not cache glyphs");
+            })
+            .await;
+            counts.push(count);
+        }
+        assert_eq!(counts[0], 4);
+        assert!(counts[1] < counts[0]);
+    }
+
+    #[tokio::test]
+    async fn disabling_subpixel_positioning_shares_cache_entries() {
+        assert_eq!(rasterised_glyph_count(false).await, 1);
+        assert!(rasterised_glyph_count(true).await > 1);
+    }
+
+    #[test]
+    fn word_quads_are_rebuilt_when_the_cache_is_reordered() {
+        let mut word_quads = WordQuadCache::default();
+        let mut builds = 0;
+        {
+            let mut draw = |word_quads: &mut WordQuadCache, generation: u64, word_id: u64| {
+                word_quads.begin_draw(generation);
+                let (mut underlay_items, mut items) = (Vec::new(), Vec::new());
+                word_quads.extend(
+                    word_id,
+                    Point { x: 0.0, y: 0.0 },
+                    1.0,
+                    &mut underlay_items,
+                    &mut items,
+                    |_, items| {
+                        builds += 1;
+                        items.push(Renderable::Empty);
+                    },
+                );
+                word_quads.evict_stale();
+                items.len()
+            };
+
+            assert_eq!(draw(&mut word_quads, 0, 0), 1);
+            assert_eq!(draw(&mut word_quads, 0, 0), 1);
+            assert_eq!(draw(&mut word_quads, 1, 0), 1);
+        }
+        assert_eq!(builds, 2);
+
+        // Words that are no longer drawn are forgotten once there are too many of them.
+        for word_id in 0..=MAX_CACHED_WORDS as u64 {
+            word_quads.begin_draw(1);
+            word_quads.extend(
+                word_id,
+                Point { x: 0.0, y: 0.0 },
+                1.0,
+                &mut Vec::new(),
+                &mut Vec::new(),
+                |_, _| {},
+            );
+            word_quads.evict_stale();
+        }
+        assert_eq!(word_quads.entries.len(), 1);
+    }
+
+    #[test]
+    fn faded_words_reuse_their_cached_quads() {
+        let vertex = Vertex {
+            position: [0.0, 0.0, 0.0],
+            color: [1.0, 1.0, 1.0, 1.0],
+            tex_coords: [0.0, 0.0],
+        };
+        let mut word_quads = WordQuadCache::default();
+        let mut builds = 0;
+        let mut alphas = Vec::new();
+        for &alpha in &[1.0, 0.5, 0.25] {
+            word_quads.begin_draw(0);
+            let mut items = Vec::new();
+            word_quads.extend(
+                0,
+                Point { x: 0.0, y: 0.0 },
+                alpha,
+                &mut Vec::new(),
+                &mut items,
+                |_, items| {
+                    builds += 1;
+                    items.push(Renderable::Triangle(vertex, vertex, vertex));
+                },
+            );
+            match items[..] {
+                [Renderable::Triangle(v0, _, _)] => alphas.push(v0.color[3]),
+                _ => panic!("the cached quad should be drawn"),
+            }
+        }
+        assert_eq!(builds, 1);
+        assert_eq!(alphas, vec![1.0, 0.5, 0.25]);
+    }
+
+    /// Creates a text renderer whose glyph cache is `1024 * scale_factor` pixels square.
+    /// Returns `None` if there is no graphics card to render with.
+    async fn text_renderer(scale_factor: f32) -> Option<TextRenderer> {
+        let context = crate::graphics::GraphicsContext::headless().await?;
+        Some(
+            crate::graphics::MultiBatch::with_default_shaders(
+                Arc::clone(&context.device),
+                Arc::clone(&context.queue),
+                crate::graphics::RenderTarget::FORMAT,
+                scale_factor,
+                FilterMode::Nearest,
+            )
+            .text_renderer,
+        )
+    }
+
+    #[test]
+    fn full_colour_glyph_caches_do_not_overwrite_bitmaps() {
+        let mut packer = RowPacker::new((20, 20));
+        let positions: Vec<_> = (0..4).map(|_| packer.allocate(10, 10)).collect();
+        assert_eq!(
+            positions,
+            vec![Some((0, 0)), Some((10, 0)), Some((0, 10)), Some((10, 10))]
+        );
+        // A fifth bitmap in the same frame would overwrite one of the others, so it is refused until the cache is cleared.
+        assert_eq!(packer.allocate(10, 10), None);
+        packer.clear();
+        assert_eq!(packer.allocate(10, 10), Some((0, 0)));
+    }
+
+    #[tokio::test]
+    async fn a_full_cache_doubles_in_size() {
+        let mut text_renderer = match text_renderer(1.0 / 32.0).await {
+            Some(text_renderer) => text_renderer,
+            None => return,
+        };
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font = noto_sans_regular(&mut font_am).await;
+        assert_eq!(text_renderer.cache.dimensions(), (32, 32));
+
+        // The glyph for "b" is taller than the whole cache, but both glyphs fit once it has doubled in size.
+        let mut glyphs = Vec::new();
+        font.if_loaded(|font| {
+            glyphs = "ab"
+                .chars()
+                .map(|c| {
+                    font.glyph(c)
+                        .scaled(Scale::uniform(60.0))
+                        .positioned(rusttype::point(0.0, 0.0))
+                })
+                .collect();
+        })
+        .await;
+        for glyph in glyphs {
+            text_renderer.cache.queue_glyph(0, glyph);
+        }
+        let generation = text_renderer.cache_generation;
+        assert!(text_renderer.cache_queued_or_grow());
+        assert_eq!(text_renderer.cache.dimensions(), (64, 64));
+        assert_eq!(text_renderer.font_texture.dimensions, (64, 64));
+        assert!(text_renderer.cache_generation > generation);
+    }
+
+    #[tokio::test]
+    async fn toggling_subpixel_positioning_rebuilds_word_quads() {
+        let mut text_renderer = match text_renderer(1.0).await {
+            Some(text_renderer) => text_renderer,
+            None => return,
+        };
+
+        let mut builds = 0;
+        let mut draw = |text_renderer: &mut TextRenderer| {
+            text_renderer
+                .word_quads
+                .begin_draw(text_renderer.cache_generation);
+            text_renderer.word_quads.extend(
+                0,
+                Point { x: 0.0, y: 0.0 },
+                1.0,
+                &mut Vec::new(),
+                &mut Vec::new(),
+                |_, _| builds += 1,
+            );
+        };
+        draw(&mut text_renderer);
+        // Setting the same value again keeps the cached quads.
+        text_renderer.set_subpixel_positioning(true);
+        draw(&mut text_renderer);
+        text_renderer.set_subpixel_positioning(false);
+        draw(&mut text_renderer);
+        assert_eq!(builds, 2);
+    }
+
+    /// Compares building the quads for the demo's paragraph of placeholder text every frame with caching them.
+    /// Run with `cargo test --release -p qs-client static_paragraph_benchmark -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn static_paragraph_benchmark() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font = noto_sans_regular(&mut font_am).await;
+        let font_family = regular_only_family(font.clone());
+        let mut rich_text = RichText::new(Default::default());
+        rich_text
+            .set_text(font_family)
+            .write("Lorem ipsum dolor sit amet, consectetur adipiscing elit. Ut facilisis elit at massa placerat, in placerat est pretium. Curabitur consequat porta ante vel pharetra. Vestibulum sit amet mauris rhoncus, facilisis felis et, elementum arcu. In hac habitasse platea dictumst. Nam at felis non lectus aliquam consectetur nec quis tellus. Proin id dictum massa. Sed id condimentum mauris. Morbi eget dictum ligula, non faucibus ante. Morbi viverra ut diam vitae malesuada. Donec porta enim non porttitor euismod. Proin faucibus sit amet diam nec molestie. Fusce porta scelerisque lectus, quis ultrices augue maximus a.")
+            .finish()
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Layouts can't be constructed directly, so take the layout of an empty node at the origin.
+        let mut stretch = stretch::Stretch::new();
+        let node = stretch.new_node(Default::default(), Vec::new()).unwrap();
+        stretch
+            .compute_layout(node, stretch::geometry::Size::undefined())
+            .unwrap();
+        let layout = *stretch.layout(node).unwrap();
+        let paragraph = rich_text.get_widget().0.read().unwrap().get_children()[0].clone();
+        let words: Vec<_> = paragraph
+            .0
+            .read()
+            .unwrap()
+            .get_children()
+            .iter()
+            .enumerate()
+            .filter_map(|(i, word)| {
+                match word
+                    .0
+                    .read()
+                    .unwrap()
+                    .get_element()
+                    .generate_render_info(&layout)
+                {
+                    MultiRenderable::Text { word, .. } => Some((
+                        Point {
+                            x: i as f32 * 50.0,
+                            y: 0.0,
+                        },
+                        word,
+                    )),
+                    _ => None,
+                }
+            })
+            .collect();
+
+        let mut cache = Cache::builder().dimensions(1024, 1024).build();
+        for (_, word) in &words {
+            for glyph in &word.glyphs {
+                cache.queue_glyph(glyph.font, quantise_glyph(&glyph.glyph, true));
+            }
+        }
+        cache
+            .cache_queued(|_, _| {})
+            .expect("could not cache glyphs");
+
+        const FRAMES: u32 = 1000;
+        let start = std::time::Instant::now();
+        for _ in 0..FRAMES {
+            let (mut underlay_items, mut items) = (Vec::new(), Vec::new());
+            for (offset, word) in &words {
+                push_word_quads(&cache, true, word, *offset, &mut underlay_items, &mut items);
+            }
+            assert!(!items.is_empty());
+        }
+        let uncached = start.elapsed() / FRAMES;
+
+        let mut word_quads = WordQuadCache::default();
+        let start = std::time::Instant::now();
+        for _ in 0..FRAMES {
+            let (mut underlay_items, mut items) = (Vec::new(), Vec::new());
+            word_quads.begin_draw(0);
+            for (offset, word) in &words {
+                word_quads.extend(
+                    word.id(),
+                    *offset,
+                    word.alpha(),
+                    &mut underlay_items,
+                    &mut items,
+                    |underlay_items, items| {
+                        push_word_quads(&cache, true, word, *offset, underlay_items, items)
+                    },
+                );
+            }
+            word_quads.evict_stale();
+            assert!(!items.is_empty());
+        }
+        let cached = start.elapsed() / FRAMES;
+
+        println!(
+            "{} words: {:?} per frame rebuilding quads, {:?} per frame with the word cache",
+            words.len(),
+            uncached,
+            cached
+        );
+    }
+
+    #[tokio::test]
+    async fn shadow_adds_offset_glyph_quad() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font = noto_sans_regular(&mut font_am).await;
+        let mut glyph = None;
+        font.if_loaded(|font| {
+            glyph = Some(
+                font.glyph('a')
+                    .scaled(Scale::uniform(24.0))
+                    .positioned(rusttype::point(0.0, 0.0)),
+            );
+        })
+        .await;
+
+        let shadow_colour = Colour::rgb(0.0, 0.0, 0.0);
+        let glyph = RenderableGlyph {
+            font: 0,
+            colour: Colour::rgb(1.0, 1.0, 1.0),
+            glyph: glyph.unwrap(),
+            character_index: 0,
+            colour_glyph: false,
+            shadow: Some(TextShadow {
+                colour: shadow_colour,
+                offset: (2.0, 3.0),
+            }),
+            outline: None,
+        };
+        let uv_rect = rusttype::Rect {
+            min: rusttype::point(0.0, 0.0),
+            max: rusttype::point(1.0, 1.0),
+        };
+        let pixel_rect = rusttype::Rect {
+            min: rusttype::point(0, -10),
+            max: rusttype::point(8, 0),
+        };
+        let (mut underlay_items, mut items) = (Vec::new(), Vec::new());
+        push_glyph_quads(
+            &glyph,
+            Point { x: 5.0, y: 0.0 },
+            20.0,
+            uv_rect,
+            pixel_rect,
+            &mut underlay_items,
+            &mut items,
+        );
+
+        assert_eq!(items.len(), 1);
+        assert_eq!(underlay_items.len(), 1);
+        let first_vertex = |renderable: &Renderable| match renderable {
+            Renderable::Quadrilateral(vertex, ..) => *vertex,
+            _ => panic!("glyphs should be drawn as quadrilaterals"),
+        };
+        let (text, shadow) = (first_vertex(&items[0]), first_vertex(&underlay_items[0]));
+        // The shadow is moved right and down, and the y axis points upwards when rendering.
+        assert_eq!(shadow.position[0] - text.position[0], 2.0);
+        assert_eq!(shadow.position[1] - text.position[1], -3.0);
+        assert_eq!(shadow.color, <[f32; 4]>::from(shadow_colour));
+    }
+
+    #[test]
+    fn font_sampler_uses_requested_filter() {
+        for &filter in &[FilterMode::Nearest, FilterMode::Linear] {
+            let descriptor = font_sampler_descriptor(filter);
+            assert_eq!(descriptor.mag_filter, filter);
+            assert_eq!(descriptor.min_filter, filter);
         }
     }
 }
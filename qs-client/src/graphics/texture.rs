@@ -179,6 +179,120 @@ impl TextureRegion {
             .await;
         region
     }
+
+    /// Generates a single quad that draws this region. `x` and `y` represent the bottom-left corner of the shape.
+    ///
+    /// If the region was rotated when it was packed, the texture coordinates are rotated back so the image appears upright.
+    /// If the region was trimmed when it was packed, `width` and `height` refer to the size of the original image, and the quad
+    /// only covers the part of it that was not trimmed away.
+    pub fn generate_render_info(
+        &self,
+        colour: Colour,
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    ) -> MultiRenderable {
+        let InternalTextureRegionInformation {
+            info:
+                TextureRegionInformation {
+                    frame,
+                    rotated,
+                    trimmed,
+                    source,
+                },
+            partitioned_texture_size,
+        } = match *self.info.try_lock().unwrap() {
+            Some(info) => info,
+            None => return MultiRenderable::Nothing,
+        };
+
+        // The size of the packed pixels, as they appear in the original image.
+        let (image_width, image_height) = if rotated {
+            (frame.h as f32, frame.w as f32)
+        } else {
+            (frame.w as f32, frame.h as f32)
+        };
+
+        // Work out where the packed pixels go inside the shape. Remember that Y increases upwards here.
+        let (x1, x2, y1, y2) = if trimmed {
+            let scale_x = width / source.w as f32;
+            let scale_y = height / source.h as f32;
+            let x1 = x + source.x as f32 * scale_x;
+            let y1 = y + height - source.y as f32 * scale_y;
+            (
+                x1,
+                x1 + image_width * scale_x,
+                y1,
+                y1 - image_height * scale_y,
+            )
+        } else {
+            (x, x + width, y + height, y)
+        };
+
+        let tex_w = partitioned_texture_size.0 as f32;
+        let tex_h = partitioned_texture_size.1 as f32;
+        let (u1, u2) = (frame.x as f32 / tex_w, (frame.x + frame.w) as f32 / tex_w);
+        let (v1, v2) = (frame.y as f32 / tex_h, (frame.y + frame.h) as f32 / tex_h);
+
+        // The texture coordinates of the top left, top right, bottom right and bottom left of the original image.
+        // Rotated images were rotated 90 degrees clockwise, so the top left of the image is now at the top right of the frame.
+        let tex_coords = if rotated {
+            [[u2, v1], [u2, v2], [u1, v2], [u1, v1]]
+        } else {
+            [[u1, v1], [u2, v1], [u2, v2], [u1, v2]]
+        };
+
+        let color = colour.into();
+        MultiRenderable::ImageRegion {
+            texture: self.clone(),
+            renderables: vec![Renderable::Quadrilateral(
+                Vertex {
+                    position: [x1, y1, 0.0],
+                    color,
+                    tex_coords: tex_coords[0],
+                },
+                Vertex {
+                    position: [x2, y1, 0.0],
+                    color,
+                    tex_coords: tex_coords[1],
+                },
+                Vertex {
+                    position: [x2, y2, 0.0],
+                    color,
+                    tex_coords: tex_coords[2],
+                },
+                Vertex {
+                    position: [x1, y2, 0.0],
+                    color,
+                    tex_coords: tex_coords[3],
+                },
+            )],
+        }
+    }
+
+    /// Populates the region's information as if it had been loaded from a partitioned texture of the given size.
+    #[cfg(test)]
+    pub(crate) fn set_info_for_testing(
+        &self,
+        info: TextureRegionInformation,
+        partitioned_texture_size: (u32, u32),
+    ) {
+        *self.info.try_lock().unwrap() = Some(InternalTextureRegionInformation {
+            info,
+            partitioned_texture_size,
+        });
+    }
+}
+
+/// How the middle slices of a nine-patch fill the space between the margins along one axis.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum NinePatchFill {
+    /// The middle slice is scaled to fit the space.
+    Stretch,
+    /// The middle slice is repeated at its original size, and the last repetition is cut short if it doesn't fit.
+    /// This suits patterned borders, which would look blurry or distorted if they were stretched.
+    Tile,
 }
 
 /// Splits a texture into nine pieces, a 3x3 grid, where the sizes of the pieces are represented using pixel measurements.
@@ -191,6 +305,10 @@ pub struct NinePatch {
     pub right_margin: u32,
     pub top_margin: u32,
     pub bottom_margin: u32,
+
+    /// How the middle column and the middle row fill the width and height of the shape.
+    pub horizontal_fill: NinePatchFill,
+    pub vertical_fill: NinePatchFill,
 }
 
 impl NinePatch {
@@ -201,10 +319,21 @@ impl NinePatch {
             right_margin: 0,
             top_margin: 0,
             bottom_margin: 0,
+            horizontal_fill: NinePatchFill::Stretch,
+            vertical_fill: NinePatchFill::Stretch,
         }
     }
 
+    /// The smallest width and height that this nine-patch can be drawn at without its corners overlapping.
+    pub fn min_size(&self) -> (f32, f32) {
+        (
+            (self.left_margin + self.right_margin) as f32,
+            (self.top_margin + self.bottom_margin) as f32,
+        )
+    }
+
     /// `x` and `y` represent the bottom-left corner of the shape.
+    /// If `width` or `height` is smaller than `min_size`, the shape is enlarged so that the middle slices never have negative size.
     pub fn generate_render_info(
         &self,
         colour: Colour,
@@ -215,87 +344,171 @@ impl NinePatch {
     ) -> MultiRenderable {
         // We need to create 16 vertices for the 3x3 grid.
 
+        let (min_width, min_height) = self.min_size();
+        let width = width.max(min_width);
+        let height = height.max(min_height);
+
         let InternalTextureRegionInformation {
-            info: TextureRegionInformation { frame, .. },
+            info:
+                TextureRegionInformation {
+                    frame,
+                    trimmed,
+                    source,
+                    ..
+                },
             partitioned_texture_size,
         } = match *self.texture_region.info.try_lock().unwrap() {
             Some(tex) => tex,
             None => return MultiRenderable::Nothing,
         };
 
-        let tex_w = partitioned_texture_size.0 as f32;
-        let tex_h = partitioned_texture_size.1 as f32;
+        // The margins are measured in the original image. If it was trimmed when it was packed, only part of it remains in the frame.
+        let (source_x, source_y, source_w, source_h) = if trimmed {
+            (source.x, source.y, source.w, source.h)
+        } else {
+            (0, 0, frame.w, frame.h)
+        };
 
-        // Therefore, we have four x-positions and four y-positions for coordinates,
-        // and four u-positions and v-positions for texture coordinates.
-        let u_positions = [
-            frame.x as f32 / tex_w,
-            (frame.x as f32 + self.left_margin as f32) / tex_w,
-            (frame.x as f32 + frame.w as f32 - self.right_margin as f32) / tex_w,
-            (frame.x as f32 + frame.w as f32) / tex_w,
-        ];
-        let v_positions = [
-            frame.y as f32 / tex_h,
-            (frame.y as f32 + self.bottom_margin as f32) / tex_h,
-            (frame.y as f32 + frame.h as f32 - self.top_margin as f32) / tex_h,
-            (frame.y as f32 + frame.h as f32) / tex_h,
-        ];
-
-        let x_positions = [
-            x,
-            x + self.left_margin as f32,
-            x + width - self.right_margin as f32,
-            x + width,
-        ];
-        let y_positions = [
-            y,
-            y + self.bottom_margin as f32,
-            y + height - self.top_margin as f32,
-            y + height,
-        ];
+        // We have four x-positions and four y-positions for coordinates, which split each axis into three slices.
+        let x_slices = nine_patch_axis(
+            [
+                0.0,
+                self.left_margin as f32,
+                source_w.saturating_sub(self.right_margin) as f32,
+                source_w as f32,
+            ],
+            [
+                x,
+                x + self.left_margin as f32,
+                x + width - self.right_margin as f32,
+                x + width,
+            ],
+            (source_x as f32, frame.w as f32),
+            frame.x as f32,
+            partitioned_texture_size.0 as f32,
+            self.horizontal_fill,
+        );
+        // Like `source.y`, the vertical edges are measured downwards from the top of the image, but Y increases upwards on screen.
+        let y_slices = nine_patch_axis(
+            [
+                0.0,
+                self.top_margin as f32,
+                source_h.saturating_sub(self.bottom_margin) as f32,
+                source_h as f32,
+            ],
+            [
+                y + height,
+                y + height - self.top_margin as f32,
+                y + self.bottom_margin as f32,
+                y,
+            ],
+            (source_y as f32, frame.h as f32),
+            frame.y as f32,
+            partitioned_texture_size.1 as f32,
+            self.vertical_fill,
+        );
 
         let color = colour.into();
 
-        MultiRenderable::ImageRegion {
-            texture: self.texture_region.clone(),
-            renderables: [
-                (0, 0),
-                (0, 1),
-                (0, 2),
-                (1, 0),
-                (1, 1),
-                (1, 2),
-                (2, 0),
-                (2, 1),
-                (2, 2),
-            ]
-            .iter()
-            .copied()
-            .map(|(i, j)| {
-                Renderable::Quadrilateral(
+        let mut renderables = Vec::new();
+        for ([x1, x2], [u1, u2]) in x_slices.iter().copied() {
+            for ([y1, y2], [v1, v2]) in y_slices.iter().copied() {
+                renderables.push(Renderable::Quadrilateral(
                     Vertex {
-                        position: [x_positions[i], y_positions[j], 0.0],
+                        position: [x1, y1, 0.0],
                         color,
-                        tex_coords: [u_positions[i], v_positions[j]],
+                        tex_coords: [u1, v1],
                     },
                     Vertex {
-                        position: [x_positions[i + 1], y_positions[j], 0.0],
+                        position: [x2, y1, 0.0],
                         color,
-                        tex_coords: [u_positions[i + 1], v_positions[j]],
+                        tex_coords: [u2, v1],
                     },
                     Vertex {
-                        position: [x_positions[i + 1], y_positions[j + 1], 0.0],
+                        position: [x2, y2, 0.0],
                         color,
-                        tex_coords: [u_positions[i + 1], v_positions[j + 1]],
+                        tex_coords: [u2, v2],
                     },
                     Vertex {
-                        position: [x_positions[i], y_positions[j + 1], 0.0],
+                        position: [x1, y2, 0.0],
                         color,
-                        tex_coords: [u_positions[i], v_positions[j + 1]],
+                        tex_coords: [u1, v2],
                     },
-                )
-            })
-            .collect(),
+                ));
+            }
+        }
+
+        MultiRenderable::ImageRegion {
+            texture: self.texture_region.clone(),
+            renderables,
+        }
+    }
+}
+
+/// Splits one axis of a nine-patch into its three slices, returning the positions and texture coordinates of both edges
+/// of each slice that still contains some pixels after trimming. If the middle slice is tiled, each repetition is returned separately.
+///
+/// `edges` are the coordinates of the edges of the slices in the original image, and `positions` are where these edges should be drawn.
+/// The positions may decrease along the axis, for instance when edges measured downwards from the top of an image are drawn in Y-up space.
+/// `packed` is the offset and size of the part of the original image that was packed into the frame, which starts at `frame_start`
+/// in a texture of size `texture_size`.
+fn nine_patch_axis(
+    edges: [f32; 4],
+    positions: [f32; 4],
+    packed: (f32, f32),
+    frame_start: f32,
+    texture_size: f32,
+    fill: NinePatchFill,
+) -> Vec<([f32; 2], [f32; 2])> {
+    let (packed_start, packed_size) = packed;
+    let packed_end = packed_start + packed_size;
+    let direction = if positions[3] < positions[0] {
+        -1.0
+    } else {
+        1.0
+    };
+    let mut slices = Vec::new();
+    for i in 0..3 {
+        // Each piece is a range of the original image, and the range of positions it is drawn across.
+        let tile_size = edges[i + 1] - edges[i];
+        let length = (positions[i + 1] - positions[i]) * direction;
+        let pieces = if i == 1 && fill == NinePatchFill::Tile && tile_size > 0.0 {
+            let count = (length / tile_size).ceil().max(0.0) as usize;
+            (0..count)
+                .map(|n| {
+                    let offset = n as f32 * tile_size;
+                    let size = tile_size.min(length - offset);
+                    let start = positions[1] + direction * offset;
+                    (
+                        [edges[1], edges[1] + size],
+                        [start, start + direction * size],
+                    )
+                })
+                .collect()
+        } else {
+            vec![([edges[i], edges[i + 1]], [positions[i], positions[i + 1]])]
+        };
+
+        for ([edge_start, edge_end], [position_start, position_end]) in pieces {
+            // Clip this piece to the pixels that were actually packed.
+            let start = edge_start.max(packed_start);
+            let end = edge_end.min(packed_end);
+            if start >= end {
+                continue;
+            }
+
+            // Map the clipped edges onto the screen, stretching them in the same way as the whole piece.
+            let position = |edge: f32| {
+                position_start
+                    + (position_end - position_start) * (edge - edge_start)
+                        / (edge_end - edge_start)
+            };
+            let tex_coord = |edge: f32| (frame_start + edge - packed_start) / texture_size;
+            slices.push((
+                [position(start), position(end)],
+                [tex_coord(start), tex_coord(end)],
+            ));
         }
     }
+    slices
 }
@@ -0,0 +1,268 @@
+use stretch::{geometry::Point, geometry::Size, number::Number};
+use wgpu::*;
+use winit::{dpi::PhysicalPosition, event::*, window::Window};
+
+use crate::ui::UI;
+
+use super::{Camera, CameraData, Viewport};
+
+/// The format of the images in every window's swap chain.
+/// Every window must use the same format so that they can share the same render pipelines.
+pub const SWAP_CHAIN_FORMAT: TextureFormat = TextureFormat::Bgra8UnormSrgb;
+
+/// Contains everything that is needed to render to a single window. Windows share the same device and queue,
+/// but each window has its own surface, swap chain, cameras and UI.
+pub struct WindowState {
+    pub window: Window,
+    surface: Surface,
+
+    /// The dimensions of the window's area we can render to.
+    pub size: winit::dpi::PhysicalSize<u32>,
+
+    /// Provides a way for us to recreate the swap chain when we (for example) resize the window.
+    swap_chain_descriptor: SwapChainDescriptor,
+    swap_chain: SwapChain,
+
+    pub camera: Camera,
+    pub ui_camera: Camera,
+    pub ui: UI,
+
+    /// If this is set, the world is rendered into the largest part of the window with this width/height aspect ratio,
+    /// and the rest of the window is left as bars. The UI still covers the whole window.
+    design_aspect_ratio: Option<f32>,
+
+    mouse_position: PhysicalPosition<f64>,
+}
+
+impl WindowState {
+    /// Creates the state for a window. The surface must have been created from this window.
+    pub fn new(window: Window, surface: Surface, device: &Device, ui: UI) -> Self {
+        // The amount of pixels we have to work with in our window.
+        let size = window.inner_size();
+        let scale_factor = window.scale_factor();
+
+        // The swap chain represents the images that will be presented to the `surface` above.
+        // When we resize the window, we need to recreate the swap chain because the images
+        // to be presented are now a different size. Windows that start minimised get a tiny swap chain,
+        // which is replaced when they are first given a real size.
+        let swap_chain_descriptor = SwapChainDescriptor {
+            usage: TextureUsage::OUTPUT_ATTACHMENT,
+            format: SWAP_CHAIN_FORMAT,
+            width: size.width.max(1),
+            height: size.height.max(1),
+            present_mode: PresentMode::Immediate,
+        };
+        let swap_chain = device.create_swap_chain(&surface, &swap_chain_descriptor);
+
+        let camera = Camera::new(CameraData::Orthographic {
+            eye: cgmath::Point2::new(0.0, 0.0),
+            view_height: 2.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+        let ui_camera = Camera::new(CameraData::Orthographic {
+            eye: cgmath::Point2::new(0.0, 0.0),
+            view_height: 800.0,
+            aspect_ratio: 1.0,
+            window_height: 0,
+            pixel_snap: false,
+            near: CameraData::DEFAULT_NEAR,
+            far: CameraData::DEFAULT_FAR,
+        });
+
+        let mut state = Self {
+            window,
+            surface,
+            size,
+            swap_chain_descriptor,
+            swap_chain,
+            camera,
+            ui_camera,
+            ui,
+            design_aspect_ratio: None,
+            mouse_position: PhysicalPosition { x: 0.0, y: 0.0 },
+        };
+
+        // Call resize at the start so that we initialise cameras etc with the correct aspect ratio.
+        state.resize(device, size, Some(scale_factor));
+        state
+    }
+
+    /// If the new size has no area, for example because the window was minimised, the swap chain is left alone,
+    /// since a swap chain can't be empty. Nothing is rendered to the window until it is resized again.
+    ///
+    /// # Arguments
+    /// If `scale_factor` is `None`, then the scale factor did not change.
+    pub fn resize(
+        &mut self,
+        device: &Device,
+        new_size: winit::dpi::PhysicalSize<u32>,
+        scale_factor: Option<f64>,
+    ) {
+        tracing::info!("Got new size: {:?} with scale {:?}", new_size, scale_factor);
+        self.size = new_size;
+        if !self.can_render() {
+            return;
+        }
+        self.recreate_swap_chain(device);
+
+        self.update_world_camera_size();
+        let CameraData::Orthographic {
+            ref mut view_height,
+            ..
+        } = self.ui_camera.get_data_mut();
+        *view_height = new_size.height as f32;
+        self.ui_camera
+            .update_window_size(new_size.width, new_size.height);
+
+        self.ui.update_size(Size {
+            width: Number::Defined(new_size.width as f32),
+            height: Number::Defined(new_size.height as f32),
+        })
+    }
+
+    /// Keeps the world camera's view the same shape as the design aspect ratio, however the window is resized.
+    /// The area the camera shows is letterboxed within the window; the bars are left as the window's clear colour.
+    /// If `aspect_ratio` is `None`, the world fills the whole window.
+    pub fn set_design_aspect_ratio(&mut self, aspect_ratio: Option<f32>) {
+        self.design_aspect_ratio = aspect_ratio;
+        self.update_world_camera_size();
+    }
+
+    /// The part of the window that the world is rendered to, or `None` if it fills the whole window.
+    pub fn world_viewport(&self) -> Option<Viewport> {
+        self.design_aspect_ratio.map(|aspect_ratio| {
+            Viewport::letterboxed(self.size.width, self.size.height, aspect_ratio)
+        })
+    }
+
+    fn update_world_camera_size(&mut self) {
+        match self.world_viewport() {
+            Some(viewport) => {
+                self.camera
+                    .update_window_size(viewport.width as u32, viewport.height as u32);
+                self.camera
+                    .set_viewport_origin(cgmath::Point2::new(viewport.x, viewport.y));
+            }
+            None => {
+                self.camera
+                    .update_window_size(self.size.width, self.size.height);
+                self.camera
+                    .set_viewport_origin(cgmath::Point2::new(0.0, 0.0));
+            }
+        }
+    }
+
+    /// Returns false if the window currently has no area to render to, for example because it is minimised.
+    pub fn can_render(&self) -> bool {
+        has_area(self.size)
+    }
+
+    fn recreate_swap_chain(&mut self, device: &Device) {
+        self.swap_chain_descriptor.width = self.size.width;
+        self.swap_chain_descriptor.height = self.size.height;
+        self.swap_chain = device.create_swap_chain(&self.surface, &self.swap_chain_descriptor);
+    }
+
+    /// Gets a handle to a texture that we can render the next frame to.
+    /// If the swap chain is outdated or lost, it is recreated. If no frame is available, this frame should be skipped.
+    pub fn get_current_frame(&mut self, device: &Device) -> Option<SwapChainFrame> {
+        match self.swap_chain.get_current_frame() {
+            Ok(frame) => Some(frame),
+            Err(error) => match recovery(error) {
+                Recovery::SkipFrame => None,
+                Recovery::RecreateSwapChain => {
+                    self.recreate_swap_chain(device);
+                    match self.swap_chain.get_current_frame() {
+                        Ok(frame) => Some(frame),
+                        Err(error) => {
+                            tracing::warn!(
+                                "could not get a frame from the new swap chain: {}",
+                                error
+                            );
+                            None
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    pub fn update_cursor(&mut self, pos: PhysicalPosition<f64>) {
+        self.mouse_position = pos;
+        self.ui.mouse_move(Point {
+            x: pos.x as f32,
+            y: pos.y as f32,
+        });
+    }
+
+    /// Returns true if the UI processed the event.
+    pub fn mouse_input(&mut self, button: MouseButton, state: ElementState) -> bool {
+        self.ui.mouse_input(button, state)
+    }
+
+    /// Returns true if the UI processed the event.
+    pub fn keyboard_input(&mut self, input: KeyboardInput) -> bool {
+        self.ui.keyboard_input(input)
+    }
+}
+
+/// What to do when the swap chain can't give us a frame.
+#[derive(Debug, PartialEq, Eq)]
+enum Recovery {
+    SkipFrame,
+    RecreateSwapChain,
+}
+
+/// Panics if there is no memory left for a frame, since nothing more can be rendered.
+fn recovery(error: SwapChainError) -> Recovery {
+    match error {
+        SwapChainError::Timeout => {
+            tracing::warn!("timed out getting the next frame");
+            Recovery::SkipFrame
+        }
+        SwapChainError::Outdated | SwapChainError::Lost => {
+            tracing::info!("recreating swap chain: {}", error);
+            Recovery::RecreateSwapChain
+        }
+        SwapChainError::OutOfMemory => panic!("could not get the next frame: {}", error),
+    }
+}
+
+fn has_area(size: winit::dpi::PhysicalSize<u32>) -> bool {
+    size.width > 0 && size.height > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winit::dpi::PhysicalSize;
+
+    #[test]
+    fn minimised_windows_have_no_area() {
+        // Minimised windows are reported as `(0, 0)` on Windows, but other platforms may only zero one dimension.
+        assert!(!has_area(PhysicalSize::new(0, 0)));
+        assert!(!has_area(PhysicalSize::new(800, 0)));
+        assert!(!has_area(PhysicalSize::new(0, 600)));
+        assert!(has_area(PhysicalSize::new(1, 1)));
+    }
+
+    #[test]
+    fn outdated_swap_chains_are_recreated() {
+        assert_eq!(
+            recovery(SwapChainError::Outdated),
+            Recovery::RecreateSwapChain
+        );
+        assert_eq!(recovery(SwapChainError::Lost), Recovery::RecreateSwapChain);
+        assert_eq!(recovery(SwapChainError::Timeout), Recovery::SkipFrame);
+    }
+
+    #[test]
+    #[should_panic]
+    fn running_out_of_memory_is_fatal() {
+        recovery(SwapChainError::OutOfMemory);
+    }
+}
@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use winit::{
+    dpi::{PhysicalPosition, PhysicalSize},
+    monitor::MonitorHandle,
+    window::{Fullscreen, Window, WindowBuilder},
+};
+
+/// The size and position of a window, which is saved when the application closes so that
+/// the window can be reopened in the same place.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct WindowGeometry {
+    /// The position of the top left of the window, including its decorations, in physical pixels.
+    pub x: i32,
+    pub y: i32,
+    /// The size of the window's area we can render to, in physical pixels.
+    pub width: u32,
+    pub height: u32,
+    pub fullscreen: bool,
+}
+
+impl WindowGeometry {
+    /// Returns the current geometry of the window, or `None` if the platform does not tell us where the window is.
+    pub fn of(window: &Window) -> Option<Self> {
+        let position = window.outer_position().ok()?;
+        let size = window.inner_size();
+        Some(Self {
+            x: position.x,
+            y: position.y,
+            width: size.width,
+            height: size.height,
+            fullscreen: window.fullscreen().is_some(),
+        })
+    }
+
+    /// Sets up a window builder so that the window it builds has this geometry's size and fullscreen state.
+    /// Window builders cannot set a position, so call `move_window` once the window has been built.
+    pub fn apply_to_builder(&self, builder: WindowBuilder) -> WindowBuilder {
+        let builder = builder.with_inner_size(PhysicalSize::new(self.width, self.height));
+        if self.fullscreen {
+            builder.with_fullscreen(Some(Fullscreen::Borderless(None)))
+        } else {
+            builder
+        }
+    }
+
+    /// Moves the window to this geometry's position.
+    pub fn move_window(&self, window: &Window) {
+        window.set_outer_position(PhysicalPosition::new(self.x, self.y));
+    }
+
+    /// Does enough of the window lie on one of these monitors that the user can see it and move it?
+    /// The monitors are given as their positions and sizes.
+    pub fn is_visible_on(
+        &self,
+        monitors: impl IntoIterator<Item = (PhysicalPosition<i32>, PhysicalSize<u32>)>,
+    ) -> bool {
+        /// The number of pixels of the window, in each direction, that must be on a monitor.
+        const MIN_VISIBLE: i64 = 64;
+
+        if self.width == 0 || self.height == 0 {
+            return false;
+        }
+        monitors.into_iter().any(|(position, size)| {
+            let overlap = |start: i32, length: u32, monitor_start: i32, monitor_length: u32| {
+                let end = start as i64 + length as i64;
+                let monitor_end = monitor_start as i64 + monitor_length as i64;
+                end.min(monitor_end) - (start as i64).max(monitor_start as i64)
+            };
+            let visible_width = overlap(self.x, self.width, position.x, size.width);
+            let visible_height = overlap(self.y, self.height, position.y, size.height);
+            visible_width >= MIN_VISIBLE.min(self.width as i64)
+                && visible_height >= MIN_VISIBLE.min(self.height as i64)
+        })
+    }
+
+    /// Like `is_visible_on`, but checks the monitors that are currently connected.
+    pub fn is_visible_on_monitors(&self, monitors: impl Iterator<Item = MonitorHandle>) -> bool {
+        self.is_visible_on(monitors.map(|monitor| (monitor.position(), monitor.size())))
+    }
+
+    /// Reads the geometry that was saved when the application was last closed, if there is one.
+    pub fn load() -> Option<Self> {
+        let contents = std::fs::read_to_string(config_path()?).ok()?;
+        match serde_json::from_str(&contents) {
+            Ok(geometry) => Some(geometry),
+            Err(error) => {
+                tracing::warn!("could not read saved window geometry: {}", error);
+                None
+            }
+        }
+    }
+
+    /// Saves this geometry so that it can be loaded next time the application starts.
+    pub fn save(&self) {
+        let path = match config_path() {
+            Some(path) => path,
+            None => return,
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|()| {
+                std::fs::write(
+                    &path,
+                    serde_json::to_string(self).expect("could not serialise window geometry"),
+                )
+            });
+        if let Err(error) = result {
+            tracing::warn!("could not save window geometry to {:?}: {}", path, error);
+        }
+    }
+}
+
+/// The file that the window geometry is saved in, inside the platform's configuration directory.
+fn config_path() -> Option<PathBuf> {
+    let dirs = directories::ProjectDirs::from("", "", "Quest Sage")?;
+    Some(dirs.config_dir().join("window.json"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GEOMETRY: WindowGeometry = WindowGeometry {
+        x: 100,
+        y: -20,
+        width: 800,
+        height: 600,
+        fullscreen: false,
+    };
+
+    #[test]
+    fn geometry_round_trips_through_json() {
+        let json = serde_json::to_string(&GEOMETRY).unwrap();
+        assert_eq!(
+            serde_json::from_str::<WindowGeometry>(&json).unwrap(),
+            GEOMETRY
+        );
+    }
+
+    #[test]
+    fn geometry_must_be_on_a_monitor() {
+        let monitor = (PhysicalPosition::new(0, 0), PhysicalSize::new(1920, 1080));
+        assert!(GEOMETRY.is_visible_on(vec![monitor]));
+
+        // The window used to be on a second monitor that has since been unplugged.
+        let off_screen = WindowGeometry {
+            x: 2000,
+            ..GEOMETRY
+        };
+        assert!(!off_screen.is_visible_on(vec![monitor]));
+        let second_monitor = (
+            PhysicalPosition::new(1920, 0),
+            PhysicalSize::new(1920, 1080),
+        );
+        assert!(off_screen.is_visible_on(vec![monitor, second_monitor]));
+    }
+}
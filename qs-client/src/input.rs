@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use winit::event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode};
+
+/// A physical input that can trigger an action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+}
+
+/// Maps physical keys and mouse buttons to named actions such as `"quit"` or `"jump"`,
+/// so that game code can ask whether an action happened without caring which key the player bound it to.
+///
+/// Raw input events are fed in by the event loop, and `end_frame` should be called once every frame,
+/// after everything that wants to query actions has done so.
+///
+/// Only the bindings are serialised, not which inputs are currently pressed.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct InputMap {
+    bindings: HashMap<String, Vec<Binding>>,
+
+    /// The inputs that are currently held down.
+    #[serde(skip)]
+    held: HashSet<Binding>,
+    /// The inputs that were pressed or released since the last call to `end_frame`.
+    #[serde(skip)]
+    pressed: HashSet<Binding>,
+    #[serde(skip)]
+    released: HashSet<Binding>,
+}
+
+impl InputMap {
+    /// Creates an input map with no bindings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an input map with the bindings the game uses before the player changes anything.
+    pub fn default_bindings() -> Self {
+        let mut map = Self::new();
+        map.bind("quit", Binding::Key(VirtualKeyCode::Escape));
+        map.bind("toggle_debug_overlay", Binding::Key(VirtualKeyCode::F3));
+        map
+    }
+
+    /// Adds a binding to an action. An action may have any number of bindings, and triggering any of them triggers the action.
+    pub fn bind(&mut self, action: impl Into<String>, binding: Binding) {
+        let bindings = self.bindings.entry(action.into()).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    /// Removes a binding from an action, if it was bound.
+    pub fn unbind(&mut self, action: &str, binding: Binding) {
+        if let Some(bindings) = self.bindings.get_mut(action) {
+            bindings.retain(|b| *b != binding);
+        }
+    }
+
+    /// Replaces every binding of an action with the given binding.
+    pub fn rebind(&mut self, action: impl Into<String>, binding: Binding) {
+        self.bindings.insert(action.into(), vec![binding]);
+    }
+
+    /// Returns the inputs that are bound to the given action.
+    pub fn bindings(&self, action: &str) -> &[Binding] {
+        self.bindings.get(action).map_or(&[], |bindings| bindings)
+    }
+
+    pub fn keyboard_input(&mut self, input: KeyboardInput) {
+        if let Some(key) = input.virtual_keycode {
+            self.input(Binding::Key(key), input.state);
+        }
+    }
+
+    pub fn mouse_input(&mut self, button: MouseButton, state: ElementState) {
+        self.input(Binding::Mouse(button), state);
+    }
+
+    fn input(&mut self, binding: Binding, state: ElementState) {
+        match state {
+            ElementState::Pressed => {
+                // Held keys send repeated presses, but the action should only be triggered once.
+                if self.held.insert(binding) {
+                    self.pressed.insert(binding);
+                }
+            }
+            ElementState::Released => {
+                if self.held.remove(&binding) {
+                    self.released.insert(binding);
+                }
+            }
+        }
+    }
+
+    /// Forgets which inputs were pressed and released this frame.
+    pub fn end_frame(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+    }
+
+    /// Is any input bound to this action currently held down?
+    pub fn is_pressed(&self, action: &str) -> bool {
+        self.any_bound(action, &self.held)
+    }
+
+    /// Was any input bound to this action pressed this frame?
+    pub fn just_pressed(&self, action: &str) -> bool {
+        self.any_bound(action, &self.pressed)
+    }
+
+    /// Was any input bound to this action released this frame?
+    pub fn just_released(&self, action: &str) -> bool {
+        self.any_bound(action, &self.released)
+    }
+
+    fn any_bound(&self, action: &str, inputs: &HashSet<Binding>) -> bool {
+        self.bindings(action)
+            .iter()
+            .any(|binding| inputs.contains(binding))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use winit::event::ModifiersState;
+
+    #[allow(deprecated)]
+    fn key(key: VirtualKeyCode, state: ElementState) -> KeyboardInput {
+        KeyboardInput {
+            scancode: 0,
+            state,
+            virtual_keycode: Some(key),
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    #[test]
+    fn rebinding_quit_to_q() {
+        let mut input = InputMap::default_bindings();
+        input.rebind("quit", Binding::Key(VirtualKeyCode::Q));
+
+        // Bindings survive being saved and loaded.
+        let mut input: InputMap =
+            serde_json::from_str(&serde_json::to_string(&input).unwrap()).unwrap();
+
+        input.keyboard_input(key(VirtualKeyCode::Escape, ElementState::Pressed));
+        assert!(!input.just_pressed("quit"));
+        input.keyboard_input(key(VirtualKeyCode::Q, ElementState::Pressed));
+        assert!(input.just_pressed("quit"));
+    }
+
+    #[test]
+    fn just_pressed_lasts_one_frame() {
+        let mut input = InputMap::new();
+        input.bind("jump", Binding::Key(VirtualKeyCode::Space));
+        input.bind("jump", Binding::Mouse(MouseButton::Left));
+
+        input.mouse_input(MouseButton::Left, ElementState::Pressed);
+        assert!(input.just_pressed("jump") && input.is_pressed("jump"));
+        input.end_frame();
+
+        // A repeated press while the button is held does not trigger the action again.
+        input.mouse_input(MouseButton::Left, ElementState::Pressed);
+        assert!(!input.just_pressed("jump") && input.is_pressed("jump"));
+        input.end_frame();
+
+        input.mouse_input(MouseButton::Left, ElementState::Released);
+        assert!(input.just_released("jump") && !input.is_pressed("jump"));
+        input.end_frame();
+        assert!(!input.just_released("jump"));
+    }
+}
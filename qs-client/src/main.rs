@@ -1,5 +1,8 @@
 pub mod assets;
 pub mod graphics;
+pub mod input;
+#[cfg(test)]
+mod test_fixtures;
 pub mod ui;
 
 fn register_tracing_subscriber() {
@@ -26,7 +29,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .unwrap();
 
     let _guard = rt.enter();
-    let (app, event_loop) = futures::executor::block_on(graphics::Application::new());
+    let (app, event_loop) = futures::executor::block_on(graphics::Application::new(
+        graphics::ApplicationConfig::default(),
+    ))
+    .map_err(|error| format!("could not start the graphics system: {}", error))?;
     app.run(event_loop);
 
     Ok(())
@@ -0,0 +1,77 @@
+//! Fixtures shared between the unit tests of several modules.
+
+use std::sync::Arc;
+
+use qs_common::assets::{Asset, AssetManager, AssetPath, LoadError, Loader};
+use rusttype::Font;
+
+use crate::assets::{FontAssetLoader, FontKey};
+use crate::graphics::{NinePatch, PartitionedTexture, Texture, TextureRegion};
+use crate::ui::{FontFace, FontFamily};
+
+pub type FontAssetManager = AssetManager<FontKey, Font<'static>, FontAssetLoader>;
+
+/// A loader for textures that can never be found. Widgets that need textures can use it to be built without a graphics card.
+pub struct MissingTextureLoader;
+
+#[async_trait::async_trait]
+impl Loader<AssetPath, PartitionedTexture> for MissingTextureLoader {
+    async fn load(&self, _key: AssetPath) -> Result<PartitionedTexture, LoadError> {
+        Err(LoadError::FileNotFound)
+    }
+}
+
+#[async_trait::async_trait]
+impl Loader<AssetPath, Texture> for MissingTextureLoader {
+    async fn load(&self, _key: AssetPath) -> Result<Texture, LoadError> {
+        Err(LoadError::FileNotFound)
+    }
+}
+
+/// Creates a nine-patch whose texture will never load.
+pub async fn missing_nine_patch() -> NinePatch {
+    let mut texture_am = AssetManager::new(MissingTextureLoader);
+    let texture = texture_am.get(AssetPath::new(vec!["missing".to_string()]));
+    NinePatch::no_margins(TextureRegion::new(texture, "missing".to_string()).await)
+}
+
+/// Loads the regular Noto Sans font from the assets folder, waiting until it is loaded.
+pub async fn noto_sans_regular(font_am: &mut FontAssetManager) -> Asset<Font<'static>> {
+    let font = font_am.get(AssetPath::new(vec!["NotoSans-Regular.ttf".to_string()]).into());
+    font.wait_until_loaded().await;
+    font
+}
+
+/// Creates a font family whose only face is the given font, with no bold or italic variants.
+pub fn regular_only_family(font: Asset<Font<'static>>) -> Arc<FontFamily> {
+    Arc::new(FontFamily::new(vec![FontFace::new(
+        "Noto Sans".to_string(),
+        font,
+        None,
+        None,
+        None,
+    )]))
+}
+
+/// Loads the Noto Sans font family from the assets folder, waiting until all of its variants are loaded.
+/// The asset manager must be kept alive for as long as the font family is used.
+pub async fn noto_sans(font_am: &mut FontAssetManager) -> Arc<FontFamily> {
+    let mut get = |name: &str| font_am.get(AssetPath::new(vec![name.to_string()]).into());
+    let variants = [
+        get("NotoSans-Regular.ttf"),
+        get("NotoSans-Bold.ttf"),
+        get("NotoSans-Italic.ttf"),
+        get("NotoSans-BoldItalic.ttf"),
+    ];
+    for variant in &variants {
+        variant.wait_until_loaded().await;
+    }
+    let [regular, bold, italic, bold_italic] = variants;
+    Arc::new(FontFamily::new(vec![FontFace::new(
+        "Noto Sans".to_string(),
+        regular,
+        Some(bold),
+        Some(italic),
+        Some(bold_italic),
+    )]))
+}
@@ -70,6 +70,25 @@ impl UiElement for Button {
         }
     }
 
+    /// The button must be large enough to fit the corners of all of its textures.
+    fn get_min_size(&self) -> Size<Dimension> {
+        let (width, height) = [
+            &self.style.released_texture,
+            &self.style.hovered_texture,
+            &self.style.pressed_texture,
+            &self.style.disabled_texture,
+        ]
+        .iter()
+        .map(|nine_patch| nine_patch.min_size())
+        .fold((0.0f32, 0.0f32), |(w1, h1), (w2, h2)| {
+            (w1.max(w2), h1.max(h2))
+        });
+        Size {
+            width: Dimension::Points(width),
+            height: Dimension::Points(height),
+        }
+    }
+
     fn generate_render_info(&self, layout: &Layout) -> MultiRenderable {
         let disabled = self.disabled.load(Ordering::Relaxed);
 
@@ -130,6 +149,10 @@ impl UiElement for Button {
         }
     }
 
+    fn accepts_focus(&self) -> bool {
+        !self.disabled.load(Ordering::Relaxed)
+    }
+
     fn mouse_enter(&mut self) {
         if self.state == ButtonState::Released {
             self.state = ButtonState::Hovered;
@@ -145,4 +168,12 @@ impl UiElement for Button {
             self.state = ButtonState::PressedNotHovered;
         }
     }
+
+    fn mouse_press_cancelled(&mut self) {
+        if self.state == ButtonState::Pressed {
+            self.state = ButtonState::Hovered;
+        } else if self.state == ButtonState::PressedNotHovered {
+            self.state = ButtonState::Released;
+        }
+    }
 }
@@ -1,4 +1,10 @@
-#[derive(Debug, Copy, Clone)]
+use serde::{Deserialize, Serialize};
+
+/// A colour with an alpha channel. The colour components are in linear space, not sRGB space:
+/// they are proportional to the intensity of light. This matches how `wgpu` treats colours written to
+/// an sRGB texture (such as the swap chain), which are converted to sRGB automatically.
+/// Colours picked in an image editor are usually written in sRGB space, so they should be created with `from_srgb`.
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Colour {
     pub r: f32,
     pub g: f32,
@@ -15,6 +21,161 @@ impl Colour {
         Self { r, g, b, a }
     }
 
+    /// Creates a colour from sRGB components, converting them to linear space. The alpha channel is already linear.
+    pub fn from_srgb(r: f32, g: f32, b: f32, a: f32) -> Self {
+        Self {
+            r: srgb_to_linear(r),
+            g: srgb_to_linear(g),
+            b: srgb_to_linear(b),
+            a,
+        }
+    }
+
+    /// Parses a colour written as a hex string in sRGB space, as used in CSS and image editors.
+    /// The accepted forms are `#rgb`, `#rrggbb` and `#rrggbbaa`. If the alpha channel is not given, the colour is opaque.
+    pub fn from_hex(hex: &str) -> Result<Self, ParseColourError> {
+        let digits = match hex.strip_prefix('#') {
+            Some(digits) => digits,
+            None => return Err(ParseColourError::MissingHash),
+        };
+        if let Some(c) = digits.chars().find(|c| !c.is_ascii_hexdigit()) {
+            return Err(ParseColourError::InvalidDigit(c));
+        }
+
+        // Every character is an ASCII hex digit, so each character is a single byte.
+        let channel = |i: usize, len: usize| {
+            let value = u8::from_str_radix(&digits[i * len..(i + 1) * len], 16).unwrap();
+            if len == 1 {
+                // `#rgb` is shorthand for `#rrggbb`.
+                (value * 17) as f32 / 255.0
+            } else {
+                value as f32 / 255.0
+            }
+        };
+        match digits.len() {
+            3 => Ok(Self::from_srgb(
+                channel(0, 1),
+                channel(1, 1),
+                channel(2, 1),
+                1.0,
+            )),
+            6 => Ok(Self::from_srgb(
+                channel(0, 2),
+                channel(1, 2),
+                channel(2, 2),
+                1.0,
+            )),
+            8 => Ok(Self::from_srgb(
+                channel(0, 2),
+                channel(1, 2),
+                channel(2, 2),
+                channel(3, 2),
+            )),
+            len => Err(ParseColourError::InvalidLength(len)),
+        }
+    }
+
+    /// Converts this colour's components into sRGB space, for example to display them to the user.
+    /// The alpha channel is unchanged.
+    pub fn to_srgb(self) -> [f32; 4] {
+        [
+            linear_to_srgb(self.r),
+            linear_to_srgb(self.g),
+            linear_to_srgb(self.b),
+            self.a,
+        ]
+    }
+
+    /// Linearly interpolates between this colour and `other`, including the alpha channel.
+    /// `t` is clamped to the range from 0 (this colour) to 1 (`other`).
+    pub fn lerp(self, other: Colour, t: f32) -> Self {
+        let t = t.clamp(0.0, 1.0);
+        let mix = |a: f32, b: f32| a + (b - a) * t;
+        Self {
+            r: mix(self.r, other.r),
+            g: mix(self.g, other.g),
+            b: mix(self.b, other.b),
+            a: mix(self.a, other.a),
+        }
+    }
+
+    /// Returns this colour with its alpha channel replaced.
+    pub fn with_alpha(self, a: f32) -> Self {
+        Self { a, ..self }
+    }
+
+    /// Moves this colour towards white by the given amount, from 0 (unchanged) to 1 (white).
+    /// The alpha channel is unchanged.
+    pub fn lighten(self, amount: f32) -> Self {
+        self.lerp(Self::WHITE.with_alpha(self.a), amount)
+    }
+
+    /// Moves this colour towards black by the given amount, from 0 (unchanged) to 1 (black).
+    /// The alpha channel is unchanged.
+    pub fn darken(self, amount: f32) -> Self {
+        self.lerp(Self::BLACK.with_alpha(self.a), amount)
+    }
+
+    /// The relative luminance of this colour, from 0 (black) to 1 (white).
+    /// This is useful for choosing whether text drawn over this colour should be light or dark.
+    pub fn luminance(self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    /// Creates an opaque colour from a hue in degrees, and a saturation and value from 0 to 1.
+    /// Like the other colour space conversions here, this works directly on the colour's (linear) components.
+    pub fn from_hsv(h: f32, s: f32, v: f32) -> Self {
+        let chroma = v * s;
+        let (r, g, b) = hue_to_rgb(h, chroma);
+        let m = v - chroma;
+        Self::rgb(r + m, g + m, b + m)
+    }
+
+    /// Creates an opaque colour from a hue in degrees, and a saturation and lightness from 0 to 1.
+    pub fn from_hsl(h: f32, s: f32, l: f32) -> Self {
+        let chroma = (1.0 - (2.0 * l - 1.0).abs()) * s;
+        let (r, g, b) = hue_to_rgb(h, chroma);
+        let m = l - 0.5 * chroma;
+        Self::rgb(r + m, g + m, b + m)
+    }
+
+    /// Returns the hue in degrees (from 0 to 360), saturation and value of this colour. The alpha channel is ignored.
+    pub fn to_hsv(self) -> (f32, f32, f32) {
+        let (hue, max, chroma) = self.hue_max_chroma();
+        let saturation = if max == 0.0 { 0.0 } else { chroma / max };
+        (hue, saturation, max)
+    }
+
+    /// Returns the hue in degrees (from 0 to 360), saturation and lightness of this colour. The alpha channel is ignored.
+    pub fn to_hsl(self) -> (f32, f32, f32) {
+        let (hue, max, chroma) = self.hue_max_chroma();
+        let lightness = max - 0.5 * chroma;
+        let saturation = if lightness <= 0.0 || lightness >= 1.0 {
+            0.0
+        } else {
+            chroma / (1.0 - (2.0 * lightness - 1.0).abs())
+        };
+        (hue, saturation, lightness)
+    }
+
+    /// Returns the hue in degrees, the largest component, and the chroma (the difference between
+    /// the largest and smallest components) of this colour.
+    fn hue_max_chroma(self) -> (f32, f32, f32) {
+        let max = self.r.max(self.g).max(self.b);
+        let min = self.r.min(self.g).min(self.b);
+        let chroma = max - min;
+        let hue = if chroma == 0.0 {
+            0.0
+        } else if max == self.r {
+            60.0 * ((self.g - self.b) / chroma)
+        } else if max == self.g {
+            60.0 * ((self.b - self.r) / chroma + 2.0)
+        } else {
+            60.0 * ((self.r - self.g) / chroma + 4.0)
+        };
+        (hue.rem_euclid(360.0), max, chroma)
+    }
+
     pub const WHITE: Self = Self::rgb(1.0, 1.0, 1.0);
     pub const BLACK: Self = Self::rgb(0.0, 0.0, 0.0);
     pub const CLEAR: Self = Self::rgba(1.0, 1.0, 1.0, 0.0);
@@ -43,3 +204,179 @@ impl From<Colour> for [f32; 4] {
         [colour.r, colour.g, colour.b, colour.a]
     }
 }
+
+/// Returns the red, green and blue components of a colour with the given hue (in degrees) and chroma,
+/// before the colour's minimum component is added.
+fn hue_to_rgb(h: f32, chroma: f32) -> (f32, f32, f32) {
+    let sector = h.rem_euclid(360.0) / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    }
+}
+
+/// The reasons why a hex string could not be parsed into a colour.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseColourError {
+    /// The string did not start with a `#` character.
+    MissingHash,
+    /// There were this many hex digits, but there must be 3, 6 or 8.
+    InvalidLength(usize),
+    /// This character is not a hex digit.
+    InvalidDigit(char),
+}
+
+/// Converts a single colour component from sRGB space into linear space.
+pub fn srgb_to_linear(component: f32) -> f32 {
+    if component <= 0.04045 {
+        component / 12.92
+    } else {
+        ((component + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a single colour component from linear space into sRGB space.
+pub fn linear_to_srgb(component: f32) -> f32 {
+    if component <= 0.003_130_8 {
+        component * 12.92
+    } else {
+        1.055 * component.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn srgb_grey_converts_to_linear() {
+        let grey = Colour::from_srgb(0.5, 0.5, 0.5, 0.5);
+        assert!((grey.r - 0.214_041).abs() < 1e-5);
+        assert!((grey.g - grey.r).abs() < 1e-6 && (grey.b - grey.r).abs() < 1e-6);
+        assert_eq!(grey.a, 0.5);
+
+        let [r, _, _, a] = grey.to_srgb();
+        assert!((r - 0.5).abs() < 1e-5);
+        assert_eq!(a, 0.5);
+    }
+
+    fn assert_srgb(colour: Colour, expected: [u8; 4]) {
+        let actual = colour.to_srgb();
+        for (actual, expected) in actual.iter().zip(&expected) {
+            assert!(
+                (actual * 255.0 - *expected as f32).abs() < 1e-3,
+                "expected {:?}, got {:?}",
+                expected,
+                colour.to_srgb()
+            );
+        }
+    }
+
+    #[test]
+    fn parses_hex_colours() {
+        assert_srgb(Colour::from_hex("#f80").unwrap(), [255, 136, 0, 255]);
+        assert_srgb(Colour::from_hex("#aabbcc").unwrap(), [170, 187, 204, 255]);
+        assert_srgb(Colour::from_hex("#AaBbCc40").unwrap(), [170, 187, 204, 64]);
+    }
+
+    #[test]
+    fn rejects_malformed_hex_colours() {
+        assert_eq!(
+            Colour::from_hex("aabbcc").unwrap_err(),
+            ParseColourError::MissingHash
+        );
+        assert_eq!(
+            Colour::from_hex("#aabbc").unwrap_err(),
+            ParseColourError::InvalidLength(5)
+        );
+        assert_eq!(
+            Colour::from_hex("#").unwrap_err(),
+            ParseColourError::InvalidLength(0)
+        );
+        assert_eq!(
+            Colour::from_hex("#aabbgg").unwrap_err(),
+            ParseColourError::InvalidDigit('g')
+        );
+        assert_eq!(
+            Colour::from_hex("#aébbcc").unwrap_err(),
+            ParseColourError::InvalidDigit('é')
+        );
+    }
+
+    #[test]
+    fn lerp_endpoints_and_midpoint() {
+        let from = Colour::rgba(0.0, 0.25, 1.0, 0.0);
+        let to = Colour::rgba(1.0, 0.75, 0.0, 1.0);
+        assert_eq!(<[f32; 4]>::from(from.lerp(to, 0.0)), [0.0, 0.25, 1.0, 0.0]);
+        assert_eq!(<[f32; 4]>::from(from.lerp(to, 1.0)), [1.0, 0.75, 0.0, 1.0]);
+        assert_eq!(<[f32; 4]>::from(from.lerp(to, 0.5)), [0.5, 0.5, 0.5, 0.5]);
+        // `t` is clamped.
+        assert_eq!(<[f32; 4]>::from(from.lerp(to, -1.0)), [0.0, 0.25, 1.0, 0.0]);
+        assert_eq!(<[f32; 4]>::from(from.lerp(to, 2.0)), [1.0, 0.75, 0.0, 1.0]);
+    }
+
+    #[test]
+    fn with_alpha_replaces_only_alpha() {
+        let colour = Colour::rgba(0.1, 0.2, 0.3, 0.4).with_alpha(0.9);
+        assert_eq!(<[f32; 4]>::from(colour), [0.1, 0.2, 0.3, 0.9]);
+        assert_eq!(<[f32; 4]>::from(colour.lighten(1.0)), [1.0, 1.0, 1.0, 0.9]);
+        assert_eq!(<[f32; 4]>::from(colour.darken(1.0)), [0.0, 0.0, 0.0, 0.9]);
+    }
+
+    fn assert_close(actual: Colour, expected: Colour) {
+        let (actual, expected) = (<[f32; 4]>::from(actual), <[f32; 4]>::from(expected));
+        for (a, e) in actual.iter().zip(&expected) {
+            assert!(
+                (a - e).abs() < 1e-5,
+                "expected {:?}, got {:?}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn hsv_and_hsl_primary_and_secondary_colours() {
+        let colours = [
+            (0.0, Colour::RED),
+            (60.0, Colour::YELLOW),
+            (120.0, Colour::GREEN),
+            (180.0, Colour::CYAN),
+            (240.0, Colour::BLUE),
+            (300.0, Colour::MAGENTA),
+        ];
+        for &(hue, colour) in &colours {
+            assert_close(Colour::from_hsv(hue, 1.0, 1.0), colour);
+            assert_close(Colour::from_hsl(hue, 1.0, 0.5), colour);
+
+            let (h, s, v) = colour.to_hsv();
+            assert!((h - hue).abs() < 1e-4 && (s - 1.0).abs() < 1e-6 && (v - 1.0).abs() < 1e-6);
+            let (h, s, l) = colour.to_hsl();
+            assert!((h - hue).abs() < 1e-4 && (s - 1.0).abs() < 1e-6 && (l - 0.5).abs() < 1e-6);
+        }
+        assert_close(Colour::from_hsv(360.0, 1.0, 1.0), Colour::RED);
+        assert_close(Colour::from_hsl(90.0, 0.0, 1.0), Colour::WHITE);
+    }
+
+    #[test]
+    fn colours_round_trip_through_hsv_and_hsl() {
+        let colour = Colour::rgb(0.2, 0.7, 0.4);
+        let (h, s, v) = colour.to_hsv();
+        assert_close(Colour::from_hsv(h, s, v), colour);
+        let (h, s, l) = colour.to_hsl();
+        assert_close(Colour::from_hsl(h, s, l), colour);
+    }
+
+    #[test]
+    fn colours_round_trip_through_json() {
+        let colour = Colour::rgba(0.25, 0.5, 0.75, 1.0);
+        let json = serde_json::to_string(&colour).unwrap();
+        let parsed: Colour = serde_json::from_str(&json).unwrap();
+        assert_eq!(<[f32; 4]>::from(parsed), <[f32; 4]>::from(colour));
+    }
+}
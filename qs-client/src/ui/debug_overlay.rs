@@ -0,0 +1,273 @@
+use std::sync::Arc;
+
+use qs_common::profile::{display_time, ProfileSnapshot, ThreadSnapshot};
+
+use stretch::{
+    geometry::Rect,
+    style::{Dimension, PositionType, Style},
+};
+
+use super::*;
+
+/// Shows the frame rate and the slowest profiled tasks on top of the UI, followed by the slowest background tasks.
+/// The overlay starts hidden, and shows nothing until it is toggled.
+///
+/// The overlay is positioned absolutely in the top left corner of its parent, so it doesn't move the other widgets around.
+/// It should be the last child of its parent, so that it is drawn on top of its siblings.
+pub struct DebugOverlay {
+    rich_text: RichText,
+    font_family: Arc<FontFamily>,
+    visible: bool,
+    /// The lines of text that are currently displayed, one per paragraph.
+    lines: Vec<String>,
+}
+
+impl DebugOverlay {
+    /// The maximum number of profiled tasks to show.
+    pub const MAX_SEGMENTS: usize = 8;
+
+    pub fn new(font_family: Arc<FontFamily>) -> Self {
+        Self {
+            rich_text: RichText::new(Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    start: Dimension::Points(0.0),
+                    top: Dimension::Points(0.0),
+                    ..Default::default()
+                },
+                ..Default::default()
+            }),
+            font_family,
+            visible: false,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Returns the widget that displays the overlay. This should be added to the UI it is to be drawn over.
+    pub fn get_widget(&self) -> Widget {
+        self.rich_text.get_widget()
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// Shows the overlay if it was hidden, or hides it if it was shown. The overlay is empty until the next `update`.
+    pub fn toggle(&mut self) {
+        self.visible = !self.visible;
+        self.lines.clear();
+        self.rich_text
+            .set_text(Arc::clone(&self.font_family))
+            .finish();
+    }
+
+    /// Displays the given frame rate and profiles, if the overlay is visible. `background` is usually a snapshot of
+    /// `qs_common::profile::background_profiler`. Background tasks run on several threads, so each task is shown
+    /// with the slowest average time that any thread took for it.
+    /// The text is only typeset again if it changed, so this is cheap to call often.
+    pub fn update(&mut self, fps: f64, profile: &ProfileSnapshot, background: &[ThreadSnapshot]) {
+        if !self.visible {
+            return;
+        }
+
+        let mut lines = vec![
+            format!("{:.1} FPS", fps),
+            format!(
+                "{} / {}",
+                display_time(profile.calculation_time),
+                display_time(profile.total_time)
+            ),
+        ];
+        lines.extend(
+            profile
+                .segments
+                .iter()
+                .take(Self::MAX_SEGMENTS)
+                .map(|segment| {
+                    format!(
+                        "{}{} {}",
+                        "-".repeat(segment.depth),
+                        display_time(segment.average_time),
+                        segment.name
+                    )
+                }),
+        );
+
+        let mut background_tasks: Vec<(&str, f64)> = Vec::new();
+        for segment in background
+            .iter()
+            .flat_map(|thread| &thread.segments)
+            .filter(|segment| segment.depth == 0)
+        {
+            match background_tasks
+                .iter_mut()
+                .find(|(name, _)| *name == segment.name)
+            {
+                Some((_, time)) => *time = time.max(segment.average_time),
+                None => background_tasks.push((segment.name, segment.average_time)),
+            }
+        }
+        if !background_tasks.is_empty() {
+            background_tasks.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+            lines.push("Background".to_string());
+            lines.extend(
+                background_tasks
+                    .into_iter()
+                    .take(Self::MAX_SEGMENTS)
+                    .map(|(name, time)| format!("{} {}", display_time(time), name)),
+            );
+        }
+        if lines == self.lines {
+            return;
+        }
+
+        let mut builder = self.rich_text.set_text(Arc::clone(&self.font_family));
+        for line in &lines {
+            builder = builder.write(line).end_paragraph();
+        }
+        // The text is typeset in the background, and will be shown once it is ready.
+        builder.finish();
+        self.lines = lines;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::FontAssetLoader;
+    use crate::graphics::MultiRenderable;
+    use crate::test_fixtures::{noto_sans_regular, regular_only_family};
+    use qs_common::assets::{Asset, AssetManager};
+    use qs_common::profile::{SegmentSnapshot, TaskName};
+    use rusttype::{Font, GlyphId};
+    use std::time::{Duration, Instant};
+    use stretch::geometry::{Point, Size};
+
+    /// Collects the glyphs drawn by every word in a renderable, leaving out glyphs that draw nothing, such as spaces.
+    fn shown_glyphs(renderable: MultiRenderable, glyphs: &mut Vec<GlyphId>) {
+        match renderable {
+            MultiRenderable::Layered(items) | MultiRenderable::Adjacent(items) => {
+                for item in items {
+                    shown_glyphs(item, glyphs);
+                }
+            }
+            MultiRenderable::Text { word, .. } => glyphs.extend(
+                word.glyphs
+                    .iter()
+                    .filter(|glyph| glyph.glyph.pixel_bounding_box().is_some())
+                    .map(|glyph| glyph.glyph.id()),
+            ),
+            _ => {}
+        }
+    }
+
+    /// Waits until the overlay draws the glyphs of the given lines, returning false if it never does.
+    async fn shows(overlay: &DebugOverlay, expected: Vec<GlyphId>) -> bool {
+        let ui = UI::new(overlay.get_widget(), Size::undefined());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let mut glyphs = Vec::new();
+            shown_glyphs(
+                ui.generate_render_info(Point { x: 0.0, y: 0.0 }, None),
+                &mut glyphs,
+            );
+            if glyphs == expected {
+                return true;
+            }
+            if Instant::now() > deadline {
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+    }
+
+    /// The glyphs that the font draws for the given text, leaving out whitespace.
+    async fn glyph_ids(font: Asset<Font<'static>>, text: String) -> Vec<GlyphId> {
+        let mut ids = Vec::new();
+        font.if_loaded(|font| {
+            ids = text
+                .chars()
+                .filter(|c| !c.is_whitespace())
+                .map(|c| font.glyph(c).id())
+                .collect();
+        })
+        .await;
+        ids
+    }
+
+    #[tokio::test]
+    async fn text_follows_fps() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font = noto_sans_regular(&mut font_am).await;
+        let font_family = regular_only_family(font.clone());
+        let mut overlay = DebugOverlay::new(font_family);
+        let profile = ProfileSnapshot {
+            total_time: 0.016,
+            calculation_time: 0.004,
+            segments: vec![SegmentSnapshot {
+                name: "render",
+                depth: 0,
+                average_time: 0.003,
+            }],
+        };
+
+        // The glyphs of every line that the overlay should show for this profile, at the given frame rate.
+        let expected = |fps: &str| {
+            glyph_ids(
+                font.clone(),
+                format!(
+                    "{} FPS {} / {} {} render",
+                    fps,
+                    display_time(0.004),
+                    display_time(0.016),
+                    display_time(0.003)
+                ),
+            )
+        };
+
+        // The overlay is hidden until it is toggled.
+        overlay.update(60.0, &profile, &[]);
+        assert!(shows(&overlay, Vec::new()).await);
+
+        overlay.toggle();
+        overlay.update(60.0, &profile, &[]);
+        assert!(shows(&overlay, expected("60.0").await).await);
+
+        overlay.update(30.0, &profile, &[]);
+        assert!(shows(&overlay, expected("30.0").await).await);
+
+        // Background tasks are listed slowest first, each with the slowest time any thread took.
+        let thread = |name: &str, times: &[(TaskName, f64)]| ThreadSnapshot {
+            thread: name.to_string(),
+            segments: times
+                .iter()
+                .map(|&(name, average_time)| SegmentSnapshot {
+                    name,
+                    depth: 0,
+                    average_time,
+                })
+                .collect(),
+        };
+        let background = [
+            thread(
+                "worker 1",
+                &[("typeset paragraph", 0.001), ("load asset", 0.002)],
+            ),
+            thread("worker 2", &[("typeset paragraph", 0.005)]),
+        ];
+        overlay.update(30.0, &profile, &background);
+        let mut expected = expected("30.0").await;
+        expected.extend(
+            glyph_ids(
+                font.clone(),
+                format!(
+                    "Background {} typeset paragraph {} load asset",
+                    display_time(0.005),
+                    display_time(0.002)
+                ),
+            )
+            .await,
+        );
+        assert!(shows(&overlay, expected).await);
+    }
+}
@@ -1,35 +1,124 @@
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, RwLock, Weak};
 
 use stretch::{
     geometry::{Point, Size},
     style::{Dimension, Style},
 };
-use winit::event::{ElementState, MouseButton};
+use winit::event::{ElementState, KeyboardInput, MouseButton, VirtualKeyCode};
 
 use crate::graphics::{MultiRenderable, NinePatch};
 
 use super::*;
 
 /// A text box the user can type into.
+/// Cloning this gives another reference to the same field. The field's own callbacks should use a
+/// [`downgrade`](Field::downgrade)d handle instead, because a clone stored in the field would keep it alive forever.
+#[derive(Clone)]
 pub struct Field {
+    text: FieldText,
+    widget: Widget,
+}
+
+/// A reference to a field that does not keep it alive. See [`Field::downgrade`].
+#[derive(Clone)]
+pub struct WeakField {
+    rich_text: Weak<RwLock<RichTextContents>>,
+    font_family: Arc<FontFamily>,
+    state: Weak<Mutex<FieldState>>,
+    widget: Weak<RwLock<WidgetContents>>,
+}
+
+impl WeakField {
+    /// Returns the field, or `None` if every `Field` referring to it has been dropped.
+    pub fn upgrade(&self) -> Option<Field> {
+        Some(Field {
+            text: FieldText {
+                rich_text: RichText(self.rich_text.upgrade()?),
+                font_family: Arc::clone(&self.font_family),
+                state: self.state.upgrade()?,
+            },
+            widget: Widget(self.widget.upgrade()?),
+        })
+    }
+}
+
+/// The text in a field, which both the `Field` and its UI element can change.
+/// Cloning this gives another reference to the same text.
+#[derive(Clone)]
+struct FieldText {
     rich_text: RichText,
+    font_family: Arc<FontFamily>,
+    state: Arc<Mutex<FieldState>>,
+}
+
+/// Called with the contents of a field.
+type FieldCallback = Box<dyn FnMut(&str) + Send + Sync>;
+
+/// Restricts which characters can be written into a field.
+/// Characters that a filter rejects are dropped, and the rest of the input is still written.
+pub enum InputFilter {
+    /// Only accepts the digits 0 to 9.
+    Numeric,
+    /// Stops accepting characters once the field contains this many characters.
+    MaxLength(usize),
+    /// Accepts the characters for which this function returns true.
+    Custom(Box<dyn Fn(char) -> bool + Send + Sync>),
+}
+
+impl InputFilter {
+    /// Should the character `c` be written into a field which currently contains `length` characters?
+    fn accepts(&self, length: usize, c: char) -> bool {
+        match self {
+            InputFilter::Numeric => c.is_ascii_digit(),
+            InputFilter::MaxLength(max_length) => length < *max_length,
+            InputFilter::Custom(accepts) => accepts(c),
+        }
+    }
+}
+
+/// The parts of a field that both the `Field` and its UI element need to change.
+struct FieldState {
     contents: String,
-    widget: Widget,
+    /// Every character written into the field must be accepted by all of these filters.
+    filters: Vec<InputFilter>,
+    /// The position and size of the caret relative to this widget, if this widget has keyboard focus.
+    caret_position: Option<Caret>,
+    /// If this is false, pressing enter submits the field instead of starting a new line.
+    multi_line: bool,
+    /// Should the field be emptied after it is submitted?
+    clear_on_submit: bool,
+    /// Called with the new contents whenever they change.
+    on_change: Option<FieldCallback>,
+    /// Called with the contents when enter is pressed in a single-line field.
+    on_submit: Option<FieldCallback>,
+}
+
+impl FieldState {
+    /// Returns the characters of `text` that the filters accept, if they are written into a field of the given length.
+    fn filter(&self, mut length: usize, text: &str) -> String {
+        text.chars()
+            .filter(|&c| {
+                let accepted = self.filters.iter().all(|filter| filter.accepts(length, c));
+                if accepted {
+                    length += 1;
+                }
+                accepted
+            })
+            .collect()
+    }
 }
 
 /// A UI element for fields.
 struct FieldElement {
-    /// A clone of the rich text object contained within the Field.
-    rich_text: RichText,
+    /// A clone of the text contained within the Field.
+    text: FieldText,
     /// The texture to draw the cursor with.
     caret_texture: NinePatch,
     /// Is the mouse currently inside this element?
     mouse_inside: bool,
-    /// The position and size of the caret relative to this widget, if this widget has keyboard focus.
-    caret_position: Option<Caret>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 struct Caret {
     /// The index in the `contents` string that the caret is at.
     edit_index: usize,
@@ -49,7 +138,7 @@ impl UiElement for FieldElement {
             pos: (x, y),
             height,
             ..
-        }) = self.caret_position
+        }) = self.text.state.lock().unwrap().caret_position
         {
             self.caret_texture.generate_render_info(
                 Colour::WHITE,
@@ -105,12 +194,27 @@ impl UiElement for FieldElement {
     fn lose_keyboard_focus(&mut self) {
         tracing::trace!("Lose keyboard focus");
     }
+
+    fn accepts_focus(&self) -> bool {
+        true
+    }
+
+    fn process_keyboard_input(&mut self, input: KeyboardInput) -> bool {
+        match (input.state, input.virtual_keycode) {
+            (ElementState::Pressed, Some(VirtualKeyCode::Return))
+            | (ElementState::Pressed, Some(VirtualKeyCode::NumpadEnter)) => {
+                self.text.enter();
+                true
+            }
+            _ => false,
+        }
+    }
 }
 
 impl FieldElement {
     /// Returns the position of the caret when the mouse is hovered over the given point.
     fn get_caret_position(&self, pos: Point<f32>) -> Option<Caret> {
-        let widget = self.rich_text.get_widget();
+        let widget = self.text.rich_text.get_widget();
         let paragraphs = widget.0.read().unwrap();
         // Check where the mouse is hovering over.
         for paragraph in paragraphs
@@ -143,7 +247,8 @@ impl FieldElement {
                                 && local_y < word_layout.size.height
                             {
                                 // We're hovering over this word.
-                                if let Some(word_info) = self.rich_text.get_word_info(word.get_id())
+                                if let Some(word_info) =
+                                    self.text.rich_text.get_word_info(word.get_id())
                                 {
                                     // Now, let's work out where our cursor is supposed to go within this word.
                                     // The right edges of characters (along with the left edge of the initial character) are 'anchor points';
@@ -209,6 +314,83 @@ impl FieldElement {
     }
 }
 
+impl FieldText {
+    /// Replaces the contents of the field. Characters rejected by the field's filters are dropped.
+    fn set(&mut self, text: &str) {
+        let contents = self.state.lock().unwrap().filter(0, text);
+        self.replace_contents(contents, None);
+    }
+
+    /// Writes text at the caret, or at the end of the field if it has no caret.
+    /// Characters rejected by the field's filters are dropped.
+    fn insert(&mut self, text: &str) {
+        let state = self.state.lock().unwrap();
+        let length = state.contents.chars().count();
+        let index = state
+            .caret_position
+            .map_or(length, |caret| caret.edit_index.min(length));
+        let byte_index = state
+            .contents
+            .char_indices()
+            .nth(index)
+            .map_or(state.contents.len(), |(i, _)| i);
+        let (before, after) = state.contents.split_at(byte_index);
+        let inserted = state.filter(length, text);
+        let contents = format!("{}{}{}", before, inserted, after);
+        drop(state);
+        self.replace_contents(contents, Some(index + inserted.chars().count()));
+    }
+
+    /// Sets the contents of the field, and moves the caret to the given character index (if it has a caret),
+    /// keeping it within the new text.
+    fn replace_contents(&mut self, contents: String, caret_index: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        if state.contents == contents {
+            return;
+        }
+        let length = contents.chars().count();
+        if let Some(caret) = &mut state.caret_position {
+            caret.edit_index = caret_index.unwrap_or(caret.edit_index).min(length);
+        }
+        state.contents = contents.clone();
+        drop(state);
+
+        self.rich_text
+            .set_text(Arc::clone(&self.font_family))
+            .write(&contents)
+            .finish();
+        self.call(|state| &mut state.on_change, &contents);
+    }
+
+    /// Handles the enter key: a multi-line field starts a new line, and a single-line field is submitted.
+    fn enter(&mut self) {
+        let state = self.state.lock().unwrap();
+        if state.multi_line {
+            drop(state);
+            self.insert("\n");
+            return;
+        }
+        let contents = state.contents.clone();
+        let clear = state.clear_on_submit;
+        drop(state);
+        self.call(|state| &mut state.on_submit, &contents);
+        if clear {
+            self.replace_contents(String::new(), None);
+        }
+    }
+
+    /// Calls one of the field's callbacks with the given contents. The callback is taken out of the field's state
+    /// while it runs, so that it can change the field without deadlocking. Changes it makes don't call it again.
+    fn call(&self, callback: fn(&mut FieldState) -> &mut Option<FieldCallback>, contents: &str) {
+        let taken = callback(&mut self.state.lock().unwrap()).take();
+        if let Some(mut function) = taken {
+            function(contents);
+            // If the callback set a new callback while it was running, keep the new one.
+            callback(&mut self.state.lock().unwrap()).get_or_insert(function);
+        }
+    }
+}
+
 impl Field {
     pub fn new(
         caret_texture: NinePatch,
@@ -216,11 +398,23 @@ impl Field {
         style: Style,
         text_style: Style,
     ) -> Self {
-        let mut rich_text = RichText::new(text_style);
-        let field_element = FieldElement {
+        let rich_text = RichText::new(text_style);
+        let text = FieldText {
             rich_text: rich_text.clone(),
+            font_family,
+            state: Arc::new(Mutex::new(FieldState {
+                contents: String::new(),
+                filters: Vec::new(),
+                caret_position: None,
+                multi_line: false,
+                clear_on_submit: false,
+                on_change: None,
+                on_submit: None,
+            })),
+        };
+        let field_element = FieldElement {
+            text: text.clone(),
             caret_texture,
-            caret_position: None,
             mouse_inside: false,
         };
         let widget = Widget::new(
@@ -229,18 +423,192 @@ impl Field {
             Vec::new(),
             style,
         );
-        rich_text
-            .set_text(font_family)
-            .write("Hello, world! This is a field.")
-            .finish();
-        Self {
-            rich_text,
-            contents: String::new(),
-            widget,
-        }
+        Self { text, widget }
     }
 
     pub fn get_widget(&self) -> Widget {
         self.widget.clone()
     }
+
+    /// Returns a handle to this field that does not keep it alive.
+    /// Callbacks set on the field can use this to change the field, for example to clear it once it is submitted.
+    pub fn downgrade(&self) -> WeakField {
+        WeakField {
+            rich_text: Arc::downgrade(&self.text.rich_text.0),
+            font_family: Arc::clone(&self.text.font_family),
+            state: Arc::downgrade(&self.text.state),
+            widget: Arc::downgrade(&self.widget.0),
+        }
+    }
+
+    /// Returns what is currently written in the field.
+    pub fn text(&self) -> String {
+        self.text.state.lock().unwrap().contents.clone()
+    }
+
+    /// Replaces the contents of the field and typesets the new text. Characters rejected by the field's filters are dropped.
+    /// If the caret was past the end of the new text, it is moved back to the end.
+    pub fn set_text(&mut self, text: &str) {
+        self.text.set(text);
+    }
+
+    /// Writes text at the caret, or at the end of the field if it has no caret, as if it had been typed.
+    /// Characters rejected by the field's filters are dropped.
+    pub fn insert(&mut self, text: &str) {
+        self.text.insert(text);
+    }
+
+    /// Only allows characters that this filter accepts to be written into the field.
+    /// Any characters already in the field that the filter rejects are removed.
+    pub fn with_filter(mut self, filter: InputFilter) -> Self {
+        let mut state = self.text.state.lock().unwrap();
+        state.filters.push(filter);
+        let contents = state.filter(0, &state.contents);
+        drop(state);
+        self.text.replace_contents(contents, None);
+        self
+    }
+
+    /// Sets a function to be called with the new contents of the field whenever they change.
+    pub fn set_on_change(&mut self, on_change: impl FnMut(&str) + Send + Sync + 'static) {
+        self.text.state.lock().unwrap().on_change = Some(Box::new(on_change));
+    }
+
+    /// Sets a function to be called with the contents of the field when enter is pressed, unless the field is multi-line.
+    pub fn set_on_submit(&mut self, on_submit: impl FnMut(&str) + Send + Sync + 'static) {
+        self.text.state.lock().unwrap().on_submit = Some(Box::new(on_submit));
+    }
+
+    /// If this is true, the field is emptied after it is submitted. By default, the text is left in the field.
+    pub fn set_clear_on_submit(&mut self, clear_on_submit: bool) {
+        self.text.state.lock().unwrap().clear_on_submit = clear_on_submit;
+    }
+
+    /// Multi-line fields start a new line when enter is pressed, instead of being submitted. Fields are single-line by default.
+    pub fn set_multi_line(&mut self, multi_line: bool) {
+        self.text.state.lock().unwrap().multi_line = multi_line;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::missing_nine_patch;
+
+    /// Creates a field whose caret texture will never load.
+    async fn field() -> Field {
+        Field::new(
+            missing_nine_patch().await,
+            Arc::new(FontFamily::new(Vec::new())),
+            Default::default(),
+            Default::default(),
+        )
+    }
+
+    #[tokio::test]
+    async fn set_text_round_trips_and_notifies() {
+        let mut field = field().await;
+        // New fields start empty.
+        assert_eq!(field.text(), "");
+
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_cloned = Arc::clone(&changes);
+        field.set_on_change(move |text| changes_cloned.lock().unwrap().push(text.to_string()));
+        field.text.state.lock().unwrap().caret_position = Some(Caret {
+            edit_index: 20,
+            pos: (0.0, 0.0),
+            height: 10.0,
+        });
+
+        field.set_text("abc");
+        assert_eq!(field.text(), "abc");
+        // Setting the same text again is not a change.
+        field.set_text("abc");
+        field.set_text("abcd");
+        assert_eq!(*changes.lock().unwrap(), vec!["abc", "abcd"]);
+
+        // The caret was past the end of the new text, so it was moved to the end.
+        let caret = field.text.state.lock().unwrap().caret_position.unwrap();
+        assert_eq!(caret.edit_index, 3);
+    }
+
+    #[tokio::test]
+    async fn numeric_filter_drops_letters() {
+        let mut field = field().await;
+        field.set_text("a1b");
+        let mut field = field
+            .with_filter(InputFilter::Numeric)
+            .with_filter(InputFilter::MaxLength(6));
+        // Adding a filter removes the characters it rejects from the field.
+        assert_eq!(field.text(), "1");
+
+        field.set_text("");
+        field.insert("12a3");
+        field.insert("b45678");
+        assert_eq!(field.text(), "123456");
+        field.set_text("x9y");
+        assert_eq!(field.text(), "9");
+    }
+
+    #[allow(deprecated)]
+    fn press(key: VirtualKeyCode) -> KeyboardInput {
+        KeyboardInput {
+            scancode: 0,
+            state: ElementState::Pressed,
+            virtual_keycode: Some(key),
+            modifiers: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn enter_submits_single_line_fields() {
+        let mut field = field().await;
+        field.set_text("search terms");
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let submitted_cloned = Arc::clone(&submitted);
+        field.set_on_submit(move |text| submitted_cloned.lock().unwrap().push(text.to_string()));
+        field.set_clear_on_submit(true);
+
+        let mut ui = UI::new(field.get_widget(), stretch::geometry::Size::undefined());
+        ui.keyboard_input(press(VirtualKeyCode::Tab));
+        assert!(ui.keyboard_input(press(VirtualKeyCode::Return)));
+        assert_eq!(*submitted.lock().unwrap(), vec!["search terms"]);
+        assert_eq!(field.text(), "");
+
+        // In a multi-line field, enter starts a new line instead.
+        field.set_text("first");
+        field.set_multi_line(true);
+        ui.keyboard_input(press(VirtualKeyCode::Return));
+        assert_eq!(field.text(), "first\n");
+        assert_eq!(submitted.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn callbacks_can_change_their_own_field() {
+        let mut field = field().await;
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let changes_cloned = Arc::clone(&changes);
+        field.set_on_change(move |text| changes_cloned.lock().unwrap().push(text.to_string()));
+        let submitted_field = field.downgrade();
+        field.set_on_submit(move |_| {
+            if let Some(mut field) = submitted_field.upgrade() {
+                field.set_text("cleared");
+            }
+        });
+
+        field.set_text("message");
+        field.text.enter();
+        assert_eq!(field.text(), "cleared");
+        assert_eq!(*changes.lock().unwrap(), vec!["message", "cleared"]);
+
+        // The callbacks are still there after they have run.
+        field.set_text("another");
+        field.text.enter();
+        assert_eq!(field.text(), "cleared");
+
+        // The callback's handle doesn't keep the field alive.
+        let weak = field.downgrade();
+        drop(field);
+        assert!(weak.upgrade().is_none());
+    }
 }
@@ -12,3 +12,7 @@ mod button;
 pub use button::*;
 mod field;
 pub use field::*;
+mod spec;
+pub use spec::*;
+mod debug_overlay;
+pub use debug_overlay::*;
@@ -0,0 +1,393 @@
+//! Describes UI layouts as data, so that they can be loaded from files instead of being built by hand in code.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use qs_common::assets::{AssetManager, AssetPath, Loader};
+use serde::{Deserialize, Serialize};
+use stretch::{
+    geometry::{Rect, Size},
+    style::{Dimension, FlexDirection, Style},
+};
+
+use crate::graphics::Texture;
+
+use super::{
+    Button, ButtonStyle, Colour, FontFamily, ImageElement, ImageMask, RichText, ScaleMode, Widget,
+    UI,
+};
+
+/// A description of a widget and its children. This can be deserialised from JSON, for example:
+/// ```json
+/// {
+///     "type": "Container",
+///     "layout": { "flex_direction": "column" },
+///     "children": [
+///         { "type": "Image", "texture": "white.png", "size": { "width": { "points": 16 }, "height": "auto" } },
+///         { "type": "Button", "style": "default", "on_click": "quit", "children": [
+///             { "type": "Text", "text": "Quit" }
+///         ] }
+///     ]
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WidgetSpec {
+    /// A widget that renders nothing itself, but lays out its children.
+    Container {
+        #[serde(default)]
+        layout: LayoutSpec,
+        #[serde(default)]
+        children: Vec<WidgetSpec>,
+    },
+    /// A widget that renders a whole texture, tinted by the given colour.
+    Image {
+        /// The asset path of the texture, with segments separated by `/`.
+        /// Paths that are absolute or that leave the asset folder are rejected when the spec is deserialised.
+        texture: AssetPath,
+        #[serde(default)]
+        colour: Colour,
+        #[serde(default)]
+        size: SizeSpec,
+        #[serde(default)]
+        layout: LayoutSpec,
+        #[serde(default)]
+        children: Vec<WidgetSpec>,
+    },
+    /// A button, whose style and click handler are looked up by name in the `SpecContext`.
+    Button {
+        style: String,
+        on_click: String,
+        #[serde(default)]
+        layout: LayoutSpec,
+        #[serde(default)]
+        children: Vec<WidgetSpec>,
+    },
+    /// Some text, written in the context's font family.
+    Text {
+        text: String,
+        #[serde(default)]
+        layout: LayoutSpec,
+    },
+}
+
+/// The subset of flexbox style properties that can be given in a `WidgetSpec`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LayoutSpec {
+    pub flex_direction: FlexDirectionSpec,
+    pub size: SizeSpec,
+    pub min_size: SizeSpec,
+    /// The margin on every side of the widget, in points.
+    pub margin: f32,
+    /// The padding on every side of the widget, in points.
+    pub padding: f32,
+}
+
+impl Default for LayoutSpec {
+    fn default() -> Self {
+        Self {
+            flex_direction: FlexDirectionSpec::Row,
+            size: Default::default(),
+            min_size: Default::default(),
+            margin: 0.0,
+            padding: 0.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FlexDirectionSpec {
+    Row,
+    Column,
+    RowReverse,
+    ColumnReverse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SizeSpec {
+    pub width: DimensionSpec,
+    pub height: DimensionSpec,
+}
+
+impl Default for SizeSpec {
+    fn default() -> Self {
+        Self {
+            width: DimensionSpec::Auto,
+            height: DimensionSpec::Auto,
+        }
+    }
+}
+
+/// Written in JSON as `"auto"`, `{ "points": 10 }` or `{ "percent": 0.5 }`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DimensionSpec {
+    Auto,
+    Points(f32),
+    Percent(f32),
+}
+
+impl From<DimensionSpec> for Dimension {
+    fn from(dimension: DimensionSpec) -> Dimension {
+        match dimension {
+            DimensionSpec::Auto => Dimension::Auto,
+            DimensionSpec::Points(points) => Dimension::Points(points),
+            DimensionSpec::Percent(percent) => Dimension::Percent(percent),
+        }
+    }
+}
+
+impl From<SizeSpec> for Size<Dimension> {
+    fn from(size: SizeSpec) -> Size<Dimension> {
+        Size {
+            width: size.width.into(),
+            height: size.height.into(),
+        }
+    }
+}
+
+impl From<LayoutSpec> for Style {
+    fn from(layout: LayoutSpec) -> Style {
+        let uniform = |points| Rect {
+            start: Dimension::Points(points),
+            end: Dimension::Points(points),
+            top: Dimension::Points(points),
+            bottom: Dimension::Points(points),
+        };
+        Style {
+            flex_direction: match layout.flex_direction {
+                FlexDirectionSpec::Row => FlexDirection::Row,
+                FlexDirectionSpec::Column => FlexDirection::Column,
+                FlexDirectionSpec::RowReverse => FlexDirection::RowReverse,
+                FlexDirectionSpec::ColumnReverse => FlexDirection::ColumnReverse,
+            },
+            size: layout.size.into(),
+            min_size: layout.min_size.into(),
+            margin: uniform(layout.margin),
+            padding: uniform(layout.padding),
+            ..Default::default()
+        }
+    }
+}
+
+/// Everything a `WidgetSpec` can refer to by name.
+pub struct SpecContext<'a, L>
+where
+    L: Loader<AssetPath, Texture> + Send + Sync + 'static,
+{
+    pub texture_am: &'a mut AssetManager<AssetPath, Texture, L>,
+    /// The font family that text is written in.
+    pub font_family: Arc<FontFamily>,
+    pub button_styles: HashMap<String, ButtonStyle>,
+    /// Functions that buttons can call when they are clicked.
+    pub handlers: HashMap<String, Arc<dyn Fn() + Send + Sync>>,
+}
+
+/// The reasons why a `WidgetSpec` could not be turned into a widget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecError {
+    /// No button style with this name was registered in the `SpecContext`.
+    UnknownButtonStyle(String),
+    /// No handler with this name was registered in the `SpecContext`.
+    UnknownHandler(String),
+}
+
+impl WidgetSpec {
+    /// Creates the widget (and its children) described by this spec.
+    ///
+    /// Text is typeset on a background task, so this must be called from within a `tokio` runtime.
+    pub fn build<L>(&self, context: &mut SpecContext<'_, L>) -> Result<Widget, SpecError>
+    where
+        L: Loader<AssetPath, Texture> + Send + Sync + 'static,
+    {
+        let build_children = |children: &[WidgetSpec], context: &mut SpecContext<'_, L>| {
+            children
+                .iter()
+                .map(|child| child.build(context))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        match self {
+            WidgetSpec::Container { layout, children } => Ok(Widget::new(
+                (),
+                build_children(children, context)?,
+                Vec::new(),
+                (*layout).into(),
+            )),
+            WidgetSpec::Image {
+                texture,
+                colour,
+                size,
+                layout,
+                children,
+            } => {
+                let texture = context.texture_am.get(texture.clone());
+                Ok(Widget::new(
+                    ImageElement {
+                        size: (*size).into(),
+                        colour: *colour,
+                        texture,
+                        scale_mode: ScaleMode::Stretch,
+                        mask: ImageMask::Rectangle,
+                    },
+                    build_children(children, context)?,
+                    Vec::new(),
+                    (*layout).into(),
+                ))
+            }
+            WidgetSpec::Button {
+                style,
+                on_click,
+                layout,
+                children,
+            } => {
+                let style = match context.button_styles.get(style) {
+                    Some(style) => style.clone(),
+                    None => return Err(SpecError::UnknownButtonStyle(style.clone())),
+                };
+                let handler = match context.handlers.get(on_click) {
+                    Some(handler) => Arc::clone(handler),
+                    None => return Err(SpecError::UnknownHandler(on_click.clone())),
+                };
+                Ok(Widget::new(
+                    Button::new(style, move || handler()),
+                    build_children(children, context)?,
+                    Vec::new(),
+                    (*layout).into(),
+                ))
+            }
+            WidgetSpec::Text { text, layout } => {
+                let mut rich_text = RichText::new((*layout).into());
+                // Typesetting continues in the background, and the widget is updated once it is finished.
+                rich_text
+                    .set_text(Arc::clone(&context.font_family))
+                    .write(text)
+                    .finish();
+                Ok(rich_text.get_widget())
+            }
+        }
+    }
+}
+
+impl UI {
+    /// Creates a UI whose root widget is described by the given spec.
+    /// The UI has no size until `update_size` is called.
+    pub fn from_spec<L>(
+        spec: &WidgetSpec,
+        context: &mut SpecContext<'_, L>,
+    ) -> Result<UI, SpecError>
+    where
+        L: Loader<AssetPath, Texture> + Send + Sync + 'static,
+    {
+        Ok(UI::new(spec.build(context)?, Size::undefined()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::FontAssetLoader;
+    use crate::test_fixtures::{
+        missing_nine_patch, noto_sans_regular, regular_only_family, MissingTextureLoader,
+    };
+
+    async fn missing_button_style() -> ButtonStyle {
+        let nine_patch = missing_nine_patch().await;
+        ButtonStyle {
+            released_texture: nine_patch.clone(),
+            hovered_texture: nine_patch.clone(),
+            pressed_texture: nine_patch.clone(),
+            disabled_texture: nine_patch,
+        }
+    }
+
+    const SPEC: &str = r#"{
+        "type": "Container",
+        "layout": { "flex_direction": "column", "padding": 4 },
+        "children": [
+            { "type": "Image", "texture": "ui/white.png", "size": { "width": { "points": 10 }, "height": { "percent": 0.5 } } },
+            { "type": "Button", "style": "default", "on_click": "count", "children": [
+                { "type": "Text", "text": "Click me" }
+            ] }
+        ]
+    }"#;
+
+    #[tokio::test]
+    async fn spec_builds_expected_widget_tree() {
+        let spec: WidgetSpec = serde_json::from_str(SPEC).unwrap();
+        // Serialising the spec and reading it back gives the same spec.
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(serde_json::from_str::<WidgetSpec>(&json).unwrap(), spec);
+
+        let mut texture_am = AssetManager::new(MissingTextureLoader);
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let mut context = SpecContext {
+            texture_am: &mut texture_am,
+            font_family: regular_only_family(noto_sans_regular(&mut font_am).await),
+            button_styles: HashMap::new(),
+            handlers: HashMap::new(),
+        };
+        context
+            .button_styles
+            .insert("default".to_string(), missing_button_style().await);
+        context
+            .handlers
+            .insert("count".to_string(), Arc::new(|| {}));
+
+        let root = spec.build(&mut context).unwrap();
+        let root = root.0.read().unwrap();
+        let children = root.get_children();
+        assert_eq!(children.len(), 2);
+
+        let image = children[0].0.read().unwrap();
+        assert_eq!(
+            image.get_element().get_size(),
+            Size {
+                width: Dimension::Points(10.0),
+                height: Dimension::Percent(0.5),
+            }
+        );
+        assert!(image.get_children().is_empty());
+
+        let button = children[1].0.read().unwrap();
+        assert!(button.get_element().accepts_focus());
+        assert_eq!(button.get_children().len(), 1);
+    }
+
+    #[test]
+    fn invalid_texture_paths_are_rejected() {
+        for texture in &[
+            "/white.png",
+            "ui//white.png",
+            "ui\\\\white.png",
+            "../white.png",
+        ] {
+            let json = format!(r#"{{ "type": "Image", "texture": "{}" }}"#, texture);
+            assert!(
+                serde_json::from_str::<WidgetSpec>(&json).is_err(),
+                "{} should be rejected",
+                texture
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn unknown_names_are_errors() {
+        let spec: WidgetSpec = serde_json::from_str(SPEC).unwrap();
+        let mut texture_am = AssetManager::new(MissingTextureLoader);
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let mut context = SpecContext {
+            texture_am: &mut texture_am,
+            font_family: regular_only_family(noto_sans_regular(&mut font_am).await),
+            button_styles: HashMap::new(),
+            handlers: HashMap::new(),
+        };
+        assert_eq!(
+            UI::from_spec(&spec, &mut context).err(),
+            Some(SpecError::UnknownButtonStyle("default".to_string()))
+        );
+    }
+}
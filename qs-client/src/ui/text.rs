@@ -1,9 +1,11 @@
-use crate::graphics::{MultiRenderable, Renderable};
-use futures::future::{AbortHandle, AbortRegistration, Abortable, Aborted};
+use crate::graphics::MultiRenderable;
+use futures::future::{AbortHandle, AbortRegistration, Abortable, FutureExt};
 use qs_common::assets::Asset;
-use rusttype::{point, Font, PositionedGlyph, Scale};
+use qs_common::assets::{AssetState, LoadStatus};
+use rusttype::{point, Font, PositionedGlyph, Scale, VMetrics};
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, Instant};
 use stretch::geometry::Size;
 use stretch::style::*;
 use tokio::task::JoinHandle;
@@ -16,6 +18,34 @@ fn new_font_face_id() -> usize {
     FONT_FACE_ID_COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
 }
 
+/// The bits of the `f32` accessibility text scale. See `set_text_scale`.
+static TEXT_SCALE: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0x3f80_0000);
+
+lazy_static::lazy_static! {
+    /// Every rich text object that has been created, so that they can be typeset again when the text scale changes.
+    static ref RICH_TEXTS: std::sync::Mutex<Vec<Weak<RwLock<RichTextContents>>>> = Default::default();
+}
+
+/// The multiplier applied to every font size, for example `1.25` for a large text mode. This is separate from the window's scale factor.
+pub fn text_scale() -> f32 {
+    f32::from_bits(TEXT_SCALE.load(std::sync::atomic::Ordering::Relaxed))
+}
+
+/// Changes the multiplier applied to every font size, and typesets all existing rich text again at the new size.
+pub fn set_text_scale(scale: f32) {
+    TEXT_SCALE.store(scale.to_bits(), std::sync::atomic::Ordering::Relaxed);
+    let rich_texts = {
+        let mut rich_texts = RICH_TEXTS.lock().unwrap();
+        rich_texts.retain(|contents| contents.strong_count() > 0);
+        rich_texts.clone()
+    };
+    for contents in rich_texts {
+        if let Some(contents) = contents.upgrade() {
+            RichText(contents).retypeset();
+        }
+    }
+}
+
 /// A font, together with bold, italic, and bold-italic variants. All variants, except regular, are optional. If a variant is not specified, the next closest variant is used.
 /// Specifically, bold and italic fall back to regular, and bold-italic falls back to bold, then italic, then regular.
 #[derive(Clone)]
@@ -47,6 +77,36 @@ impl FontFace {
             bold_italic,
         }
     }
+
+    /// Returns the font asset used to render the given emphasis style, falling back to the next closest variant
+    /// if the requested variant was not specified.
+    fn variant(&self, emphasis: FontEmphasis) -> &Asset<Font<'static>> {
+        let fallbacks = match emphasis {
+            FontEmphasis::Regular => vec![],
+            FontEmphasis::Bold => vec![&self.bold],
+            FontEmphasis::Italic => vec![&self.italic],
+            FontEmphasis::BoldItalic => vec![&self.bold_italic, &self.bold, &self.italic],
+        };
+        fallbacks
+            .into_iter()
+            .find_map(|variant| variant.as_ref())
+            .unwrap_or(&self.regular)
+    }
+
+    /// Returns the vertical metrics (ascent, descent and line gap) of this font face, as used when typesetting text
+    /// with the given emphasis and size. This waits for the relevant font variant to load.
+    /// Returns `None` if the font could not be loaded.
+    pub async fn v_metrics(&self, emphasis: FontEmphasis, size: FontSize) -> Option<VMetrics> {
+        let font = self.variant(emphasis);
+        font.wait_until_loaded_or_failed().await;
+        let data = font.data.upgrade()?;
+        let status = data.read().await;
+        if let LoadStatus::Loaded(font) = &*status {
+            Some(font.v_metrics(size.scale(text_scale())))
+        } else {
+            None
+        }
+    }
 }
 
 impl std::fmt::Debug for FontFace {
@@ -65,6 +125,42 @@ impl FontFamily {
     pub fn new(list: Vec<FontFace>) -> Self {
         Self(list)
     }
+
+    /// Returns the vertical metrics of the most preferred font face in this family.
+    /// See [`FontFace::v_metrics`].
+    pub async fn v_metrics(&self, emphasis: FontEmphasis, size: FontSize) -> Option<VMetrics> {
+        self.0.first()?.v_metrics(emphasis, size).await
+    }
+
+    /// Returns true if any variant of any font face in this family is still loading. This never waits.
+    fn any_loading(&self) -> bool {
+        self.0.iter().any(|font_face| {
+            std::iter::once(&font_face.regular)
+                .chain(&font_face.bold)
+                .chain(&font_face.italic)
+                .chain(&font_face.bold_italic)
+                .any(|font| {
+                    // A font that is being written to has probably just finished loading, but it isn't ready yet.
+                    !matches!(
+                        font.try_state(),
+                        Some(AssetState::Loaded) | Some(AssetState::Failed)
+                    )
+                })
+        })
+    }
+
+    /// Returns true if the regular variant of any font face in this family has loaded. Waits for fonts that are still loading.
+    async fn any_loaded(&self) -> bool {
+        for font_face in &self.0 {
+            font_face.regular.wait_until_loaded_or_failed().await;
+            if let Some(data) = font_face.regular.data.upgrade() {
+                if let LoadStatus::Loaded(_) = &*data.read().await {
+                    return true;
+                }
+            }
+        }
+        false
+    }
 }
 
 /// Represents a single segment of rich text that has the same formatting.
@@ -84,6 +180,9 @@ pub struct RichTextStyle {
     size: FontSize,
     emphasis: FontEmphasis,
     colour: Colour,
+    vertical_shift: VerticalShift,
+    shadow: Option<TextShadow>,
+    outline: Option<TextOutline>,
 }
 
 impl RichTextStyle {
@@ -93,10 +192,29 @@ impl RichTextStyle {
             size: Default::default(),
             emphasis: Default::default(),
             colour: Colour::default(),
+            vertical_shift: Default::default(),
+            shadow: None,
+            outline: None,
         }
     }
 }
 
+/// A copy of some text drawn underneath it in another colour, so that the text stands out from busy backgrounds.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TextShadow {
+    pub colour: Colour,
+    /// How far the shadow is drawn from the text, in pixels. Positive values move the shadow right and down.
+    pub offset: (f32, f32),
+}
+
+/// A border drawn around each glyph of some text in another colour.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct TextOutline {
+    pub colour: Colour,
+    /// How far the outline extends from the edge of each glyph, in pixels.
+    pub width: f32,
+}
+
 /// An abstract font size, which may be scaled to various sizes according to the user's preferences.
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum FontSize {
@@ -116,6 +234,18 @@ impl Default for FontSize {
     }
 }
 
+impl FontSize {
+    /// The scale at which glyphs of this font size are rendered.
+    fn scale(self, scale_factor: f32) -> Scale {
+        match self {
+            FontSize::H1 => Scale::uniform(72.0 * scale_factor),
+            FontSize::H2 => Scale::uniform(48.0 * scale_factor),
+            FontSize::H3 => Scale::uniform(36.0 * scale_factor),
+            FontSize::Text => Scale::uniform(24.0 * scale_factor),
+        }
+    }
+}
+
 /// A font emphasis style. This could be regular, bold, italic or bold and italic.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
 pub enum FontEmphasis {
@@ -131,9 +261,67 @@ impl Default for FontEmphasis {
     }
 }
 
+/// How large superscript and subscript glyphs are, relative to the size of the surrounding text.
+const SHIFTED_GLYPH_SCALE: f32 = 0.65;
+/// How far superscript glyphs are raised, as a fraction of the x-height of the surrounding text.
+const SUPERSCRIPT_RAISE: f32 = 0.6;
+/// How far subscript glyphs are lowered, as a fraction of the x-height of the surrounding text.
+const SUBSCRIPT_DROP: f32 = 0.5;
+
+/// Whether text is raised above or lowered below the baseline, for example in exponents or chemical formulae.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum VerticalShift {
+    #[default]
+    None,
+    Superscript,
+    Subscript,
+}
+
+impl VerticalShift {
+    /// Given the scale of the surrounding text, what scale should glyphs with this shift be rendered at?
+    fn glyph_scale(self, scale: Scale) -> Scale {
+        match self {
+            VerticalShift::None => scale,
+            VerticalShift::Superscript | VerticalShift::Subscript => Scale {
+                x: scale.x * SHIFTED_GLYPH_SCALE,
+                y: scale.y * SHIFTED_GLYPH_SCALE,
+            },
+        }
+    }
+
+    /// How far the baseline of glyphs with this shift should be moved, given the font and scale of the surrounding text.
+    /// Like `rusttype`, the Y axis points downwards, so superscripts have a negative offset.
+    fn baseline_offset(self, font: &Font<'static>, scale: Scale) -> f32 {
+        if self == VerticalShift::None {
+            return 0.0;
+        }
+
+        // The x-height is (roughly) the height of the top of a lowercase 'x' above the baseline.
+        // If the font has no 'x', just guess based on the ascent.
+        let x_height = font
+            .glyph('x')
+            .scaled(scale)
+            .exact_bounding_box()
+            .map(|bounding_box| -bounding_box.min.y)
+            .unwrap_or_else(|| 0.5 * font.v_metrics(scale).ascent);
+        match self {
+            VerticalShift::None => 0.0,
+            VerticalShift::Superscript => -SUPERSCRIPT_RAISE * x_height,
+            VerticalShift::Subscript => SUBSCRIPT_DROP * x_height,
+        }
+    }
+}
+
 type RichTextParagraph = Vec<RichTextSegment>;
 
 /// You may clone this rich text object to get another view of it which can be safely passed between threads.
+///
+/// # Locking
+/// The contents are behind a `std` lock, because they are read synchronously while laying out and rendering the UI.
+/// This lock must never be held across an `.await`, and is only held for short sections that do not wait for anything:
+/// - `set_text` only swaps the abort handle and text ID;
+/// - the typesetting task does all of its asynchronous work (waiting for fonts and the font ID maps) and builds
+///   the new widgets before it locks the contents, then holds the lock only to swap the new widgets in.
 #[derive(Clone)]
 pub struct RichText(pub Arc<RwLock<RichTextContents>>);
 
@@ -149,22 +337,53 @@ impl RichText {
                 ..style
             },
         );
-        Self(Arc::new(RwLock::new(RichTextContents {
+        let contents = Arc::new(RwLock::new(RichTextContents {
             paragraphs: Vec::new(),
             widget,
             typeset_abort_handle: None,
+            text_id: 0,
+            rebuild_deadline: None,
+            debounce: Self::DEFAULT_DEBOUNCE,
+            placeholders: false,
             word_info: HashMap::new(),
-        })))
+        }));
+        let mut rich_texts = RICH_TEXTS.lock().unwrap();
+        // Forget texts that have been dropped whenever the list is full, so that it only grows with the number of live texts.
+        if rich_texts.len() == rich_texts.capacity() {
+            rich_texts.retain(|contents| contents.strong_count() > 0);
+        }
+        rich_texts.push(Arc::downgrade(&contents));
+        drop(rich_texts);
+        Self(contents)
+    }
+
+    /// How long text updates are collected for before the text is typeset, by default.
+    pub const DEFAULT_DEBOUNCE: Duration = Duration::from_millis(16);
+
+    /// Sets how long text updates are collected for before the text is typeset and its widgets are rebuilt.
+    /// If the text is set many times within this window, only the most recent text is typeset.
+    pub fn set_debounce(&mut self, debounce: Duration) {
+        self.0.write().unwrap().debounce = debounce;
+    }
+
+    /// If `placeholders` is true, text whose fonts are still loading is laid out straight away with blank words,
+    /// each roughly as large as the real word will be, so that the layout doesn't jump when the fonts load.
+    /// The text is typeset again once the fonts have loaded. By default, nothing is shown until then.
+    /// Placeholder words are blank rather than drawn as boxes ("tofu"), because the text renderer can only draw glyphs
+    /// from a font, and no font has loaded yet.
+    pub fn set_placeholders(&mut self, placeholders: bool) {
+        self.0.write().unwrap().placeholders = placeholders;
     }
 
     pub fn set_text(&mut self, font_family: Arc<FontFamily>) -> RichTextContentsBuilder {
-        let mut write = self.0.write().unwrap();
-        let (abort_handle, abort_registration) = AbortHandle::new_pair();
-        if let Some(old_abort_handle) = write.typeset_abort_handle.take() {
-            old_abort_handle.abort();
-        }
-        write.typeset_abort_handle = Some(abort_handle);
+        let TypesetTicket {
+            text_id,
+            rebuild_deadline,
+            abort_registration,
+        } = self.new_ticket();
         RichTextContentsBuilder {
+            text_id,
+            rebuild_deadline,
             output: Self(Arc::clone(&self.0)),
             style: RichTextStyle::default(font_family),
             paragraphs: Vec::new(),
@@ -174,6 +393,38 @@ impl RichText {
         }
     }
 
+    /// Typesets the current text again, for example because the text scale has changed.
+    /// If newer text is still waiting to be typeset, that text will be typeset at the current scale anyway,
+    /// so this does nothing and returns `None`.
+    pub fn retypeset(&mut self) -> Option<JoinHandle<Result<(), TypesetError>>> {
+        if self.0.read().unwrap().rebuild_deadline.is_some() {
+            return None;
+        }
+        let ticket = self.new_ticket();
+        let paragraphs = self.0.read().unwrap().paragraphs.clone();
+        Some(spawn_typeset(Self(Arc::clone(&self.0)), ticket, paragraphs))
+    }
+
+    /// Cancels any typesetting in progress, and returns what a new typesetting task needs to write its output.
+    fn new_ticket(&mut self) -> TypesetTicket {
+        let mut write = self.0.write().unwrap();
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        if let Some(old_abort_handle) = write.typeset_abort_handle.take() {
+            old_abort_handle.abort();
+        }
+        write.typeset_abort_handle = Some(abort_handle);
+        write.text_id += 1;
+        let debounce = write.debounce;
+        let rebuild_deadline = *write
+            .rebuild_deadline
+            .get_or_insert_with(|| Instant::now() + debounce);
+        TypesetTicket {
+            text_id: write.text_id,
+            rebuild_deadline,
+            abort_registration,
+        }
+    }
+
     /// Returns the widget that this rich text object is managing.
     /// This is essentially clone of an `Arc`, so lifetimes are irrelevant.
     pub fn get_widget(&self) -> Widget {
@@ -225,6 +476,7 @@ pub struct WordInfo {
 
 #[derive(Debug, Copy, Clone)]
 pub struct GlyphInfo {
+    /// The pixels covered by the glyph, relative to the word's origin. Glyphs that draw nothing, like spaces, have no bounding box.
     pub bounding_box: Option<rusttype::Rect<i32>>,
     /// This is the index of the character in the original text.
     pub character_index: usize,
@@ -268,20 +520,33 @@ pub struct RichTextContents {
 
     /// If we're currently typesetting some text but try to set the value of the text again, we can use this abort handle
     /// to cancel the typeset task so we don't accidentally typeset something twice (or worse, the order of execution
-    /// of the tasks is swapped).
+    /// of the tasks is swapped). The task stops the next time it yields, which it does before every segment of text.
     typeset_abort_handle: Option<AbortHandle>,
+
+    /// Incremented every time `set_text` is called. A typesetting task only writes its output if no newer text has been set,
+    /// since an aborted task may already have been about to write its output when it was aborted.
+    text_id: u64,
+
+    /// When text is set, it is not typeset until this time, so that many updates in quick succession only rebuild
+    /// the widgets once. This is `None` if there are no updates waiting to be written.
+    rebuild_deadline: Option<Instant>,
+    debounce: Duration,
+
+    /// See `RichText::set_placeholders`.
+    placeholders: bool,
 }
 
-impl RichTextContents {
-    fn write(&mut self, paragraphs: Vec<RichTextParagraph>, typeset: TypesetText) {
-        self.paragraphs = paragraphs;
-        self.word_info.clear();
+/// The widgets produced by typesetting some rich text, which have not yet been added to the rich text's widget.
+struct TypesetWidgets {
+    paragraphs: Vec<Widget>,
+    word_info: HashMap<WidgetID, WordInfo>,
+}
 
-        // Construct the widget hierarchy.
-        let mut write = self.widget.0.write().unwrap();
-        let word_info_map = &mut self.word_info;
-        write.clear_children();
-        typeset
+impl TypesetWidgets {
+    /// Constructs the widget hierarchy for some typeset text.
+    fn new(typeset: TypesetText) -> Self {
+        let mut word_info_map = HashMap::new();
+        let paragraphs = typeset
             .paragraphs
             .into_iter()
             .map(|paragraph| {
@@ -308,17 +573,48 @@ impl RichTextContents {
                     },
                 )
             })
-            .for_each(|item| write.add_child(item));
-        write.force_layout();
+            .collect();
+        Self {
+            paragraphs,
+            word_info: word_info_map,
+        }
+    }
+}
+
+impl RichTextContents {
+    fn write(&mut self, paragraphs: Vec<RichTextParagraph>, widgets: TypesetWidgets) {
+        self.paragraphs = paragraphs;
+        self.word_info = widgets.word_info;
+
+        self.widget
+            .0
+            .write()
+            .unwrap()
+            .set_children(widgets.paragraphs);
     }
 }
 
+/// The reasons why rich text could not be typeset.
+#[derive(Debug)]
+pub enum TypesetError {
+    /// None of the fonts in the font family could be loaded, so we have nothing to draw the text with.
+    NoLoadedFonts,
+    /// The asset manager containing a font was dropped while the text was being typeset.
+    FontDropped,
+    /// The text was changed before it finished being typeset, so typesetting was cancelled.
+    Aborted,
+}
+
 /// Builds up a rich text object to be put into a `RichText` object. When the builder is finished, the text in the rich text object will be updated.
 /// Then, a background task will typeset the text.
 #[must_use = "call the finish function to let the builder update the rich text object"]
 pub struct RichTextContentsBuilder {
     /// Where should we write the output to once this builder is finished?
     output: RichText,
+    /// The `text_id` of the output when this builder was created.
+    text_id: u64,
+    /// When the text should be typeset. See `RichTextContents::rebuild_deadline`.
+    rebuild_deadline: Instant,
 
     style: RichTextStyle,
     paragraphs: Vec<RichTextParagraph>,
@@ -426,6 +722,22 @@ impl RichTextContentsBuilder {
         self.internal(style, styled)
     }
 
+    /// Apply the `superscript` style to the rich text produced in this function.
+    /// Do not call `finish` on this internal builder.
+    pub fn superscript(self, styled: impl FnOnce(Self) -> Self) -> Self {
+        let mut style = self.style.clone();
+        style.vertical_shift = VerticalShift::Superscript;
+        self.internal(style, styled)
+    }
+
+    /// Apply the `subscript` style to the rich text produced in this function.
+    /// Do not call `finish` on this internal builder.
+    pub fn subscript(self, styled: impl FnOnce(Self) -> Self) -> Self {
+        let mut style = self.style.clone();
+        style.vertical_shift = VerticalShift::Subscript;
+        self.internal(style, styled)
+    }
+
     /// Apply a colour to the rich text produced in this function.
     /// Do not call `finish` on this internal builder.
     pub fn coloured(self, colour: Colour, styled: impl FnOnce(Self) -> Self) -> Self {
@@ -434,6 +746,32 @@ impl RichTextContentsBuilder {
         self.internal(style, styled)
     }
 
+    /// Draw a shadow in the given colour underneath the rich text produced in this function.
+    /// The shadow is offset `dx` pixels to the right and `dy` pixels down from the text.
+    /// Do not call `finish` on this internal builder.
+    pub fn shadow(
+        self,
+        colour: Colour,
+        dx: f32,
+        dy: f32,
+        styled: impl FnOnce(Self) -> Self,
+    ) -> Self {
+        let mut style = self.style.clone();
+        style.shadow = Some(TextShadow {
+            colour,
+            offset: (dx, dy),
+        });
+        self.internal(style, styled)
+    }
+
+    /// Draw an outline `width` pixels wide in the given colour around the rich text produced in this function.
+    /// Do not call `finish` on this internal builder.
+    pub fn outline(self, colour: Colour, width: f32, styled: impl FnOnce(Self) -> Self) -> Self {
+        let mut style = self.style.clone();
+        style.outline = Some(TextOutline { colour, width });
+        self.internal(style, styled)
+    }
+
     /// Call the given `styled` function on a new internal builder with the given style,
     /// then append all of its result data to this original builder.
     /// This allows functions to create styles on specific spans of text with ease.
@@ -442,6 +780,8 @@ impl RichTextContentsBuilder {
         let child = Self {
             // The output field should never be used because `finish` should never be called on this internal builder.
             output: RichText(Arc::clone(&self.output.0)),
+            text_id: self.text_id,
+            rebuild_deadline: self.rebuild_deadline,
             style,
             paragraphs: Vec::new(),
             current_paragraph: Vec::new(),
@@ -460,10 +800,11 @@ impl RichTextContentsBuilder {
 
     /// Writes the output of this builder to the rich text struct. Returns a handle to the task that is typesetting the text.
     /// To wait until typesetting is finished, `.await` on this handle.
+    /// Typesetting errors, other than being aborted by a newer call to `set_text`, are also logged.
     ///
     /// # Panics
     /// If this is an internal builder (e.g. produced by the `h1` function), this will panic.
-    pub fn finish(self) -> JoinHandle<Result<(), Aborted>> {
+    pub fn finish(self) -> JoinHandle<Result<(), TypesetError>> {
         if self.is_internal {
             panic!("cannot call `finish` on internal builders");
         }
@@ -472,19 +813,105 @@ impl RichTextContentsBuilder {
         if !self.current_paragraph.is_empty() {
             paragraphs.push(self.current_paragraph);
         }
-        let output = self.output;
-        tokio::spawn(Abortable::new(
-            async move {
-                // We clone the paragraph data here so that the background thread can't cause the main thread to halt.
-                let paragraphs_cloned = paragraphs.clone();
-                let typeset_text = typeset_rich_text(paragraphs_cloned).await;
+        let ticket = TypesetTicket {
+            text_id: self.text_id,
+            rebuild_deadline: self.rebuild_deadline,
+            abort_registration: self.abort_registration,
+        };
+        spawn_typeset(self.output, ticket, paragraphs)
+    }
+}
+
+/// Identifies one attempt to typeset a rich text object. See `RichText::new_ticket`.
+struct TypesetTicket {
+    text_id: u64,
+    rebuild_deadline: Instant,
+    abort_registration: AbortRegistration,
+}
 
+/// Typesets the given paragraphs on a background task, then writes them to the rich text if no newer text has been set since.
+fn spawn_typeset(
+    output: RichText,
+    ticket: TypesetTicket,
+    paragraphs: Vec<RichTextParagraph>,
+) -> JoinHandle<Result<(), TypesetError>> {
+    let TypesetTicket {
+        text_id,
+        rebuild_deadline,
+        abort_registration,
+    } = ticket;
+    let contents = Arc::clone(&output.0);
+    let typeset = Abortable::new(
+        async move {
+            // If the text is set again before the deadline, this task is aborted while it is waiting.
+            tokio::time::sleep_until(rebuild_deadline.into()).await;
+
+            let placeholders = output.0.read().unwrap().placeholders;
+            if placeholders
+                && paragraphs
+                    .iter()
+                    .flatten()
+                    .any(|segment| segment.style.font_family.any_loading())
+            {
+                let placeholder_text = TypesetText {
+                    paragraphs: paragraphs
+                        .iter()
+                        .map(|paragraph| typeset_placeholder_paragraph(paragraph, text_scale()))
+                        .collect(),
+                };
+                let widgets = TypesetWidgets::new(placeholder_text);
                 let mut rich_text = output.0.write().unwrap();
-                rich_text.write(paragraphs, typeset_text);
-            },
-            self.abort_registration,
-        ))
-    }
+                if rich_text.text_id != text_id {
+                    return Err(TypesetError::Aborted);
+                }
+                // The text is not written yet, so that if the text scale changes, the new text is typeset rather than the old.
+                rich_text.word_info = widgets.word_info;
+                rich_text
+                    .widget
+                    .0
+                    .write()
+                    .unwrap()
+                    .set_children(widgets.paragraphs);
+            }
+
+            // We clone the paragraph data here so that the background thread can't cause the main thread to halt.
+            // If the text scale changes while we're typesetting, start again so the text isn't left at the old size.
+            let typeset_text = loop {
+                let scale_factor = text_scale();
+                let typeset_text = typeset_rich_text(paragraphs.clone(), scale_factor).await?;
+                if text_scale() == scale_factor {
+                    break typeset_text;
+                }
+            };
+            let widgets = TypesetWidgets::new(typeset_text);
+
+            // This is the only time the task locks the rich text, and nothing in this block awaits.
+            let mut rich_text = output.0.write().unwrap();
+            if rich_text.text_id != text_id {
+                return Err(TypesetError::Aborted);
+            }
+            rich_text.write(paragraphs, widgets);
+            rich_text.rebuild_deadline = None;
+            Ok(())
+        },
+        abort_registration,
+    );
+    tokio::spawn(async move {
+        let result = typeset.await.unwrap_or(Err(TypesetError::Aborted));
+        if result.is_err() {
+            // If typesetting failed, the deadline must still be cleared, or later text would be typeset
+            // straight away using the old deadline. Newer text keeps its own deadline.
+            let mut rich_text = contents.write().unwrap();
+            if rich_text.text_id == text_id {
+                rich_text.rebuild_deadline = None;
+            }
+        }
+        match &result {
+            Ok(()) | Err(TypesetError::Aborted) => {}
+            Err(error) => tracing::error!("could not typeset rich text: {:?}", error),
+        }
+        result
+    })
 }
 
 pub struct TypesetText {
@@ -502,6 +929,11 @@ pub struct RenderableGlyph {
     pub glyph: PositionedGlyph<'static>,
     /// This is the index of the character in the original text.
     pub character_index: usize,
+    /// True if this glyph is drawn from a colour bitmap (e.g. an emoji) rather than from an outline.
+    pub colour_glyph: bool,
+    /// Effects drawn underneath the glyph. Colour glyphs are drawn without them.
+    pub shadow: Option<TextShadow>,
+    pub outline: Option<TextOutline>,
 }
 
 /// An indivisible unit of text, represented as a list of glyphs positioned relative to the word's origin point.
@@ -510,14 +942,31 @@ pub struct RenderableWord {
     pub glyphs: Vec<RenderableGlyph>,
     pub size: (u32, u32),
 
-    /// When we try to render this text, we need to convert it to a list of renderables.
-    /// However, this is quite expensive, so we cache the result here.
-    /// TODO actually make this cache work
-    cached_renderables: Option<Vec<Renderable>>,
+    /// Identifies this word to the `TextRenderer`, which caches the quads it draws for each word.
+    /// Clones of a word share its ID, so if the glyphs of a clone are changed, the old quads may be drawn.
+    id: u64,
+    /// Multiplies the alpha of every glyph, shadow and outline when the word is drawn.
+    /// This is kept apart from the glyphs' colours so that a fading word can reuse the quads cached for its ID.
+    alpha: f32,
+}
+
+impl RenderableWord {
+    /// A unique ID given to this word when it was typeset.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// How much the alpha of every glyph, shadow and outline in this word is multiplied by when it is drawn.
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
 
-    /// What cache generation was the `cached_renderables` variable built for? If this does not match the `cache_generation` in
-    /// the `TextRenderer`, we will have to recalculate the cached renderables list.
-    cache_generation: u64,
+    /// Multiplies the alpha of every glyph, shadow and outline in this word.
+    /// The faded word keeps its ID, since the text renderer fades the quads it cached for the original as it draws them.
+    pub fn with_alpha(mut self, alpha: f32) -> Self {
+        self.alpha *= alpha;
+        self
+    }
 }
 
 /// An paragraph of text comprised of a number of words.
@@ -571,100 +1020,111 @@ async fn get_font_id(font_face: &FontFace, emphasis: FontEmphasis, font_size: Fo
     }))
 }
 
-async fn get_font_for_character(
+/// A loaded font that a segment's characters may be drawn with, together with its font ID.
+struct CandidateFont {
+    id: usize,
+    font: Font<'static>,
+}
+
+/// Returns the loaded fonts that could be used for text in the given style, most preferred first.
+/// For each face in the family, the requested variant comes first, falling back to less emphasised variants.
+///
+/// This waits for and locks each font asset once, so that the fonts can then be searched for every character
+/// in a segment without awaiting anything.
+async fn candidate_fonts(
     font_family: &FontFamily,
     emphasis: FontEmphasis,
     font_size: FontSize,
-    c: char,
-) -> Option<(usize, rusttype::Glyph<'static>)> {
+) -> Result<Vec<CandidateFont>, TypesetError> {
+    let mut candidates = Vec::new();
     for font_face in &font_family.0 {
-        if emphasis == FontEmphasis::BoldItalic {
-            if let Some(ref font_style) = font_face.bold_italic {
-                font_style.wait_until_loaded().await;
-                if let Some(data) = font_style.data.upgrade() {
-                    if let qs_common::assets::LoadStatus::Loaded(ref font) = &*data.write().await {
-                        let glyph = font.glyph(c);
-                        if glyph.id().0 != 0 {
-                            return Some((
-                                get_font_id(font_face, FontEmphasis::BoldItalic, font_size).await,
-                                glyph,
-                            ));
-                        }
-                    }
-                }
-            }
-        }
-
-        if emphasis == FontEmphasis::Bold || emphasis == FontEmphasis::BoldItalic {
-            if let Some(ref font_style) = font_face.bold {
-                font_style.wait_until_loaded().await;
-                if let Some(data) = font_style.data.upgrade() {
-                    if let qs_common::assets::LoadStatus::Loaded(ref font) = &*data.write().await {
-                        let glyph = font.glyph(c);
-                        if glyph.id().0 != 0 {
-                            return Some((
-                                get_font_id(font_face, FontEmphasis::Bold, font_size).await,
-                                glyph,
-                            ));
-                        }
-                    }
+        let variants = [
+            (FontEmphasis::BoldItalic, font_face.bold_italic.as_ref()),
+            (FontEmphasis::Bold, font_face.bold.as_ref()),
+            (FontEmphasis::Italic, font_face.italic.as_ref()),
+            (FontEmphasis::Regular, Some(&font_face.regular)),
+        ];
+        for (variant_emphasis, font_style) in variants.iter() {
+            let wanted = match variant_emphasis {
+                FontEmphasis::BoldItalic => emphasis == FontEmphasis::BoldItalic,
+                FontEmphasis::Bold => {
+                    emphasis == FontEmphasis::Bold || emphasis == FontEmphasis::BoldItalic
                 }
-            }
-        }
-
-        if emphasis == FontEmphasis::Italic || emphasis == FontEmphasis::BoldItalic {
-            if let Some(ref font_style) = font_face.italic {
-                font_style.wait_until_loaded().await;
-                if let Some(data) = font_style.data.upgrade() {
-                    if let qs_common::assets::LoadStatus::Loaded(ref font) = &*data.write().await {
-                        let glyph = font.glyph(c);
-                        if glyph.id().0 != 0 {
-                            return Some((
-                                get_font_id(font_face, FontEmphasis::Italic, font_size).await,
-                                glyph,
-                            ));
-                        }
-                    }
+                FontEmphasis::Italic => {
+                    emphasis == FontEmphasis::Italic || emphasis == FontEmphasis::BoldItalic
                 }
-            }
-        }
+                FontEmphasis::Regular => true,
+            };
+            let font_style = match font_style {
+                Some(font_style) if wanted => font_style,
+                _ => continue,
+            };
 
-        font_face.regular.wait_until_loaded().await;
-        if let Some(data) = font_face.regular.data.upgrade() {
-            if let qs_common::assets::LoadStatus::Loaded(ref font) = &*data.write().await {
-                let glyph = font.glyph(c);
-                if glyph.id().0 != 0 {
-                    return Some((
-                        get_font_id(font_face, FontEmphasis::Regular, font_size).await,
-                        glyph,
-                    ));
-                }
-            }
+            font_style.wait_until_loaded_or_failed().await;
+            let data = font_style.data.upgrade().ok_or(TypesetError::FontDropped)?;
+            // Fonts are reference counted, so this clone is cheap.
+            let font = match &*data.read().await {
+                LoadStatus::Loaded(font) => font.clone(),
+                _ => continue,
+            };
+            candidates.push(CandidateFont {
+                id: get_font_id(font_face, *variant_emphasis, font_size).await,
+                font,
+            });
         }
     }
-
-    None
+    Ok(candidates)
 }
 
-async fn typeset_rich_text(paragraphs: Vec<RichTextParagraph>) -> TypesetText {
-    let scale_factor = 1.0;
-
-    let mut renderable_paragraphs = Vec::new();
-    for paragraph in paragraphs {
-        let line_result = typeset_rich_text_paragraph(paragraph, scale_factor).await;
-        renderable_paragraphs.push(line_result);
-    }
+/// Returns the first candidate font that has a glyph for the given character.
+fn font_for_character(
+    candidates: &[CandidateFont],
+    c: char,
+) -> Option<(&CandidateFont, rusttype::Glyph<'static>)> {
+    candidates.iter().find_map(|candidate| {
+        let glyph = candidate.font.glyph(c);
+        if glyph.id().0 != 0 {
+            Some((candidate, glyph))
+        } else {
+            None
+        }
+    })
+}
 
-    TypesetText {
+/// Typesets each paragraph in its own task, so that long documents can be typeset on several threads at once.
+/// The paragraphs are returned in the order they were given.
+/// Each font size is multiplied by `scale_factor`.
+async fn typeset_rich_text(
+    paragraphs: Vec<RichTextParagraph>,
+    scale_factor: f32,
+) -> Result<TypesetText, TypesetError> {
+    // Dropping a remote handle cancels its task, so if this future is aborted, the paragraphs stop being typeset too.
+    let handles: Vec<_> = paragraphs
+        .into_iter()
+        .map(|paragraph| {
+            let (task, handle) =
+                typeset_rich_text_paragraph(paragraph, scale_factor).remote_handle();
+            tokio::spawn(task);
+            handle
+        })
+        .collect();
+    let renderable_paragraphs = futures::future::join_all(handles)
+        .await
+        .into_iter()
+        .collect::<Result<_, _>>()?;
+
+    Ok(TypesetText {
         paragraphs: renderable_paragraphs,
-    }
+    })
 }
 
 /// Typeset a single paragraph. Assumes that the Y coordinate of each character is zero.
 async fn typeset_rich_text_paragraph(
     paragraph: Vec<RichTextSegment>,
     scale_factor: f32,
-) -> RenderableParagraph {
+) -> Result<RenderableParagraph, TypesetError> {
+    let _guard = qs_common::profile::background_profiler().time("typeset paragraph");
+
     // The current paragraph, which is filled with words.
     let mut output = Vec::new();
     // The current word, defined as a sequence of whitespace characters followed by one or more non-whitespace characters.
@@ -673,97 +1133,101 @@ async fn typeset_rich_text_paragraph(
     // The current X position on the word.
     let mut caret_x = 0.0;
     let mut line_height = 0.0;
+    // How far the lowest glyph in the current word (e.g. a subscript) extends below the bottom of the word.
+    let mut overhang = 0.0;
 
-    // Contains the last glyph's font ID and glyph ID, if there was a previous glyph on this line.
+    // Contains the last glyph's font ID, scale and glyph ID, if there was a previous glyph in this word.
+    // This is kept between glued segments so that kerning still applies when the style changes mid-word.
     let mut last_glyph = None;
 
     let mut character_index = 0;
 
-    for segment in paragraph {
-        let scale = match segment.style.size {
-            FontSize::H1 => Scale::uniform(72.0 * scale_factor),
-            FontSize::H2 => Scale::uniform(48.0 * scale_factor),
-            FontSize::H3 => Scale::uniform(36.0 * scale_factor),
-            FontSize::Text => Scale::uniform(24.0 * scale_factor),
-        };
+    // Most segments in a paragraph share a handful of styles, so the candidate fonts for each style are only resolved once.
+    // Font families are identified by their address, which is stable because each segment holds an `Arc` to its family.
+    let mut candidates_by_style = HashMap::new();
 
-        if !segment.glue_to_previous {
+    for (index, segment) in paragraph.into_iter().enumerate() {
+        // Fonts that have already loaded can be used without waiting, so typesetting might never need to yield.
+        // Yield anyway, so that if a newer call to `set_text` aborts this task, it stops here instead of running to completion.
+        let () = tokio::task::yield_now().await;
+
+        let scale = segment.style.size.scale(scale_factor);
+
+        let glyph_scale = segment.style.vertical_shift.glyph_scale(scale);
+
+        // The first segment starts the first word, so there is no previous word to add.
+        if index != 0 && !segment.glue_to_previous {
             // Add the previous word to the paragraph.
-            output.push(RenderableWord {
-                glyphs: std::mem::take(&mut word),
-                size: (caret_x as u32, line_height as u32),
-                cached_renderables: None,
-                cache_generation: 0,
-            });
+            output.push(finish_word(
+                std::mem::take(&mut word),
+                caret_x,
+                line_height,
+                overhang,
+            ));
             caret_x = 0.0;
             line_height = 0.0;
+            overhang = 0.0;
+            last_glyph = None;
         }
 
-        for c in segment.text.chars() {
-            let mut font_and_glyph = get_font_for_character(
-                &*segment.style.font_family,
-                segment.style.emphasis,
-                segment.style.size,
-                c,
-            )
-            .await;
-
-            if font_and_glyph.is_none() {
-                // Replace this glyph with a generic 'character not found' glyph.
-                font_and_glyph = get_font_for_character(
+        let style_key = (
+            Arc::as_ptr(&segment.style.font_family) as usize,
+            segment.style.emphasis,
+            segment.style.size,
+        );
+        let candidates = match candidates_by_style.entry(style_key) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => entry.insert(
+                candidate_fonts(
                     &*segment.style.font_family,
                     segment.style.emphasis,
                     segment.style.size,
-                    '\u{FFFD}',
                 )
-                .await;
-
-                if font_and_glyph.is_none() {
-                    // If that glyph wasn't in the font, we'll just try a normal question mark.
-                    font_and_glyph = get_font_for_character(
-                        &*segment.style.font_family,
-                        segment.style.emphasis,
-                        segment.style.size,
-                        '?',
-                    )
-                    .await;
-
-                    if font_and_glyph.is_none() {
-                        // Really at this point there's no alternatives left.
-                        // We'll just not render this character.
-                        character_index += 1;
-                        continue;
-                    }
-                }
-            }
-
-            let (font, base_glyph) =
-                font_and_glyph.expect("no replacement characters found in font");
-
-            let font_id_to_font_map = FONT_ID_TO_FONT_MAP.read().await;
-            let font_asset = font_id_to_font_map
-                .get(&font)
-                .expect("could not retrieve font for font ID");
-            let font_asset_data = font_asset
-                .data
-                .upgrade()
-                .expect("asset manager containing font was dropped");
+                .await?,
+            ),
+        };
 
-            let mut descender_height = 0.0;
-            if let qs_common::assets::LoadStatus::Loaded(font_data) = &*font_asset_data.read().await
-            {
-                descender_height = font_data.v_metrics(scale).descent;
-                if let Some((last_font_id, last_glyph_id)) = last_glyph.take() {
-                    if font == last_font_id {
-                        caret_x += font_data.pair_kerning(scale, last_glyph_id, base_glyph.id());
+        for c in segment.text.chars() {
+            // If the character isn't in any font, replace it with a generic 'character not found' glyph,
+            // or if that glyph isn't in the font either, a normal question mark.
+            let font_and_glyph = font_for_character(candidates, c)
+                .or_else(|| font_for_character(candidates, '\u{FFFD}'))
+                .or_else(|| font_for_character(candidates, '?'));
+
+            let (candidate, base_glyph) = match font_and_glyph {
+                Some(font_and_glyph) => font_and_glyph,
+                None => {
+                    // If no font could be loaded, there's no point trying the rest of the text.
+                    if !segment.style.font_family.any_loaded().await {
+                        return Err(TypesetError::NoLoadedFonts);
                     }
+                    // Really at this point there's no alternatives left.
+                    // We'll just not render this character.
+                    character_index += 1;
+                    continue;
                 }
             };
+            let (font, font_data) = (candidate.id, &candidate.font);
+
+            let descender_height = font_data.v_metrics(scale).descent;
+            let baseline_offset = segment
+                .style
+                .vertical_shift
+                .baseline_offset(font_data, scale);
+            let colour_glyph =
+                crate::graphics::raster_glyph_image(font_data, base_glyph.id(), glyph_scale)
+                    .is_some();
+            if let Some((last_font_id, last_glyph_scale, last_glyph_id)) = last_glyph.take() {
+                if font == last_font_id && glyph_scale == last_glyph_scale {
+                    caret_x += font_data.pair_kerning(glyph_scale, last_glyph_id, base_glyph.id());
+                }
+            }
 
-            last_glyph = Some((font, base_glyph.id()));
+            last_glyph = Some((font, glyph_scale, base_glyph.id()));
+            let baseline = descender_height + baseline_offset;
             let glyph = base_glyph
-                .scaled(scale)
-                .positioned(point(caret_x, descender_height));
+                .scaled(glyph_scale)
+                .positioned(point(caret_x, baseline));
 
             caret_x += glyph.unpositioned().h_metrics().advance_width;
             let v_metrics = glyph.unpositioned().font().v_metrics(scale);
@@ -771,11 +1235,25 @@ async fn typeset_rich_text_paragraph(
             if glyph_line_height > line_height {
                 line_height = glyph_line_height
             }
+
+            // Shifted glyphs may poke out of the top or bottom of the word, so make sure the word is large enough to contain them.
+            let shifted_v_metrics = glyph.unpositioned().font().v_metrics(glyph_scale);
+            let glyph_top = baseline - shifted_v_metrics.ascent;
+            if -glyph_top > line_height {
+                line_height = -glyph_top;
+            }
+            let glyph_bottom = baseline - shifted_v_metrics.descent;
+            if glyph_bottom > overhang {
+                overhang = glyph_bottom;
+            }
             word.push(RenderableGlyph {
                 font,
                 colour: segment.style.colour,
                 glyph,
                 character_index,
+                colour_glyph,
+                shadow: segment.style.shadow,
+                outline: segment.style.outline,
             });
 
             character_index += 1;
@@ -783,12 +1261,731 @@ async fn typeset_rich_text_paragraph(
     }
 
     // Add the current word to the line.
-    output.push(RenderableWord {
-        glyphs: std::mem::take(&mut word),
-        size: (caret_x as u32, line_height as u32),
-        cached_renderables: None,
-        cache_generation: 0,
-    });
+    output.push(finish_word(
+        std::mem::take(&mut word),
+        caret_x,
+        line_height,
+        overhang,
+    ));
+
+    Ok(RenderableParagraph(output))
+}
+
+/// How wide each character of a placeholder word is, as a fraction of the font size.
+const PLACEHOLDER_ADVANCE: f32 = 0.5;
+/// How tall placeholder words are, as a fraction of the font size. Most fonts' lines are a little taller than their size.
+const PLACEHOLDER_LINE_HEIGHT: f32 = 1.2;
 
+/// Lays out a paragraph without any fonts, guessing how large each word will be once its fonts have loaded.
+/// The words have no glyphs, so they take up space but draw nothing.
+fn typeset_placeholder_paragraph(
+    paragraph: &[RichTextSegment],
+    scale_factor: f32,
+) -> RenderableParagraph {
+    let mut output = Vec::new();
+    let mut width = 0.0;
+    let mut line_height = 0.0;
+    for (index, segment) in paragraph.iter().enumerate() {
+        // Words are split in the same places as `typeset_rich_text_paragraph` splits them.
+        if index != 0 && !segment.glue_to_previous {
+            output.push(finish_word(Vec::new(), width, line_height, 0.0));
+            width = 0.0;
+            line_height = 0.0;
+        }
+        let scale = segment.style.size.scale(scale_factor);
+        let glyph_scale = segment.style.vertical_shift.glyph_scale(scale);
+        width += segment.text.chars().count() as f32 * PLACEHOLDER_ADVANCE * glyph_scale.x;
+        line_height = f32::max(line_height, PLACEHOLDER_LINE_HEIGHT * scale.y);
+    }
+    output.push(finish_word(Vec::new(), width, line_height, 0.0));
     RenderableParagraph(output)
 }
+
+static NEXT_WORD_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+/// Creates a word from a list of typeset glyphs. Words are laid out with their bottom edge at `y = 0`, so if
+/// any glyph extends `overhang` pixels below this (e.g. a subscript), the glyphs are moved up and the word is enlarged
+/// so that nothing is clipped.
+fn finish_word(
+    mut glyphs: Vec<RenderableGlyph>,
+    width: f32,
+    mut line_height: f32,
+    overhang: f32,
+) -> RenderableWord {
+    if overhang > 0.0 {
+        for RenderableGlyph { glyph, .. } in &mut glyphs {
+            let position = glyph.position();
+            glyph.set_position(point(position.x, position.y - overhang));
+        }
+        line_height += overhang;
+    }
+
+    RenderableWord {
+        glyphs,
+        size: (width as u32, line_height as u32),
+        id: NEXT_WORD_ID.fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        alpha: 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assets::{FontAssetLoader, FontKey};
+    use crate::test_fixtures::noto_sans;
+    use qs_common::assets::{AssetManager, AssetPath, LoadError, Loader};
+
+    /// Loads Noto Sans with a `kern` table added, which kerns "A" and "V" closer together.
+    /// Noto Sans only has kerning in its `GPOS` table, which rusttype doesn't read.
+    struct KernedFontLoader;
+
+    /// How much closer together "A" and "V" are drawn by `KernedFontLoader`'s font, in font units.
+    const KERNED_AV: i16 = -100;
+
+    #[async_trait::async_trait]
+    impl Loader<AssetPath, Font<'static>> for KernedFontLoader {
+        async fn load(&self, _key: AssetPath) -> Result<Font<'static>, LoadError> {
+            let data: &[u8] = include_bytes!("../../assets/NotoSans-Regular.ttf");
+            let font = Font::try_from_bytes(data).ok_or(LoadError::InvalidData)?;
+            let pair = (font.glyph('A').id().0, font.glyph('V').id().0);
+            Font::try_from_vec(with_kern_table(data, pair, KERNED_AV)).ok_or(LoadError::InvalidData)
+        }
+    }
+
+    /// Adds a version 0 `kern` table containing a single pair to a TrueType font.
+    fn with_kern_table(font: &[u8], (left, right): (u16, u16), value: i16) -> Vec<u8> {
+        let read_u16 = |at: usize| u16::from_be_bytes([font[at], font[at + 1]]);
+        let read_u32 =
+            |at: usize| u32::from_be_bytes([font[at], font[at + 1], font[at + 2], font[at + 3]]);
+
+        let mut kern = Vec::new();
+        // The table header, then one horizontal format 0 subtable with one pair.
+        for word in &[
+            0u16,
+            1,
+            0,
+            14 + 6,
+            0x0001,
+            1,
+            6,
+            0,
+            0,
+            left,
+            right,
+            value as u16,
+        ] {
+            kern.extend_from_slice(&word.to_be_bytes());
+        }
+
+        let table_count = read_u16(4) as usize;
+        let mut tables: Vec<([u8; 4], &[u8])> = (0..table_count)
+            .map(|i| {
+                let record = 12 + 16 * i;
+                let tag = [
+                    font[record],
+                    font[record + 1],
+                    font[record + 2],
+                    font[record + 3],
+                ];
+                let (offset, length) = (
+                    read_u32(record + 8) as usize,
+                    read_u32(record + 12) as usize,
+                );
+                (tag, &font[offset..offset + length])
+            })
+            .collect();
+        tables.push((*b"kern", &kern));
+        tables.sort_by_key(|(tag, _)| *tag);
+
+        // The offset table is copied apart from the number of tables, since nothing checks the search hints.
+        let mut output = font[0..12].to_vec();
+        output[4..6].copy_from_slice(&(tables.len() as u16).to_be_bytes());
+        let mut offset = 12 + 16 * tables.len();
+        for (tag, data) in &tables {
+            output.extend_from_slice(tag);
+            output.extend_from_slice(&0u32.to_be_bytes());
+            output.extend_from_slice(&(offset as u32).to_be_bytes());
+            output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            offset += data.len().div_ceil(4) * 4;
+        }
+        for (_, data) in &tables {
+            output.extend_from_slice(data);
+            output.resize(output.len().div_ceil(4) * 4, 0);
+        }
+        output
+    }
+
+    /// Splits the given builder's paragraph into segments ready for typesetting.
+    fn segments(builder: RichTextContentsBuilder) -> Vec<RichTextSegment> {
+        builder.current_paragraph
+    }
+
+    #[tokio::test]
+    async fn superscript_is_smaller_and_raised() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+
+        let builder = RichText::new(Default::default())
+            .set_text(font_family)
+            .write("x")
+            .superscript(|b| b.write_glued("2"));
+        let paragraph = typeset_rich_text_paragraph(segments(builder), 1.0)
+            .await
+            .unwrap();
+
+        let word = paragraph.0.last().unwrap();
+        let (x, two) = (&word.glyphs[0].glyph, &word.glyphs[1].glyph);
+        assert!(two.scale().y < x.scale().y);
+        // The Y axis points downwards, so the raised baseline has a smaller Y coordinate.
+        assert!(two.position().y < x.position().y);
+        assert!(word.size.1 as f32 >= -two.pixel_bounding_box().unwrap().min.y as f32);
+    }
+
+    #[tokio::test]
+    async fn word_info_has_glyph_positions_for_caret_placement() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+
+        let mut rich_text = RichText::new(Default::default());
+        rich_text
+            .set_text(font_family)
+            .write("Hello world")
+            .finish()
+            .await
+            .unwrap()
+            .unwrap();
+
+        let paragraph = rich_text.get_widget().0.read().unwrap().get_children()[0].clone();
+        let words = paragraph.0.read().unwrap().get_children().clone();
+        let mut previous_index = None;
+        for word in words {
+            let word = word.0.read().unwrap();
+            let size = match word.get_element().get_size() {
+                Size {
+                    width: Dimension::Points(width),
+                    height: Dimension::Points(height),
+                } => (width, height),
+                _ => panic!("words should have a fixed size"),
+            };
+            let word_info = rich_text.get_word_info(word.get_id()).unwrap();
+            for glyph in word_info.glyphs {
+                assert!(previous_index < Some(glyph.character_index));
+                previous_index = Some(glyph.character_index);
+
+                // Spaces have no bounding box, but every letter lies within its word.
+                if let Some(bounding_box) = glyph.bounding_box {
+                    assert!(bounding_box.min.x >= 0 && bounding_box.max.x as f32 <= size.0 + 1.0);
+                    assert!(bounding_box.width() > 0 && bounding_box.height() as f32 <= size.1);
+                }
+            }
+        }
+        // "Hello world" has eleven characters.
+        assert_eq!(previous_index, Some(10));
+    }
+
+    #[tokio::test]
+    async fn word_info_is_looked_up_by_word_widget() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+
+        let mut rich_text = RichText::new(Default::default());
+        rich_text
+            .set_text(font_family)
+            .write("The quick brown fox")
+            .end_paragraph()
+            .write("jumps over the lazy dog")
+            .finish()
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Layouts can't be constructed directly, so take the layout of an empty node at the origin.
+        let mut stretch = stretch::Stretch::new();
+        let node = stretch.new_node(Default::default(), Vec::new()).unwrap();
+        stretch.compute_layout(node, Size::undefined()).unwrap();
+        let layout = *stretch.layout(node).unwrap();
+
+        let mut widget_ids = std::collections::HashSet::new();
+        let mut word_ids = std::collections::HashSet::new();
+        for paragraph in rich_text.get_widget().0.read().unwrap().get_children() {
+            for word in paragraph.0.read().unwrap().get_children() {
+                let word = word.0.read().unwrap();
+                assert!(widget_ids.insert(word.get_id()));
+                let renderable = match word.get_element().generate_render_info(&layout) {
+                    MultiRenderable::Text { word, .. } => word,
+                    _ => panic!("a word should render as text"),
+                };
+                assert!(word_ids.insert(renderable.id()));
+
+                let word_info = rich_text.get_word_info(word.get_id()).unwrap();
+                assert_eq!(word_info.glyphs.len(), renderable.glyphs.len());
+                for (info, glyph) in word_info.glyphs.iter().zip(&renderable.glyphs) {
+                    assert_eq!(info.character_index, glyph.character_index);
+                }
+            }
+        }
+        // The paragraphs contain nine words, and widgets that aren't words have no word info.
+        assert_eq!(widget_ids.len(), 9);
+        assert!(rich_text
+            .get_word_info(rich_text.get_widget().0.read().unwrap().get_id())
+            .is_none());
+    }
+
+    #[test]
+    fn get_widget_shares_the_container() {
+        let rich_text = RichText::new(Default::default());
+        let (a, b) = (rich_text.get_widget(), rich_text.get_widget());
+        assert!(Arc::ptr_eq(&a.0, &b.0));
+        assert!(Arc::ptr_eq(&a.0, &rich_text.0.read().unwrap().widget.0));
+    }
+
+    #[tokio::test]
+    async fn kerning_applies_across_glued_segments() {
+        let mut font_am = AssetManager::new(KernedFontLoader);
+        let font = font_am.get(AssetPath::new(vec!["kerned.ttf".to_string()]));
+        font.wait_until_loaded().await;
+        let font_family = Arc::new(FontFamily::new(vec![FontFace::new(
+            "Kerned Noto Sans".to_string(),
+            font,
+            None,
+            None,
+            None,
+        )]));
+
+        let builder = RichText::new(Default::default())
+            .set_text(font_family)
+            .write("A")
+            .coloured(Colour::RED, |b| b.write_glued("V"));
+        let paragraph = typeset_rich_text_paragraph(segments(builder), 1.0)
+            .await
+            .unwrap();
+
+        let word = paragraph.0.last().unwrap();
+        let (a, v) = (&word.glyphs[0].glyph, &word.glyphs[1].glyph);
+        let kerning = a.font().pair_kerning(a.scale(), a.id(), v.id());
+        // "AV" is kerned closer together, so this test would fail if kerning were skipped.
+        assert!(
+            kerning < 0.0,
+            "expected A and V to be kerned, got {}",
+            kerning
+        );
+        let expected = a.position().x + a.unpositioned().h_metrics().advance_width + kerning;
+        assert_eq!(v.position().x, expected);
+    }
+
+    #[tokio::test]
+    async fn doubling_the_text_scale_doubles_glyphs_and_words() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+
+        let builder = RichText::new(Default::default())
+            .set_text(font_family)
+            .write("Large text");
+        let paragraph = segments(builder);
+        let normal = typeset_rich_text(vec![paragraph.clone()], 1.0)
+            .await
+            .unwrap();
+        let large = typeset_rich_text(vec![paragraph], 2.0).await.unwrap();
+
+        let words = normal.paragraphs[0].0.iter().zip(&large.paragraphs[0].0);
+        for (normal, large) in words.filter(|(word, _)| !word.glyphs.is_empty()) {
+            assert_eq!(
+                large.glyphs[0].glyph.scale().y,
+                2.0 * normal.glyphs[0].glyph.scale().y
+            );
+            // Word sizes are rounded to whole pixels, so they may be a pixel or two away from exactly double.
+            let (width, height) = (normal.size.0 as i32, normal.size.1 as i32);
+            assert!((large.size.0 as i32 - 2 * width).abs() <= 2);
+            assert!((large.size.1 as i32 - 2 * height).abs() <= 2);
+        }
+    }
+
+    /// Lists the sizes of the words that a rich text object currently shows.
+    fn word_sizes(rich_text: &RichText) -> Vec<(u32, u32)> {
+        // Layouts can't be constructed directly, so take the layout of an empty node at the origin.
+        let mut stretch = stretch::Stretch::new();
+        let node = stretch.new_node(Default::default(), Vec::new()).unwrap();
+        stretch.compute_layout(node, Size::undefined()).unwrap();
+        let layout = *stretch.layout(node).unwrap();
+
+        let mut sizes = Vec::new();
+        for paragraph in rich_text.get_widget().0.read().unwrap().get_children() {
+            for word in paragraph.0.read().unwrap().get_children() {
+                if let MultiRenderable::Text { word, .. } = word
+                    .0
+                    .read()
+                    .unwrap()
+                    .get_element()
+                    .generate_render_info(&layout)
+                {
+                    sizes.push(word.size);
+                }
+            }
+        }
+        sizes
+    }
+
+    /// The text scale is shared by the whole process, so changing it would change the size of text in other tests
+    /// running at the same time. This test runs itself again in a separate process, where it is the only test.
+    #[test]
+    fn setting_the_text_scale_retypesets_existing_text() {
+        const ISOLATED: &str = "QS_TEXT_SCALE_TEST";
+        if std::env::var_os(ISOLATED).is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "ui::text::tests::setting_the_text_scale_retypesets_existing_text",
+                    "--test-threads=1",
+                ])
+                .env(ISOLATED, "1")
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        tokio::runtime::Runtime::new().unwrap().block_on(async {
+            let mut font_am = AssetManager::new(FontAssetLoader::default());
+            let font_family = noto_sans(&mut font_am).await;
+            let mut rich_text = RichText::new(Default::default());
+            rich_text
+                .set_text(font_family)
+                .write("Large text")
+                .finish()
+                .await
+                .unwrap()
+                .unwrap();
+            let normal = word_sizes(&rich_text);
+            assert_eq!(normal.len(), 2);
+
+            set_text_scale(2.0);
+            // The text is typeset again in the background.
+            let deadline = Instant::now() + std::time::Duration::from_secs(5);
+            let mut large = word_sizes(&rich_text);
+            while large == normal && Instant::now() < deadline {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                large = word_sizes(&rich_text);
+            }
+            set_text_scale(1.0);
+
+            assert_eq!(large.len(), normal.len());
+            for (normal, large) in normal.iter().zip(&large) {
+                // Word sizes are rounded to whole pixels, so they may be a pixel or two away from exactly double.
+                assert!((large.0 as i32 - 2 * normal.0 as i32).abs() <= 2);
+                assert!((large.1 as i32 - 2 * normal.1 as i32).abs() <= 2);
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn v_metrics_match_rusttype() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+
+        let v_metrics = font_family
+            .v_metrics(FontEmphasis::Bold, FontSize::H2)
+            .await
+            .unwrap();
+
+        let bold = font_family.0[0].bold.as_ref().unwrap();
+        assert!(
+            bold.if_loaded(|font| {
+                assert_eq!(
+                    v_metrics.ascent,
+                    font.v_metrics(Scale::uniform(48.0)).ascent
+                )
+            })
+            .await
+        );
+    }
+
+    /// A loader whose fonts never finish loading.
+    struct SlowFontLoader;
+
+    #[async_trait::async_trait]
+    impl qs_common::assets::Loader<FontKey, Font<'static>> for SlowFontLoader {
+        async fn load(&self, _key: FontKey) -> Result<Font<'static>, qs_common::assets::LoadError> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn placeholders_are_shown_while_fonts_load() {
+        let mut font_am = AssetManager::new(SlowFontLoader);
+        let font_family = Arc::new(FontFamily::new(vec![FontFace::new(
+            "Loading".to_string(),
+            font_am.get(AssetPath::new(vec!["NotoSans-Regular.ttf".to_string()]).into()),
+            None,
+            None,
+            None,
+        )]));
+        let mut rich_text = RichText::new(Default::default());
+        rich_text.set_placeholders(true);
+        // The real text will never be typeset, because the font never loads.
+        let _typeset = rich_text
+            .set_text(font_family)
+            .write("Hello world")
+            .finish();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let paragraphs = loop {
+            let paragraphs = rich_text
+                .get_widget()
+                .0
+                .read()
+                .unwrap()
+                .get_children()
+                .clone();
+            if !paragraphs.is_empty() || Instant::now() > deadline {
+                break paragraphs;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        };
+        assert_eq!(paragraphs.len(), 1);
+
+        // The text is split after the space, and each word has the size of its characters, including the space.
+        let paragraph = paragraphs[0].0.read().unwrap();
+        let size_of = |characters: f32| Size {
+            width: Dimension::Points((characters * PLACEHOLDER_ADVANCE * 24.0).floor()),
+            height: Dimension::Points((PLACEHOLDER_LINE_HEIGHT * 24.0).floor()),
+        };
+        let sizes = paragraph
+            .get_children()
+            .iter()
+            .map(|word| word.0.read().unwrap().get_element().get_size())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![size_of(6.0), size_of(5.0)]);
+    }
+
+    #[tokio::test]
+    async fn no_loaded_fonts_is_an_error() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = Arc::new(FontFamily::new(vec![FontFace::new(
+            "Missing".to_string(),
+            font_am.get(AssetPath::new(vec!["missing.ttf".to_string()]).into()),
+            None,
+            None,
+            None,
+        )]));
+
+        let result = RichText::new(Default::default())
+            .set_text(font_family)
+            .write("hello")
+            .finish()
+            .await
+            .unwrap();
+        assert!(matches!(result, Err(TypesetError::NoLoadedFonts)));
+    }
+
+    #[test]
+    fn dropped_rich_texts_are_forgotten() {
+        for _ in 0..2000 {
+            RichText::new(Default::default());
+        }
+        // Other tests may be holding a few rich texts, but none of these ones.
+        assert!(RICH_TEXTS.lock().unwrap().len() < 1000);
+    }
+
+    #[tokio::test]
+    async fn failed_typesetting_clears_the_rebuild_deadline() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = Arc::new(FontFamily::new(vec![FontFace::new(
+            "Missing".to_string(),
+            font_am.get(AssetPath::new(vec!["missing.ttf".to_string()]).into()),
+            None,
+            None,
+            None,
+        )]));
+        let mut rich_text = RichText::new(Default::default());
+        rich_text.set_debounce(Duration::from_millis(500));
+
+        for _ in 0..2 {
+            let builder = rich_text.set_text(Arc::clone(&font_family)).write("hello");
+            // Each new text waits for the debounce again, rather than reusing the deadline of the text that failed.
+            let deadline = rich_text.0.read().unwrap().rebuild_deadline.unwrap();
+            assert!(deadline > Instant::now() + Duration::from_millis(250));
+            let result = builder.finish().await.unwrap();
+            assert!(matches!(result, Err(TypesetError::NoLoadedFonts)));
+        }
+
+        // The failed text was never written, so the empty text from before it is typeset again.
+        let retypeset = rich_text
+            .retypeset()
+            .expect("nothing should be waiting to be typeset");
+        assert!(retypeset.await.unwrap().is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_set_text_does_not_deadlock() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+        let rich_text = RichText::new(Default::default());
+
+        let tasks: Vec<_> = (0..50)
+            .map(|i| {
+                let mut rich_text = rich_text.clone();
+                let font_family = Arc::clone(&font_family);
+                tokio::spawn(async move {
+                    rich_text
+                        .set_text(font_family)
+                        .write(&format!("text {}", i))
+                        .finish()
+                        .await
+                        .unwrap()
+                })
+            })
+            .collect();
+        let mut finished = 0;
+        for task in tasks {
+            match task.await.unwrap() {
+                Ok(()) => finished += 1,
+                Err(TypesetError::Aborted) => {}
+                Err(error) => panic!("typesetting failed: {:?}", error),
+            }
+        }
+
+        // The last text to be set is never superseded, and only complete text is ever written.
+        assert!(finished >= 1);
+        let contents = rich_text.0.read().unwrap();
+        assert_eq!(contents.paragraphs.len(), 1);
+        assert_eq!(contents.widget.0.read().unwrap().get_children().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn set_text_aborts_previous_typesetting() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+        let unused_references = Arc::strong_count(&font_family);
+        let mut rich_text = RichText::new(Default::default());
+        rich_text.set_debounce(Duration::from_secs(0));
+
+        // This text has so many segments that it would take several seconds to typeset.
+        let mut builder = rich_text.set_text(Arc::clone(&font_family));
+        for _ in 0..200_000 {
+            builder = builder.write("word ");
+        }
+        let first = builder.finish();
+        // Give the first text time to start typesetting, so that it is aborted between segments rather than while it waits.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let second = rich_text
+            .set_text(noto_sans(&mut font_am).await)
+            .write("second")
+            .finish();
+        assert!(matches!(first.await.unwrap(), Err(TypesetError::Aborted)));
+        assert!(second.await.unwrap().is_ok());
+
+        // Once the first text's typesetting tasks have stopped, nothing refers to its font family any more.
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while Arc::strong_count(&font_family) > unused_references && Instant::now() < deadline {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        assert_eq!(Arc::strong_count(&font_family), unused_references);
+    }
+
+    #[tokio::test]
+    async fn rapid_updates_rebuild_once() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+        let mut rich_text = RichText::new(Default::default());
+        // A long window keeps this test reliable on a slow machine.
+        rich_text.set_debounce(Duration::from_millis(500));
+
+        // Without the debounce, each update would have time to be typeset before the next one.
+        let mut tasks = Vec::new();
+        for i in 0..5 {
+            tasks.push(
+                rich_text
+                    .set_text(Arc::clone(&font_family))
+                    .write(&format!("update {}", i))
+                    .finish(),
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut rebuilds = 0;
+        for task in tasks {
+            if task.await.unwrap().is_ok() {
+                rebuilds += 1;
+            }
+        }
+
+        assert_eq!(rebuilds, 1);
+        let contents = rich_text.0.read().unwrap();
+        assert_eq!(contents.paragraphs[0][1].text, "4");
+    }
+
+    /// Times typesetting a 1000 character paragraph.
+    /// Run with `cargo test --release -- --ignored --nocapture typesetting_benchmark`.
+    #[tokio::test]
+    #[ignore]
+    async fn typesetting_benchmark() {
+        const ITERATIONS: u32 = 100;
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+        let text: String = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. "
+            .chars()
+            .cycle()
+            .take(1000)
+            .collect();
+
+        let start = std::time::Instant::now();
+        for _ in 0..ITERATIONS {
+            let builder = RichText::new(Default::default())
+                .set_text(Arc::clone(&font_family))
+                .write(&text);
+            typeset_rich_text_paragraph(segments(builder), 1.0)
+                .await
+                .unwrap();
+        }
+        println!(
+            "typesetting {} characters took {:?}",
+            text.len(),
+            start.elapsed() / ITERATIONS
+        );
+    }
+
+    /// Lists the font, glyph and position of every glyph in each paragraph, so that typeset text can be compared.
+    fn glyph_positions(paragraphs: &[RenderableParagraph]) -> Vec<Vec<(usize, u16, f32, f32)>> {
+        paragraphs
+            .iter()
+            .map(|paragraph| {
+                paragraph
+                    .0
+                    .iter()
+                    .flat_map(|word| &word.glyphs)
+                    .map(|glyph| {
+                        let position = glyph.glyph.position();
+                        (glyph.font, glyph.glyph.id().0, position.x, position.y)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn parallel_typesetting_matches_sequential() {
+        let mut font_am = AssetManager::new(FontAssetLoader::default());
+        let font_family = noto_sans(&mut font_am).await;
+
+        let mut builder = RichText::new(Default::default()).set_text(font_family);
+        for i in 0..50 {
+            builder = builder
+                .write(&format!("Paragraph {} has", i))
+                .bold(|b| b.write(&"words ".repeat(i % 7 + 1)))
+                .end_paragraph();
+        }
+        let paragraphs = builder.paragraphs;
+
+        let mut sequential = Vec::new();
+        for paragraph in paragraphs.clone() {
+            sequential.push(typeset_rich_text_paragraph(paragraph, 1.0).await.unwrap());
+        }
+        let parallel = typeset_rich_text(paragraphs, 1.0).await.unwrap();
+
+        assert_eq!(parallel.paragraphs.len(), 50);
+        assert_eq!(
+            glyph_positions(&parallel.paragraphs),
+            glyph_positions(&sequential)
+        );
+    }
+}
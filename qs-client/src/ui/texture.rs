@@ -1,14 +1,165 @@
-use qs_common::assets::Asset;
+use futures::FutureExt;
+use qs_common::assets::{Asset, LoadStatus};
 use stretch::{geometry::Size, result::Layout, style::Dimension};
 
-use crate::graphics::{MultiRenderable, Renderable, Texture, Vertex};
+use crate::graphics::{MultiRenderable, Renderable, Texture, TextureRegion, Vertex};
 
 use super::{Colour, UiElement};
 
+/// How an image is fitted into the layout box of the element that draws it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScaleMode {
+    /// The image is stretched to fill the box exactly, ignoring its aspect ratio.
+    Stretch,
+    /// The image is scaled to be as large as possible while still fitting inside the box, keeping its aspect ratio.
+    /// It is centred, leaving empty space on two sides of the box if the aspect ratios differ.
+    Fit,
+    /// The image is scaled to be as small as possible while still covering the box, keeping its aspect ratio.
+    /// It is centred, and the parts that stick out of the box are cropped.
+    Fill,
+    /// The image is drawn at its original size in the centre of the box, cropped if it is larger than the box.
+    Center,
+    /// The image is repeated at its original size from the top left of the box, and the last row and column are cropped.
+    Tile,
+}
+
+/// The shape that an image is cut into, measured within the layout box of the element that draws it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImageMask {
+    /// The whole image is drawn.
+    Rectangle,
+    /// The corners of the box are rounded off with the given radius, which is reduced if the box is too small for it.
+    RoundedRectangle { corner_radius: f32 },
+    /// Only the largest circle that fits in the centre of the box is drawn. This is useful for avatars.
+    Circle,
+}
+
 pub struct ImageElement {
     pub size: Size<Dimension>,
     pub colour: Colour,
     pub texture: Asset<Texture>,
+    pub scale_mode: ScaleMode,
+    pub mask: ImageMask,
+}
+
+impl ImageElement {
+    /// Cuts the image into the largest circle that fits in its box.
+    pub fn circular(self) -> Self {
+        Self {
+            mask: ImageMask::Circle,
+            ..self
+        }
+    }
+
+    /// Rounds off the corners of the image's box.
+    pub fn with_corner_radius(self, corner_radius: f32) -> Self {
+        Self {
+            mask: ImageMask::RoundedRectangle { corner_radius },
+            ..self
+        }
+    }
+}
+
+/// A rectangle in layout coordinates, where Y increases downwards, together with the texture coordinates drawn across it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+struct ImageQuad {
+    x: f32,
+    y: f32,
+    width: f32,
+    height: f32,
+    u: (f32, f32),
+    v: (f32, f32),
+}
+
+impl ImageQuad {
+    /// Returns the vertex at the given point in layout coordinates, with the texture coordinates that this quad has there.
+    fn vertex(&self, x: f32, y: f32, color: [f32; 4]) -> Vertex {
+        let lerp = |(start, end): (f32, f32), t: f32| start + (end - start) * t;
+        Vertex {
+            position: [x, -y, 0.0],
+            color,
+            tex_coords: [
+                lerp(self.u, (x - self.x) / self.width),
+                lerp(self.v, (y - self.y) / self.height),
+            ],
+        }
+    }
+
+    /// Centres a rectangle of the given size in the layout box, cropping any part of it that falls outside the box.
+    fn centred(layout: &Layout, width: f32, height: f32) -> Self {
+        let (x, u) = centred_axis(layout.location.x, layout.size.width, width);
+        let (y, v) = centred_axis(layout.location.y, layout.size.height, height);
+        Self {
+            x: x.0,
+            y: y.0,
+            width: x.1,
+            height: y.1,
+            u,
+            v,
+        }
+    }
+}
+
+/// Centres a line of the given length in the range of length `available` starting at `start`, cropping it to fit.
+/// Returns the start and length of the visible part, and the texture coordinates of its ends.
+fn centred_axis(start: f32, available: f32, length: f32) -> ((f32, f32), (f32, f32)) {
+    if length <= available {
+        ((start + (available - length) / 2.0, length), (0.0, 1.0))
+    } else {
+        let cropped = (1.0 - available / length) / 2.0;
+        ((start, available), (cropped, 1.0 - cropped))
+    }
+}
+
+/// Works out which quads to draw so that an image of the given size is fitted into the layout box using the given mode.
+fn image_quads(scale_mode: ScaleMode, layout: &Layout, dimensions: (u32, u32)) -> Vec<ImageQuad> {
+    let (image_width, image_height) = (dimensions.0 as f32, dimensions.1 as f32);
+    let (box_width, box_height) = (layout.size.width, layout.size.height);
+    match scale_mode {
+        ScaleMode::Stretch => vec![ImageQuad {
+            x: layout.location.x,
+            y: layout.location.y,
+            width: box_width,
+            height: box_height,
+            u: (0.0, 1.0),
+            v: (0.0, 1.0),
+        }],
+        ScaleMode::Fit | ScaleMode::Fill => {
+            let (scale_x, scale_y) = (box_width / image_width, box_height / image_height);
+            let scale = if scale_mode == ScaleMode::Fit {
+                scale_x.min(scale_y)
+            } else {
+                scale_x.max(scale_y)
+            };
+            vec![ImageQuad::centred(
+                layout,
+                image_width * scale,
+                image_height * scale,
+            )]
+        }
+        ScaleMode::Center => vec![ImageQuad::centred(layout, image_width, image_height)],
+        ScaleMode::Tile => {
+            let columns = (box_width / image_width).ceil().max(0.0) as u32;
+            let rows = (box_height / image_height).ceil().max(0.0) as u32;
+            let mut quads = Vec::new();
+            for row in 0..rows {
+                for column in 0..columns {
+                    let (x, y) = (column as f32 * image_width, row as f32 * image_height);
+                    let width = image_width.min(box_width - x);
+                    let height = image_height.min(box_height - y);
+                    quads.push(ImageQuad {
+                        x: layout.location.x + x,
+                        y: layout.location.y + y,
+                        width,
+                        height,
+                        u: (0.0, width / image_width),
+                        v: (0.0, height / image_height),
+                    });
+                }
+            }
+            quads
+        }
+    }
 }
 
 impl UiElement for ImageElement {
@@ -17,43 +168,354 @@ impl UiElement for ImageElement {
     }
 
     fn generate_render_info(&self, layout: &Layout) -> MultiRenderable {
+        // The image's size is only known once it has loaded. Until then, nothing is drawn anyway, so stretching it is fine.
+        // Rendering can't wait for the asset's lock, so if it is busy we also just stretch the image for this frame.
+        let dimensions =
+            self.texture
+                .data
+                .upgrade()
+                .and_then(|data| match &*data.read().now_or_never()? {
+                    LoadStatus::Loaded(texture) => Some(texture.dimensions),
+                    _ => None,
+                });
+        let quads = match dimensions {
+            Some(dimensions) if dimensions.0 > 0 && dimensions.1 > 0 => {
+                image_quads(self.scale_mode, layout, dimensions)
+            }
+            _ => image_quads(ScaleMode::Stretch, layout, (1, 1)),
+        };
+
         let color = self.colour.into();
         MultiRenderable::Image {
             texture: self.texture.clone(),
-            renderables: vec![Renderable::Quadrilateral(
-                Vertex {
-                    position: [layout.location.x, -layout.location.y, 0.0],
-                    color,
-                    tex_coords: [0.0, 0.0],
-                },
-                Vertex {
-                    position: [
-                        layout.location.x + layout.size.width,
-                        -layout.location.y,
-                        0.0,
-                    ],
-                    color,
-                    tex_coords: [1.0, 0.0],
-                },
-                Vertex {
-                    position: [
-                        layout.location.x + layout.size.width,
-                        -layout.location.y - layout.size.height,
-                        0.0,
-                    ],
-                    color,
-                    tex_coords: [1.0, 1.0],
-                },
-                Vertex {
-                    position: [
-                        layout.location.x,
-                        -layout.location.y - layout.size.height,
-                        0.0,
-                    ],
-                    color,
-                    tex_coords: [0.0, 1.0],
+            renderables: masked_renderables(&quads, self.mask, layout, color),
+        }
+    }
+}
+
+/// The number of straight edges used to approximate a whole circle. Rounded corners use a quarter of this each.
+const CIRCLE_SEGMENTS: usize = 48;
+
+/// Returns the outline of the given mask as a convex polygon in layout coordinates, or `None` if nothing is cut away.
+fn mask_outline(mask: ImageMask, layout: &Layout) -> Option<Vec<(f32, f32)>> {
+    let (x, y) = (layout.location.x, layout.location.y);
+    let (width, height) = (layout.size.width, layout.size.height);
+    let arc = |centre: (f32, f32), radius: f32, start_angle: f32, segments: usize| {
+        (0..=segments).map(move |i| {
+            let angle =
+                start_angle + std::f32::consts::FRAC_PI_2 * 4.0 * i as f32 / CIRCLE_SEGMENTS as f32;
+            (
+                centre.0 + radius * angle.cos(),
+                centre.1 + radius * angle.sin(),
+            )
+        })
+    };
+    match mask {
+        ImageMask::Rectangle => None,
+        ImageMask::Circle => {
+            let centre = (x + width / 2.0, y + height / 2.0);
+            let radius = width.min(height) / 2.0;
+            Some(arc(centre, radius, 0.0, CIRCLE_SEGMENTS - 1).collect())
+        }
+        ImageMask::RoundedRectangle { corner_radius } => {
+            let r = corner_radius.max(0.0).min(width / 2.0).min(height / 2.0);
+            let quarter = CIRCLE_SEGMENTS / 4;
+            // Y points downwards, so increasing angles go clockwise on the screen, starting from the bottom right.
+            let corners = [
+                ((x + width - r, y + height - r), 0.0),
+                ((x + r, y + height - r), std::f32::consts::FRAC_PI_2),
+                ((x + r, y + r), std::f32::consts::PI),
+                ((x + width - r, y + r), 3.0 * std::f32::consts::FRAC_PI_2),
+            ];
+            Some(
+                corners
+                    .iter()
+                    .flat_map(|&(centre, start_angle)| arc(centre, r, start_angle, quarter))
+                    .collect(),
+            )
+        }
+    }
+}
+
+/// Clips a convex polygon to the given quad's rectangle, using the Sutherland-Hodgman algorithm.
+fn clip_to_quad(polygon: &[(f32, f32)], quad: &ImageQuad) -> Vec<(f32, f32)> {
+    let (x1, y1) = (quad.x, quad.y);
+    let (x2, y2) = (quad.x + quad.width, quad.y + quad.height);
+    // The polygon is clipped against each edge of the rectangle in turn. Each edge is given by which coordinate it bounds,
+    // its position, and whether points inside the rectangle have greater coordinates than it.
+    let mut output = polygon.to_vec();
+    for &(is_x, bound, keep_greater) in &[
+        (true, x1, true),
+        (true, x2, false),
+        (false, y1, true),
+        (false, y2, false),
+    ] {
+        let input = std::mem::take(&mut output);
+        if input.is_empty() {
+            break;
+        }
+        let inside = |(px, py): (f32, f32)| {
+            let value = if is_x { px } else { py };
+            if keep_greater {
+                value >= bound
+            } else {
+                value <= bound
+            }
+        };
+        let crossing = |(ax, ay): (f32, f32), (bx, by): (f32, f32)| {
+            let t = if is_x {
+                (bound - ax) / (bx - ax)
+            } else {
+                (bound - ay) / (by - ay)
+            };
+            (ax + (bx - ax) * t, ay + (by - ay) * t)
+        };
+        for (i, &current) in input.iter().enumerate() {
+            let previous = input[(i + input.len() - 1) % input.len()];
+            match (inside(previous), inside(current)) {
+                (true, true) => output.push(current),
+                (true, false) => output.push(crossing(previous, current)),
+                (false, true) => {
+                    output.push(crossing(previous, current));
+                    output.push(current);
+                }
+                (false, false) => {}
+            }
+        }
+    }
+    output
+}
+
+/// Converts the quads that an image is drawn with into renderables, cutting them into the shape of the mask.
+fn masked_renderables(
+    quads: &[ImageQuad],
+    mask: ImageMask,
+    layout: &Layout,
+    color: [f32; 4],
+) -> Vec<Renderable> {
+    let outline = match mask_outline(mask, layout) {
+        Some(outline) => outline,
+        None => {
+            return quads
+                .iter()
+                .map(|quad| {
+                    let (x1, y1) = (quad.x, quad.y);
+                    let (x2, y2) = (quad.x + quad.width, quad.y + quad.height);
+                    Renderable::Quadrilateral(
+                        quad.vertex(x1, y1, color),
+                        quad.vertex(x2, y1, color),
+                        quad.vertex(x2, y2, color),
+                        quad.vertex(x1, y2, color),
+                    )
+                })
+                .collect()
+        }
+    };
+
+    // The clipped outlines are convex, so they can be drawn as a fan of triangles around their first point.
+    let mut renderables = Vec::new();
+    for quad in quads {
+        let clipped = clip_to_quad(&outline, quad);
+        for i in 2..clipped.len() {
+            let vertex = |(x, y): (f32, f32)| quad.vertex(x, y, color);
+            renderables.push(Renderable::Triangle(
+                vertex(clipped[0]),
+                vertex(clipped[i - 1]),
+                vertex(clipped[i]),
+            ));
+        }
+    }
+    renderables
+}
+
+/// Draws a single region of a partitioned texture, such as a sprite from a texture atlas.
+/// Unlike a `NinePatch`, this is drawn using a single quad.
+pub struct SpriteElement {
+    pub size: Size<Dimension>,
+    pub colour: Colour,
+    pub texture_region: TextureRegion,
+}
+
+impl UiElement for SpriteElement {
+    fn get_size(&self) -> Size<Dimension> {
+        self.size
+    }
+
+    fn generate_render_info(&self, layout: &Layout) -> MultiRenderable {
+        self.texture_region.generate_render_info(
+            self.colour,
+            layout.location.x,
+            -layout.location.y - layout.size.height,
+            layout.size.width,
+            layout.size.height,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::MissingTextureLoader;
+
+    /// Lists the positions of the vertices of the given renderables, in layout coordinates.
+    fn positions(renderables: &[Renderable]) -> Vec<(f32, f32)> {
+        renderables
+            .iter()
+            .flat_map(|renderable| match renderable {
+                Renderable::Triangle(a, b, c) => vec![*a, *b, *c],
+                Renderable::Quadrilateral(a, b, c, d) => vec![*a, *b, *c, *d],
+                Renderable::Empty => vec![],
+            })
+            .map(|vertex| (vertex.position[0], -vertex.position[1]))
+            .collect()
+    }
+
+    fn square_box() -> Layout {
+        // Stretch doesn't let us construct a layout directly, so lay out a fixed size node to get one.
+        let mut stretch = stretch::Stretch::new();
+        let node = stretch
+            .new_node(
+                stretch::style::Style {
+                    size: Size {
+                        width: Dimension::Points(40.0),
+                        height: Dimension::Points(40.0),
+                    },
+                    ..Default::default()
                 },
-            )],
+                vec![],
+            )
+            .unwrap();
+        stretch.compute_layout(node, Size::undefined()).unwrap();
+        *stretch.layout(node).unwrap()
+    }
+
+    fn quad(x: f32, y: f32, width: f32, height: f32, u: (f32, f32), v: (f32, f32)) -> ImageQuad {
+        ImageQuad {
+            x,
+            y,
+            width,
+            height,
+            u,
+            v,
+        }
+    }
+
+    #[test]
+    fn wide_image_in_a_square_box() {
+        let layout = square_box();
+        let quads = |mode| image_quads(mode, &layout, (20, 10));
+
+        assert_eq!(
+            quads(ScaleMode::Stretch),
+            vec![quad(0.0, 0.0, 40.0, 40.0, (0.0, 1.0), (0.0, 1.0))]
+        );
+        assert_eq!(
+            quads(ScaleMode::Fit),
+            vec![quad(0.0, 10.0, 40.0, 20.0, (0.0, 1.0), (0.0, 1.0))]
+        );
+        // Scaled up to 80x40, so a quarter of the image is cropped from each side.
+        assert_eq!(
+            quads(ScaleMode::Fill),
+            vec![quad(0.0, 0.0, 40.0, 40.0, (0.25, 0.75), (0.0, 1.0))]
+        );
+        assert_eq!(
+            quads(ScaleMode::Center),
+            vec![quad(10.0, 15.0, 20.0, 10.0, (0.0, 1.0), (0.0, 1.0))]
+        );
+
+        let tiles = quads(ScaleMode::Tile);
+        assert_eq!(tiles.len(), 2 * 4);
+        assert_eq!(
+            tiles[7],
+            quad(20.0, 30.0, 20.0, 10.0, (0.0, 1.0), (0.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn circular_images_stay_inside_the_circle() {
+        let layout = square_box();
+        let quads = image_quads(ScaleMode::Stretch, &layout, (20, 10));
+        let renderables = masked_renderables(&quads, ImageMask::Circle, &layout, [1.0; 4]);
+        assert!(!renderables.is_empty());
+
+        for (x, y) in positions(&renderables) {
+            let distance = ((x - 20.0).powi(2) + (y - 20.0).powi(2)).sqrt();
+            assert!(
+                distance <= 20.0 + 1e-3,
+                "({}, {}) is outside the circle",
+                x,
+                y
+            );
+        }
+        // The centre of the image is drawn with the centre of the texture.
+        if let Renderable::Triangle(a, ..) = &renderables[0] {
+            let (u, v) = (a.tex_coords[0], a.tex_coords[1]);
+            assert!((u - (a.position[0] / 40.0)).abs() < 1e-5);
+            assert!((v - (-a.position[1] / 40.0)).abs() < 1e-5);
         }
     }
+
+    #[test]
+    fn rounded_corners_cut_off_the_corners() {
+        let layout = square_box();
+        let quads = image_quads(ScaleMode::Tile, &layout, (20, 10));
+        let renderables = masked_renderables(
+            &quads,
+            ImageMask::RoundedRectangle { corner_radius: 8.0 },
+            &layout,
+            [1.0; 4],
+        );
+        let points = positions(&renderables);
+        assert!(points
+            .iter()
+            .all(|&(x, y)| (0.0..=40.0).contains(&x) && (0.0..=40.0).contains(&y)));
+        assert!(!points.contains(&(0.0, 0.0)));
+        // The middle of each edge is still drawn.
+        assert!(points.contains(&(20.0, 0.0)));
+    }
+
+    /// Lists the colours of the images in a renderable, in the order they are drawn.
+    fn image_colours(renderable: MultiRenderable) -> Vec<[f32; 4]> {
+        match renderable {
+            MultiRenderable::Image { renderables, .. } => match &renderables[0] {
+                Renderable::Quadrilateral(vertex, ..) => vec![vertex.color],
+                _ => vec![],
+            },
+            MultiRenderable::Layered(items) | MultiRenderable::Adjacent(items) => {
+                items.into_iter().flat_map(image_colours).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn images_are_both_widgets_and_backgrounds() {
+        let mut texture_am = qs_common::assets::AssetManager::new(MissingTextureLoader);
+        let texture = texture_am.get(qs_common::assets::AssetPath::new(vec![
+            "missing".to_string()
+        ]));
+        let image = |colour| ImageElement {
+            size: Size {
+                width: Dimension::Points(10.0),
+                height: Dimension::Points(10.0),
+            },
+            colour,
+            texture: texture.clone(),
+            scale_mode: ScaleMode::Stretch,
+            mask: ImageMask::Rectangle,
+        };
+
+        let widget = crate::ui::Widget::new(
+            image(Colour::RED),
+            Vec::new(),
+            vec![Box::new(image(Colour::BLUE))],
+            Default::default(),
+        );
+        let ui = crate::ui::UI::new(widget, Size::undefined());
+        let renderable = ui.generate_render_info(stretch::geometry::Point { x: 0.0, y: 0.0 }, None);
+
+        // The background is drawn underneath the widget's own image.
+        let colours: Vec<[f32; 4]> = vec![Colour::BLUE.into(), Colour::RED.into()];
+        assert_eq!(image_colours(renderable), colours);
+    }
 }
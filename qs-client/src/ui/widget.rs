@@ -1,10 +1,12 @@
 use qs_common::assets::Asset;
-use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, RwLock, Weak};
-use winit::event::{ElementState, MouseButton};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, Mutex, RwLock, Weak};
+use winit::event::{ElementState, KeyboardInput, ModifiersState, MouseButton, VirtualKeyCode};
 
 use stretch::{
     geometry, geometry::Point, geometry::Size, node::Node, node::Stretch, number::Number,
-    result::Layout, style::Dimension, style::Style,
+    result::Layout, style::AlignItems, style::Dimension, style::JustifyContent, style::Style,
 };
 
 use crate::graphics::*;
@@ -16,6 +18,14 @@ pub trait UiElement: Send + Sync {
     /// for the font to load before this can be calculated.
     fn get_size(&self) -> Size<Dimension>;
 
+    /// The smallest size that this element may be laid out at. A dimension of `Auto` leaves the widget's own style unchanged.
+    fn get_min_size(&self) -> Size<Dimension> {
+        Size {
+            width: Dimension::Auto,
+            height: Dimension::Auto,
+        }
+    }
+
     /// Generates information about how to render this widget, based on the calculated layout info.
     /// Asynchronous, asset-based information must be called on a background task and just used here.
     fn generate_render_info(&self, layout: &Layout) -> MultiRenderable;
@@ -46,6 +56,73 @@ pub trait UiElement: Send + Sync {
 
     /// This is called when we lose keyboard focus, for example when another widget gains keyboard focus or we surrender it.
     fn lose_keyboard_focus(&mut self) {}
+
+    /// Can this element be given keyboard focus by pressing Tab?
+    fn accepts_focus(&self) -> bool {
+        false
+    }
+
+    /// Processes a keyboard input event. This is only called on the element that currently has keyboard focus.
+    /// Returns true if the event was processed.
+    fn process_keyboard_input(&mut self, _input: KeyboardInput) -> bool {
+        false
+    }
+
+    /// This is called when the user presses the left mouse button on this element and moves the mouse far enough to start dragging.
+    /// Return a payload to begin a drag, or `None` if this element can't be dragged.
+    /// While a drag is in progress, the mouse release event is not sent to any element.
+    fn on_drag_start(&mut self) -> Option<DragPayload> {
+        None
+    }
+
+    /// This is called when a drag is released over this element. If this element accepts the payload, return `None`;
+    /// otherwise return the payload so that it can be offered to the elements beneath this one.
+    fn on_drop(&mut self, payload: DragPayload) -> Option<DragPayload> {
+        Some(payload)
+    }
+
+    /// This is called on the element that started a drag if the payload was released without being accepted by any element.
+    /// The payload is given back so that the drag can be undone.
+    fn on_drag_cancel(&mut self, _payload: DragPayload) {}
+
+    /// This is called on every element when a mouse press turns into a drag, since the release that ends the drag is
+    /// not sent to any element. Elements that track whether they are being held, such as buttons, should stop doing so.
+    fn mouse_press_cancelled(&mut self) {}
+
+    /// While this element is the source of a drag, this generates the 'ghost' that follows the cursor.
+    /// The position of the cursor is given in the same coordinate system as layouts passed into `generate_render_info`.
+    fn generate_drag_ghost_render_info(
+        &self,
+        _payload: &DragPayload,
+        _cursor: Point<f32>,
+    ) -> MultiRenderable {
+        MultiRenderable::Nothing
+    }
+
+    /// A name for this type of element, used to pick its border colour in a [`DebugDrawConfig`].
+    fn debug_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// The data that is carried from one widget to another in a drag-and-drop operation.
+/// Elements should downcast this to whatever type they expect to receive.
+pub type DragPayload = Box<dyn Any + Send + Sync>;
+
+/// How far the mouse must move, in pixels, after being pressed before a drag begins.
+const DRAG_THRESHOLD: f32 = 4.0;
+
+/// The state of a drag-and-drop operation in a UI.
+enum DragState {
+    /// No mouse button is held, or the held mouse button did not start a drag.
+    None,
+    /// The left mouse button was pressed at the given position, but the mouse hasn't moved far enough to start a drag yet.
+    Pressed(Point<f32>),
+    /// The `source` widget gave us this payload, which is now following the cursor.
+    Dragging {
+        source: Widget,
+        payload: DragPayload,
+    },
 }
 
 /// What was the result of clicking a UI element?
@@ -59,6 +136,148 @@ pub enum MouseInputProcessResult {
     TakeKeyboardFocus,
 }
 
+/// Describes how the borders around each widget are drawn when debugging a UI's layout.
+#[derive(Clone)]
+pub struct DebugDrawConfig {
+    /// The borders are drawn with the top-left pixel of this texture, tinted by the border colour.
+    pub line_texture: Asset<Texture>,
+    /// The width of each border line, in pixels.
+    pub thickness: f32,
+    /// The colour of borders around elements that have no colour in `element_colours`.
+    pub colour: super::Colour,
+    /// Border colours for particular types of UI element, keyed by their [`debug_name`](UiElement::debug_name).
+    pub element_colours: HashMap<&'static str, super::Colour>,
+}
+
+impl DebugDrawConfig {
+    /// Draws white borders one pixel thick.
+    pub fn new(line_texture: Asset<Texture>) -> Self {
+        Self {
+            line_texture,
+            thickness: 1.0,
+            colour: super::Colour::WHITE,
+            element_colours: HashMap::new(),
+        }
+    }
+
+    /// Draws borders around elements of type `T` in the given colour, so that they can be told apart from other widgets.
+    pub fn set_element_colour<T: UiElement>(&mut self, colour: super::Colour) {
+        self.element_colours
+            .insert(std::any::type_name::<T>(), colour);
+    }
+
+    /// Generates the four lines surrounding a widget with the given global layout.
+    fn border(&self, element_name: &str, layout: &Layout) -> MultiRenderable {
+        let (x0, y0) = (layout.location.x, -layout.location.y);
+        let (x1, y1) = (
+            layout.location.x + layout.size.width,
+            -layout.location.y - layout.size.height,
+        );
+        // Create four lines of the configured thickness around the inside edge of the widget.
+        let thickness = self.thickness;
+        let color = self
+            .element_colours
+            .get(element_name)
+            .copied()
+            .unwrap_or(self.colour)
+            .into();
+        let tex_coords = [0.0, 0.0];
+        MultiRenderable::Image {
+            texture: self.line_texture.clone(),
+            renderables: vec![
+                Renderable::Quadrilateral(
+                    Vertex {
+                        position: [x0, y0, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x0 + thickness, y0, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x0 + thickness, y1, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x0, y1, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                ),
+                Renderable::Quadrilateral(
+                    Vertex {
+                        position: [x1, y0, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x1 - thickness, y0, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x1 - thickness, y1, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x1, y1, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                ),
+                Renderable::Quadrilateral(
+                    Vertex {
+                        position: [x0, y0, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x0, y0 - thickness, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x1, y0 - thickness, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x1, y0, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                ),
+                Renderable::Quadrilateral(
+                    Vertex {
+                        position: [x0, y1, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x0, y1 + thickness, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x1, y1 + thickness, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                    Vertex {
+                        position: [x1, y1, 0.0],
+                        color,
+                        tex_coords,
+                    },
+                ),
+            ],
+        }
+    }
+}
+
 /// If we don't want to specify a UI element, just use the unit type.
 /// This will not render anything or effect the display hierarchy.
 impl UiElement for () {
@@ -76,9 +295,30 @@ impl UiElement for () {
 
 /// A widget is some UI element together with a list of children that can be laid out according to flexbox rules.
 /// You can clone the widget to get another reference to the same widget.
+///
+/// The contents are behind a `std` lock rather than an async lock, because layout, rendering and input handling
+/// all run synchronously on the main thread. Background tasks (such as typesetting rich text) may lock widgets too,
+/// but must never hold a widget's lock across an `.await`, so the main thread is only ever blocked briefly.
 #[derive(Clone)]
 pub struct Widget(pub Arc<RwLock<WidgetContents>>);
 
+/// Prints the widget tree, showing each widget's ID, tag and element type.
+impl std::fmt::Debug for Widget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let read = self.0.read().unwrap();
+        let name = format!("Widget#{}", read.id);
+        let mut tuple = f.debug_tuple(&name);
+        if let Some(tag) = &read.tag {
+            tuple.field(tag);
+        }
+        tuple.field(&format_args!("{}", read.element.debug_name()));
+        for child in &read.children {
+            tuple.field(child);
+        }
+        tuple.finish()
+    }
+}
+
 /// Represents a globally unique widget ID.
 /// These can be generated by calling `new_widget_id`.
 pub type WidgetID = u64;
@@ -95,6 +335,7 @@ pub struct WidgetContents {
     children: Vec<Widget>,
     /// The list of UI elements that will be rendered on sequential layers behind this one with the exact same
     /// layout. This is useful for creating backgrounds or highlights.
+    /// Backgrounds are not laid out themselves, so their own sizes are ignored, and they always fill this widget.
     backgrounds: Vec<Box<dyn UiElement>>,
     layout: Option<Layout>,
     style: Style,
@@ -109,6 +350,8 @@ pub struct WidgetContents {
 
     /// A globally unique identifier among all widgets in an app. Generated automatically when created.
     id: WidgetID,
+    /// An optional name given to this widget, so that it can be found with `find_by_tag` and picked out in debug output.
+    tag: Option<String>,
 }
 
 struct UiStatus {
@@ -125,17 +368,157 @@ struct UiStatus {
 /// this widget has not been added to a UI yet.
 type UiReference = Weak<UiStatus>;
 
-/// Temporarily contains style information about a widget so we can lay it out.
-struct WidgetStyle {
-    widget: Widget,
+/// Keeps the flexbox nodes of a widget tree between layouts. Each time the tree is laid out, only the nodes
+/// whose styles or children changed are updated, so `stretch` only needs to recompute the layout of the parts of
+/// the tree that changed.
+struct LayoutTree {
+    stretch: Stretch,
+    nodes: HashMap<WidgetID, LayoutNode>,
+}
+
+/// The node of a single widget, together with the style and children it was last given.
+struct LayoutNode {
+    node: Node,
     style: Style,
-    children: Vec<WidgetStyle>,
+    children: Vec<Node>,
+}
+
+impl LayoutTree {
+    fn new() -> Self {
+        Self {
+            stretch: Stretch::new(),
+            nodes: HashMap::new(),
+        }
+    }
+
+    /// Lays out the tree with the given root widget to fill the given size, then stores each widget's layout.
+    fn layout(&mut self, root: &Widget, size: geometry::Size<Number>) {
+        let mut seen = HashSet::new();
+        let root_node = self.update_node(root, &mut seen);
+
+        // Remove the nodes of widgets that are no longer in the tree. Their parents are no longer using them,
+        // because every parent whose children changed was given its new children by `update_node`.
+        let stretch = &mut self.stretch;
+        self.nodes.retain(|id, node| {
+            let keep = seen.contains(id);
+            if !keep {
+                stretch.remove(node.node);
+            }
+            keep
+        });
+
+        self.stretch
+            .compute_layout(root_node, size)
+            .expect("could not layout");
+        self.store_layouts(root);
+    }
+
+    /// Makes sure that this widget's node and its children's nodes match the widgets' current styles and children.
+    /// Adds every widget in this subtree to `seen`.
+    fn update_node(&mut self, widget: &Widget, seen: &mut HashSet<WidgetID>) -> Node {
+        let read = widget.0.read().unwrap();
+        let style = read.get_style();
+        let children: Vec<_> = read
+            .children
+            .iter()
+            .map(|child| self.update_node(child, seen))
+            .collect();
+        seen.insert(read.id);
+
+        match self.nodes.get_mut(&read.id) {
+            Some(layout_node) => {
+                if !styles_equal(&layout_node.style, &style) {
+                    self.stretch
+                        .set_style(layout_node.node, style)
+                        .expect("could not update node style");
+                    layout_node.style = style;
+                }
+                if layout_node.children != children {
+                    self.stretch
+                        .set_children(layout_node.node, children.clone())
+                        .expect("could not update node children");
+                    layout_node.children = children;
+                }
+                layout_node.node
+            }
+            None => {
+                let node = self
+                    .stretch
+                    .new_node(style, children.clone())
+                    .expect("could not add node");
+                self.nodes.insert(
+                    read.id,
+                    LayoutNode {
+                        node,
+                        style,
+                        children,
+                    },
+                );
+                node
+            }
+        }
+    }
+
+    /// Children may have been added since `update_node` ran, if another thread was changing the tree.
+    /// Those children get no layout here, but adding them forced another layout, which will include them.
+    fn store_layouts(&self, widget: &Widget) {
+        let children = {
+            let mut write = widget.0.write().unwrap();
+            let node = match self.nodes.get(&write.id) {
+                Some(layout_node) => layout_node.node,
+                None => return,
+            };
+            write.layout = Some(*self.stretch.layout(node).expect("could not get layout"));
+            write.children.clone()
+        };
+        for child in &children {
+            self.store_layouts(child);
+        }
+    }
+}
+
+/// `stretch` styles cannot be compared with `==`, so this compares them field by field.
+fn styles_equal(a: &Style, b: &Style) -> bool {
+    a.display == b.display
+        && a.position_type == b.position_type
+        && a.direction == b.direction
+        && a.flex_direction == b.flex_direction
+        && a.flex_wrap == b.flex_wrap
+        && a.overflow == b.overflow
+        && a.align_items == b.align_items
+        && a.align_self == b.align_self
+        && a.align_content == b.align_content
+        && a.justify_content == b.justify_content
+        && a.position == b.position
+        && a.margin == b.margin
+        && a.padding == b.padding
+        && a.border == b.border
+        && a.flex_grow == b.flex_grow
+        && a.flex_shrink == b.flex_shrink
+        && a.flex_basis == b.flex_basis
+        && a.size == b.size
+        && a.min_size == b.min_size
+        && a.max_size == b.max_size
+        && a.aspect_ratio == b.aspect_ratio
 }
 
 impl WidgetContents {
     fn get_style(&self) -> Style {
+        let min_size = self.element.get_min_size();
         Style {
             size: self.element.get_size(),
+            min_size: Size {
+                width: if min_size.width == Dimension::Auto {
+                    self.style.min_size.width
+                } else {
+                    min_size.width
+                },
+                height: if min_size.height == Dimension::Auto {
+                    self.style.min_size.height
+                } else {
+                    min_size.height
+                },
+            },
             ..self.style
         }
     }
@@ -164,14 +547,37 @@ impl WidgetContents {
         self.force_layout();
     }
 
+    /// Replaces all of this widget's children. Like `add_child`, this tells the new children which UI they are in,
+    /// so that they can force the UI to be laid out again.
+    pub fn set_children(&mut self, children: Vec<Widget>) {
+        for child in &children {
+            child.update_ui_reference(self.ui_reference.clone());
+        }
+        self.children = children;
+        self.force_layout();
+    }
+
     pub fn get_children(&self) -> &Vec<Widget> {
         &self.children
     }
 
+    pub fn get_element(&self) -> &dyn UiElement {
+        self.element.as_ref()
+    }
+
     /// Returns a globally unique widget identifier suitable for checking reference equality.
     pub fn get_id(&self) -> WidgetID {
         self.id
     }
+
+    pub fn get_tag(&self) -> Option<&str> {
+        self.tag.as_deref()
+    }
+
+    /// Names this widget. Tags need not be unique, but `find_by_tag` only returns the first widget with a given tag.
+    pub fn set_tag(&mut self, tag: impl Into<String>) {
+        self.tag = Some(tag.into());
+    }
 }
 
 impl Widget {
@@ -190,9 +596,21 @@ impl Widget {
             ui_reference: Default::default(),
             hover_position: None,
             id: new_widget_id(),
+            tag: None,
         })))
     }
 
+    /// Searches this widget and its descendants, depth first, for a widget with the given tag.
+    pub fn find_by_tag(&self, tag: &str) -> Option<Widget> {
+        let read = self.0.read().unwrap();
+        if read.tag.as_deref() == Some(tag) {
+            return Some(self.clone());
+        }
+        read.children
+            .iter()
+            .find_map(|child| child.find_by_tag(tag))
+    }
+
     /// Updates which UI we are inside.
     /// This is called when this widget or a parent is added to a UI, or added to a widget which itself is in a UI.
     fn update_ui_reference(&self, ui_reference: UiReference) {
@@ -203,35 +621,16 @@ impl Widget {
         write.ui_reference = ui_reference;
     }
 
-    /// Generates stretch node information for this node and children nodes.
-    /// Returns the node for this widget, along with a map from child widgets to their information.
-    fn generate_styles(&self) -> WidgetStyle {
-        let mut children = Vec::new();
-        let read = self.0.read().unwrap();
-        let style = read.get_style();
-        let child_nodes = read.children.clone();
-        for child in child_nodes {
-            children.push(child.generate_styles());
-        }
-
-        WidgetStyle {
-            widget: self.clone(),
-            style,
-            children,
-        }
-    }
-
     /// Generates a `MultiRenderable` so that we can render this widget.
     ///
     /// Y coordinates are typically reversed in this method; the flexbox library expects Y to increase in the downwards direction
     /// but our render expects Y to increase in the upwards direction.
     ///
-    /// If render_debug is a texture, additional lines will be drawn using this texture for debug information for each
-    /// child widget.
+    /// If `debug` is given, a border is drawn around this widget and each of its descendants.
     fn generate_render_info(
         &self,
         offset: Point<f32>,
-        debug_line_texture: Option<Asset<Texture>>,
+        debug: Option<&DebugDrawConfig>,
     ) -> MultiRenderable {
         let read = self.0.read().unwrap();
         if let Some(mut layout) = read.layout {
@@ -241,118 +640,11 @@ impl Widget {
             layout.location.y += offset.y;
             items.push(read.element.generate_render_info(&layout));
             for child in &read.children {
-                items.push(child.generate_render_info(layout.location, debug_line_texture.clone()));
+                items.push(child.generate_render_info(layout.location, debug));
             }
 
-            if let Some(debug_line_texture) = debug_line_texture {
-                let (x0, y0) = (layout.location.x, -layout.location.y);
-                let (x1, y1) = (
-                    layout.location.x + layout.size.width,
-                    -layout.location.y - layout.size.height,
-                );
-                const SIZE: f32 = 1.0;
-                // Create four lines of the given thickness (`SIZE`) to surround the widget.
-                let color = super::Colour {
-                    r: 1.0,
-                    g: 1.0,
-                    b: 1.0,
-                    a: 1.0,
-                }
-                .into();
-                let tex_coords = [0.0, 0.0];
-                items.push(MultiRenderable::Image {
-                    texture: debug_line_texture,
-                    renderables: vec![
-                        Renderable::Quadrilateral(
-                            Vertex {
-                                position: [x0, y0, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x0 + SIZE, y0, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x0 + SIZE, y1, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x0, y1, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                        ),
-                        Renderable::Quadrilateral(
-                            Vertex {
-                                position: [x1, y0, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x1 - SIZE, y0, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x1 - SIZE, y1, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x1, y1, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                        ),
-                        Renderable::Quadrilateral(
-                            Vertex {
-                                position: [x0, y0, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x0, y0 + SIZE, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x1, y0 + SIZE, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x1, y0, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                        ),
-                        Renderable::Quadrilateral(
-                            Vertex {
-                                position: [x0, y1, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x0, y1 - SIZE, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x1, y1 - SIZE, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                            Vertex {
-                                position: [x1, y1, 0.0],
-                                color,
-                                tex_coords,
-                            },
-                        ),
-                    ],
-                })
+            if let Some(debug) = debug {
+                items.push(debug.border(read.element.debug_name(), &layout));
             }
 
             let renderable = if items.is_empty() {
@@ -448,6 +740,49 @@ impl Widget {
         }
     }
 
+    /// Tells this widget and all of its descendants that the current mouse press will not be released normally.
+    fn cancel_mouse_press(&self) {
+        let mut write = self.0.write().unwrap();
+        write.element.mouse_press_cancelled();
+        for child in &write.children {
+            child.cancel_mouse_press();
+        }
+    }
+
+    /// Appends this widget and its descendants that accept keyboard focus to `focusable`, in depth-first order.
+    /// This is the order in which Tab moves focus between widgets.
+    fn collect_focusable_widgets(&self, focusable: &mut Vec<Widget>) {
+        let read = self.0.read().unwrap();
+        if read.element.accepts_focus() {
+            focusable.push(self.clone());
+        }
+        for child in &read.children {
+            child.collect_focusable_widgets(focusable);
+        }
+    }
+
+    /// Appends this widget and its descendants that contain the given point to `widgets`, outermost widgets first.
+    /// The `pos` input is relative to the *parent widget's* coordinate system.
+    fn collect_widgets_at(&self, pos: Point<f32>, widgets: &mut Vec<Widget>) {
+        let read = self.0.read().unwrap();
+        if let Some(layout) = &read.layout {
+            let local_pos = Point {
+                x: pos.x - layout.location.x,
+                y: pos.y - layout.location.y,
+            };
+            if local_pos.x >= 0.0
+                && local_pos.x <= layout.size.width
+                && local_pos.y >= 0.0
+                && local_pos.y <= layout.size.height
+            {
+                widgets.push(self.clone());
+                for child in &read.children {
+                    child.collect_widgets_at(local_pos, widgets);
+                }
+            }
+        }
+    }
+
     /// Call this to invoke event-handling code for when a widget gains keyboard focus.
     fn take_keyboard_focus(&self) {
         let read = self.0.read().unwrap();
@@ -466,14 +801,63 @@ impl Widget {
     }
 }
 
+/// A widget displayed on top of the rest of the UI, blocking interaction with everything behind it.
+struct Modal {
+    /// The full-screen backdrop widget, which contains the modal widget itself as its only child.
+    layer: Widget,
+    /// If true, pressing Escape closes this modal.
+    dismissible: bool,
+}
+
+/// The semi-transparent layer that covers the UI behind a modal.
+struct ModalBackdrop {
+    /// If this is `None`, the backdrop is invisible but still blocks input.
+    texture: Option<Asset<Texture>>,
+    colour: super::Colour,
+}
+
+impl UiElement for ModalBackdrop {
+    fn get_size(&self) -> Size<Dimension> {
+        Size {
+            width: Dimension::Percent(1.0),
+            height: Dimension::Percent(1.0),
+        }
+    }
+
+    fn generate_render_info(&self, layout: &Layout) -> MultiRenderable {
+        match &self.texture {
+            Some(texture) => super::ImageElement {
+                size: self.get_size(),
+                colour: self.colour,
+                texture: texture.clone(),
+                scale_mode: super::ScaleMode::Stretch,
+                mask: super::ImageMask::Rectangle,
+            }
+            .generate_render_info(layout),
+            None => MultiRenderable::Nothing,
+        }
+    }
+}
+
 /// Represents an entire user interface. Holds a root widget.
 pub struct UI {
     root: Widget,
     size: Size<Number>,
 
+    /// Modal widgets, ordered from bottom to top. Only the top modal receives input.
+    modals: Vec<Modal>,
+    /// The texture and colour used to draw the backdrop behind modals.
+    modal_backdrop: Option<(Asset<Texture>, super::Colour)>,
+
     ui_status: Arc<UiStatus>,
+    /// The flexbox nodes of the root widget and each modal layer, keyed by the ID of the tree's root widget.
+    layout_trees: Mutex<HashMap<WidgetID, LayoutTree>>,
 
     mouse_position: Point<f32>,
+    /// The modifier keys that are currently held, used to detect Shift+Tab.
+    modifiers: ModifiersState,
+
+    drag: DragState,
 }
 
 impl UI {
@@ -488,8 +872,15 @@ impl UI {
             root,
             size,
             ui_status,
+            layout_trees: Mutex::new(HashMap::new()),
+
+            modals: Vec::new(),
+            modal_backdrop: None,
 
             mouse_position: Point { x: 0.0, y: 0.0 },
+            modifiers: ModifiersState::empty(),
+
+            drag: DragState::None,
         }
     }
 
@@ -500,45 +891,128 @@ impl UI {
             .store(true, Ordering::Relaxed);
     }
 
+    /// Returns true if a widget in this UI has changed (for example, because new text was typeset)
+    /// so that the layout must be recalculated.
+    pub fn needs_layout(&self) -> bool {
+        self.ui_status.force_layout_signal.load(Ordering::Relaxed)
+    }
+
     /// Generates a `MultiRenderable` so that we can render this UI.
     ///
     /// Y coordinates are typically reversed in this method; the flexbox library expects Y to increase in the downwards direction
     /// but our render expects Y to increase in the upwards direction.
     ///
-    /// If render_debug is a texture, additional lines will be drawn using this texture for debug information for each
-    /// child widget.
+    /// If `debug` is given, a border is drawn around every widget in this UI.
     ///
-    /// If `force_layout` has been called by a child UI element, the UI layout will be recalculated first.
+    /// If `force_layout` has been called by a child UI element, or the UI has been resized, the UI layout will be
+    /// recalculated first. Otherwise, the previous layout is reused.
     pub fn generate_render_info(
         &self,
         offset: Point<f32>,
-        debug_line_texture: Option<Asset<Texture>>,
+        debug: Option<&DebugDrawConfig>,
     ) -> MultiRenderable {
-        self.layout(self.size);
-        self.root.generate_render_info(offset, debug_line_texture)
+        // The signal is cleared before laying out, so that a change made by another thread during layout is not lost.
+        if self
+            .ui_status
+            .force_layout_signal
+            .swap(false, Ordering::Relaxed)
+        {
+            self.layout(self.size);
+        }
+        let renderable = self.root.generate_render_info(offset, debug);
+        if self.modals.is_empty() && !matches!(self.drag, DragState::Dragging { .. }) {
+            return renderable;
+        }
+
+        let mut layers = vec![renderable];
+        for modal in &self.modals {
+            layers.push(modal.layer.generate_render_info(offset, debug));
+        }
+        if let DragState::Dragging { source, payload } = &self.drag {
+            let cursor = Point {
+                x: self.mouse_position.x + offset.x,
+                y: self.mouse_position.y + offset.y,
+            };
+            layers.push(
+                source
+                    .0
+                    .read()
+                    .unwrap()
+                    .element
+                    .generate_drag_ghost_render_info(payload, cursor),
+            );
+        }
+        MultiRenderable::Layered(layers)
+    }
+
+    /// Sets the texture and colour of the backdrop drawn behind modals pushed after this call.
+    /// Typically this is a plain white texture together with a semi-transparent colour.
+    pub fn set_modal_backdrop(&mut self, texture: Asset<Texture>, colour: super::Colour) {
+        self.modal_backdrop = Some((texture, colour));
+    }
+
+    /// Displays a widget centred on top of the rest of the UI, over a full-screen backdrop.
+    /// Until it is removed with `pop_modal`, all mouse and keyboard input is sent to this widget only.
+    /// If `dismissible` is true, pressing Escape also removes the modal.
+    pub fn push_modal(&mut self, widget: Widget, dismissible: bool) {
+        let (texture, colour) = match &self.modal_backdrop {
+            Some((texture, colour)) => (Some(texture.clone()), *colour),
+            None => (None, Default::default()),
+        };
+        let layer = Widget::new(
+            ModalBackdrop { texture, colour },
+            vec![widget],
+            Vec::new(),
+            Style {
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..Default::default()
+            },
+        );
+        layer.update_ui_reference(Arc::downgrade(&self.ui_status));
+        self.clear_keyboard_focus();
+        self.modals.push(Modal { layer, dismissible });
+        self.ui_status
+            .force_layout_signal
+            .store(true, Ordering::Relaxed);
+    }
+
+    /// Removes the top modal, returning the widget that was passed to `push_modal`.
+    pub fn pop_modal(&mut self) -> Option<Widget> {
+        let modal = self.modals.pop()?;
+        self.clear_keyboard_focus();
+        self.ui_status
+            .force_layout_signal
+            .store(true, Ordering::Relaxed);
+        let layer = modal.layer.0.read().unwrap();
+        layer.children.first().cloned()
+    }
+
+    /// The widget that input events are sent to: the top modal's layer if there is one, otherwise the root widget.
+    fn input_root(&self) -> &Widget {
+        match self.modals.last() {
+            Some(modal) => &modal.layer,
+            None => &self.root,
+        }
     }
 
     /// Lays out this UI according to flexbox rules.
     /// This is called when we want to render this UI but the layout has been invalidated by
     /// changing some content in a child widget or UI element.
     fn layout(&self, size: geometry::Size<Number>) {
-        let styles: WidgetStyle = self.root.generate_styles();
-
-        let layouts: Vec<_> = {
-            let mut stretch = Stretch::new();
-            let (node, nodes) = generate_nodes(&mut stretch, &styles);
-            stretch
-                .compute_layout(node, size)
-                .expect("could not layout");
-            nodes
-                .into_iter()
-                .map(|(style, node)| (style, *stretch.layout(node).expect("could not get layout")))
-                .collect()
-        };
-
-        for (style, layout) in layouts {
-            let mut write = style.widget.0.write().unwrap();
-            write.layout = Some(layout);
+        let roots: Vec<_> = std::iter::once(&self.root)
+            .chain(self.modals.iter().map(|modal| &modal.layer))
+            .map(|root| (root.0.read().unwrap().id, root))
+            .collect();
+
+        let mut layout_trees = self.layout_trees.lock().unwrap();
+        // Forget the layout trees of modals that have been removed.
+        layout_trees.retain(|id, _| roots.iter().any(|(root_id, _)| root_id == id));
+        for (id, root) in roots {
+            layout_trees
+                .entry(id)
+                .or_insert_with(LayoutTree::new)
+                .layout(root, size);
         }
     }
 
@@ -546,32 +1020,1082 @@ impl UI {
     /// The position must be passed relative to the UI's coordinates.
     pub fn mouse_move(&mut self, pos: Point<f32>) {
         self.mouse_position = pos;
-        self.root.process_mouse_move(pos);
+        self.input_root().process_mouse_move(pos);
+
+        if let DragState::Pressed(start) = self.drag {
+            let (dx, dy) = (pos.x - start.x, pos.y - start.y);
+            if dx * dx + dy * dy > DRAG_THRESHOLD * DRAG_THRESHOLD {
+                self.drag = self.start_drag(start);
+            }
+        }
     }
 
     /// Processes a mouse input event by propagating it downwards through UI elements until one of them consumes it.
     /// Returns true if the event was processed.
     pub fn mouse_input(&mut self, button: MouseButton, state: ElementState) -> bool {
-        self.root.process_mouse_input(button, state)
+        if button == MouseButton::Left {
+            match state {
+                ElementState::Pressed => self.drag = DragState::Pressed(self.mouse_position),
+                ElementState::Released => {
+                    if let DragState::Dragging { source, payload } =
+                        std::mem::replace(&mut self.drag, DragState::None)
+                    {
+                        self.input_root().cancel_mouse_press();
+                        self.finish_drag(source, payload);
+                        return true;
+                    }
+                }
+            }
+        }
+
+        // If there is a modal, its backdrop consumes every click so that the widgets behind it are never clicked.
+        self.input_root().process_mouse_input(button, state) || !self.modals.is_empty()
+    }
+
+    /// Returns all widgets containing the given point, outermost widgets first.
+    fn widgets_at(&self, pos: Point<f32>) -> Vec<Widget> {
+        let mut widgets = Vec::new();
+        self.input_root().collect_widgets_at(pos, &mut widgets);
+        widgets
+    }
+
+    /// Offers the innermost widget under the point where the mouse was pressed the chance to start a drag,
+    /// then its parent, and so on, until one of them provides a payload.
+    fn start_drag(&self, start: Point<f32>) -> DragState {
+        for widget in self.widgets_at(start).into_iter().rev() {
+            let payload = widget.0.write().unwrap().element.on_drag_start();
+            if let Some(payload) = payload {
+                return DragState::Dragging {
+                    source: widget,
+                    payload,
+                };
+            }
+        }
+        DragState::None
+    }
+
+    /// Offers the payload to each widget under the cursor, innermost first. If none of them accept it, the drag is cancelled.
+    fn finish_drag(&self, source: Widget, mut payload: DragPayload) {
+        for widget in self.widgets_at(self.mouse_position).into_iter().rev() {
+            match widget.0.write().unwrap().element.on_drop(payload) {
+                Some(rejected) => payload = rejected,
+                None => return,
+            }
+        }
+        source.0.write().unwrap().element.on_drag_cancel(payload);
+    }
+
+    /// Updates which modifier keys are currently held.
+    pub fn modifiers_changed(&mut self, modifiers: ModifiersState) {
+        self.modifiers = modifiers;
+    }
+
+    /// Processes a keyboard input event.
+    /// Tab and Shift+Tab move keyboard focus between focusable widgets, and Escape closes the top modal if it is dismissible.
+    /// All other events are sent to the focused widget.
+    /// Returns true if the event was processed.
+    pub fn keyboard_input(&mut self, input: KeyboardInput) -> bool {
+        if input.state == ElementState::Pressed {
+            match input.virtual_keycode {
+                Some(VirtualKeyCode::Tab) => {
+                    self.cycle_keyboard_focus(self.modifiers.shift());
+                    return true;
+                }
+                Some(VirtualKeyCode::Escape) if matches!(self.modals.last(), Some(modal) if modal.dismissible) =>
+                {
+                    self.pop_modal();
+                    return true;
+                }
+                _ => {}
+            }
+        }
+
+        let focused = self
+            .ui_status
+            .keyboard_focused_widget
+            .read()
+            .unwrap()
+            .clone();
+        if let Some(focused) = focused {
+            focused
+                .0
+                .write()
+                .unwrap()
+                .element
+                .process_keyboard_input(input)
+        } else {
+            false
+        }
+    }
+
+    /// Moves keyboard focus to the next focusable widget in depth-first order, or the previous one if `backwards` is true.
+    /// Focus wraps around at either end of the list. If no widget is focused, the first (or last) focusable widget gains focus.
+    fn cycle_keyboard_focus(&self, backwards: bool) {
+        let mut focusable = Vec::new();
+        self.input_root().collect_focusable_widgets(&mut focusable);
+        if focusable.is_empty() {
+            return;
+        }
+
+        let focused_id = self
+            .ui_status
+            .keyboard_focused_widget
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|widget| widget.0.read().unwrap().get_id());
+        let current_index = focused_id.and_then(|id| {
+            focusable
+                .iter()
+                .position(|widget| widget.0.read().unwrap().get_id() == id)
+        });
+
+        let len = focusable.len();
+        let next_index = match (current_index, backwards) {
+            (Some(index), false) => (index + 1) % len,
+            (Some(index), true) => (index + len - 1) % len,
+            (None, false) => 0,
+            (None, true) => len - 1,
+        };
+        focusable[next_index].take_keyboard_focus();
+    }
+
+    /// Removes keyboard focus from whichever widget currently has it.
+    fn clear_keyboard_focus(&self) {
+        let focused = self
+            .ui_status
+            .keyboard_focused_widget
+            .write()
+            .unwrap()
+            .take();
+        if let Some(focused) = focused {
+            focused.0.write().unwrap().element.lose_keyboard_focus();
+        }
     }
 }
 
-/// Returns the node corresponding to this widget, along with a vector containing all child widget styles and their nodes.
-/// This vector notably includes the current node that was returned as the first return value.
-fn generate_nodes<'a>(
-    stretch: &mut Stretch,
-    widget_style: &'a WidgetStyle,
-) -> (Node, Vec<(&'a WidgetStyle, Node)>) {
-    let mut children = Vec::new();
-    let mut child_nodes = Vec::new();
-    for child in &widget_style.children {
-        let (node, mut new_child_nodes) = generate_nodes(stretch, child);
-        children.push(node);
-        child_nodes.append(&mut new_child_nodes);
-    }
-    let node = stretch
-        .new_node(widget_style.style, children)
-        .expect("could not add node");
-    child_nodes.push((widget_style, node));
-    (node, child_nodes)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{
+        missing_nine_patch, noto_sans_regular, regular_only_family, MissingTextureLoader,
+    };
+    use crate::ui::{Button, ButtonStyle, Colour, Field, FontFamily, SpriteElement};
+    use qs_common::assets::{AssetManager, AssetPath};
+
+    /// Creates a texture region that behaves as if it had been loaded from a 64x64 partitioned texture.
+    async fn loaded_region(info: texture_atlas::TextureRegionInformation) -> TextureRegion {
+        let region = missing_nine_patch().await.texture_region;
+        region.set_info_for_testing(info, (64, 64));
+        region
+    }
+
+    fn rect(x: u32, y: u32, w: u32, h: u32) -> texture_atlas::Rect {
+        texture_atlas::Rect { x, y, w, h }
+    }
+
+    /// Extracts the vertices of the quads in a renderable of a texture region.
+    fn image_region_quads(renderable: MultiRenderable) -> Vec<[Vertex; 4]> {
+        match renderable {
+            MultiRenderable::ImageRegion { renderables, .. } => renderables
+                .into_iter()
+                .map(|renderable| match renderable {
+                    Renderable::Quadrilateral(a, b, c, d) => [a, b, c, d],
+                    _ => panic!("texture regions should be drawn with quadrilaterals"),
+                })
+                .collect(),
+            _ => panic!("expected a texture region to be rendered"),
+        }
+    }
+
+    fn button_style(nine_patch: NinePatch) -> ButtonStyle {
+        ButtonStyle {
+            released_texture: nine_patch.clone(),
+            hovered_texture: nine_patch.clone(),
+            pressed_texture: nine_patch.clone(),
+            disabled_texture: nine_patch,
+        }
+    }
+
+    /// An element with a fixed size, used to give widgets a size in tests.
+    struct Block(f32, f32);
+
+    impl UiElement for Block {
+        fn get_size(&self) -> Size<Dimension> {
+            Size {
+                width: Dimension::Points(self.0),
+                height: Dimension::Points(self.1),
+            }
+        }
+
+        fn generate_render_info(&self, _layout: &Layout) -> MultiRenderable {
+            MultiRenderable::Nothing
+        }
+    }
+
+    fn block(width: f32, height: f32) -> Widget {
+        Widget::new(
+            Block(width, height),
+            Vec::new(),
+            Vec::new(),
+            Default::default(),
+        )
+    }
+
+    #[allow(deprecated)]
+    fn key_press(key: VirtualKeyCode) -> KeyboardInput {
+        KeyboardInput {
+            scancode: 0,
+            state: ElementState::Pressed,
+            virtual_keycode: Some(key),
+            modifiers: ModifiersState::empty(),
+        }
+    }
+
+    /// The contents of an inventory slot, shared between the slot and the test.
+    type SlotContents = Arc<RwLock<Option<u32>>>;
+
+    /// An inventory slot that can hold a single item, which can be dragged to other slots.
+    struct Slot(SlotContents);
+
+    impl UiElement for Slot {
+        fn get_size(&self) -> Size<Dimension> {
+            Size {
+                width: Dimension::Points(50.0),
+                height: Dimension::Points(50.0),
+            }
+        }
+
+        fn generate_render_info(&self, _layout: &Layout) -> MultiRenderable {
+            MultiRenderable::Nothing
+        }
+
+        fn on_drag_start(&mut self) -> Option<DragPayload> {
+            self.0
+                .write()
+                .unwrap()
+                .take()
+                .map(|item| Box::new(item) as DragPayload)
+        }
+
+        fn on_drop(&mut self, payload: DragPayload) -> Option<DragPayload> {
+            let mut item = self.0.write().unwrap();
+            if item.is_some() {
+                return Some(payload);
+            }
+            match payload.downcast() {
+                Ok(payload) => {
+                    *item = Some(*payload);
+                    None
+                }
+                Err(payload) => Some(payload),
+            }
+        }
+
+        fn on_drag_cancel(&mut self, payload: DragPayload) {
+            *self.0.write().unwrap() = payload.downcast().ok().map(|item| *item);
+        }
+    }
+
+    /// Creates a UI containing two slots side by side, the first of which contains an item.
+    fn two_slots() -> (UI, SlotContents, SlotContents) {
+        let (first, second) = (Arc::new(RwLock::new(Some(7))), Arc::new(RwLock::new(None)));
+        let slot = |item: &SlotContents| {
+            Widget::new(
+                Slot(Arc::clone(item)),
+                Vec::new(),
+                Vec::new(),
+                Default::default(),
+            )
+        };
+        let root = Widget::new(
+            (),
+            vec![slot(&first), slot(&second)],
+            Vec::new(),
+            Default::default(),
+        );
+        let ui = UI::new(root, Size::undefined());
+        ui.layout(ui.size);
+        (ui, first, second)
+    }
+
+    fn drag(ui: &mut UI, from: (f32, f32), to: (f32, f32)) {
+        ui.mouse_move(Point {
+            x: from.0,
+            y: from.1,
+        });
+        ui.mouse_input(MouseButton::Left, ElementState::Pressed);
+        ui.mouse_move(Point {
+            x: from.0 + 10.0,
+            y: from.1,
+        });
+        ui.mouse_move(Point { x: to.0, y: to.1 });
+        ui.mouse_input(MouseButton::Left, ElementState::Released);
+    }
+
+    #[test]
+    fn drag_and_drop_moves_payload() {
+        let (mut ui, first, second) = two_slots();
+        drag(&mut ui, (10.0, 10.0), (75.0, 10.0));
+        assert_eq!(*first.read().unwrap(), None);
+        assert_eq!(*second.read().unwrap(), Some(7));
+    }
+
+    #[test]
+    fn drop_outside_target_cancels() {
+        let (mut ui, first, second) = two_slots();
+        drag(&mut ui, (10.0, 10.0), (500.0, 500.0));
+        assert_eq!(*first.read().unwrap(), Some(7));
+        assert_eq!(*second.read().unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn dragging_out_of_a_button_releases_it() {
+        let region = |x| {
+            loaded_region(texture_atlas::TextureRegionInformation {
+                frame: rect(x, 0, 32, 32),
+                rotated: false,
+                trimmed: false,
+                source: rect(0, 0, 32, 32),
+            })
+        };
+        let released = NinePatch::no_margins(region(0).await);
+        let pressed = NinePatch::no_margins(region(32).await);
+        let style = ButtonStyle {
+            released_texture: released.clone(),
+            hovered_texture: released.clone(),
+            pressed_texture: pressed,
+            disabled_texture: released,
+        };
+
+        let (first, second) = (Arc::new(RwLock::new(Some(7))), Arc::new(RwLock::new(None)));
+        let slot = |item: &SlotContents| {
+            Widget::new(
+                Slot(Arc::clone(item)),
+                Vec::new(),
+                Vec::new(),
+                Default::default(),
+            )
+        };
+        let button = Widget::new(
+            Button::new(style, || {}),
+            vec![slot(&first)],
+            Vec::new(),
+            Default::default(),
+        );
+        let root = Widget::new(
+            (),
+            vec![button.clone(), slot(&second)],
+            Vec::new(),
+            Default::default(),
+        );
+        let mut ui = UI::new(root, Size::undefined());
+        ui.layout(ui.size);
+
+        let render = |button: &Widget| {
+            let read = button.0.read().unwrap();
+            image_region_quads(
+                read.element
+                    .generate_render_info(read.layout.as_ref().unwrap()),
+            )[0][0]
+                .tex_coords
+        };
+        let released_coords = render(&button);
+
+        drag(&mut ui, (10.0, 10.0), (75.0, 10.0));
+        assert_eq!(*second.read().unwrap(), Some(7));
+
+        // Returning to the button must not show it as pressed, since the press ended with the drag.
+        ui.mouse_move(Point { x: 10.0, y: 10.0 });
+        assert_eq!(render(&button), released_coords);
+    }
+
+    fn focused_id(ui: &UI) -> Option<WidgetID> {
+        ui.ui_status
+            .keyboard_focused_widget
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|widget| widget.0.read().unwrap().get_id())
+    }
+
+    #[tokio::test]
+    async fn tab_cycles_focus_in_document_order() {
+        let nine_patch = missing_nine_patch().await;
+        let button_style = button_style(nine_patch.clone());
+
+        let button = || {
+            Widget::new(
+                Button::new(button_style.clone(), || {}),
+                Vec::new(),
+                Vec::new(),
+                Default::default(),
+            )
+        };
+        let first = button();
+        let second = button();
+        let field = Field::new(
+            nine_patch,
+            Arc::new(FontFamily::new(Vec::new())),
+            Default::default(),
+            Default::default(),
+        )
+        .get_widget();
+        let ids: Vec<_> = [&first, &second, &field]
+            .iter()
+            .map(|widget| widget.0.read().unwrap().get_id())
+            .collect();
+
+        let root = Widget::new(
+            (),
+            vec![first, second, field],
+            Vec::new(),
+            Default::default(),
+        );
+        let mut ui = UI::new(root, Size::undefined());
+        assert_eq!(focused_id(&ui), None);
+
+        for &id in ids.iter().chain(ids.iter()) {
+            assert!(ui.keyboard_input(key_press(VirtualKeyCode::Tab)));
+            assert_eq!(focused_id(&ui), Some(id));
+        }
+
+        ui.modifiers_changed(ModifiersState::SHIFT);
+        for &id in &[ids[1], ids[0], ids[2]] {
+            assert!(ui.keyboard_input(key_press(VirtualKeyCode::Tab)));
+            assert_eq!(focused_id(&ui), Some(id));
+        }
+    }
+
+    #[tokio::test]
+    async fn modal_backdrop_blocks_clicks() {
+        let clicked = Arc::new(AtomicBool::new(false));
+        let clicked_clone = Arc::clone(&clicked);
+        let button = Widget::new(
+            Button::new(button_style(missing_nine_patch().await), move || {
+                clicked_clone.store(true, Ordering::Relaxed)
+            }),
+            vec![block(50.0, 50.0)],
+            Vec::new(),
+            Default::default(),
+        );
+        let root = Widget::new((), vec![button], Vec::new(), Default::default());
+        let mut ui = UI::new(
+            root,
+            Size {
+                width: Number::Defined(200.0),
+                height: Number::Defined(200.0),
+            },
+        );
+        ui.layout(ui.size);
+        ui.mouse_move(Point { x: 10.0, y: 10.0 });
+
+        ui.push_modal(block(20.0, 20.0), true);
+        ui.layout(ui.size);
+        assert!(ui.mouse_input(MouseButton::Left, ElementState::Pressed));
+        assert!(ui.mouse_input(MouseButton::Left, ElementState::Released));
+        assert!(!clicked.load(Ordering::Relaxed));
+
+        // Once the modal is dismissed, the same click reaches the button.
+        assert!(ui.keyboard_input(key_press(VirtualKeyCode::Escape)));
+        ui.mouse_input(MouseButton::Left, ElementState::Pressed);
+        ui.mouse_input(MouseButton::Left, ElementState::Released);
+        assert!(clicked.load(Ordering::Relaxed));
+    }
+
+    #[tokio::test]
+    async fn nine_patch_button_is_never_smaller_than_its_corners() {
+        let mut nine_patch = NinePatch::no_margins(
+            loaded_region(texture_atlas::TextureRegionInformation {
+                frame: rect(0, 0, 32, 32),
+                rotated: false,
+                trimmed: false,
+                source: rect(0, 0, 32, 32),
+            })
+            .await,
+        );
+        nine_patch.left_margin = 8;
+        nine_patch.right_margin = 8;
+        nine_patch.top_margin = 8;
+        nine_patch.bottom_margin = 8;
+
+        let button = Widget::new(
+            Button::new(button_style(nine_patch), || {}),
+            Vec::new(),
+            Vec::new(),
+            Default::default(),
+        );
+        let container = Widget::new(
+            Block(4.0, 4.0),
+            vec![button.clone()],
+            Vec::new(),
+            Default::default(),
+        );
+        let ui = UI::new(container, Size::undefined());
+        ui.layout(ui.size);
+
+        let read = button.0.read().unwrap();
+        let layout = read.layout.unwrap();
+        assert!(layout.size.width >= 16.0 && layout.size.height >= 16.0);
+
+        // Squash the layout further to check that rendering also clamps the size.
+        let mut squashed = layout;
+        squashed.size = Size {
+            width: 4.0,
+            height: 4.0,
+        };
+        let quads = image_region_quads(read.element.generate_render_info(&squashed));
+        assert_eq!(quads.len(), 9);
+        for [a, b, c, _] in quads {
+            assert!(b.position[0] >= a.position[0]);
+            assert!(b.position[1] >= c.position[1]);
+        }
+    }
+
+    #[tokio::test]
+    async fn sprite_is_a_single_quad_covering_its_frame() {
+        let sprite = SpriteElement {
+            size: Size {
+                width: Dimension::Points(20.0),
+                height: Dimension::Points(10.0),
+            },
+            colour: Colour::WHITE,
+            texture_region: loaded_region(texture_atlas::TextureRegionInformation {
+                frame: rect(16, 32, 16, 8),
+                rotated: false,
+                trimmed: false,
+                source: rect(0, 0, 16, 8),
+            })
+            .await,
+        };
+        let root = Widget::new(sprite, Vec::new(), Vec::new(), Default::default());
+        let ui = UI::new(root.clone(), Size::undefined());
+        ui.layout(ui.size);
+
+        let read = root.0.read().unwrap();
+        let quads = image_region_quads(read.element.generate_render_info(&read.layout.unwrap()));
+        assert_eq!(quads.len(), 1);
+        let [top_left, top_right, bottom_right, bottom_left] = quads[0];
+        assert_eq!(top_left.tex_coords, [0.25, 0.5]);
+        assert_eq!(top_right.tex_coords, [0.5, 0.5]);
+        assert_eq!(bottom_right.tex_coords, [0.5, 0.625]);
+        assert_eq!(bottom_left.tex_coords, [0.25, 0.625]);
+        assert_eq!(top_right.position[0] - top_left.position[0], 20.0);
+        assert_eq!(top_left.position[1] - bottom_left.position[1], 10.0);
+    }
+
+    #[tokio::test]
+    async fn trimmed_sprite_covers_untrimmed_part_of_source() {
+        // A 32x32 image whose transparent border was trimmed, leaving a 16x8 rectangle 4 pixels from the left and 6 from the top.
+        let region = loaded_region(texture_atlas::TextureRegionInformation {
+            frame: rect(0, 0, 16, 8),
+            rotated: false,
+            trimmed: true,
+            source: rect(4, 6, 32, 32),
+        })
+        .await;
+
+        // Draw the sprite at twice its original size, with its bottom left corner at the origin.
+        let quads =
+            image_region_quads(region.generate_render_info(Colour::WHITE, 0.0, 0.0, 64.0, 64.0));
+        assert_eq!(quads.len(), 1);
+        let [top_left, _, bottom_right, _] = quads[0];
+        assert_eq!(top_left.position[0], 8.0);
+        assert_eq!(top_left.position[1], 64.0 - 12.0);
+        assert_eq!(bottom_right.position[0], 8.0 + 32.0);
+        assert_eq!(bottom_right.position[1], 64.0 - 12.0 - 16.0);
+    }
+
+    #[tokio::test]
+    async fn trimmed_nine_patch_keeps_margins_in_source_space() {
+        // A 16x16 nine-patch with 4 pixel margins, where the leftmost 2 columns were trimmed away.
+        let mut nine_patch = NinePatch::no_margins(
+            loaded_region(texture_atlas::TextureRegionInformation {
+                frame: rect(0, 0, 14, 16),
+                rotated: false,
+                trimmed: true,
+                source: rect(2, 0, 16, 16),
+            })
+            .await,
+        );
+        nine_patch.left_margin = 4;
+        nine_patch.right_margin = 4;
+        nine_patch.top_margin = 4;
+        nine_patch.bottom_margin = 4;
+
+        let quads = image_region_quads(nine_patch.generate_render_info(
+            Colour::WHITE,
+            0.0,
+            0.0,
+            100.0,
+            100.0,
+        ));
+        assert_eq!(quads.len(), 9);
+        // The left column starts where the trimmed pixels end, and the middle column still starts at the left margin.
+        let left_edges: Vec<_> = quads.iter().map(|[a, ..]| a.position[0]).collect();
+        assert_eq!(left_edges[0], 2.0);
+        assert_eq!(left_edges[3], 4.0);
+        assert_eq!(quads[0][0].tex_coords[0], 0.0);
+        assert_eq!(quads[3][0].tex_coords[0], 2.0 / 64.0);
+    }
+
+    #[tokio::test]
+    async fn vertically_trimmed_nine_patch_loses_its_top_rows() {
+        // A 16x16 nine-patch with 4 pixel margins, where the top 3 rows were trimmed away.
+        let mut nine_patch = NinePatch::no_margins(
+            loaded_region(texture_atlas::TextureRegionInformation {
+                frame: rect(0, 0, 16, 13),
+                rotated: false,
+                trimmed: true,
+                source: rect(0, 3, 16, 16),
+            })
+            .await,
+        );
+        nine_patch.left_margin = 4;
+        nine_patch.right_margin = 4;
+        nine_patch.top_margin = 4;
+        nine_patch.bottom_margin = 4;
+
+        let quads = image_region_quads(nine_patch.generate_render_info(
+            Colour::WHITE,
+            0.0,
+            0.0,
+            100.0,
+            100.0,
+        ));
+        assert_eq!(quads.len(), 9);
+        // Each column is drawn from the top row down. Only the last pixel of the top row remains, at the top of the frame.
+        let [top_left, _, _, bottom_left] = quads[0];
+        assert_eq!((top_left.position[1], top_left.tex_coords[1]), (97.0, 0.0));
+        assert_eq!(
+            (bottom_left.position[1], bottom_left.tex_coords[1]),
+            (96.0, 1.0 / 64.0)
+        );
+        // The bottom row was not trimmed, so it ends at the bottom of both the shape and the frame.
+        let [top_left, _, _, bottom_left] = quads[2];
+        assert_eq!(
+            (top_left.position[1], top_left.tex_coords[1]),
+            (4.0, 9.0 / 64.0)
+        );
+        assert_eq!(
+            (bottom_left.position[1], bottom_left.tex_coords[1]),
+            (0.0, 13.0 / 64.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn backgrounds_fill_their_widget() {
+        let region = loaded_region(texture_atlas::TextureRegionInformation {
+            frame: rect(0, 0, 4, 4),
+            rotated: false,
+            trimmed: false,
+            source: rect(0, 0, 4, 4),
+        })
+        .await;
+        // The background asks to be much smaller than the widget it is drawn behind.
+        let background = SpriteElement {
+            size: Size {
+                width: Dimension::Points(4.0),
+                height: Dimension::Points(4.0),
+            },
+            colour: Colour::WHITE,
+            texture_region: region,
+        };
+        let widget = Widget::new(
+            Block(100.0, 50.0),
+            Vec::new(),
+            vec![Box::new(background)],
+            Default::default(),
+        );
+        let ui = UI::new(widget, Size::undefined());
+
+        // The background is the first layer, underneath the widget's own element.
+        let background = match ui.generate_render_info(Point { x: 0.0, y: 0.0 }, None) {
+            MultiRenderable::Layered(mut layers) => layers.remove(0),
+            _ => panic!("expected the background to be layered under the element"),
+        };
+        let quads = image_region_quads(background);
+        let [top_left, _, bottom_right, _] = quads[0];
+        assert_eq!(&top_left.position[..2], &[0.0, 0.0]);
+        assert_eq!(&bottom_right.position[..2], &[100.0, -50.0]);
+    }
+
+    #[tokio::test]
+    async fn debug_borders_use_the_configured_thickness_and_colours() {
+        let mut texture_am = AssetManager::new(MissingTextureLoader);
+        let mut debug =
+            DebugDrawConfig::new(texture_am.get(AssetPath::new(vec!["white".to_string()])));
+        debug.thickness = 3.0;
+        debug.set_element_colour::<Block>(Colour::rgb(1.0, 0.0, 0.0));
+        let ui = UI::new(
+            Widget::new((), vec![block(30.0, 20.0)], Vec::new(), Default::default()),
+            Size::undefined(),
+        );
+
+        /// Collects the border quads in a renderable, children before their parents.
+        fn borders(renderable: MultiRenderable, result: &mut Vec<Vec<[Vertex; 4]>>) {
+            match renderable {
+                MultiRenderable::Adjacent(items) => {
+                    for item in items {
+                        borders(item, result);
+                    }
+                }
+                MultiRenderable::Image { renderables, .. } => result.push(
+                    renderables
+                        .into_iter()
+                        .map(|renderable| match renderable {
+                            Renderable::Quadrilateral(a, b, c, d) => [a, b, c, d],
+                            _ => panic!("borders should be drawn with quadrilaterals"),
+                        })
+                        .collect(),
+                ),
+                _ => {}
+            }
+        }
+        let mut result = Vec::new();
+        borders(
+            ui.generate_render_info(Point { x: 0.0, y: 0.0 }, Some(&debug)),
+            &mut result,
+        );
+        assert_eq!(result.len(), 2);
+        for border in &result {
+            let [left, right, top, bottom] = [border[0], border[1], border[2], border[3]];
+            assert_eq!(left[1].position[0] - left[0].position[0], 3.0);
+            assert_eq!(right[0].position[0] - right[1].position[0], 3.0);
+            assert_eq!(top[0].position[1] - top[1].position[1], 3.0);
+            assert_eq!(bottom[1].position[1] - bottom[0].position[1], 3.0);
+            // Every edge is drawn inside the 30x20 block.
+            assert!(border.iter().flatten().all(|vertex| {
+                (0.0..=30.0).contains(&vertex.position[0])
+                    && (-20.0..=0.0).contains(&vertex.position[1])
+            }));
+        }
+
+        // The block is coloured by type, and its unit type parent uses the default colour.
+        assert_eq!(result[0][0][0].color, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(result[1][0][0].color, [1.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[tokio::test]
+    async fn tiled_nine_patch_repeats_the_middle_slice() {
+        // A 24x24 nine-patch with 4 pixel margins, so the middle slice is 16 pixels wide.
+        let mut nine_patch = NinePatch::no_margins(
+            loaded_region(texture_atlas::TextureRegionInformation {
+                frame: rect(0, 0, 24, 24),
+                rotated: false,
+                trimmed: false,
+                source: rect(0, 0, 24, 24),
+            })
+            .await,
+        );
+        nine_patch.left_margin = 4;
+        nine_patch.right_margin = 4;
+        nine_patch.top_margin = 4;
+        nine_patch.bottom_margin = 4;
+        nine_patch.horizontal_fill = NinePatchFill::Tile;
+
+        // The middle column is 50 pixels wide, which fits three whole tiles and a 2 pixel partial tile.
+        let quads = image_region_quads(nine_patch.generate_render_info(
+            Colour::WHITE,
+            0.0,
+            0.0,
+            58.0,
+            24.0,
+        ));
+        assert_eq!(quads.len(), (1 + 4 + 1) * 3);
+        let left_edges: Vec<_> = quads
+            .iter()
+            .step_by(3)
+            .map(|[a, ..]| a.position[0])
+            .collect();
+        assert_eq!(left_edges, vec![0.0, 4.0, 20.0, 36.0, 52.0, 54.0]);
+
+        // Every tile starts at the start of the middle slice, and the last one is cut short.
+        let last_tile = &quads[4 * 3];
+        assert_eq!(last_tile[0].tex_coords[0], 4.0 / 64.0);
+        assert_eq!(last_tile[1].tex_coords[0], 6.0 / 64.0);
+        assert_eq!(last_tile[1].position[0], 54.0);
+    }
+
+    #[tokio::test]
+    async fn typesetting_text_forces_layout() {
+        let mut font_am = AssetManager::new(crate::assets::FontAssetLoader::default());
+        let font_family = regular_only_family(noto_sans_regular(&mut font_am).await);
+
+        let mut rich_text = crate::ui::RichText::new(Default::default());
+        let root = Widget::new(
+            (),
+            vec![rich_text.get_widget()],
+            Vec::new(),
+            Default::default(),
+        );
+        let ui = UI::new(root, Size::undefined());
+        ui.ui_status
+            .force_layout_signal
+            .store(false, Ordering::Relaxed);
+
+        rich_text
+            .set_text(font_family)
+            .write("hello")
+            .finish()
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(ui.needs_layout());
+    }
+
+    #[test]
+    fn children_added_later_can_force_layout() {
+        let root = Widget::new((), Vec::new(), Vec::new(), Default::default());
+        let ui = UI::new(root.clone(), Size::undefined());
+        let (child, grandchild) = (
+            Widget::new((), Vec::new(), Vec::new(), Default::default()),
+            Widget::new((), Vec::new(), Vec::new(), Default::default()),
+        );
+        let clear_signal = || {
+            ui.ui_status
+                .force_layout_signal
+                .store(false, Ordering::Relaxed)
+        };
+
+        // The grandchild is added before its parent is in the UI, so it only learns about the UI when its parent does.
+        child.0.write().unwrap().add_child(grandchild.clone());
+        root.0.write().unwrap().add_child(child.clone());
+        clear_signal();
+        grandchild.0.read().unwrap().force_layout();
+        assert!(ui.needs_layout());
+
+        let replacement = Widget::new((), Vec::new(), Vec::new(), Default::default());
+        child
+            .0
+            .write()
+            .unwrap()
+            .set_children(vec![replacement.clone()]);
+        clear_signal();
+        replacement.0.read().unwrap().force_layout();
+        assert!(ui.needs_layout());
+    }
+
+    #[test]
+    fn find_by_tag_searches_descendants() {
+        let leaf = block(10.0, 10.0);
+        leaf.0.write().unwrap().set_tag("leaf");
+        let root = Widget::new(
+            (),
+            vec![
+                block(10.0, 10.0),
+                Widget::new((), vec![leaf.clone()], Vec::new(), Default::default()),
+            ],
+            Vec::new(),
+            Default::default(),
+        );
+
+        let found = root.find_by_tag("leaf").unwrap();
+        assert_eq!(
+            found.0.read().unwrap().get_id(),
+            leaf.0.read().unwrap().get_id()
+        );
+        assert!(root.find_by_tag("missing").is_none());
+        assert!(format!("{:?}", root).contains("\"leaf\""));
+    }
+
+    #[test]
+    fn accessors_reflect_the_tree_built_by_new() {
+        let children = vec![block(10.0, 20.0), block(30.0, 40.0)];
+        let root = Widget::new((), children.clone(), Vec::new(), Default::default());
+        {
+            let contents = root.0.read().unwrap();
+            let ids: Vec<_> = contents
+                .get_children()
+                .iter()
+                .map(|child| child.0.read().unwrap().get_id())
+                .collect();
+            let expected: Vec<_> = children
+                .iter()
+                .map(|child| child.0.read().unwrap().get_id())
+                .collect();
+            assert_eq!(ids, expected);
+            assert_ne!(ids[0], ids[1]);
+            assert!(!ids.contains(&contents.get_id()));
+            assert!(contents.get_layout().is_none());
+        }
+
+        let ui = UI::new(root, GRID_SIZE);
+        ui.generate_render_info(Point { x: 0.0, y: 0.0 }, None);
+        let layout = children[1].0.read().unwrap().get_layout().unwrap();
+        assert_eq!(layout.location.x, 10.0);
+        assert_eq!((layout.size.width, layout.size.height), (30.0, 40.0));
+    }
+
+    #[test]
+    fn layout_is_reused_until_forced() {
+        let (root, leaves) = grid_tree();
+        let mut ui = UI::new(root, GRID_SIZE);
+        let leaf_width = || leaves[0].0.read().unwrap().layout.unwrap().size.width;
+        let origin = Point { x: 0.0, y: 0.0 };
+
+        ui.generate_render_info(origin, None);
+        assert_eq!(leaf_width(), 10.0);
+
+        // Nothing told the UI about this change, so the second render keeps the first render's layout.
+        leaves[0].0.write().unwrap().style.min_size.width = Dimension::Points(50.0);
+        ui.generate_render_info(origin, None);
+        assert_eq!(leaf_width(), 10.0);
+
+        leaves[0].0.read().unwrap().force_layout();
+        ui.generate_render_info(origin, None);
+        assert_eq!(leaf_width(), 50.0);
+
+        // Resizing the UI also lays it out again.
+        leaves[0].0.write().unwrap().style.min_size.width = Dimension::Points(20.0);
+        ui.update_size(GRID_SIZE);
+        ui.generate_render_info(origin, None);
+        assert_eq!(leaf_width(), 20.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn rendering_while_text_is_rebuilt() {
+        let mut font_am = AssetManager::new(crate::assets::FontAssetLoader::default());
+        let font_family = regular_only_family(noto_sans_regular(&mut font_am).await);
+
+        let rich_text = crate::ui::RichText::new(Default::default());
+        let root = Widget::new(
+            (),
+            vec![rich_text.get_widget()],
+            Vec::new(),
+            Default::default(),
+        );
+        let ui = UI::new(root, GRID_SIZE);
+
+        // A background task keeps replacing the text's widgets while the UI is laid out and rendered here.
+        let done = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let mut rich_text = rich_text.clone();
+            let done = Arc::clone(&done);
+            rich_text.set_debounce(std::time::Duration::from_millis(0));
+            tokio::spawn(async move {
+                for i in 0..50 {
+                    rich_text
+                        .set_text(Arc::clone(&font_family))
+                        .write(&format!("text {}", i))
+                        .finish()
+                        .await
+                        .unwrap()
+                        .unwrap();
+                }
+                done.store(true, Ordering::Relaxed);
+            })
+        };
+        while !done.load(Ordering::Relaxed) {
+            ui.generate_render_info(Point { x: 0.0, y: 0.0 }, None);
+            let _ = tokio::task::yield_now().await;
+        }
+        writer.await.unwrap();
+    }
+
+    /// A tree of 1111 widgets: ten columns, each with ten rows, each with ten fixed-size leaves.
+    /// Returns the root and the leaves.
+    fn grid_tree() -> (Widget, Vec<Widget>) {
+        // The size of a widget comes from its element, so the leaves' sizes are given as minimum sizes.
+        let sized = |size: f32| Style {
+            min_size: Size {
+                width: Dimension::Points(size),
+                height: Dimension::Points(size),
+            },
+            ..Default::default()
+        };
+        let mut leaves = Vec::new();
+        let columns = (0..10)
+            .map(|_| {
+                let rows = (0..10)
+                    .map(|_| {
+                        let row: Vec<_> = (0..10)
+                            .map(|_| Widget::new((), Vec::new(), Vec::new(), sized(10.0)))
+                            .collect();
+                        leaves.extend(row.iter().cloned());
+                        Widget::new((), row, Vec::new(), Default::default())
+                    })
+                    .collect();
+                Widget::new(
+                    (),
+                    rows,
+                    Vec::new(),
+                    Style {
+                        flex_direction: stretch::style::FlexDirection::Column,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        (
+            Widget::new((), columns, Vec::new(), Default::default()),
+            leaves,
+        )
+    }
+
+    /// The position and size of every widget in the tree, in depth-first order.
+    fn layouts(widget: &Widget) -> Vec<(Point<f32>, Size<f32>)> {
+        let read = widget.0.read().unwrap();
+        let layout = read.layout.unwrap();
+        let mut result = vec![(layout.location, layout.size)];
+        for child in &read.children {
+            result.extend(layouts(child));
+        }
+        result
+    }
+
+    const GRID_SIZE: Size<Number> = Size {
+        width: Number::Defined(1000.0),
+        height: Number::Defined(1000.0),
+    };
+
+    #[test]
+    fn incremental_layout_matches_full_layout() {
+        let (root, leaves) = grid_tree();
+        let ui = UI::new(root.clone(), GRID_SIZE);
+        ui.layout(GRID_SIZE);
+
+        // Resize one leaf, and remove a whole row.
+        leaves[0].0.write().unwrap().style.min_size.width = Dimension::Points(50.0);
+        let column = root.0.read().unwrap().children[1].clone();
+        column.0.write().unwrap().children.pop();
+        ui.layout(GRID_SIZE);
+        let incremental = layouts(&root);
+
+        LayoutTree::new().layout(&root, GRID_SIZE);
+        assert_eq!(incremental, layouts(&root));
+        assert_eq!(incremental[3].1.width, 50.0);
+    }
+
+    /// Compares laying out a large tree from scratch with laying it out incrementally after changing a single leaf.
+    /// Run with `cargo test --release -- --ignored --nocapture incremental_layout_benchmark`.
+    #[test]
+    #[ignore]
+    fn incremental_layout_benchmark() {
+        const ITERATIONS: u32 = 100;
+        let (root, leaves) = grid_tree();
+
+        let start = std::time::Instant::now();
+        for i in 0..ITERATIONS {
+            leaves[0].0.write().unwrap().style.min_size.width = Dimension::Points(i as f32);
+            LayoutTree::new().layout(&root, GRID_SIZE);
+        }
+        let full = start.elapsed() / ITERATIONS;
+
+        let mut tree = LayoutTree::new();
+        tree.layout(&root, GRID_SIZE);
+        let start = std::time::Instant::now();
+        for i in 0..ITERATIONS {
+            leaves[0].0.write().unwrap().style.min_size.width = Dimension::Points(i as f32);
+            tree.layout(&root, GRID_SIZE);
+        }
+        let incremental = start.elapsed() / ITERATIONS;
+
+        println!(
+            "{} widgets: full layout {:?}, incremental layout {:?}",
+            1 + 10 + 100 + leaves.len(),
+            full,
+            incremental
+        );
+    }
 }
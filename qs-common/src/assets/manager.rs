@@ -89,7 +89,10 @@ where
                 stored_assets.insert(id, Arc::clone(&data));
                 tokio::spawn(async move {
                     let k_clone = k.clone();
-                    let loaded = loader.load(k).await;
+                    let loaded = {
+                        let _guard = crate::profile::background_profiler().time("load asset");
+                        loader.load(k).await
+                    };
                     let mut data = data.write().await;
                     *data = AssetManager::<K, T, L>::on_load(k_clone, &mut *data, loaded);
                 });
@@ -97,6 +100,61 @@ where
             })
             .clone()
     }
+
+    /// Returns the keys of every asset this manager has been asked for, with whether each one has loaded yet.
+    /// Assets that another task is writing to at the moment, usually because they have just finished loading,
+    /// are reported as still loading, so this never waits.
+    pub fn statuses(&self) -> impl Iterator<Item = (&K, AssetState)> {
+        self.assets.iter().map(|(key, asset)| {
+            let state = asset
+                .data
+                .upgrade()
+                .and_then(|data| {
+                    futures::FutureExt::now_or_never(data.read()).map(|status| status.state())
+                })
+                .unwrap_or(AssetState::Loading);
+            (key, state)
+        })
+    }
+
+    /// Counts the assets in this manager by state, and estimates how much memory the loaded ones use.
+    /// Like `statuses`, this never waits.
+    pub fn stats(&self) -> AssetStats {
+        let mut stats = AssetStats {
+            total: self.assets.len(),
+            ..Default::default()
+        };
+        for data in self.stored_assets.values() {
+            match futures::FutureExt::now_or_never(data.read()).as_deref() {
+                Some(LoadStatus::Loaded(value)) => {
+                    stats.loaded += 1;
+                    stats.approx_bytes += self.asset_loader.approx_bytes(value);
+                }
+                Some(LoadStatus::Failed(_)) => stats.errored += 1,
+                Some(LoadStatus::Loading(_, _)) | None => stats.loading += 1,
+            }
+        }
+        stats
+    }
+}
+
+/// Whether an asset has finished loading, without the asset itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetState {
+    Loading,
+    Loaded,
+    Failed,
+}
+
+/// How many assets an asset manager holds in each state. See `AssetManager::stats`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AssetStats {
+    pub total: usize,
+    pub loaded: usize,
+    pub loading: usize,
+    pub errored: usize,
+    /// The sum of `Loader::approx_bytes` over every loaded asset.
+    pub approx_bytes: usize,
 }
 
 /// Represents an asset that is being loaded on a background thread.
@@ -182,6 +240,35 @@ impl<T> Asset<T> {
         }
     }
 
+    /// Like `if_loaded`, but never waits. If another task is currently writing to the asset, for example because it has
+    /// just finished loading, this acts as if the asset were not loaded yet. This is useful when rendering, because
+    /// frames should be drawn with whatever is ready rather than waiting for assets.
+    pub fn try_if_loaded(&self, func: impl FnOnce(&T)) -> bool {
+        if let Some(data) = self.data.upgrade() {
+            // Only take the read lock if it's free right now.
+            match futures::FutureExt::now_or_never(data.read()) {
+                Some(status) => match &*status {
+                    LoadStatus::Loaded(value) => {
+                        func(value);
+                        true
+                    }
+                    LoadStatus::Loading(_, _) | LoadStatus::Failed(_) => false,
+                },
+                None => false,
+            }
+        } else {
+            false
+        }
+    }
+
+    /// Returns whether the asset has loaded, without waiting. Returns `None` if the asset manager was dropped,
+    /// or if another task is writing to the asset right now.
+    pub fn try_state(&self) -> Option<AssetState> {
+        let data = self.data.upgrade()?;
+        let status = futures::FutureExt::now_or_never(data.read())?;
+        Some(status.state())
+    }
+
     /// Waits for the asset to be loaded (or until the load fails).
     pub async fn wait_until_loaded_or_failed(&self) {
         let (tx, mut rx) = tokio::sync::mpsc::channel(1);
@@ -229,6 +316,16 @@ pub enum LoadStatus<T> {
     Failed(LoadError),
 }
 
+impl<T> LoadStatus<T> {
+    pub fn state(&self) -> AssetState {
+        match self {
+            LoadStatus::Loading(_, _) => AssetState::Loading,
+            LoadStatus::Loaded(_) => AssetState::Loaded,
+            LoadStatus::Failed(_) => AssetState::Failed,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum LoadError {
     /// The file that the asset is contained within could not be found.
@@ -237,6 +334,9 @@ pub enum LoadError {
     FileNotReadable,
     /// The provided asset data, for example the contents of a file, were invalid.
     InvalidData,
+    /// The key asked for a face of a font collection that the collection does not contain.
+    /// Faces are numbered from zero, and `count` is the number of faces in the file.
+    FaceIndexOutOfRange { index: u32, count: u32 },
     /// This should never be seen. This indicates that the asset has been loaded twice.
     MultiplyLoaded,
 }
@@ -247,6 +347,12 @@ pub enum LoadError {
 #[async_trait::async_trait]
 pub trait Loader<K, T> {
     async fn load(&self, key: K) -> Result<T, LoadError>;
+
+    /// Estimates how many bytes a loaded asset occupies, for `AssetManager::stats`.
+    /// Loaders that don't know report zero.
+    fn approx_bytes(&self, _asset: &T) -> usize {
+        0
+    }
 }
 
 /// Represents an asset that owns its data. Use this when you need to pass in an asset,
@@ -277,3 +383,92 @@ impl<T> std::ops::Deref for OwnedAsset<T> {
         &self.asset
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A loader whose assets never finish loading.
+    struct SlowLoader;
+
+    #[async_trait::async_trait]
+    impl Loader<u32, u32> for SlowLoader {
+        async fn load(&self, _key: u32) -> Result<u32, LoadError> {
+            std::future::pending().await
+        }
+    }
+
+    /// A loader that loads even keys straight away, and fails to load odd keys.
+    /// Keys above 100 never finish loading.
+    struct ParityLoader;
+
+    #[async_trait::async_trait]
+    impl Loader<u32, u32> for ParityLoader {
+        async fn load(&self, key: u32) -> Result<u32, LoadError> {
+            if key > 100 {
+                std::future::pending().await
+            } else if key % 2 == 1 {
+                Err(LoadError::InvalidData)
+            } else {
+                Ok(key)
+            }
+        }
+
+        fn approx_bytes(&self, asset: &u32) -> usize {
+            *asset as usize
+        }
+    }
+
+    #[tokio::test]
+    async fn stats_count_loaded_failed_and_loading_assets() {
+        let mut manager = AssetManager::new(ParityLoader);
+        for key in &[2, 4, 5, 200] {
+            manager.get(*key);
+        }
+        manager.get(2).wait_until_loaded().await;
+        manager.get(4).wait_until_loaded().await;
+        manager.get(5).wait_until_loaded_or_failed().await;
+
+        assert_eq!(
+            manager.stats(),
+            AssetStats {
+                total: 4,
+                loaded: 2,
+                loading: 1,
+                errored: 1,
+                approx_bytes: 6,
+            }
+        );
+        let mut statuses = manager
+            .statuses()
+            .map(|(key, state)| (*key, state))
+            .collect::<Vec<_>>();
+        statuses.sort_by_key(|(key, _)| *key);
+        assert_eq!(
+            statuses,
+            vec![
+                (2, AssetState::Loaded),
+                (4, AssetState::Loaded),
+                (5, AssetState::Failed),
+                (200, AssetState::Loading),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn try_if_loaded_never_waits() {
+        let mut manager = AssetManager::new(SlowLoader);
+        let asset = manager.get(7);
+        assert!(!asset.try_if_loaded(|_| panic!("the asset should still be loading")));
+
+        let loaded = OwnedAsset::new(7);
+        let mut value = None;
+        assert!(loaded.try_if_loaded(|v| value = Some(*v)));
+        assert_eq!(value, Some(7));
+
+        // While something else is writing to the asset, it is treated as not loaded rather than waited for.
+        let data = loaded.data.upgrade().unwrap();
+        let _guard = data.write().await;
+        assert!(!loaded.try_if_loaded(|_| {}));
+    }
+}
@@ -1,4 +1,7 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
 use tokio::fs::File;
 use tokio::io::BufReader;
 
@@ -8,7 +11,8 @@ pub struct AssetPath {
     segments: Vec<String>,
 }
 
-impl std::fmt::Debug for AssetPath {
+/// Writes the path with its segments separated by `/`, like `ui/white.png`. This can be parsed back with `FromStr`.
+impl std::fmt::Display for AssetPath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         for (segment, i) in self.segments.iter().zip(0..) {
             if i != 0 {
@@ -20,49 +24,346 @@ impl std::fmt::Debug for AssetPath {
     }
 }
 
+impl std::fmt::Debug for AssetPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
 use lazy_static::lazy_static;
 lazy_static! {
-    static ref ASSET_FOLDER: PathBuf = find_folder::Search::Kids(3)
-        .for_folder("assets")
-        .expect("Could not find asset dir");
+    static ref ASSET_FOLDER: RwLock<Option<PathBuf>> = RwLock::new(None);
 }
 
-impl AssetPath {
-    /// Creates a path from a list of segments. Segments like `..` and `.` are supported.
-    pub fn new(segments: Vec<String>) -> Self {
-        let mut new_segments = Vec::new();
-
-        for segment in segments {
-            match segment.as_str() {
-                "." => {}
-                ".." => {
-                    if new_segments.is_empty() {
-                        panic!("Could not parse path, use of `..` would escape asset directory");
-                    } else {
-                        new_segments.pop();
-                    }
-                }
-                _ => {
-                    new_segments.push(segment);
-                }
+/// Sets the directory that asset paths are relative to, such as a path relative to where the game is installed.
+/// Paths that have already been resolved are not affected.
+pub fn set_asset_root(root: impl Into<PathBuf>) {
+    *ASSET_FOLDER.write().unwrap() = Some(root.into());
+}
+
+/// Returns the directory that asset paths are relative to.
+/// Unless `set_asset_root` has been called, this is the first `assets` folder found near the working directory.
+pub fn asset_root() -> PathBuf {
+    if let Some(root) = ASSET_FOLDER.read().unwrap().as_ref() {
+        return root.clone();
+    }
+    ASSET_FOLDER
+        .write()
+        .unwrap()
+        .get_or_insert_with(|| {
+            find_folder::Search::Kids(3)
+                .for_folder("assets")
+                .expect("Could not find asset dir")
+        })
+        .clone()
+}
+
+static CASE_INSENSITIVE: AtomicBool = AtomicBool::new(false);
+
+/// If enabled, asset paths that don't exist fall back to a file whose name differs only in case, with a warning.
+/// This finds assets that would load on Windows or macOS but not on Linux, without failing to load them.
+/// It is disabled by default, so that typos in asset names are not hidden.
+pub fn set_case_insensitive_paths(enabled: bool) {
+    CASE_INSENSITIVE.store(enabled, Ordering::Relaxed);
+}
+
+/// Why a string could not be parsed as an asset path.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AssetPathError {
+    /// Asset paths are relative to the `assets` directory, so they can't start with `/`.
+    Absolute,
+    /// Asset paths are separated with `/` on every platform.
+    Backslash,
+    /// Paths can't contain empty segments, like `ui//white.png`.
+    EmptySegment,
+    /// Too many `..` segments would have left the `assets` directory.
+    EscapesAssetFolder,
+}
+
+/// Resolves `.` and `..` segments, returning `None` if a `..` would escape the asset directory.
+fn normalise(segments: Vec<String>) -> Option<Vec<String>> {
+    let mut new_segments = Vec::new();
+
+    for segment in segments {
+        match segment.as_str() {
+            "." => {}
+            ".." => {
+                new_segments.pop()?;
+            }
+            _ => {
+                new_segments.push(segment);
             }
         }
+    }
+
+    Some(new_segments)
+}
 
-        AssetPath {
-            segments: new_segments,
+impl AssetPath {
+    /// Creates a path from a list of segments. Segments like `..` and `.` are supported.
+    pub fn new(segments: Vec<String>) -> Self {
+        match normalise(segments) {
+            Some(segments) => AssetPath { segments },
+            None => panic!("Could not parse path, use of `..` would escape asset directory"),
         }
     }
 
     pub fn to_path(&self) -> PathBuf {
-        let mut path = ASSET_FOLDER.clone();
+        let mut path = asset_root();
         for segment in &self.segments {
             path.push(segment);
         }
         path
     }
 
+    /// Finds the file this path refers to, following any symbolic links.
+    /// Segments containing separators, or links pointing elsewhere, could still lead out of the `assets` directory,
+    /// so this returns a `PermissionDenied` error unless the file really is inside it.
+    pub fn resolve(&self) -> std::io::Result<PathBuf> {
+        let root = asset_root();
+        match resolve_within(&root, &self.to_path()) {
+            Err(error)
+                if error.kind() == std::io::ErrorKind::NotFound
+                    && CASE_INSENSITIVE.load(Ordering::Relaxed) =>
+            {
+                let path = find_ignoring_case(&root, &self.segments).ok_or(error)?;
+                tracing::warn!(
+                    "asset {:?} was found at {} with different case",
+                    self,
+                    path.display()
+                );
+                resolve_within(&root, &path)
+            }
+            result => result,
+        }
+    }
+
     pub async fn read_file(&self) -> std::io::Result<BufReader<File>> {
-        let f = File::open(self.to_path()).await?;
+        let path = self.clone();
+        let path = tokio::task::spawn_blocking(move || path.resolve())
+            .await
+            .expect("asset path resolution task panicked")?;
+        let f = File::open(path).await?;
         Ok(BufReader::new(f))
     }
 }
+
+/// Canonicalises `path`, checking that it ends up inside `root`.
+fn resolve_within(root: &Path, path: &Path) -> std::io::Result<PathBuf> {
+    let root = root.canonicalize()?;
+    let path = path.canonicalize()?;
+    if path.starts_with(&root) {
+        Ok(path)
+    } else {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!("{} is outside the asset directory", path.display()),
+        ))
+    }
+}
+
+/// Looks for a file under `root` whose segments match the given ones, ignoring case.
+/// Segments that exist with the exact case given are preferred.
+fn find_ignoring_case(root: &Path, segments: &[String]) -> Option<PathBuf> {
+    let mut path = root.to_path_buf();
+    for segment in segments {
+        let exact = path.join(segment);
+        if exact.exists() {
+            path = exact;
+            continue;
+        }
+        let segment = segment.to_lowercase();
+        path = std::fs::read_dir(&path)
+            .ok()?
+            .filter_map(Result::ok)
+            .find(|entry| entry.file_name().to_string_lossy().to_lowercase() == segment)?
+            .path();
+    }
+    Some(path)
+}
+
+/// Parses a path like `ui/white.png`, in the same form that the `Display` implementation writes.
+impl FromStr for AssetPath {
+    type Err = AssetPathError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Err(AssetPathError::Absolute);
+        }
+        if s.contains('\\') {
+            return Err(AssetPathError::Backslash);
+        }
+        let segments: Vec<String> = s.split('/').map(str::to_string).collect();
+        if segments.iter().any(String::is_empty) {
+            return Err(AssetPathError::EmptySegment);
+        }
+        normalise(segments)
+            .map(|segments| AssetPath { segments })
+            .ok_or(AssetPathError::EscapesAssetFolder)
+    }
+}
+
+impl serde::Serialize for AssetPath {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for AssetPath {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|error| {
+            serde::de::Error::custom(format!("invalid asset path {:?}: {:?}", s, error))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    lazy_static! {
+        static ref SETTINGS_LOCK: Mutex<()> = Mutex::new(());
+    }
+
+    /// Lets a test change the global asset settings, restoring them when it is dropped.
+    /// Only one test can hold a guard at a time, so tests never see each other's settings.
+    struct SettingsGuard {
+        root: Option<PathBuf>,
+        case_insensitive: bool,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl SettingsGuard {
+        fn new() -> Self {
+            // A test that failed while holding the lock has still restored the settings.
+            let lock = SETTINGS_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+            Self {
+                root: ASSET_FOLDER.read().unwrap().clone(),
+                case_insensitive: CASE_INSENSITIVE.load(Ordering::Relaxed),
+                _lock: lock,
+            }
+        }
+    }
+
+    impl Drop for SettingsGuard {
+        fn drop(&mut self) {
+            *ASSET_FOLDER.write().unwrap() = self.root.take();
+            CASE_INSENSITIVE.store(self.case_insensitive, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn parsing_normalises_and_round_trips() {
+        let path: AssetPath = "ui/../ui/./white.png".parse().unwrap();
+        assert_eq!(
+            path,
+            AssetPath::new(vec!["ui".to_string(), "white.png".to_string()])
+        );
+
+        assert_eq!(path.to_string(), "ui/white.png");
+        assert_eq!(path.to_string().parse::<AssetPath>().unwrap(), path);
+
+        let json = serde_json::to_string(&path).unwrap();
+        assert_eq!(json, r#""ui/white.png""#);
+        assert_eq!(serde_json::from_str::<AssetPath>(&json).unwrap(), path);
+    }
+
+    #[test]
+    fn parsing_rejects_paths_outside_the_asset_folder() {
+        let parse = |s: &str| s.parse::<AssetPath>().err();
+        assert_eq!(parse("/etc/passwd"), Some(AssetPathError::Absolute));
+        assert_eq!(parse("ui\\white.png"), Some(AssetPathError::Backslash));
+        assert_eq!(parse("ui//white.png"), Some(AssetPathError::EmptySegment));
+        assert_eq!(
+            parse("ui/../../secret"),
+            Some(AssetPathError::EscapesAssetFolder)
+        );
+        assert!(serde_json::from_str::<AssetPath>(r#""../secret""#).is_err());
+    }
+
+    #[test]
+    fn paths_are_relative_to_the_configured_root() {
+        let path: AssetPath = "ui/white.png".parse().unwrap();
+        let settings = SettingsGuard::new();
+        set_asset_root("/opt/quest-sage/assets");
+        assert_eq!(
+            path.to_path(),
+            Path::new("/opt/quest-sage/assets/ui/white.png")
+        );
+        // The root is put back once the test is done with it.
+        let root = ASSET_FOLDER.read().unwrap().clone();
+        drop(settings);
+        assert_ne!(*ASSET_FOLDER.read().unwrap(), root);
+    }
+
+    #[test]
+    fn resolving_outside_the_root_is_denied() {
+        let dir = std::env::temp_dir().join(format!("qs-asset-path-{}", std::process::id()));
+        let root = dir.join("assets");
+        std::fs::create_dir_all(root.join("ui")).unwrap();
+        std::fs::write(root.join("inside.txt"), "").unwrap();
+        std::fs::write(dir.join("outside.txt"), "").unwrap();
+
+        let inside = resolve_within(&root, &root.join("inside.txt"));
+        // A segment containing a separator can climb out of the root without using a `..` segment.
+        let escaped = resolve_within(&root, &root.join("ui/../../outside.txt"));
+        #[cfg(unix)]
+        let linked = {
+            std::os::unix::fs::symlink(dir.join("outside.txt"), root.join("link.txt")).unwrap();
+            resolve_within(&root, &root.join("link.txt"))
+        };
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(inside.is_ok());
+        assert_eq!(
+            escaped.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        #[cfg(unix)]
+        assert_eq!(
+            linked.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+    }
+
+    #[test]
+    fn mis_cased_paths_are_found_ignoring_case() {
+        let root = std::env::temp_dir().join(format!("qs-asset-case-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("ui")).unwrap();
+        std::fs::write(root.join("ui/white.png"), "").unwrap();
+
+        let segments = vec!["UI".to_string(), "White.png".to_string()];
+        let found = find_ignoring_case(&root, &segments);
+        let missing = find_ignoring_case(&root, &["ui".to_string(), "black.png".to_string()]);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(found, Some(root.join("ui").join("white.png")));
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn resolving_falls_back_to_mis_cased_files_when_enabled() {
+        let root =
+            std::env::temp_dir().join(format!("qs-asset-resolve-case-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("ui")).unwrap();
+        std::fs::write(root.join("ui/white.png"), "white").unwrap();
+        let path: AssetPath = "UI/White.png".parse().unwrap();
+
+        let settings = SettingsGuard::new();
+        set_asset_root(&root);
+        let case_sensitive = path.resolve();
+        set_case_insensitive_paths(true);
+        let case_insensitive = path.resolve().and_then(std::fs::read_to_string);
+        drop(settings);
+        std::fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            case_sensitive.unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+        assert_eq!(case_insensitive.unwrap(), "white");
+        assert!(!CASE_INSENSITIVE.load(Ordering::Relaxed));
+    }
+}
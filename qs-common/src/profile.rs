@@ -1,21 +1,27 @@
 //! The `profile` module provides utilities for profiling often-called functions.
 
 use std::collections::HashMap;
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
 pub type TaskName = &'static str;
 
+/// Formats a number of seconds with one decimal place, in whichever of seconds, milliseconds, microseconds
+/// or nanoseconds gives a value of at least one, such as `1.2ms`.
 pub fn display_time(seconds: f64) -> String {
-    let (time, time_unit) = if seconds >= 1.0 {
-        (seconds, " ")
-    } else if seconds >= 0.001 {
-        (seconds * 1000.0, "m")
-    } else if seconds >= 0.000_001 {
-        (seconds * 1_000_000.0, "\u{03BC}") // micro SI prefix (Greek lowercase mu)
-    } else {
-        (seconds * 1_000_000_000.0, "n")
-    };
-    format!("{:3}{}s", time as u32, time_unit)
+    let units = [
+        (1.0, ""),
+        (1000.0, "m"),
+        (1_000_000.0, "\u{03BC}"), // micro SI prefix (Greek lowercase mu)
+        (1_000_000_000.0, "n"),
+    ];
+    // Choose the unit by the rounded value, so that 999.96 milliseconds is shown as `1.0s` rather than `1000.0ms`.
+    let (time, time_unit) = units
+        .iter()
+        .map(|(factor, unit)| (seconds * factor, *unit))
+        .find(|(time, _)| (time * 10.0).round() >= 10.0)
+        .unwrap_or((seconds * 1_000_000_000.0, "n"));
+    format!("{:.1}{}s", time, time_unit)
 }
 
 /// Allows profiling of events that happen repeatedly in a roughly predictable manner.
@@ -28,6 +34,9 @@ pub struct CycleProfiler {
     /// Times between iterations of the main segment to
     /// deduce how much time was spent not actually doing profiled stuff.
     pub stopwatch: InterpolatedStopwatch,
+
+    /// When `pause` was called, if the profiler is paused.
+    paused_at: Option<Instant>,
 }
 
 impl CycleProfiler {
@@ -35,8 +44,65 @@ impl CycleProfiler {
         Self {
             main_segment: ProfileSegment::new(interpolation_amount),
             stopwatch: InterpolatedStopwatch::new(interpolation_amount),
+            paused_at: None,
         }
     }
+
+    /// Stops recording times, for example while a loading screen is shown or the window is unfocused,
+    /// so that the averages only describe normal frames. While paused, the stopwatch ignores ticks
+    /// and timing any segment or task records nothing.
+    pub fn pause(&mut self) {
+        if self.paused_at.is_none() {
+            self.paused_at = Some(Instant::now());
+            self.stopwatch.paused = true;
+            self.main_segment.set_paused(true);
+        }
+    }
+
+    /// Starts recording times again. The time spent paused is not counted between the stopwatch's ticks.
+    pub fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.stopwatch.paused = false;
+            self.stopwatch.skip(paused_at.elapsed());
+            self.main_segment.set_paused(false);
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Copies the average times recorded so far, so that they can be displayed
+    /// without borrowing the profiler while it is timing something else.
+    pub fn snapshot(&self) -> ProfileSnapshot {
+        let mut segments = Vec::new();
+        self.main_segment.snapshot_sub_tasks(0, &mut segments);
+        ProfileSnapshot {
+            total_time: self.stopwatch.average_time().as_secs_f64(),
+            calculation_time: self.main_segment.average_time(),
+            segments,
+        }
+    }
+}
+
+/// The average times recorded by a `CycleProfiler` at some point in time. All times are in seconds.
+#[derive(Debug, Clone, Default)]
+pub struct ProfileSnapshot {
+    /// The average time between iterations of the main segment.
+    pub total_time: f64,
+    /// The average time spent inside the main segment.
+    pub calculation_time: f64,
+    /// Every task inside the main segment, in the same order as the profiler's `Display` output,
+    /// except that the tasks at each level are sorted so that the slowest task comes first.
+    pub segments: Vec<SegmentSnapshot>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SegmentSnapshot {
+    pub name: TaskName,
+    /// How many tasks this task is nested inside, not counting the main segment.
+    pub depth: usize,
+    pub average_time: f64,
 }
 
 impl std::fmt::Display for CycleProfiler {
@@ -67,6 +133,8 @@ pub struct ProfileSegment {
     durations_seconds: Vec<f64>,
     offset: usize,
     pub ticks: u64,
+    /// If true, durations are not recorded. Sub-tasks are paused along with their parents.
+    paused: bool,
 }
 
 impl ProfileSegment {
@@ -77,6 +145,14 @@ impl ProfileSegment {
             durations_seconds: vec![1.0; interpolation_amount],
             offset: 0,
             ticks: 0,
+            paused: false,
+        }
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        for task in self.sub_tasks.values_mut() {
+            task.set_paused(paused);
         }
     }
 
@@ -101,6 +177,45 @@ impl ProfileSegment {
         Ok(())
     }
 
+    fn snapshot_sub_tasks(&self, depth: usize, segments: &mut Vec<SegmentSnapshot>) {
+        let mut sub_tasks = self
+            .sub_tasks
+            .iter()
+            .map(|(name, task)| (*name, task, task.average_time()))
+            .collect::<Vec<_>>();
+        sub_tasks.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap_or(std::cmp::Ordering::Equal));
+        for (name, task, average_time) in sub_tasks {
+            segments.push(SegmentSnapshot {
+                name,
+                depth,
+                average_time,
+            });
+            task.snapshot_sub_tasks(depth + 1, segments);
+        }
+    }
+
+    /// Lists every task nested inside this segment, at any depth, together with its recorded times.
+    /// Tasks are sorted by their paths, so each task comes just after its parent.
+    pub fn timings(&self) -> Vec<SegmentTiming> {
+        let mut timings = Vec::new();
+        self.collect_timings(&mut Vec::new(), &mut timings);
+        timings.sort_by(|a, b| a.path.cmp(&b.path));
+        timings
+    }
+
+    fn collect_timings(&self, path: &mut Vec<TaskName>, timings: &mut Vec<SegmentTiming>) {
+        for (name, task) in &self.sub_tasks {
+            path.push(name);
+            timings.push(SegmentTiming {
+                path: path.clone(),
+                average_time: task.average_time(),
+                ticks: task.ticks,
+            });
+            task.collect_timings(path, timings);
+            path.pop();
+        }
+    }
+
     /// Call this function every time the given event happens, supplying the duration of the interval.
     fn tick(&mut self, duration: f64) {
         self.durations_seconds[self.offset] = duration;
@@ -121,6 +236,17 @@ impl ProfileSegment {
     }
 }
 
+/// The times recorded for one task in a profile. See `ProfileSegment::timings`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentTiming {
+    /// The names of the tasks this task is nested inside, outermost first, followed by the name of this task.
+    pub path: Vec<TaskName>,
+    /// In seconds.
+    pub average_time: f64,
+    /// How many times the task has been timed.
+    pub ticks: u64,
+}
+
 impl std::fmt::Display for ProfileSegment {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         self.display(f, 0)
@@ -136,6 +262,9 @@ pub struct ProfileSegmentGuard<'a> {
 
 impl Drop for ProfileSegmentGuard<'_> {
     fn drop(&mut self) {
+        if self.segment.paused {
+            return;
+        }
         self.segment.tick(
             Instant::now()
                 .duration_since(self.start_instant)
@@ -149,57 +278,410 @@ impl<'a> ProfileSegmentGuard<'a> {
     /// Currently they're treated as separate iterations but that'll mess up the percentage counts.
     pub fn task(&mut self, name: TaskName) -> &mut ProfileSegment {
         let interpolation_amount = self.segment.interpolation_amount;
-        self.segment
-            .sub_tasks
-            .entry(name)
-            .or_insert_with(|| ProfileSegment::new(interpolation_amount))
+        let paused = self.segment.paused;
+        self.segment.sub_tasks.entry(name).or_insert_with(|| {
+            let mut task = ProfileSegment::new(interpolation_amount);
+            task.paused = paused;
+            task
+        })
+    }
+}
+
+/// Like a `ProfileSegment`, but tasks may be timed from any thread, including `tokio` worker threads.
+/// Each thread's tasks are recorded separately, and are shown together when the profiler is displayed.
+/// Unlike `ProfileSegment`, this can be shared between threads, so recording a task briefly takes a lock.
+pub struct ThreadProfiler {
+    interpolation_amount: usize,
+    /// The tasks timed on each thread, keyed by the thread's name and ID.
+    threads: Mutex<HashMap<String, ProfileSegment>>,
+}
+
+impl ThreadProfiler {
+    pub fn new(interpolation_amount: usize) -> Self {
+        Self {
+            interpolation_amount,
+            threads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Starts timing a task on the current thread. When the guard is dropped, its duration is recorded.
+    pub fn time(&self, name: TaskName) -> ThreadSegmentGuard<'_> {
+        ThreadSegmentGuard {
+            profiler: self,
+            path: vec![name],
+            start_instant: Instant::now(),
+        }
+    }
+
+    /// Copies the average times recorded so far on each thread, sorted by thread name.
+    pub fn snapshot(&self) -> Vec<ThreadSnapshot> {
+        let threads = self.threads.lock().unwrap();
+        let mut snapshots = threads
+            .iter()
+            .map(|(thread, segment)| {
+                let mut segments = Vec::new();
+                segment.snapshot_sub_tasks(0, &mut segments);
+                ThreadSnapshot {
+                    thread: thread.clone(),
+                    segments,
+                }
+            })
+            .collect::<Vec<_>>();
+        snapshots.sort_by(|a, b| a.thread.cmp(&b.thread));
+        snapshots
+    }
+
+    fn record(&self, path: &[TaskName], duration: f64) {
+        let current = std::thread::current();
+        let thread = format!("{} {:?}", current.name().unwrap_or("unnamed"), current.id());
+        let interpolation_amount = self.interpolation_amount;
+        let mut threads = self.threads.lock().unwrap();
+        let mut segment = threads
+            .entry(thread)
+            .or_insert_with(|| ProfileSegment::new(interpolation_amount));
+        for name in path {
+            segment = segment
+                .sub_tasks
+                .entry(name)
+                .or_insert_with(|| ProfileSegment::new(interpolation_amount));
+        }
+        segment.tick(duration);
+    }
+}
+
+impl std::fmt::Display for ThreadProfiler {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for snapshot in self.snapshot() {
+            writeln!(f, "{}:", snapshot.thread)?;
+            for segment in snapshot.segments {
+                writeln!(
+                    f,
+                    "{:indent$}{}: {}",
+                    "",
+                    display_time(segment.average_time),
+                    segment.name,
+                    indent = 4 * (segment.depth + 1)
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The average times recorded by a `ThreadProfiler` on one thread.
+#[derive(Debug, Clone)]
+pub struct ThreadSnapshot {
+    /// The thread's name and ID.
+    pub thread: String,
+    pub segments: Vec<SegmentSnapshot>,
+}
+
+/// Times a task for a `ThreadProfiler`. When dropped, the duration of this struct's life
+/// is recorded on the thread that drops it.
+pub struct ThreadSegmentGuard<'a> {
+    profiler: &'a ThreadProfiler,
+    /// The names of this task and the tasks it is nested inside, outermost first.
+    path: Vec<TaskName>,
+    start_instant: Instant,
+}
+
+impl ThreadSegmentGuard<'_> {
+    /// Starts timing a task nested inside this one.
+    pub fn task(&self, name: TaskName) -> Self {
+        let mut path = self.path.clone();
+        path.push(name);
+        Self {
+            profiler: self.profiler,
+            path,
+            start_instant: Instant::now(),
+        }
     }
 }
 
+impl Drop for ThreadSegmentGuard<'_> {
+    fn drop(&mut self) {
+        self.profiler
+            .record(&self.path, self.start_instant.elapsed().as_secs_f64());
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref BACKGROUND_PROFILER: ThreadProfiler = ThreadProfiler::new(25);
+}
+
+/// A profiler for work done on background tasks, such as typesetting and loading assets.
+pub fn background_profiler() -> &'static ThreadProfiler {
+    &BACKGROUND_PROFILER
+}
+
 /// An interpolated stopwatch counts the time between successive events, and calculates the average
 /// time between those events, by storing the times of the last `n` events, where `n` is some arbitrary
 /// constant specified in the stopwatch constructor.
+///
+/// The time the stopwatch was created counts as the first event, so every tick measures one interval.
 pub struct InterpolatedStopwatch {
     times: Vec<Instant>,
     offset: usize,
     pub ticks: u64,
+    /// If true, ticks are ignored. See `CycleProfiler::pause`.
+    paused: bool,
+    smoothing: Smoothing,
+    /// The exponential moving average of the intervals so far, in seconds, if `smoothing` is `Exponential`.
+    moving_average: Option<f64>,
+}
+
+/// How an `InterpolatedStopwatch` averages the intervals between its ticks.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Smoothing {
+    /// The mean of the most recent intervals, as many as the stopwatch stores.
+    Window,
+    /// An exponential moving average, where each new interval has the given weight, between 0 and 1.
+    /// Higher weights follow changes more quickly, and lower weights are smoother.
+    Exponential(f64),
 }
 
 impl InterpolatedStopwatch {
     pub fn new(interpolation_amount: usize) -> InterpolatedStopwatch {
+        Self::starting_at(interpolation_amount, Instant::now())
+    }
+
+    /// Creates a stopwatch whose first interval is measured from the given time.
+    pub fn starting_at(interpolation_amount: usize, start: Instant) -> InterpolatedStopwatch {
         InterpolatedStopwatch {
-            times: vec![Instant::now(); interpolation_amount],
+            times: vec![start; interpolation_amount],
             offset: 0,
             ticks: 0,
+            paused: false,
+            smoothing: Smoothing::Window,
+            moving_average: None,
         }
     }
 
+    /// Changes how intervals are averaged. Switching to exponential smoothing starts a new average from the next tick.
+    pub fn set_smoothing(&mut self, smoothing: Smoothing) {
+        self.smoothing = smoothing;
+        self.moving_average = None;
+    }
+
     /// Call this function every time the given event happens.
     /// You will be able to retrieve the average time between calls to `tick`
     /// using the `average_time` function.
     ///
     /// Returns the time between the previous tick and this tick.
     pub fn tick(&mut self) -> Duration {
+        self.tick_at(Instant::now())
+    }
+
+    /// Like `tick`, but the event is recorded as happening at the given time instead of now.
+    pub fn tick_at(&mut self, now: Instant) -> Duration {
+        if self.paused {
+            return Duration::from_secs(0);
+        }
         let prev_offset = match self.offset {
             0 => self.times.len() - 1,
             _ => self.offset - 1,
         };
 
-        self.times[self.offset] = Instant::now();
+        self.times[self.offset] = now;
         let old_time = self.times[prev_offset];
         let time = self.times[self.offset].duration_since(old_time);
         self.offset = (self.offset + 1) % self.times.len();
         self.ticks += 1;
+        if let Smoothing::Exponential(weight) = self.smoothing {
+            let seconds = time.as_secs_f64();
+            self.moving_average = Some(match self.moving_average {
+                Some(average) => weight * seconds + (1.0 - weight) * average,
+                None => seconds,
+            });
+        }
         time
     }
 
+    /// Moves every recorded tick later by the given amount, as if that time had never passed.
+    fn skip(&mut self, duration: Duration) {
+        for time in &mut self.times {
+            *time += duration;
+        }
+    }
+
+    /// The average interval between ticks, or zero if the stopwatch hasn't been ticked yet.
+    /// Before the stopwatch has been ticked as many times as it can store, only the intervals so far are averaged.
     pub fn average_time(&self) -> Duration {
-        let prev_offset = match self.offset {
-            0 => self.times.len() - 1,
-            _ => self.offset - 1,
-        };
-        self.times[prev_offset]
-            .duration_since(self.times[self.offset])
-            .div_f64(self.times.len() as f64)
+        if let Smoothing::Exponential(_) = self.smoothing {
+            return Duration::from_secs_f64(self.moving_average.unwrap_or(0.0));
+        }
+
+        // Storing `n` times gives us `n - 1` intervals.
+        let len = self.times.len();
+        let intervals = self.ticks.min(len as u64 - 1) as usize;
+        if intervals == 0 {
+            return Duration::from_secs(0);
+        }
+        let newest = (self.offset + len - 1) % len;
+        let oldest = (newest + len - intervals) % len;
+        self.times[newest]
+            .duration_since(self.times[oldest])
+            .div_f64(intervals as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times_are_shown_in_the_largest_whole_unit() {
+        assert_eq!(display_time(0.0), "0.0ns");
+        assert_eq!(display_time(0.000_000_000_5), "0.5ns");
+        assert_eq!(display_time(0.000_000_25), "250.0ns");
+        assert_eq!(display_time(0.000_001_2), "1.2\u{03BC}s");
+        assert_eq!(display_time(0.000_999_99), "1.0ms");
+        assert_eq!(display_time(0.0012), "1.2ms");
+        assert_eq!(display_time(0.016), "16.0ms");
+        assert_eq!(display_time(1.25), "1.2s");
+        // Longer durations are still shown in seconds.
+        assert_eq!(display_time(3.0 * 60.0 * 60.0), "10800.0s");
+    }
+
+    #[test]
+    fn timings_follow_the_task_tree() {
+        let mut profiler = CycleProfiler::new(4);
+        for _ in 0..3 {
+            let mut main_segment = profiler.main_segment.time();
+            {
+                let mut render = main_segment.task("render").time();
+                let _ui = render.task("ui").time();
+            }
+            let _update = main_segment.task("update").time();
+        }
+
+        let timings = profiler.main_segment.timings();
+        let paths = timings
+            .iter()
+            .map(|timing| (timing.path.clone(), timing.ticks))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                (vec!["render"], 3),
+                (vec!["render", "ui"], 3),
+                (vec!["update"], 3),
+            ]
+        );
+        assert_eq!(
+            timings[0].average_time,
+            profiler.main_segment.sub_tasks["render"].average_time()
+        );
+    }
+
+    #[test]
+    fn paused_durations_are_not_averaged() {
+        let mut profiler = CycleProfiler::new(4);
+        for _ in 0..4 {
+            let mut main_segment = profiler.main_segment.time();
+            let _guard = main_segment.task("fast").time();
+        }
+        let average = profiler.main_segment.average_time();
+        let task_average = profiler.main_segment.sub_tasks["fast"].average_time();
+        assert!(average < 0.1);
+
+        profiler.pause();
+        {
+            let mut main_segment = profiler.main_segment.time();
+            let mut fast = main_segment.task("fast").time();
+            // Tasks first timed while paused are paused too.
+            let _new_task = fast.task("new").time();
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        profiler.resume();
+
+        assert_eq!(profiler.main_segment.ticks, 4);
+        assert_eq!(profiler.main_segment.average_time(), average);
+        let fast = &profiler.main_segment.sub_tasks["fast"];
+        assert_eq!(fast.average_time(), task_average);
+        assert_eq!(fast.sub_tasks["new"].ticks, 0);
+        assert!(!fast.sub_tasks["new"].paused);
+    }
+
+    #[test]
+    fn tasks_from_every_thread_are_shown() {
+        let profiler = ThreadProfiler::new(4);
+        std::thread::scope(|scope| {
+            for name in &["first", "second"] {
+                let profiler = &profiler;
+                std::thread::Builder::new()
+                    .name(name.to_string())
+                    .spawn_scoped(scope, move || {
+                        let guard = profiler.time("typeset");
+                        let _nested = guard.task("shape");
+                    })
+                    .unwrap();
+            }
+        });
+
+        let snapshot = profiler.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert!(snapshot[0].thread.starts_with("first"));
+        assert!(snapshot[1].thread.starts_with("second"));
+        for thread in &snapshot {
+            let names = thread
+                .segments
+                .iter()
+                .map(|segment| (segment.name, segment.depth))
+                .collect::<Vec<_>>();
+            assert_eq!(names, vec![("typeset", 0), ("shape", 1)]);
+        }
+        let display = profiler.to_string();
+        assert!(display.contains("first") && display.contains("second"));
+    }
+
+    #[test]
+    fn averages_only_include_real_intervals() {
+        let start = Instant::now();
+        let mut stopwatch = InterpolatedStopwatch::starting_at(100, start);
+        assert_eq!(stopwatch.average_time(), Duration::from_secs(0));
+        for &millis in &[10, 30, 60] {
+            stopwatch.tick_at(start + Duration::from_millis(millis));
+        }
+        assert_eq!(stopwatch.average_time(), Duration::from_millis(20));
+
+        // Once the buffer is full, the oldest intervals are forgotten.
+        let mut stopwatch = InterpolatedStopwatch::starting_at(3, start);
+        for &millis in &[100, 110, 120, 130] {
+            stopwatch.tick_at(start + Duration::from_millis(millis));
+        }
+        assert_eq!(stopwatch.average_time(), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn exponential_smoothing_weights_recent_intervals() {
+        let start = Instant::now();
+        let mut stopwatch = InterpolatedStopwatch::starting_at(100, start);
+        stopwatch.set_smoothing(Smoothing::Exponential(0.5));
+        for &millis in &[10, 20, 60] {
+            stopwatch.tick_at(start + Duration::from_millis(millis));
+        }
+        // 10, then 0.5 * 10 + 0.5 * 10, then 0.5 * 40 + 0.5 * 10.
+        let average = stopwatch.average_time().as_secs_f64();
+        assert!((average - 0.025).abs() < 1e-9);
+    }
+
+    #[test]
+    fn time_spent_paused_is_not_counted_by_the_stopwatch() {
+        let start = Instant::now();
+        let mut stopwatch = InterpolatedStopwatch::new(2);
+        stopwatch.tick_at(start);
+        stopwatch.tick_at(start + Duration::from_millis(10));
+
+        stopwatch.paused = true;
+        assert_eq!(
+            stopwatch.tick_at(start + Duration::from_millis(500)),
+            Duration::from_secs(0)
+        );
+        stopwatch.paused = false;
+        stopwatch.skip(Duration::from_millis(500));
+
+        let time = stopwatch.tick_at(start + Duration::from_millis(520));
+        assert_eq!(time, Duration::from_millis(10));
     }
 }
@@ -13,6 +13,58 @@ pub struct TextureAtlas {
     pub frames: HashMap<String, TextureRegionInformation>,
 }
 
+impl TextureAtlas {
+    /// Returns the texture coordinates of the named frame as `[u_min, v_min, u_max, v_max]`, where `(0, 0)` is the top left
+    /// of the atlas and `(1, 1)` is the bottom right. Returns `None` if there is no such frame.
+    ///
+    /// The rectangle covers the packed pixels. If the frame was rotated, the image lies on its side within this rectangle:
+    /// the top left of the original image is at `(u_max, v_min)`, and its top right is at `(u_max, v_max)`.
+    pub fn uv_rect(&self, name: &str) -> Option<[f32; 4]> {
+        let frame = self.frames.get(name)?.frame;
+        let (width, height) = (self.width as f32, self.height as f32);
+        Some([
+            frame.x as f32 / width,
+            frame.y as f32 / height,
+            (frame.x + frame.w) as f32 / width,
+            (frame.y + frame.h) as f32 / height,
+        ])
+    }
+
+    /// Adds every frame of another atlas to this one, as if the other atlas's image had been copied into this atlas's image
+    /// with its top left corner at `offset`. The atlas grows to fit the other atlas if necessary. Copying the images
+    /// themselves is up to the caller.
+    ///
+    /// If any frame names are in both atlases, nothing is merged, and the duplicated names are returned in sorted order.
+    pub fn merge(&mut self, other: &TextureAtlas, offset: (u32, u32)) -> Result<(), MergeError> {
+        let mut duplicates: Vec<_> = other
+            .frames
+            .keys()
+            .filter(|name| self.frames.contains_key(*name))
+            .cloned()
+            .collect();
+        if !duplicates.is_empty() {
+            duplicates.sort();
+            return Err(MergeError::DuplicateFrames(duplicates));
+        }
+
+        self.width = self.width.max(offset.0 + other.width);
+        self.height = self.height.max(offset.1 + other.height);
+        for (name, info) in &other.frames {
+            let mut info = *info;
+            info.frame.x += offset.0;
+            info.frame.y += offset.1;
+            self.frames.insert(name.clone(), info);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum MergeError {
+    /// Both atlases contained frames with these names.
+    DuplicateFrames(Vec<String>),
+}
+
 /// Roughly corresponds to [texture_packer::Frame].
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct TextureRegionInformation {
@@ -55,3 +107,75 @@ pub struct Rect {
     /// Height of the rectangle.
     pub h: u32,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(x: u32, y: u32, w: u32, h: u32, rotated: bool) -> TextureRegionInformation {
+        let (source_w, source_h) = if rotated { (h, w) } else { (w, h) };
+        TextureRegionInformation {
+            frame: Rect { x, y, w, h },
+            rotated,
+            trimmed: false,
+            source: Rect {
+                x: 0,
+                y: 0,
+                w: source_w,
+                h: source_h,
+            },
+        }
+    }
+
+    #[test]
+    fn uv_rects_are_fractions_of_the_atlas() {
+        let mut frames = HashMap::new();
+        frames.insert("button.png".to_string(), region(64, 32, 32, 16, false));
+        // A 16x32 image that was packed on its side.
+        frames.insert("caret.png".to_string(), region(0, 128, 32, 16, true));
+        let atlas = TextureAtlas {
+            width: 256,
+            height: 512,
+            frames,
+        };
+
+        assert_eq!(
+            atlas.uv_rect("button.png"),
+            Some([0.25, 0.0625, 0.375, 0.09375])
+        );
+        assert_eq!(
+            atlas.uv_rect("caret.png"),
+            Some([0.0, 0.25, 0.125, 0.28125])
+        );
+        assert_eq!(atlas.uv_rect("missing.png"), None);
+    }
+
+    #[test]
+    fn merging_translates_frames_and_rejects_duplicates() {
+        let atlas = |names: &[&str]| TextureAtlas {
+            width: 64,
+            height: 64,
+            frames: names
+                .iter()
+                .map(|name| (name.to_string(), region(8, 16, 32, 32, false)))
+                .collect(),
+        };
+        let mut base = atlas(&["grass.png", "stone.png"]);
+        base.merge(&atlas(&["sword.png"]), (64, 0)).unwrap();
+
+        assert_eq!((base.width, base.height), (128, 64));
+        let sword = base.frames["sword.png"];
+        assert_eq!((sword.frame.x, sword.frame.y), (72, 16));
+        assert_eq!((sword.source.x, sword.source.y), (0, 0));
+        assert_eq!(base.frames["grass.png"].frame.x, 8);
+
+        match base.merge(&atlas(&["stone.png", "sword.png", "shield.png"]), (0, 64)) {
+            Err(MergeError::DuplicateFrames(names)) => {
+                assert_eq!(names, ["stone.png", "sword.png"])
+            }
+            Ok(()) => panic!("duplicate frames should not be merged"),
+        }
+        assert!(!base.frames.contains_key("shield.png"));
+        assert_eq!(base.height, 64);
+    }
+}